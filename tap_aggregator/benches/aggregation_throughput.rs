@@ -0,0 +1,64 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Measures the `aggregate_receipts` throughput of an aggregator running locally.
+
+use std::collections::HashSet;
+
+use alloy_primitives::Address;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use ethers_signers::{LocalWallet, Signer};
+use jsonrpsee::http_client::HttpClientBuilder;
+use rand::rngs::OsRng;
+use tap_aggregator::{bench::generate_receipts, client::AggregatorClient, server};
+use tap_core::{ethers_compat::convert_address, tap_eip712_domain};
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let wallet = LocalWallet::new(&mut OsRng);
+    let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+    let allocation_id = Address::from([0xabu8; 20]);
+
+    let (handle, local_addr) = runtime
+        .block_on(server::run_server(
+            0,
+            wallet.clone(),
+            HashSet::from([convert_address(wallet.address())]),
+            domain_separator.clone(),
+            10 * 1024 * 1024,
+            100 * 1024,
+            32,
+            8,
+            false,
+        ))
+        .unwrap();
+    let client = runtime
+        .block_on(AggregatorClient::connect(
+            HttpClientBuilder::default()
+                .build(format!("http://127.0.0.1:{}", local_addr.port()))
+                .unwrap(),
+        ))
+        .unwrap();
+
+    let mut group = c.benchmark_group("Aggregator throughput");
+    for num_receipts in [10, 100, 1000] {
+        let receipts =
+            generate_receipts(0, &domain_separator, &wallet, allocation_id, num_receipts).unwrap();
+        group.throughput(Throughput::Elements(num_receipts as u64));
+        group.bench_function(format!("Aggregate {} receipts", num_receipts), |b| {
+            b.to_async(&runtime).iter(|| async {
+                client
+                    .aggregate_receipts(black_box(&receipts), None)
+                    .await
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    handle.stop().unwrap();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -1,25 +1,38 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::{hash_set, HashSet};
+use std::{
+    collections::{hash_set, BTreeMap, HashSet},
+    time::Duration,
+};
 
 use alloy_primitives::Address;
-use alloy_sol_types::{Eip712Domain, SolStruct};
+use alloy_sol_types::Eip712Domain;
 use anyhow::{bail, Ok, Result};
-use ethers_core::types::Signature;
 use ethers_signers::LocalWallet;
 
 use tap_core::{
-    rav::ReceiptAggregateVoucher, receipt::Receipt, signed_message::EIP712SignedMessage,
+    rav::ReceiptAggregateVoucher,
+    receipt::Receipt,
+    signed_message::{EIP712SignedMessage, Eip712Message, Signature},
 };
 
+/// Checks the receipts and aggregates them into a RAV signed by `wallet`.
+///
+/// With `require_sorted`, the receipts must be sorted by timestamp, such that clients already
+/// ordering their storage get unsorted batches rejected early, before any signature is recovered.
 pub fn check_and_aggregate_receipts(
     domain_separator: &Eip712Domain,
     receipts: &[EIP712SignedMessage<Receipt>],
     previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
     wallet: &LocalWallet,
     accepted_addresses: &HashSet<Address>,
+    require_sorted: bool,
 ) -> Result<EIP712SignedMessage<ReceiptAggregateVoucher>> {
+    if require_sorted {
+        check_receipts_sorted(receipts)?;
+    }
+
     check_signatures_unique(receipts)?;
 
     // Check that the receipts are signed by an accepted signer address
@@ -71,7 +84,171 @@ pub fn check_and_aggregate_receipts(
     Ok(EIP712SignedMessage::new(domain_separator, rav, wallet)?)
 }
 
-fn check_signature_is_from_one_of_addresses<M: SolStruct>(
+/// Splits the receipts whose signer is one of the `allowed_senders` from the others, such that
+/// only the former get aggregated. Returns the allowed receipts along with the indices of the
+/// excluded ones, in `receipts` order.
+///
+/// Fails if a receipt's signer is not one of the `accepted_addresses`, allowed or not.
+pub fn partition_allowed_receipts(
+    domain_separator: &Eip712Domain,
+    receipts: Vec<EIP712SignedMessage<Receipt>>,
+    accepted_addresses: &HashSet<Address>,
+    allowed_senders: &HashSet<Address>,
+) -> Result<(Vec<EIP712SignedMessage<Receipt>>, Vec<usize>)> {
+    let mut allowed_receipts = Vec::with_capacity(receipts.len());
+    let mut excluded = Vec::new();
+    for (index, receipt) in receipts.into_iter().enumerate() {
+        let signer = receipt.recover_signer(domain_separator)?;
+        if !accepted_addresses.contains(&signer) {
+            bail!(tap_core::Error::InvalidRecoveredSigner { address: signer });
+        }
+        if allowed_senders.contains(&signer) {
+            allowed_receipts.push(receipt);
+        } else {
+            excluded.push(index);
+        }
+    }
+    Ok((allowed_receipts, excluded))
+}
+
+/// Groups the receipts by (sender, allocation id) and aggregates each group into its own RAV,
+/// so that escrow from different senders is never mixed into a single RAV.
+///
+/// The returned RAVs are ordered by sender address, then by allocation id.
+///
+/// A `previous_rav` cannot be attributed to a sender, so it is only accepted when all the
+/// receipts belong to a single (sender, allocation id) pair.
+///
+/// See [`check_and_aggregate_receipts`] for `require_sorted`.
+pub fn check_and_aggregate_receipts_by_sender(
+    domain_separator: &Eip712Domain,
+    receipts: &[EIP712SignedMessage<Receipt>],
+    previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    wallet: &LocalWallet,
+    accepted_addresses: &HashSet<Address>,
+    require_sorted: bool,
+) -> Result<Vec<EIP712SignedMessage<ReceiptAggregateVoucher>>> {
+    if require_sorted {
+        check_receipts_sorted(receipts)?;
+    }
+
+    check_signatures_unique(receipts)?;
+
+    // Group the receipts by recovered signer and allocation id
+    let mut receipts_by_sender: BTreeMap<(Address, Address), Vec<EIP712SignedMessage<Receipt>>> =
+        BTreeMap::new();
+    for receipt in receipts.iter() {
+        let sender = receipt.recover_signer(domain_separator)?;
+        receipts_by_sender
+            .entry((sender, receipt.message.allocation_id))
+            .or_default()
+            .push(receipt.clone());
+    }
+
+    if receipts_by_sender.is_empty() {
+        return Err(tap_core::Error::NoValidReceiptsForRAVRequest.into());
+    }
+
+    if previous_rav.is_some() && receipts_by_sender.len() > 1 {
+        return Err(tap_core::Error::PreviousRavForMultipleSenders.into());
+    }
+
+    receipts_by_sender
+        .into_values()
+        .map(|sender_receipts| {
+            check_and_aggregate_receipts(
+                domain_separator,
+                &sender_receipts,
+                previous_rav.clone(),
+                wallet,
+                accepted_addresses,
+                // Already checked, and grouping preserves the order
+                false,
+            )
+        })
+        .collect()
+}
+
+/// Groups the receipts by time bucket, `timestamp_ns / bucket`, and aggregates each bucket into its
+/// own RAV, ordered by bucket.
+///
+/// Each RAV builds on the RAV of the previous bucket, the first one on `previous_rav`, so the RAVs
+/// form a chain whose last RAV is the one [`check_and_aggregate_receipts`] would return for all the
+/// receipts. Buckets without receipts don't get a RAV.
+///
+/// See [`check_and_aggregate_receipts`] for `require_sorted`.
+pub fn check_and_aggregate_receipts_by_bucket(
+    domain_separator: &Eip712Domain,
+    receipts: &[EIP712SignedMessage<Receipt>],
+    previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    wallet: &LocalWallet,
+    accepted_addresses: &HashSet<Address>,
+    require_sorted: bool,
+    bucket: Duration,
+) -> Result<Vec<EIP712SignedMessage<ReceiptAggregateVoucher>>> {
+    let bucket_ns = u64::try_from(bucket.as_nanos()).unwrap_or(u64::MAX);
+    if bucket_ns == 0 {
+        bail!("The bucket duration must be greater than zero");
+    }
+
+    if require_sorted {
+        check_receipts_sorted(receipts)?;
+    }
+
+    check_signatures_unique(receipts)?;
+
+    let mut receipts_by_bucket: BTreeMap<u64, Vec<EIP712SignedMessage<Receipt>>> = BTreeMap::new();
+    for receipt in receipts.iter() {
+        receipts_by_bucket
+            .entry(receipt.message.timestamp_ns.as_nanos() / bucket_ns)
+            .or_default()
+            .push(receipt.clone());
+    }
+
+    if receipts_by_bucket.is_empty() {
+        return Err(tap_core::Error::NoValidReceiptsForRAVRequest.into());
+    }
+
+    let mut ravs = Vec::with_capacity(receipts_by_bucket.len());
+    let mut previous_rav = previous_rav;
+    for bucket_receipts in receipts_by_bucket.into_values() {
+        let rav = check_and_aggregate_receipts(
+            domain_separator,
+            &bucket_receipts,
+            previous_rav,
+            wallet,
+            accepted_addresses,
+            // Already checked, and grouping preserves the order
+            false,
+        )?;
+        previous_rav = Some(rav.clone());
+        ravs.push(rav);
+    }
+    Ok(ravs)
+}
+
+/// Returns, for each receipt, whether its signature recovers to one of the `accepted_addresses`.
+///
+/// Nothing is aggregated nor signed, so this can be used to verify receipts on behalf of clients.
+pub fn verify_receipts(
+    domain_separator: &Eip712Domain,
+    receipts: &[EIP712SignedMessage<Receipt>],
+    accepted_addresses: &HashSet<Address>,
+) -> Vec<bool> {
+    receipts
+        .iter()
+        .map(|receipt| {
+            check_signature_is_from_one_of_addresses(
+                receipt.clone(),
+                domain_separator,
+                accepted_addresses,
+            )
+            .is_ok()
+        })
+        .collect()
+}
+
+fn check_signature_is_from_one_of_addresses<M: Eip712Message>(
     message: EIP712SignedMessage<M>,
     domain_separator: &Eip712Domain,
     accepted_addresses: &HashSet<Address>,
@@ -109,6 +286,24 @@ fn check_signatures_unique(receipts: &[EIP712SignedMessage<Receipt>]) -> Result<
     Ok(())
 }
 
+fn check_receipts_sorted(receipts: &[EIP712SignedMessage<Receipt>]) -> Result<()> {
+    for (index, pair) in receipts.windows(2).enumerate() {
+        let (previous_ts, receipt_ts) = (
+            pair[0].message.timestamp_ns.as_nanos(),
+            pair[1].message.timestamp_ns.as_nanos(),
+        );
+        if receipt_ts < previous_ts {
+            return Err(tap_core::Error::UnsortedReceipts {
+                index: index + 1,
+                previous_ts,
+                receipt_ts,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
 fn check_receipt_timestamps(
     receipts: &[EIP712SignedMessage<Receipt>],
     previous_rav: Option<&EIP712SignedMessage<ReceiptAggregateVoucher>>,
@@ -116,10 +311,10 @@ fn check_receipt_timestamps(
     if let Some(previous_rav) = &previous_rav {
         for receipt in receipts.iter() {
             let receipt = &receipt.message;
-            if previous_rav.message.timestampNs >= receipt.timestamp_ns {
+            if previous_rav.message.timestamp() >= receipt.timestamp_ns {
                 return Err(tap_core::Error::ReceiptTimestampLowerThanRav {
                     rav_ts: previous_rav.message.timestampNs,
-                    receipt_ts: receipt.timestamp_ns,
+                    receipt_ts: receipt.timestamp_ns.as_nanos(),
                 }
                 .into());
             }
@@ -131,7 +326,9 @@ fn check_receipt_timestamps(
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::str::FromStr;
+    use std::time::Duration;
 
     use alloy_primitives::Address;
     use alloy_sol_types::Eip712Domain;
@@ -139,7 +336,10 @@ mod tests {
     use rstest::*;
 
     use crate::aggregator;
-    use tap_core::{receipt::Receipt, signed_message::EIP712SignedMessage, tap_eip712_domain};
+    use tap_core::{
+        ethers_compat::convert_address, rav::ReceiptAggregateVoucher, receipt::Receipt,
+        signed_message::EIP712SignedMessage, tap_eip712_domain, timestamp::TimestampNs,
+    };
 
     #[fixture]
     fn keys() -> (LocalWallet, Address) {
@@ -147,8 +347,8 @@ mod tests {
             "1ab42cc412b618bdea3a599e3c9bae199ebf030895b039e9db1e30dafb12b727",
         )
         .unwrap();
-        let address: [u8; 20] = wallet.address().into();
-        (wallet, address.into())
+        let address = convert_address(wallet.address());
+        (wallet, address)
     }
 
     #[fixture]
@@ -232,9 +432,11 @@ mod tests {
                     &domain_separator,
                     Receipt {
                         allocation_id: allocation_ids[0],
-                        timestamp_ns: i,
+                        timestamp_ns: TimestampNs::from_nanos(i),
                         nonce: 0,
                         value: 42,
+                        metadata: None,
+                        parent: None,
                     },
                     &keys.0,
                 )
@@ -352,4 +554,334 @@ mod tests {
 
         assert!(res.is_ok());
     }
+
+    #[rstest]
+    #[test]
+    /// Test that a batch mixing receipts from two senders produces one RAV per sender
+    fn check_and_aggregate_receipts_by_sender_two_senders(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let other_wallet = LocalWallet::from_str(
+            "2ab42cc412b618bdea3a599e3c9bae199ebf030895b039e9db1e30dafb12b727",
+        )
+        .unwrap();
+        let other_address: Address = convert_address(other_wallet.address());
+
+        let receipts = vec![
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_ids[0], 42).unwrap(),
+                &keys.0,
+            )
+            .unwrap(),
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_ids[0], 43).unwrap(),
+                &other_wallet,
+            )
+            .unwrap(),
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_ids[0], 44).unwrap(),
+                &keys.0,
+            )
+            .unwrap(),
+        ];
+
+        let accepted_addresses = HashSet::from([keys.1, other_address]);
+        let ravs = aggregator::check_and_aggregate_receipts_by_sender(
+            &domain_separator,
+            &receipts,
+            None,
+            &keys.0,
+            &accepted_addresses,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(ravs.len(), 2);
+        let mut values = ravs
+            .iter()
+            .map(|rav| rav.message.valueAggregate)
+            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, vec![43, 42 + 44]);
+
+        // A single previous RAV is ambiguous when there are several senders
+        let previous_rav = ravs[0].clone();
+        assert!(aggregator::check_and_aggregate_receipts_by_sender(
+            &domain_separator,
+            &receipts,
+            Some(previous_rav),
+            &keys.0,
+            &accepted_addresses,
+            false,
+        )
+        .is_err());
+    }
+
+    #[rstest]
+    #[test]
+    /// Test that the receipts of a sender that is not allowed are excluded from the RAV, while
+    /// the receipts of a sender that is not accepted still fail the aggregation
+    fn check_and_aggregate_receipts_disallowed_sender(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let disallowed_wallet = LocalWallet::from_str(
+            "2ab42cc412b618bdea3a599e3c9bae199ebf030895b039e9db1e30dafb12b727",
+        )
+        .unwrap();
+        let disallowed_address: Address = convert_address(disallowed_wallet.address());
+
+        let allowed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        let disallowed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 43).unwrap(),
+            &disallowed_wallet,
+        )
+        .unwrap();
+
+        let accepted_addresses = HashSet::from([keys.1, disallowed_address]);
+        let allowed_senders = HashSet::from([keys.1]);
+
+        // Only the allowed sender's receipts are kept, the others are reported as excluded
+        let (allowed_receipts, excluded) = aggregator::partition_allowed_receipts(
+            &domain_separator,
+            vec![disallowed_receipt.clone(), allowed_receipt.clone()],
+            &accepted_addresses,
+            &allowed_senders,
+        )
+        .unwrap();
+        assert_eq!(allowed_receipts, vec![allowed_receipt.clone()]);
+        assert_eq!(excluded, vec![0]);
+
+        // Nothing is left to aggregate when all the receipts are excluded
+        let (allowed_receipts, _) = aggregator::partition_allowed_receipts(
+            &domain_separator,
+            vec![disallowed_receipt.clone()],
+            &accepted_addresses,
+            &allowed_senders,
+        )
+        .unwrap();
+        assert!(aggregator::check_and_aggregate_receipts(
+            &domain_separator,
+            &allowed_receipts,
+            None,
+            &keys.0,
+            &accepted_addresses,
+            false,
+        )
+        .is_err());
+
+        // A signer that is not accepted fails the aggregation, whether allowed or not
+        let res = aggregator::partition_allowed_receipts(
+            &domain_separator,
+            vec![allowed_receipt, disallowed_receipt],
+            &HashSet::from([keys.1]),
+            &accepted_addresses,
+        );
+        assert!(matches!(
+            res.unwrap_err().downcast::<tap_core::Error>(),
+            Ok(tap_core::Error::InvalidRecoveredSigner { address }) if address == disallowed_address
+        ));
+    }
+
+    #[rstest]
+    #[test]
+    fn check_and_aggregate_receipts_require_sorted(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+        #[values(true, false)] require_sorted: bool,
+    ) {
+        let mut receipts = [10, 20, 20, 30]
+            .into_iter()
+            .map(|timestamp_ns| {
+                EIP712SignedMessage::new(
+                    &domain_separator,
+                    Receipt {
+                        allocation_id: allocation_ids[0],
+                        timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                        nonce: timestamp_ns,
+                        value: 42,
+                        metadata: None,
+                        parent: None,
+                    },
+                    &keys.0,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let accepted_addresses = HashSet::from([keys.1]);
+
+        // Sorted receipts are aggregated either way, equal timestamps being allowed
+        let rav = aggregator::check_and_aggregate_receipts(
+            &domain_separator,
+            &receipts,
+            None,
+            &keys.0,
+            &accepted_addresses,
+            require_sorted,
+        )
+        .unwrap();
+        assert_eq!(rav.message.timestampNs, 30);
+
+        // Unsorted receipts are only rejected when sorting is required
+        receipts.swap(2, 3);
+        let res = aggregator::check_and_aggregate_receipts(
+            &domain_separator,
+            &receipts,
+            None,
+            &keys.0,
+            &accepted_addresses,
+            require_sorted,
+        );
+        if require_sorted {
+            assert!(matches!(
+                res.unwrap_err().downcast::<tap_core::Error>(),
+                Ok(tap_core::Error::UnsortedReceipts {
+                    index: 3,
+                    previous_ts: 30,
+                    receipt_ts: 20,
+                })
+            ));
+        } else {
+            assert_eq!(res.unwrap().message.timestampNs, 30);
+        }
+    }
+
+    #[rstest]
+    #[test]
+    /// Test that receipts spanning three hourly buckets are aggregated into three chained RAVs
+    fn check_and_aggregate_receipts_by_bucket_hourly(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let hour_ns = 3_600_000_000_000u64;
+        let receipts = [
+            (hour_ns, 1),
+            (hour_ns + 10, 2),
+            (2 * hour_ns + 5, 4),
+            (4 * hour_ns - 1, 8),
+            (4 * hour_ns - 1, 16),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(nonce, (timestamp_ns, value))| {
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt {
+                    allocation_id: allocation_ids[0],
+                    timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                    nonce: nonce as u64,
+                    value,
+                    metadata: None,
+                    parent: None,
+                },
+                &keys.0,
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+        let accepted_addresses = HashSet::from([keys.1]);
+
+        let ravs = aggregator::check_and_aggregate_receipts_by_bucket(
+            &domain_separator,
+            &receipts,
+            None,
+            &keys.0,
+            &accepted_addresses,
+            true,
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+
+        let ravs = ravs
+            .iter()
+            .map(|rav| (rav.message.timestampNs, rav.message.valueAggregate))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ravs,
+            vec![
+                (hour_ns + 10, 3),
+                (2 * hour_ns + 5, 7),
+                (4 * hour_ns - 1, 31)
+            ]
+        );
+
+        // The last RAV is the one of the whole batch
+        let rav = aggregator::check_and_aggregate_receipts(
+            &domain_separator,
+            &receipts,
+            None,
+            &keys.0,
+            &accepted_addresses,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            ravs.last(),
+            Some(&(rav.message.timestampNs, rav.message.valueAggregate))
+        );
+
+        assert!(aggregator::check_and_aggregate_receipts_by_bucket(
+            &domain_separator,
+            &receipts,
+            None,
+            &keys.0,
+            &accepted_addresses,
+            true,
+            Duration::ZERO,
+        )
+        .is_err());
+    }
+
+    #[rstest]
+    #[test]
+    /// Test that the RAV hash computed by the receiver is the one signed by the aggregator
+    fn aggregated_rav_signing_hash(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let receipts = (40..45)
+            .map(|value| {
+                EIP712SignedMessage::new(
+                    &domain_separator,
+                    Receipt::new(allocation_ids[0], value).unwrap(),
+                    &keys.0,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let signed_rav = aggregator::check_and_aggregate_receipts(
+            &domain_separator,
+            &receipts,
+            None,
+            &keys.0,
+            &HashSet::from([keys.1]),
+            false,
+        )
+        .unwrap();
+
+        // Computed on the receiver side, without looking at the aggregator's response
+        let expected_rav =
+            ReceiptAggregateVoucher::aggregate_receipts(allocation_ids[0], &receipts, None)
+                .unwrap();
+        let expected_hash: [u8; 32] = expected_rav.eip712_signing_hash(&domain_separator).into();
+
+        let recovered_address = signed_rav.signature.recover(expected_hash).unwrap();
+        assert_eq!(recovered_address, keys.1);
+    }
 }
@@ -24,6 +24,9 @@ use strum::{self, IntoEnumIterator};
 pub enum TapRpcApiVersion {
     #[strum(serialize = "0.0")]
     V0_0,
+    /// `aggregate_receipts` returns one RAV per (sender, allocation id) instead of a single RAV.
+    #[strum(serialize = "0.1")]
+    V0_1,
 }
 
 // We implement our own Serialize and Deserialize traits for `TapRpcApiVersion` because
@@ -60,12 +63,16 @@ pub static TAP_RPC_API_VERSIONS_DEPRECATED: &[TapRpcApiVersion] = &[];
 pub struct TapRpcApiVersionsInfo {
     pub versions_supported: Vec<TapRpcApiVersion>,
     pub versions_deprecated: Vec<TapRpcApiVersion>,
+    /// Prefix of the method names, when the server was started with one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method_prefix: Option<String>,
 }
 
 pub fn tap_rpc_api_versions_info() -> TapRpcApiVersionsInfo {
     TapRpcApiVersionsInfo {
         versions_supported: TapRpcApiVersion::iter().collect::<Vec<_>>(),
         versions_deprecated: TAP_RPC_API_VERSIONS_DEPRECATED.to_vec(),
+        method_prefix: None,
     }
 }
 
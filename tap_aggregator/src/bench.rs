@@ -0,0 +1,126 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers to measure the throughput of a running aggregator, e.g. for capacity planning or to
+//! compare versions. They are also used by the `aggregation_throughput` criterion benchmark.
+
+use std::time::Instant;
+
+use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
+use anyhow::{ensure, Result};
+use ethers_signers::LocalWallet;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tap_core::{receipt::Receipt, signed_message::EIP712SignedMessage, timestamp::TimestampNs};
+
+use crate::client::AggregatorClient;
+
+/// Generates `num_receipts` valid receipts of `allocation_id` signed by `wallet`, with values and
+/// nonces drawn from a PRNG seeded with `seed`, such that the same seed always yields the same
+/// receipts. The receipts have strictly increasing timestamps, such that they can be aggregated in
+/// consecutive batches.
+pub fn generate_receipts(
+    seed: u64,
+    domain_separator: &Eip712Domain,
+    wallet: &LocalWallet,
+    allocation_id: Address,
+    num_receipts: usize,
+) -> Result<Vec<EIP712SignedMessage<Receipt>>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (1..=num_receipts as u64)
+        .map(|timestamp_ns| {
+            let receipt = Receipt {
+                allocation_id,
+                timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                nonce: rng.gen(),
+                value: rng.gen_range(1..1_000_000),
+                metadata: None,
+                parent: None,
+            };
+            Ok(EIP712SignedMessage::new(domain_separator, receipt, wallet)?)
+        })
+        .collect()
+}
+
+/// Aggregates `receipts` through `client` in consecutive batches of `batch_size` receipts, each
+/// building on the previous RAV, as a receiver would. Returns the throughput in receipts per second.
+///
+/// The batches are sent one after the other, so this measures the throughput seen by a single
+/// receiver, including the network roundtrips.
+pub async fn measure_throughput(
+    client: &AggregatorClient,
+    receipts: &[EIP712SignedMessage<Receipt>],
+    batch_size: usize,
+) -> Result<f64> {
+    ensure!(batch_size > 0, "The batch size must be positive");
+
+    let start = Instant::now();
+    let mut previous_rav = None;
+    for batch in receipts.chunks(batch_size) {
+        // The receipts share one sender, hence a single RAV whatever the API version
+        previous_rav = client.aggregate_receipts(batch, previous_rav).await?.pop();
+    }
+    Ok(receipts.len() as f64 / start.elapsed().as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ethers_signers::{coins_bip39::English, MnemonicBuilder, Signer};
+    use jsonrpsee::http_client::HttpClientBuilder;
+    use tap_core::{ethers_compat::convert_address, tap_eip712_domain};
+
+    use super::*;
+    use crate::server;
+
+    #[tokio::test]
+    async fn measure_throughput_of_running_aggregator() {
+        let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()
+            .unwrap();
+        let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+        let allocation_id = Address::from([0xabu8; 20]);
+
+        // The same seed yields the same receipts
+        let receipts =
+            generate_receipts(42, &domain_separator, &wallet, allocation_id, 50).unwrap();
+        assert_eq!(
+            receipts,
+            generate_receipts(42, &domain_separator, &wallet, allocation_id, 50).unwrap()
+        );
+        assert_ne!(
+            receipts,
+            generate_receipts(43, &domain_separator, &wallet, allocation_id, 50).unwrap()
+        );
+
+        let (handle, local_addr) = server::run_server(
+            0,
+            wallet.clone(),
+            HashSet::from([convert_address(wallet.address())]),
+            domain_separator,
+            100 * 1024,
+            100 * 1024,
+            1,
+            1,
+            false,
+        )
+        .await
+        .unwrap();
+        let client = AggregatorClient::connect(
+            HttpClientBuilder::default()
+                .build(format!("http://127.0.0.1:{}", local_addr.port()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let throughput = measure_throughput(&client, &receipts, 10).await.unwrap();
+        assert!(throughput > 0.0);
+        assert!(measure_throughput(&client, &receipts, 0).await.is_err());
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client side of the TAP aggregator JSON-RPC API.
+//!
+//! [`AggregatorClient`] negotiates the API version with the aggregator once, when connecting, and
+//! then uses that pinned version for all subsequent calls. Deprecation warnings returned by the
+//! aggregator are forwarded to the logger instead of being silently dropped.
+
+use std::str::FromStr;
+
+use alloy_primitives::Bytes;
+use anyhow::{anyhow, Result};
+use jsonrpsee::{core::client::ClientT, http_client::HttpClient, rpc_params};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use tap_core::{
+    rav::ReceiptAggregateVoucher, receipt::Receipt, signed_message::EIP712SignedMessage,
+};
+
+use crate::{
+    api_versioning::TapRpcApiVersion,
+    compact::encode_receipts,
+    jsonrpsee_helpers::{JsonRpcResponse, JsonRpcWarning, RawJsonRpcResponse},
+    server::AggregatedRavs,
+};
+
+/// Versions advertised by the aggregator.
+///
+/// Kept as strings (unlike [`crate::api_versioning::TapRpcApiVersionsInfo`]) so that a newer
+/// aggregator advertising versions unknown to this client can still be negotiated with.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ServerApiVersions {
+    versions_supported: Vec<String>,
+    versions_deprecated: Vec<String>,
+}
+
+/// JSON-RPC client for the TAP aggregator, pinned to an API version supported by both ends.
+pub struct AggregatorClient {
+    client: HttpClient,
+    api_version: TapRpcApiVersion,
+}
+
+impl AggregatorClient {
+    /// Queries the aggregator's `api_versions` and pins the highest version supported by both the
+    /// aggregator and this client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or if there is no mutually supported version.
+    pub async fn connect(client: HttpClient) -> Result<Self> {
+        let server_versions: JsonRpcResponse<ServerApiVersions> =
+            client.request("api_versions", rpc_params!()).await?;
+        let server_versions = server_versions.data;
+
+        let api_version =
+            select_api_version(&server_versions.versions_supported).ok_or_else(|| {
+                anyhow!(
+                    "No mutually supported API version. Aggregator supports {:?}.",
+                    server_versions.versions_supported
+                )
+            })?;
+
+        if server_versions
+            .versions_deprecated
+            .contains(&api_version.to_string())
+        {
+            warn!(
+                "The aggregator API version {} will be deprecated. Please upgrade this client.",
+                api_version
+            );
+        }
+
+        Ok(Self {
+            client,
+            api_version,
+        })
+    }
+
+    /// The API version pinned when connecting.
+    pub fn api_version(&self) -> &TapRpcApiVersion {
+        &self.api_version
+    }
+
+    /// Returns the aggregator's current time, as a Unix Epoch timestamp in nanoseconds, e.g. to
+    /// measure the drift between the local clock and the aggregator's.
+    pub async fn server_time(&self) -> Result<u64> {
+        let response: JsonRpcResponse<u64> =
+            self.client.request("server_time", rpc_params!()).await?;
+        Ok(response.data)
+    }
+
+    /// Calls `aggregate_receipts` with the pinned API version, logging any warnings returned.
+    /// Returns one RAV per (sender, allocation id) from API version 0.1, and a single RAV before.
+    ///
+    /// The returned RAVs are checked against the response checksum, see
+    /// [`JsonRpcResponse::verify_checksum`].
+    pub async fn aggregate_receipts(
+        &self,
+        receipts: &[EIP712SignedMessage<Receipt>],
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    ) -> Result<Vec<EIP712SignedMessage<ReceiptAggregateVoucher>>> {
+        let response: RawJsonRpcResponse = self
+            .client
+            .request(
+                "aggregate_receipts",
+                rpc_params!(self.api_version.to_string(), receipts, previous_rav),
+            )
+            .await?;
+        let response = response.verify_checksum::<AggregatedRavs>()?;
+        log_warnings(response.warnings.as_deref());
+        Ok(response.data.into_vec())
+    }
+
+    /// Same as [`AggregatorClient::aggregate_receipts`], sending the receipts in the compact binary
+    /// format of [`crate::compact`] through `aggregate_receipts_compact`, e.g. for large batches.
+    pub async fn aggregate_receipts_compact(
+        &self,
+        receipts: &[EIP712SignedMessage<Receipt>],
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    ) -> Result<Vec<EIP712SignedMessage<ReceiptAggregateVoucher>>> {
+        let receipts = Bytes::from(encode_receipts(receipts)?);
+        let response: RawJsonRpcResponse = self
+            .client
+            .request(
+                "aggregate_receipts_compact",
+                rpc_params!(self.api_version.to_string(), receipts, previous_rav),
+            )
+            .await?;
+        let response = response.verify_checksum::<AggregatedRavs>()?;
+        log_warnings(response.warnings.as_deref());
+        Ok(response.data.into_vec())
+    }
+}
+
+/// Returns the highest version known to this client that is part of `server_supported`.
+/// Relies on `TapRpcApiVersion` variants being declared in increasing order.
+fn select_api_version(server_supported: &[String]) -> Option<TapRpcApiVersion> {
+    TapRpcApiVersion::iter()
+        .filter(|version| {
+            server_supported
+                .iter()
+                .filter_map(|v| TapRpcApiVersion::from_str(v).ok())
+                .any(|v| v == *version)
+        })
+        .last()
+}
+
+fn log_warnings(warnings: Option<&[JsonRpcWarning]>) {
+    for warning in warnings.unwrap_or_default() {
+        warn!("Aggregator warning: {}", warning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonrpsee::{
+        http_client::HttpClientBuilder, proc_macros::rpc, server::ServerBuilder,
+        server::ServerHandle,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::jsonrpsee_helpers::JsonRpcResult;
+
+    #[rpc(server)]
+    trait MockAggregator {
+        #[method(name = "api_versions")]
+        fn api_versions(&self) -> JsonRpcResult<Value>;
+    }
+
+    struct MockAggregatorImpl {
+        versions: Value,
+    }
+
+    impl MockAggregatorServer for MockAggregatorImpl {
+        fn api_versions(&self) -> JsonRpcResult<Value> {
+            Ok(JsonRpcResponse::ok(self.versions.clone()))
+        }
+    }
+
+    async fn run_mock_aggregator(versions: Value) -> (ServerHandle, HttpClient) {
+        let server = ServerBuilder::new()
+            .http_only()
+            .build("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server
+            .start(MockAggregatorImpl { versions }.into_rpc())
+            .unwrap();
+        let client = HttpClientBuilder::default()
+            .build(format!("http://127.0.0.1:{}", addr.port()))
+            .unwrap();
+        (handle, client)
+    }
+
+    #[tokio::test]
+    async fn connect_pins_highest_mutual_version() {
+        // The aggregator advertises a version unknown to this client, which must be skipped.
+        let (handle, client) = run_mock_aggregator(json!({
+            "versions_supported": ["0.0", "99.0"],
+            "versions_deprecated": ["0.0"],
+        }))
+        .await;
+
+        let client = AggregatorClient::connect(client).await.unwrap();
+        assert_eq!(*client.api_version(), TapRpcApiVersion::V0_0);
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
+    #[tokio::test]
+    async fn connect_fails_without_mutual_version() {
+        let (handle, client) = run_mock_aggregator(json!({
+            "versions_supported": ["99.0"],
+            "versions_deprecated": [],
+        }))
+        .await;
+
+        assert!(AggregatorClient::connect(client).await.is_err());
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+}
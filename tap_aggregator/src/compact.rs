@@ -0,0 +1,283 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compact binary encoding of the receipts of a RAV request, used by `aggregate_receipts_compact`
+//! to keep large batches well below the HTTP request size limit.
+//!
+//! The receipts are laid out column by column, after a header holding each distinct allocation id
+//! once, such that receipts refer to their allocation id by index. Absent metadata and parents are
+//! reduced to a single flag byte. All integers are little-endian:
+//!
+//! - format version (`u8`), number of allocation ids (`u32`), then the allocation ids (20 bytes each)
+//! - number of receipts (`u32`)
+//! - allocation id indexes (`u32` each)
+//! - timestamps (`u64` each), then nonces (`u64` each), then values (`u128` each)
+//! - metadata, each as a `0` byte if absent, or a `1` byte followed by the 32 bytes
+//! - parents, encoded as the metadata
+//! - signatures, each as `r` and `s` (32 bytes each, big-endian) followed by `v` (`u64`)
+//!
+//! Only secp256k1 signatures are supported.
+
+use alloy_primitives::{Address, FixedBytes, U256};
+use anyhow::{anyhow, bail, ensure, Result};
+use tap_core::{
+    receipt::Receipt,
+    signed_message::{EIP712SignedMessage, Signature, SignatureScheme},
+    timestamp::TimestampNs,
+};
+
+const FORMAT_VERSION: u8 = 1;
+
+const NO_WORD: u8 = 0;
+const FULL_WORD: u8 = 1;
+
+/// Encodes `receipts` in the compact binary format, see the [module documentation](self).
+///
+/// # Errors
+///
+/// Returns an error if a receipt is not signed with secp256k1, or if there are more than
+/// `u32::MAX` receipts.
+pub fn encode_receipts(receipts: &[EIP712SignedMessage<Receipt>]) -> Result<Vec<u8>> {
+    let receipts_count = u32::try_from(receipts.len())
+        .map_err(|_| anyhow!("Too many receipts to encode: {}", receipts.len()))?;
+
+    let mut allocation_ids: Vec<Address> = vec![];
+    let mut allocation_indexes = Vec::with_capacity(receipts.len());
+    for receipt in receipts {
+        ensure!(
+            receipt.scheme == SignatureScheme::Secp256k1,
+            "Only secp256k1 signatures can be encoded, found {:?}",
+            receipt.scheme
+        );
+        let allocation_id = receipt.message.allocation_id;
+        let index = match allocation_ids.iter().position(|id| *id == allocation_id) {
+            Some(index) => index,
+            None => {
+                allocation_ids.push(allocation_id);
+                allocation_ids.len() - 1
+            }
+        };
+        // There can't be more distinct allocation ids than receipts
+        allocation_indexes.push(index as u32);
+    }
+
+    let mut bytes = vec![FORMAT_VERSION];
+    bytes.extend_from_slice(&(allocation_ids.len() as u32).to_le_bytes());
+    for allocation_id in &allocation_ids {
+        bytes.extend_from_slice(allocation_id.as_slice());
+    }
+    bytes.extend_from_slice(&receipts_count.to_le_bytes());
+    for index in allocation_indexes {
+        bytes.extend_from_slice(&index.to_le_bytes());
+    }
+    for receipt in receipts {
+        bytes.extend_from_slice(&receipt.message.timestamp_ns.as_nanos().to_le_bytes());
+    }
+    for receipt in receipts {
+        bytes.extend_from_slice(&receipt.message.nonce.to_le_bytes());
+    }
+    for receipt in receipts {
+        bytes.extend_from_slice(&receipt.message.value.to_le_bytes());
+    }
+    for receipt in receipts {
+        push_word(&mut bytes, receipt.message.metadata.as_ref());
+    }
+    for receipt in receipts {
+        push_word(&mut bytes, receipt.message.parent.as_ref());
+    }
+    for receipt in receipts {
+        bytes.extend_from_slice(&receipt.signature.r.to_be_bytes::<32>());
+        bytes.extend_from_slice(&receipt.signature.s.to_be_bytes::<32>());
+        bytes.extend_from_slice(&receipt.signature.v.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Decodes receipts encoded with [`encode_receipts`], reconstructing the exact signed receipts.
+///
+/// # Errors
+///
+/// Returns an error if `bytes` is not a valid encoding of receipts in a supported format version.
+pub fn decode_receipts(bytes: &[u8]) -> Result<Vec<EIP712SignedMessage<Receipt>>> {
+    let mut reader = Reader(bytes);
+
+    let version = reader.array::<1>()?[0];
+    ensure!(
+        version == FORMAT_VERSION,
+        "Unsupported compact receipts format version {version}"
+    );
+    let allocation_ids = (0..reader.u32()?)
+        .map(|_| Ok(Address::from(reader.array::<20>()?)))
+        .collect::<Result<Vec<_>>>()?;
+    let receipts_count = reader.u32()?;
+
+    let allocation_ids = (0..receipts_count)
+        .map(|_| {
+            let index = reader.u32()?;
+            allocation_ids
+                .get(index as usize)
+                .copied()
+                .ok_or_else(|| anyhow!("Invalid allocation id index {index}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let timestamps = (0..receipts_count)
+        .map(|_| reader.u64())
+        .collect::<Result<Vec<_>>>()?;
+    let nonces = (0..receipts_count)
+        .map(|_| reader.u64())
+        .collect::<Result<Vec<_>>>()?;
+    let values = (0..receipts_count)
+        .map(|_| Ok(u128::from_le_bytes(reader.array()?)))
+        .collect::<Result<Vec<_>>>()?;
+    let metadata = (0..receipts_count)
+        .map(|_| reader.word())
+        .collect::<Result<Vec<_>>>()?;
+    let parents = (0..receipts_count)
+        .map(|_| reader.word())
+        .collect::<Result<Vec<_>>>()?;
+    let signatures = (0..receipts_count)
+        .map(|_| {
+            Ok(Signature {
+                r: U256::from_be_bytes(reader.array::<32>()?),
+                s: U256::from_be_bytes(reader.array::<32>()?),
+                v: reader.u64()?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(
+        reader.0.is_empty(),
+        "{} trailing bytes after the receipts",
+        reader.0.len()
+    );
+
+    let receipts = allocation_ids
+        .into_iter()
+        .zip(timestamps)
+        .zip(nonces)
+        .zip(values)
+        .zip(metadata)
+        .zip(parents)
+        .zip(signatures)
+        .map(
+            |((((((allocation_id, timestamp_ns), nonce), value), metadata), parent), signature)| {
+                EIP712SignedMessage {
+                    message: Receipt {
+                        allocation_id,
+                        timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                        nonce,
+                        value,
+                        metadata,
+                        parent,
+                    },
+                    signature,
+                    scheme: SignatureScheme::Secp256k1,
+                }
+            },
+        )
+        .collect();
+    Ok(receipts)
+}
+
+/// Pushes `word` as a flag byte, followed by the word if any.
+fn push_word(bytes: &mut Vec<u8>, word: Option<&FixedBytes<32>>) {
+    match word {
+        None => bytes.push(NO_WORD),
+        Some(word) => {
+            bytes.push(FULL_WORD);
+            bytes.extend_from_slice(word.as_slice());
+        }
+    }
+}
+
+/// Consumes `bytes` from the front.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        ensure!(self.0.len() >= N, "Unexpected end of the compact receipts");
+        let (head, tail) = self.0.split_at(N);
+        self.0 = tail;
+        Ok(head.try_into()?)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    /// Reads a word pushed by [`push_word`].
+    fn word(&mut self) -> Result<Option<FixedBytes<32>>> {
+        match self.array::<1>()?[0] {
+            NO_WORD => Ok(None),
+            FULL_WORD => Ok(Some(FixedBytes::from(self.array::<32>()?))),
+            flag => bail!("Invalid word flag {flag}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers_signers::{coins_bip39::English, LocalWallet, MnemonicBuilder};
+    use tap_core::tap_eip712_domain;
+
+    use super::*;
+    use crate::bench::generate_receipts;
+
+    #[test]
+    fn compact_receipts_roundtrip_smaller_than_json() {
+        let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()
+            .unwrap();
+        let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+
+        let mut receipts = generate_receipts(
+            42,
+            &domain_separator,
+            &wallet,
+            Address::from([0xabu8; 20]),
+            100,
+        )
+        .unwrap();
+        receipts.extend(
+            generate_receipts(
+                43,
+                &domain_separator,
+                &wallet,
+                Address::from([0xcdu8; 20]),
+                100,
+            )
+            .unwrap(),
+        );
+        receipts[0] = EIP712SignedMessage::new(
+            &domain_separator,
+            receipts[0]
+                .message
+                .clone()
+                .with_metadata([0x01; 32])
+                .with_parent(receipts[1].unique_hash()),
+            &wallet,
+        )
+        .unwrap();
+
+        let compact = encode_receipts(&receipts).unwrap();
+        assert_eq!(decode_receipts(&compact).unwrap(), receipts);
+
+        let json = serde_json::to_vec(&receipts).unwrap();
+        assert!(
+            compact.len() * 2 < json.len(),
+            "compact: {} bytes, JSON: {} bytes",
+            compact.len(),
+            json.len()
+        );
+
+        // Truncated or trailing bytes are rejected
+        assert!(decode_receipts(&compact[..compact.len() - 1]).is_err());
+        let mut trailing = compact.clone();
+        trailing.push(0);
+        assert!(decode_receipts(&trailing).is_err());
+    }
+}
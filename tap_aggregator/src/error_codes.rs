@@ -13,7 +13,6 @@
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum JsonRpcErrorCode {
     /// -32000 -- Generic error.
-    #[allow(dead_code)]
     Generic = -32000,
     /// -32001 -- Invalid API version.
     InvalidVersion = -32001,
@@ -32,4 +31,6 @@ pub enum JsonRpcWarningCode {
     Generic = -32050,
     /// -32051 -- Requested API version is deprecated.
     DeprecatedVersion = -32051,
+    /// -32052 -- Receipts were excluded from the RAV, as their signer is not an allowed sender.
+    ExcludedReceipts = -32052,
 }
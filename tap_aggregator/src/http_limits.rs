@@ -0,0 +1,251 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! HTTP body size limits of the aggregator that can be changed while the server runs, see
+//! [`crate::server::ServerConfig::with_http_size_limits`].
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Result};
+use hyper::body::HttpBody;
+use tower::{Layer, Service};
+
+/// JSON-RPC error code of a response that exceeds the size limit, same as jsonrpsee's.
+const OVERSIZED_RESPONSE_CODE: i32 = -32702;
+
+/// Handle on the request and response body size limits of a running server.
+///
+/// The limits are read on each HTTP request, so changes apply to the next requests. They can't be
+/// raised above the hard caps given on creation, which are the limits the server itself is built
+/// with. WebSocket messages are only bounded by the hard caps.
+#[derive(Debug, Clone)]
+pub struct HttpSizeLimits {
+    request_limit: Arc<AtomicU32>,
+    response_limit: Arc<AtomicU32>,
+    max_request_limit: u32,
+    max_response_limit: u32,
+}
+
+impl HttpSizeLimits {
+    /// Creates the limits, in bytes, with the hard caps they can't be raised above.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a limit is above its hard cap
+    pub fn new(
+        request_limit: u32,
+        response_limit: u32,
+        max_request_limit: u32,
+        max_response_limit: u32,
+    ) -> Result<Self> {
+        let limits = Self {
+            request_limit: Arc::new(AtomicU32::new(0)),
+            response_limit: Arc::new(AtomicU32::new(0)),
+            max_request_limit,
+            max_response_limit,
+        };
+        limits.set_request_limit(request_limit)?;
+        limits.set_response_limit(response_limit)?;
+        Ok(limits)
+    }
+
+    pub fn request_limit(&self) -> u32 {
+        self.request_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn response_limit(&self) -> u32 {
+        self.response_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn max_request_limit(&self) -> u32 {
+        self.max_request_limit
+    }
+
+    pub fn max_response_limit(&self) -> u32 {
+        self.max_response_limit
+    }
+
+    /// Sets the request body size limit, in bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the limit unchanged, if `limit` is above the hard cap
+    pub fn set_request_limit(&self, limit: u32) -> Result<()> {
+        if limit > self.max_request_limit {
+            bail!(
+                "Request size limit {} is above the hard cap of {}",
+                limit,
+                self.max_request_limit
+            );
+        }
+        self.request_limit.store(limit, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sets the response body size limit, in bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, leaving the limit unchanged, if `limit` is above the hard cap
+    pub fn set_response_limit(&self, limit: u32) -> Result<()> {
+        if limit > self.max_response_limit {
+            bail!(
+                "Response size limit {} is above the hard cap of {}",
+                limit,
+                self.max_response_limit
+            );
+        }
+        self.response_limit.store(limit, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl<S> Layer<S> for HttpSizeLimits {
+    type Service = HttpSizeLimitsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HttpSizeLimitsService {
+            inner,
+            limits: self.clone(),
+        }
+    }
+}
+
+/// Service enforcing [`HttpSizeLimits`]. Requests above the limit are answered with HTTP 413, and
+/// responses above the limit are replaced with a JSON-RPC error, as jsonrpsee does for its own
+/// limits.
+#[derive(Clone)]
+pub struct HttpSizeLimitsService<S> {
+    inner: S,
+    limits: HttpSizeLimits,
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+impl<S> Service<hyper::Request<hyper::Body>> for HttpSizeLimitsService<S>
+where
+    S: Service<hyper::Request<hyper::Body>, Response = hyper::Response<hyper::Body>>
+        + Clone
+        + Send
+        + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = hyper::Response<hyper::Body>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        // The body is read before calling the inner service, so the service made ready by
+        // `poll_ready` is taken along and a clone is left for the next request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limits = self.limits.clone();
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+            let Some(body) = read_body(body, limits.request_limit()).await? else {
+                return Ok(hyper::Response::builder()
+                    .status(hyper::StatusCode::PAYLOAD_TOO_LARGE)
+                    .body(hyper::Body::empty())?);
+            };
+            let id = request_id(&body);
+
+            let response = inner
+                .call(hyper::Request::from_parts(parts, hyper::Body::from(body)))
+                .await
+                .map_err(Into::<BoxError>::into)?;
+
+            let (parts, body) = response.into_parts();
+            let response_limit = limits.response_limit();
+            match read_body(body, response_limit).await? {
+                Some(body) => Ok(hyper::Response::from_parts(parts, hyper::Body::from(body))),
+                None => Ok(hyper::Response::builder()
+                    .status(parts.status)
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(hyper::Body::from(
+                        serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "error": {
+                                "code": OVERSIZED_RESPONSE_CODE,
+                                "message": "Response is too big",
+                                "data": format!("Exceeded max limit of {}", response_limit),
+                            },
+                            "id": id,
+                        })
+                        .to_string(),
+                    ))?),
+            }
+        })
+    }
+}
+
+/// Returns the id of the JSON-RPC request in `body`, or `null` if there is none, e.g. for a batch.
+fn request_id(body: &[u8]) -> serde_json::Value {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|request| request.get("id").cloned())
+        .unwrap_or_default()
+}
+
+/// Reads `body` in full, or returns `None` as soon as it exceeds `limit` bytes.
+async fn read_body(mut body: hyper::Body, limit: u32) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > limit as usize {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::body::to_bytes;
+    use serde_json::{json, Value};
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn oversized_response_keeps_request_id() {
+        let limits = HttpSizeLimits::new(1024, 16, 1024, 1024).unwrap();
+        let service = limits.layer(service_fn(|_request: hyper::Request<hyper::Body>| async {
+            Ok::<_, BoxError>(hyper::Response::new(hyper::Body::from(vec![b'0'; 32])))
+        }));
+
+        let request = |id: Value| {
+            hyper::Request::new(hyper::Body::from(
+                json!({"jsonrpc": "2.0", "id": id, "method": "server_time"}).to_string(),
+            ))
+        };
+
+        // The oversized response is replaced by an error answering the same request
+        let response = service.clone().oneshot(request(json!(7))).await.unwrap();
+        let response: Value =
+            serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(response["id"], json!(7));
+        assert_eq!(response["error"]["code"], json!(OVERSIZED_RESPONSE_CODE));
+
+        // Responses within the raised limit go through
+        limits.set_response_limit(32).unwrap();
+        let response = service.oneshot(request(json!("abc"))).await.unwrap();
+        assert_eq!(
+            to_bytes(response.into_body()).await.unwrap(),
+            vec![b'0'; 32]
+        );
+    }
+}
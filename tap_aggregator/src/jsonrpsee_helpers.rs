@@ -1,10 +1,11 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use alloy_primitives::{keccak256, B256};
+use anyhow::{anyhow, Result};
 use jsonrpsee::core::Serialize;
-use serde::Deserialize;
-use serde_json::value::Value;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::value::{RawValue, Value};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct JsonRpcWarning {
@@ -19,10 +20,17 @@ pub struct JsonRpcResponse<T: Serialize> {
     pub data: T,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub warnings: Option<Vec<JsonRpcWarning>>,
+    /// Keccak256 hash of the JSON serialization of `data`, as sent by the server, to detect a
+    /// truncated or corrupted response before using it, see [`JsonRpcResponse::verify_checksum`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<B256>,
 }
 
 pub type JsonRpcError = jsonrpsee::types::ErrorObjectOwned;
 pub type JsonRpcResult<T> = Result<JsonRpcResponse<T>, JsonRpcError>;
+/// Response whose `data` is kept as received, to be checked with
+/// [`JsonRpcResponse::verify_checksum`].
+pub type RawJsonRpcResponse = JsonRpcResponse<Box<RawValue>>;
 
 impl<T: Serialize> JsonRpcResponse<T> {
     /// Helper method that returns a JsonRpcResponse with the given data and no warnings.
@@ -30,6 +38,7 @@ impl<T: Serialize> JsonRpcResponse<T> {
         JsonRpcResponse {
             data,
             warnings: None,
+            checksum: None,
         }
     }
 
@@ -43,8 +52,48 @@ impl<T: Serialize> JsonRpcResponse<T> {
             } else {
                 Some(warnings)
             },
+            checksum: None,
         }
     }
+
+    /// Returns the response with the checksum of its `data`.
+    ///
+    /// The server serializes `data` with `serde_json` in compact form, so the checksum covers the
+    /// exact bytes of `data` in the response.
+    pub fn with_checksum(mut self) -> Self {
+        self.checksum = serde_json::to_vec(&self.data).ok().map(keccak256);
+        self
+    }
+}
+
+impl RawJsonRpcResponse {
+    /// Checks the bytes of `data`, as received, against the checksum of the response, then
+    /// deserializes `data`. A response damaged in transit is rejected here rather than failing
+    /// later with a confusing signature error, even when it is still valid JSON.
+    ///
+    /// Responses without a checksum, e.g. from an older aggregator, are not checked.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the checksum of `data` differs from the response's checksum, or if
+    /// `data` cannot be deserialized into `T`.
+    pub fn verify_checksum<T: Serialize + DeserializeOwned>(self) -> Result<JsonRpcResponse<T>> {
+        if let Some(expected) = self.checksum {
+            let computed = keccak256(self.data.get().as_bytes());
+            if computed != expected {
+                return Err(anyhow!(
+                    "Response checksum mismatch (expected {}, computed {}), the response was truncated or corrupted",
+                    expected,
+                    computed
+                ));
+            }
+        }
+        Ok(JsonRpcResponse {
+            data: serde_json::from_str(self.data.get())?,
+            warnings: self.warnings,
+            checksum: self.checksum,
+        })
+    }
 }
 
 impl JsonRpcWarning {
@@ -56,3 +105,9 @@ impl JsonRpcWarning {
         }
     }
 }
+
+impl std::fmt::Display for JsonRpcWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
@@ -3,7 +3,12 @@
 
 pub mod aggregator;
 pub mod api_versioning;
+#[cfg(any(test, feature = "bench"))]
+pub mod bench;
+pub mod client;
+pub mod compact;
 pub mod error_codes;
+pub mod http_limits;
 pub mod jsonrpsee_helpers;
 pub mod metrics;
 pub mod server;
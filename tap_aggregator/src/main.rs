@@ -5,30 +5,55 @@
 
 use std::borrow::Cow;
 use std::collections::HashSet;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use alloy_primitives::{Address, FixedBytes, U256};
 use alloy_sol_types::Eip712Domain;
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Result};
+use clap::{parser::ValueSource, ArgAction, ArgMatches, CommandFactory, FromArgMatches};
 use ethers_signers::{LocalWallet, Signer};
+use figment::{
+    providers::{Format, Serialized, Toml},
+    Figment,
+};
+use serde::{Deserialize, Serialize};
 use tokio::signal::unix::{signal, SignalKind};
 
 use log::{debug, info};
 use tap_aggregator::metrics;
 use tap_aggregator::server;
+use tap_core::ethers_compat::convert_address;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Serialize)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Path to a TOML file providing values for any of the other settings.
+    /// Values set through the command line or the environment take precedence over the file.
+    #[arg(long, env = "TAP_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
     /// Port to listen on for JSON-RPC requests.
-    /// Defaults to 8080.
     #[arg(long, default_value_t = 8080, env = "TAP_PORT")]
     port: u16,
 
     /// Signer private key for signing Receipt Aggregate Vouchers, as a hex string.
+    /// Either this or a keystore must be provided.
     #[arg(long, env = "TAP_PRIVATE_KEY")]
-    private_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private_key: Option<String>,
+
+    /// Path to an encrypted JSON keystore holding the signer private key, instead of
+    /// `--private-key`.
+    #[arg(long, env = "TAP_KEYSTORE_PATH")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keystore_path: Option<PathBuf>,
+
+    /// Password decrypting the keystore at `--keystore-path`.
+    #[arg(long, env = "TAP_KEYSTORE_PASSWORD")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keystore_password: Option<String>,
 
     /// Signer public keys. Not the counterpart of the signer private key. Signers that are allowed
     /// for the incoming receipts / RAV to aggregate. Useful when needing to accept receipts that
@@ -36,6 +61,7 @@ struct Args {
     /// different gateway / aggregator that use a different signing key).
     /// Expects a comma-separated list of Ethereum addresses.
     #[arg(long, env = "TAP_PUBLIC_KEYS")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     public_keys: Option<Vec<Address>>,
 
     /// Maximum request body size in bytes.
@@ -53,32 +79,127 @@ struct Args {
     #[arg(long, default_value_t = 32, env = "TAP_MAX_CONNECTIONS")]
     max_connections: u32,
 
+    /// Maximum number of receipt aggregations processed at once, across all connections.
+    /// Further aggregation requests wait for a running one to finish.
+    #[arg(long, default_value_t = 8, env = "TAP_MAX_CONCURRENT_AGGREGATIONS")]
+    max_concurrent_aggregations: u32,
+
+    /// Reject aggregation requests whose receipts are not sorted by timestamp.
+    #[arg(long, default_value_t = false, action = ArgAction::Set, env = "TAP_REQUIRE_SORTED")]
+    require_sorted: bool,
+
+    /// Prefix prepended to every JSON-RPC method name (e.g. `tap_v2_` to serve
+    /// `tap_v2_aggregate_receipts`), such that several aggregator versions can share one endpoint.
+    /// Defaults to no prefix.
+    #[arg(long, env = "TAP_METHOD_PREFIX")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method_prefix: Option<String>,
+
+    /// Have identical `aggregate_receipts` requests received while one of them is being processed
+    /// share its RAV, instead of being signed once each.
+    #[arg(long, default_value_t = false, action = ArgAction::Set, env = "TAP_COALESCE_REQUESTS")]
+    coalesce_requests: bool,
+
+    /// Senders whose receipts `aggregate_receipts` aggregates, among the accepted signers. Receipts
+    /// from the other accepted signers are left out of the RAV.
+    /// Expects a comma-separated list of Ethereum addresses.
+    /// Defaults to all the accepted signers.
+    #[arg(long, env = "TAP_ALLOWED_SENDERS")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_senders: Option<Vec<Address>>,
+
     /// Metrics server port.
-    /// Defaults to 5000.
     #[arg(long, default_value_t = 5000, env = "TAP_METRICS_PORT")]
     metrics_port: u16,
 
     /// Domain name to be used for the EIP-712 domain separator.
     #[arg(long, env = "TAP_DOMAIN_NAME")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     domain_name: Option<String>,
 
     /// Domain version to be used for the EIP-712 domain separator.
     #[arg(long, env = "TAP_DOMAIN_VERSION")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     domain_version: Option<String>,
 
     /// Domain chain ID to be used for the EIP-712 domain separator.
     #[arg(long, env = "TAP_DOMAIN_CHAIN_ID")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     domain_chain_id: Option<String>,
 
     /// Domain verifying contract to be used for the EIP-712 domain separator.
     #[arg(long, env = "TAP_DOMAIN_VERIFYING_CONTRACT")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     domain_verifying_contract: Option<Address>,
 
     /// Domain salt to be used for the EIP-712 domain separator.
     #[arg(long, env = "TAP_DOMAIN_SALT")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain_salt: Option<String>,
+}
+
+/// Settings resolved from the command line, the environment and the optional TOML config file.
+/// See [`Args`] for the documentation of each field.
+#[derive(Deserialize, Debug, PartialEq)]
+struct Config {
+    port: u16,
+    private_key: Option<String>,
+    keystore_path: Option<PathBuf>,
+    keystore_password: Option<String>,
+    public_keys: Option<Vec<Address>>,
+    max_request_body_size: u32,
+    max_response_body_size: u32,
+    max_connections: u32,
+    max_concurrent_aggregations: u32,
+    require_sorted: bool,
+    #[serde(default)]
+    method_prefix: String,
+    coalesce_requests: bool,
+    allowed_senders: Option<Vec<Address>>,
+    metrics_port: u16,
+    domain_name: Option<String>,
+    domain_version: Option<String>,
+    domain_chain_id: Option<String>,
+    domain_verifying_contract: Option<Address>,
     domain_salt: Option<String>,
 }
 
+/// Merges the TOML config file (if any) with the command line and environment settings, the latter
+/// taking precedence. The defaults of the command line options only apply to the settings missing
+/// from the config file.
+fn load_config(matches: &ArgMatches) -> Result<Config> {
+    let args = Args::from_arg_matches(matches)?;
+    let mut figment = Figment::from(Serialized::defaults(&args));
+    if let Some(config_path) = &args.config {
+        figment = figment.merge(Toml::file(config_path));
+    }
+
+    let mut explicit_args = serde_json::to_value(&args)?;
+    if let Some(explicit_args) = explicit_args.as_object_mut() {
+        explicit_args.retain(|id, _| {
+            matches
+                .value_source(id)
+                .is_some_and(|source| source != ValueSource::DefaultValue)
+        });
+    }
+    Ok(figment
+        .merge(Serialized::defaults(explicit_args))
+        .extract()?)
+}
+
+/// Loads the signer wallet from either the private key or the keystore.
+fn create_wallet(config: &Config) -> Result<LocalWallet> {
+    match (&config.private_key, &config.keystore_path) {
+        (Some(private_key), None) => Ok(LocalWallet::from_str(private_key)?),
+        (None, Some(keystore_path)) => server::load_wallet_from_keystore(
+            keystore_path,
+            config.keystore_password.as_deref().unwrap_or_default(),
+        ),
+        (Some(_), Some(_)) => bail!("Only one of the private key and the keystore can be set"),
+        (None, None) => bail!("Either the private key or the keystore must be set"),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize the logger.
@@ -88,41 +209,42 @@ async fn main() -> Result<()> {
     // See https://github.com/paritytech/jsonrpsee/pull/922 for more info.
     tracing_subscriber::fmt::init();
 
-    let args = Args::parse();
-    debug!("Settings: {:?}", args);
+    let config = load_config(&Args::command().get_matches())?;
+    debug!("Settings: {:?}", config);
 
     // Start the metrics server.
     // We just let it gracelessly get killed at the end of main()
-    tokio::spawn(metrics::run_server(args.metrics_port));
+    tokio::spawn(metrics::run_server(config.metrics_port));
 
-    // Create a wallet from the mnemonic.
-    let wallet = LocalWallet::from_str(&args.private_key)?;
+    // Create a wallet from the private key or the keystore.
+    let wallet = create_wallet(&config)?;
 
     info!("Wallet address: {:#40x}", wallet.address());
 
     // Create the EIP-712 domain separator.
-    let domain_separator = create_eip712_domain(&args)?;
+    let domain_separator = create_eip712_domain(&config)?;
 
     // Create HashSet of *all* allowed signers
     let mut accepted_addresses: HashSet<Address> = std::collections::HashSet::new();
-    accepted_addresses.insert(wallet.address().0.into());
-    if let Some(public_keys) = &args.public_keys {
+    accepted_addresses.insert(convert_address(wallet.address()));
+    if let Some(public_keys) = &config.public_keys {
         accepted_addresses.extend(public_keys.iter().cloned());
     }
 
     // Start the JSON-RPC server.
     // This await is non-blocking
-    let (handle, _) = server::run_server(
-        args.port,
-        wallet,
-        accepted_addresses,
-        domain_separator,
-        args.max_request_body_size,
-        args.max_response_body_size,
-        args.max_connections,
-    )
-    .await?;
-    info!("Server started. Listening on port {}.", args.port);
+    let mut server_config =
+        server::ServerConfig::new(config.port, wallet, accepted_addresses, domain_separator)
+            .with_body_size_limits(config.max_request_body_size, config.max_response_body_size)
+            .with_concurrency_limits(config.max_connections, config.max_concurrent_aggregations)
+            .with_require_sorted(config.require_sorted)
+            .with_method_prefix(config.method_prefix)
+            .with_request_coalescing(config.coalesce_requests);
+    if let Some(allowed_senders) = config.allowed_senders {
+        server_config = server_config.with_allowed_senders(allowed_senders.into_iter().collect());
+    }
+    let (handle, _) = server::run_server_with_config(server_config).await?;
+    info!("Server started. Listening on port {}.", config.port);
 
     // Have tokio wait for SIGTERM or SIGINT.
     let mut signal_sigint = signal(SignalKind::interrupt())?;
@@ -143,30 +265,31 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn create_eip712_domain(args: &Args) -> Result<Eip712Domain> {
+fn create_eip712_domain(config: &Config) -> Result<Eip712Domain> {
     // Transfrom the args into the types expected by Eip712Domain::new().
 
     // Transform optional strings into optional Cow<str>.
-    let name = args.domain_name.clone().map(Cow::Owned);
-    let version = args.domain_version.clone().map(Cow::Owned);
+    let name = config.domain_name.clone().map(Cow::Owned);
+    let version = config.domain_version.clone().map(Cow::Owned);
 
     // Transform optional strings into optional U256.
-    if args.domain_chain_id.is_some() {
+    if config.domain_chain_id.is_some() {
         debug!("Parsing domain chain ID...");
     }
-    let chain_id: Option<U256> = args
+    let chain_id: Option<U256> = config
         .domain_chain_id
         .as_ref()
         .map(|s| s.parse())
         .transpose()?;
 
-    if args.domain_salt.is_some() {
+    if config.domain_salt.is_some() {
         debug!("Parsing domain salt...");
     }
-    let salt: Option<FixedBytes<32>> = args.domain_salt.as_ref().map(|s| s.parse()).transpose()?;
+    let salt: Option<FixedBytes<32>> =
+        config.domain_salt.as_ref().map(|s| s.parse()).transpose()?;
 
     // Transform optional strings into optional Address.
-    let verifying_contract: Option<Address> = args.domain_verifying_contract;
+    let verifying_contract: Option<Address> = config.domain_verifying_contract;
 
     // Create the EIP-712 domain separator.
     Ok(Eip712Domain::new(
@@ -177,3 +300,110 @@ fn create_eip712_domain(args: &Args) -> Result<Eip712Domain> {
         salt,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use alloy_primitives::{Address, U256};
+    use alloy_sol_types::Eip712Domain;
+    use clap::CommandFactory;
+    use ethers_signers::{LocalWallet, Signer};
+    use figment::Jail;
+
+    use super::{create_eip712_domain, create_wallet, load_config, Args};
+
+    #[test]
+    fn load_config_from_toml_file() {
+        Jail::expect_with(|jail| {
+            // Only the environment set up here is seen
+            jail.clear_env();
+            jail.create_file(
+                "config.toml",
+                r#"
+                port = 9090
+                private_key = "1ab42cc412b618bdea3a599e3c9bae199ebf030895b039e9db1e30dafb12b727"
+                public_keys = ["0xabababababababababababababababababababab"]
+                max_request_body_size = 1024
+                max_connections = 8
+                metrics_port = 5001
+                domain_chain_id = "1"
+                domain_verifying_contract = "0x1111111111111111111111111111111111111111"
+                "#,
+            )?;
+            jail.set_env("TAP_METRICS_PORT", 5002);
+
+            // The command line and the environment take precedence over the config file
+            let matches = Args::command().get_matches_from([
+                "tap_aggregator",
+                "--config",
+                "config.toml",
+                "--max-connections",
+                "16",
+            ]);
+            let config = load_config(&matches).unwrap();
+
+            assert_eq!(config.port, 9090);
+            assert_eq!(
+                config.private_key.as_deref(),
+                Some("1ab42cc412b618bdea3a599e3c9bae199ebf030895b039e9db1e30dafb12b727")
+            );
+            assert_eq!(
+                config.public_keys,
+                Some(vec![Address::from_str(
+                    "0xabababababababababababababababababababab"
+                )
+                .unwrap()])
+            );
+            assert_eq!(config.max_request_body_size, 1024);
+            // Not in the config file, so the default is used
+            assert_eq!(config.max_response_body_size, 100 * 1024);
+            assert_eq!(config.max_connections, 16);
+            assert_eq!(config.metrics_port, 5002);
+            assert_eq!(config.method_prefix, "");
+            assert!(!config.coalesce_requests);
+
+            let domain_separator = create_eip712_domain(&config).unwrap();
+            assert_eq!(
+                domain_separator,
+                Eip712Domain::new(
+                    None,
+                    None,
+                    Some(U256::from(1u64)),
+                    Some(Address::from([0x11u8; 20])),
+                    None
+                )
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn create_wallet_from_keystore() {
+        Jail::expect_with(|jail| {
+            jail.clear_env();
+            let (wallet, keystore_name) = LocalWallet::new_keystore(
+                jail.directory(),
+                &mut rand::thread_rng(),
+                "password",
+                None,
+            )
+            .unwrap();
+            jail.set_env("TAP_KEYSTORE_PATH", keystore_name);
+            jail.set_env("TAP_KEYSTORE_PASSWORD", "password");
+
+            let matches = Args::command().get_matches_from(["tap_aggregator"]);
+            let config = load_config(&matches).unwrap();
+            assert_eq!(create_wallet(&config).unwrap().address(), wallet.address());
+
+            // A private key can't be set along with the keystore
+            jail.set_env(
+                "TAP_PRIVATE_KEY",
+                "1ab42cc412b618bdea3a599e3c9bae199ebf030895b039e9db1e30dafb12b727",
+            );
+            let matches = Args::command().get_matches_from(["tap_aggregator"]);
+            assert!(create_wallet(&load_config(&matches).unwrap()).is_err());
+            Ok(())
+        });
+    }
+}
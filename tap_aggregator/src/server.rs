@@ -1,25 +1,51 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{collections::HashSet, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
 
-use alloy_primitives::Address;
+use alloy_primitives::{keccak256, Address, Bytes, B256};
 use alloy_sol_types::Eip712Domain;
 use anyhow::Result;
 use ethers_signers::LocalWallet;
-use jsonrpsee::{proc_macros::rpc, server::ServerBuilder, server::ServerHandle};
+use jsonrpsee::{
+    core::{async_trait, SubscriptionResult},
+    proc_macros::rpc,
+    server::{ServerBuilder, ServerHandle},
+    PendingSubscriptionSink, RpcModule, SubscriptionMessage,
+};
 use lazy_static::lazy_static;
 use prometheus::{register_counter, register_int_counter, Counter, IntCounter};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, OnceCell, Semaphore};
+use tower::{
+    layer::util::Identity,
+    util::{BoxCloneService, BoxLayer},
+    ServiceBuilder,
+};
 
-use crate::aggregator::check_and_aggregate_receipts;
+use crate::aggregator::{
+    check_and_aggregate_receipts, check_and_aggregate_receipts_by_bucket,
+    check_and_aggregate_receipts_by_sender, partition_allowed_receipts, verify_receipts,
+};
 use crate::api_versioning::{
     tap_rpc_api_versions_info, TapRpcApiVersion, TapRpcApiVersionsInfo,
     TAP_RPC_API_VERSIONS_DEPRECATED,
 };
+use crate::compact::decode_receipts;
 use crate::error_codes::{JsonRpcErrorCode, JsonRpcWarningCode};
+use crate::http_limits::HttpSizeLimits;
 use crate::jsonrpsee_helpers::{JsonRpcError, JsonRpcResponse, JsonRpcResult, JsonRpcWarning};
 use tap_core::{
-    rav::ReceiptAggregateVoucher, receipt::Receipt, signed_message::EIP712SignedMessage,
+    clock::{Clock, SystemClock},
+    rav::ReceiptAggregateVoucher,
+    receipt::Receipt,
+    signed_message::EIP712SignedMessage,
 };
 
 // Register the metrics into the global metrics registry.
@@ -67,6 +93,25 @@ lazy_static! {
     .unwrap();
 }
 
+lazy_static! {
+    /// Prefixed method names registered so far. jsonrpsee only takes static method names, so each
+    /// one is leaked once and reused by the servers started later with the same prefix.
+    static ref PREFIXED_METHOD_NAMES: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+/// Returns the static copy of `method_name`, leaking it on first use only.
+fn intern_method_name(method_name: String) -> &'static str {
+    let mut names = PREFIXED_METHOD_NAMES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(&name) = names.get(method_name.as_str()) {
+        return name;
+    }
+    let name: &'static str = Box::leak(method_name.into_boxed_str());
+    names.insert(name);
+    name
+}
+
 /// Generates the `RpcServer` trait that is used to define the JSON-RPC API.
 ///
 /// Note that because of the way the `rpc` macro works, we cannot document the RpcServer trait here.
@@ -79,21 +124,204 @@ pub trait Rpc {
     #[method(name = "api_versions")]
     fn api_versions(&self) -> JsonRpcResult<TapRpcApiVersionsInfo>;
 
-    /// Aggregates the given receipts into a receipt aggregate voucher.
+    /// Returns the server's current time, as a Unix Epoch timestamp in nanoseconds.
+    #[method(name = "server_time")]
+    fn server_time(&self) -> JsonRpcResult<u64>;
+
+    /// Aggregates the given receipts into a receipt aggregate voucher, or into one receipt
+    /// aggregate voucher per (sender, allocation id) from API version 0.1.
     /// Returns an error if the user expected API version is not supported.
     #[method(name = "aggregate_receipts")]
-    fn aggregate_receipts(
+    async fn aggregate_receipts(
+        &self,
+        api_version: String,
+        receipts: Vec<EIP712SignedMessage<Receipt>>,
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    ) -> JsonRpcResult<AggregatedRavs>;
+
+    /// Aggregates the given receipts into one receipt aggregate voucher per time bucket of
+    /// `bucket_ns` nanoseconds, each building on the previous one.
+    /// Returns an error if the user expected API version is not supported.
+    #[method(name = "aggregate_receipts_by_bucket")]
+    async fn aggregate_receipts_by_bucket(
         &self,
         api_version: String,
         receipts: Vec<EIP712SignedMessage<Receipt>>,
         previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
-    ) -> JsonRpcResult<EIP712SignedMessage<ReceiptAggregateVoucher>>;
+        bucket_ns: u64,
+    ) -> JsonRpcResult<Vec<EIP712SignedMessage<ReceiptAggregateVoucher>>>;
+
+    /// Same as `aggregate_receipts`, with the receipts in the compact binary format of
+    /// [`crate::compact`], hex encoded.
+    #[method(name = "aggregate_receipts_compact")]
+    async fn aggregate_receipts_compact(
+        &self,
+        api_version: String,
+        receipts: Bytes,
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    ) -> JsonRpcResult<AggregatedRavs>;
+
+    /// Verifies the signatures of the given receipts, without aggregating them.
+    /// Returns an error if the user expected API version is not supported.
+    #[method(name = "verify_receipts")]
+    fn verify_receipts(
+        &self,
+        api_version: String,
+        receipts: Vec<EIP712SignedMessage<Receipt>>,
+    ) -> JsonRpcResult<Vec<bool>>;
+
+    /// Streams every RAV produced by this server to the subscriber.
+    #[subscription(name = "subscribe_ravs" => "rav", unsubscribe = "unsubscribe_ravs", item = EIP712SignedMessage<ReceiptAggregateVoucher>)]
+    async fn subscribe_ravs(&self) -> SubscriptionResult;
+}
+
+/// Number of RAVs buffered for each subscriber of `subscribe_ravs`. Slower subscribers miss the oldest RAVs.
+const RAV_EVENTS_CAPACITY: usize = 128;
+
+/// RAVs returned by `aggregate_receipts`, whose shape depends on the API version.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum AggregatedRavs {
+    /// API version 0.0: a single RAV for all the receipts.
+    Single(EIP712SignedMessage<ReceiptAggregateVoucher>),
+    /// API version 0.1: one RAV per (sender, allocation id), see
+    /// [`crate::aggregator::check_and_aggregate_receipts_by_sender`].
+    PerSender(Vec<EIP712SignedMessage<ReceiptAggregateVoucher>>),
+}
+
+impl AggregatedRavs {
+    pub fn as_slice(&self) -> &[EIP712SignedMessage<ReceiptAggregateVoucher>] {
+        match self {
+            AggregatedRavs::Single(rav) => std::slice::from_ref(rav),
+            AggregatedRavs::PerSender(ravs) => ravs,
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<EIP712SignedMessage<ReceiptAggregateVoucher>> {
+        match self {
+            AggregatedRavs::Single(rav) => vec![rav],
+            AggregatedRavs::PerSender(ravs) => ravs,
+        }
+    }
 }
 
+/// Outcome of an `aggregate_receipts` call in progress, shared by the identical calls made meanwhile.
+type InFlightAggregation = Arc<OnceCell<JsonRpcResult<AggregatedRavs>>>;
+
 struct RpcImpl {
     wallet: LocalWallet,
     accepted_addresses: HashSet<Address>,
+    /// Senders whose receipts `aggregate_receipts` aggregates, see
+    /// [`ServerConfig::with_allowed_senders`].
+    allowed_senders: Option<HashSet<Address>>,
     domain_separator: Eip712Domain,
+    rav_events: broadcast::Sender<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    /// Bounds the number of aggregations running at once, whatever the number of connections.
+    aggregation_permits: Arc<Semaphore>,
+    /// Whether receipts must be sorted by timestamp, see [`crate::aggregator::check_and_aggregate_receipts`].
+    require_sorted: bool,
+    /// Prepended to every method name, see [`ServerConfig::with_method_prefix`].
+    method_prefix: String,
+    /// `aggregate_receipts` calls in progress, by hash of their parameters, if identical calls are
+    /// coalesced (see [`ServerConfig::with_request_coalescing`]).
+    in_flight_aggregations: Option<Arc<Mutex<HashMap<B256, InFlightAggregation>>>>,
+    /// Source of the time returned by `server_time`.
+    clock: Arc<dyn Clock>,
+}
+
+impl RpcImpl {
+    /// Returns the RPC module of the server, with the method prefix prepended to every method name.
+    fn into_rpc_module(self) -> Result<RpcModule<Self>> {
+        let method_prefix = self.method_prefix.clone();
+        let mut module = self.into_rpc();
+        if method_prefix.is_empty() {
+            return Ok(module);
+        }
+        let method_names = module.method_names().collect::<Vec<_>>();
+        for method_name in method_names {
+            module.register_alias(
+                intern_method_name(format!("{method_prefix}{method_name}")),
+                method_name,
+            )?;
+            // Clients find out about the prefix through the unprefixed `api_versions`
+            if method_name != "api_versions" {
+                module.remove_method(method_name);
+            }
+        }
+        Ok(module)
+    }
+
+    /// Waits until fewer than the maximum number of concurrent aggregations are running.
+    /// The aggregation slot is held until the returned permit is dropped.
+    async fn acquire_aggregation_permit(
+        &self,
+    ) -> Result<tokio::sync::SemaphorePermit<'_>, JsonRpcError> {
+        self.aggregation_permits.acquire().await.map_err(|e| {
+            jsonrpsee::types::ErrorObject::owned(
+                JsonRpcErrorCode::Generic as i32,
+                e.to_string(),
+                None::<()>,
+            )
+        })
+    }
+
+    /// Runs `aggregate` on `receipts` in an aggregation slot, records the Prometheus metrics of
+    /// the outcome, and broadcasts the RAVs returned by `aggregated_ravs` to the subscribers.
+    async fn aggregate_and_broadcast<T>(
+        &self,
+        receipts: Vec<EIP712SignedMessage<Receipt>>,
+        aggregate: impl FnOnce(Vec<EIP712SignedMessage<Receipt>>) -> JsonRpcResult<T>,
+        aggregated_ravs: fn(&T) -> &[EIP712SignedMessage<ReceiptAggregateVoucher>],
+    ) -> JsonRpcResult<T> {
+        // Excess requests are queued until an aggregation slot is free
+        let _permit = self.acquire_aggregation_permit().await?;
+
+        // Values for Prometheus metrics
+        let receipts_grt: u128 = receipts.iter().map(|r| r.message.value).sum();
+        let receipts_count: u64 = receipts.len() as u64;
+
+        match aggregate(receipts) {
+            Ok(res) => {
+                TOTAL_GRT_AGGREGATED.inc_by(receipts_grt as f64);
+                TOTAL_AGGREGATED_RECEIPTS.inc_by(receipts_count);
+                AGGREGATION_SUCCESS_COUNTER.inc();
+                // Sending only fails when there are no subscribers
+                for rav in aggregated_ravs(&res.data) {
+                    let _ = self.rav_events.send(rav.clone());
+                }
+                Ok(res)
+            }
+            Err(e) => {
+                AGGREGATION_FAILURE_COUNTER.inc();
+                Err(e)
+            }
+        }
+    }
+
+    async fn aggregate_receipts_once(
+        &self,
+        api_version: String,
+        receipts: Vec<EIP712SignedMessage<Receipt>>,
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    ) -> JsonRpcResult<AggregatedRavs> {
+        self.aggregate_and_broadcast(
+            receipts,
+            |receipts| {
+                aggregate_receipts_(
+                    api_version,
+                    &self.wallet,
+                    &self.accepted_addresses,
+                    self.allowed_senders.as_ref(),
+                    &self.domain_separator,
+                    receipts,
+                    previous_rav,
+                    self.require_sorted,
+                )
+            },
+            AggregatedRavs::as_slice,
+        )
+        .await
+    }
 }
 
 /// Helper method that checks if the given API version is supported.
@@ -126,16 +354,13 @@ fn check_api_version_deprecation(api_version: &TapRpcApiVersion) -> Option<JsonR
     }
 }
 
-fn aggregate_receipts_(
-    api_version: String,
-    wallet: &LocalWallet,
-    accepted_addresses: &HashSet<Address>,
-    domain_separator: &Eip712Domain,
-    receipts: Vec<EIP712SignedMessage<Receipt>>,
-    previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
-) -> JsonRpcResult<EIP712SignedMessage<ReceiptAggregateVoucher>> {
+/// Helper method that parses the given API version and collects its deprecation warnings.
+/// Returns an error if the API version is not supported.
+fn check_api_version(
+    api_version: &str,
+) -> Result<(TapRpcApiVersion, Vec<JsonRpcWarning>), JsonRpcError> {
     // Return an error if the API version is not supported.
-    let api_version = match parse_api_version(api_version.as_str()) {
+    let api_version = match parse_api_version(api_version) {
         Ok(v) => v,
         Err(e) => {
             VERSION_ERROR_COUNT.inc();
@@ -150,19 +375,106 @@ fn aggregate_receipts_(
         DEPRECATION_WARNING_COUNT.inc();
     }
 
+    Ok((api_version, warnings))
+}
+
+fn aggregate_receipts_(
+    api_version: String,
+    wallet: &LocalWallet,
+    accepted_addresses: &HashSet<Address>,
+    allowed_senders: Option<&HashSet<Address>>,
+    domain_separator: &Eip712Domain,
+    receipts: Vec<EIP712SignedMessage<Receipt>>,
+    previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    require_sorted: bool,
+) -> JsonRpcResult<AggregatedRavs> {
+    let (api_version, mut warnings) = check_api_version(api_version.as_str())?;
+
+    // Only the receipts of the allowed senders are aggregated, when they are set
+    let receipts = match allowed_senders {
+        Some(allowed_senders) => partition_allowed_receipts(
+            domain_separator,
+            receipts,
+            accepted_addresses,
+            allowed_senders,
+        ),
+        None => Ok((receipts, Vec::new())),
+    };
+
+    let res = receipts.and_then(|(receipts, excluded)| {
+        let ravs = match api_version {
+            TapRpcApiVersion::V0_0 => check_and_aggregate_receipts(
+                domain_separator,
+                &receipts,
+                previous_rav,
+                wallet,
+                accepted_addresses,
+                require_sorted,
+            )
+            .map(AggregatedRavs::Single),
+            TapRpcApiVersion::V0_1 => check_and_aggregate_receipts_by_sender(
+                domain_separator,
+                &receipts,
+                previous_rav,
+                wallet,
+                accepted_addresses,
+                require_sorted,
+            )
+            .map(AggregatedRavs::PerSender),
+        }?;
+        Ok((ravs, excluded))
+    });
+
+    // Handle aggregation error
+    match res {
+        Ok((res, excluded)) => {
+            if !excluded.is_empty() {
+                warnings.push(JsonRpcWarning::new(
+                    JsonRpcWarningCode::ExcludedReceipts as i32,
+                    format!(
+                        "{} receipt(s) not signed by an allowed sender were excluded from the RAV.",
+                        excluded.len()
+                    ),
+                    Some(excluded),
+                ));
+            }
+            Ok(JsonRpcResponse::warn(res, warnings).with_checksum())
+        }
+        Err(e) => Err(jsonrpsee::types::ErrorObject::owned(
+            JsonRpcErrorCode::Aggregation as i32,
+            e.to_string(),
+            None::<()>,
+        )),
+    }
+}
+
+fn aggregate_receipts_by_bucket_(
+    api_version: String,
+    wallet: &LocalWallet,
+    accepted_addresses: &HashSet<Address>,
+    domain_separator: &Eip712Domain,
+    receipts: Vec<EIP712SignedMessage<Receipt>>,
+    previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    require_sorted: bool,
+    bucket: Duration,
+) -> JsonRpcResult<Vec<EIP712SignedMessage<ReceiptAggregateVoucher>>> {
+    let (api_version, warnings) = check_api_version(api_version.as_str())?;
+
     let res = match api_version {
-        TapRpcApiVersion::V0_0 => check_and_aggregate_receipts(
+        TapRpcApiVersion::V0_0 | TapRpcApiVersion::V0_1 => check_and_aggregate_receipts_by_bucket(
             domain_separator,
             &receipts,
             previous_rav,
             wallet,
             accepted_addresses,
+            require_sorted,
+            bucket,
         ),
     };
 
     // Handle aggregation error
     match res {
-        Ok(res) => Ok(JsonRpcResponse::warn(res, warnings)),
+        Ok(res) => Ok(JsonRpcResponse::warn(res, warnings).with_checksum()),
         Err(e) => Err(jsonrpsee::types::ErrorObject::owned(
             JsonRpcErrorCode::Aggregation as i32,
             e.to_string(),
@@ -171,43 +483,328 @@ fn aggregate_receipts_(
     }
 }
 
+fn verify_receipts_(
+    api_version: String,
+    accepted_addresses: &HashSet<Address>,
+    domain_separator: &Eip712Domain,
+    receipts: Vec<EIP712SignedMessage<Receipt>>,
+) -> JsonRpcResult<Vec<bool>> {
+    let (api_version, warnings) = check_api_version(api_version.as_str())?;
+
+    let res = match api_version {
+        TapRpcApiVersion::V0_0 | TapRpcApiVersion::V0_1 => {
+            verify_receipts(domain_separator, &receipts, accepted_addresses)
+        }
+    };
+
+    Ok(JsonRpcResponse::warn(res, warnings))
+}
+
+#[async_trait]
 impl RpcServer for RpcImpl {
     fn api_versions(&self) -> JsonRpcResult<TapRpcApiVersionsInfo> {
-        Ok(JsonRpcResponse::ok(tap_rpc_api_versions_info()))
+        let mut versions_info = tap_rpc_api_versions_info();
+        if !self.method_prefix.is_empty() {
+            versions_info.method_prefix = Some(self.method_prefix.clone());
+        }
+        Ok(JsonRpcResponse::ok(versions_info))
     }
 
-    fn aggregate_receipts(
+    fn server_time(&self) -> JsonRpcResult<u64> {
+        let now_ns = self.clock.now_ns().map_err(|e| {
+            jsonrpsee::types::ErrorObject::owned(
+                JsonRpcErrorCode::Generic as i32,
+                e.to_string(),
+                None::<()>,
+            )
+        })?;
+        Ok(JsonRpcResponse::ok(now_ns))
+    }
+
+    async fn aggregate_receipts(
         &self,
         api_version: String,
         receipts: Vec<EIP712SignedMessage<Receipt>>,
         previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
-    ) -> JsonRpcResult<EIP712SignedMessage<ReceiptAggregateVoucher>> {
-        // Values for Prometheus metrics
-        let receipts_grt: u128 = receipts.iter().map(|r| r.message.value).sum();
-        let receipts_count: u64 = receipts.len() as u64;
+    ) -> JsonRpcResult<AggregatedRavs> {
+        let Some(in_flight_aggregations) = &self.in_flight_aggregations else {
+            return self
+                .aggregate_receipts_once(api_version, receipts, previous_rav)
+                .await;
+        };
+        let Ok(params) = serde_json::to_vec(&(&api_version, &receipts, &previous_rav)) else {
+            return self
+                .aggregate_receipts_once(api_version, receipts, previous_rav)
+                .await;
+        };
+        let key = keccak256(params);
+
+        // The first call aggregates, the identical calls made meanwhile wait for its outcome. The
+        // map is left consistent by every critical section, so a poisoned lock is still usable.
+        let in_flight = in_flight_aggregations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key)
+            .or_default()
+            .clone();
+        let res = in_flight
+            .get_or_init(|| self.aggregate_receipts_once(api_version, receipts, previous_rav))
+            .await
+            .clone();
+
+        // Identical calls made from now on are aggregated anew
+        let mut in_flight_aggregations = in_flight_aggregations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if matches!(in_flight_aggregations.get(&key), Some(entry) if Arc::ptr_eq(entry, &in_flight))
+        {
+            in_flight_aggregations.remove(&key);
+        }
+        res
+    }
+
+    async fn aggregate_receipts_by_bucket(
+        &self,
+        api_version: String,
+        receipts: Vec<EIP712SignedMessage<Receipt>>,
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+        bucket_ns: u64,
+    ) -> JsonRpcResult<Vec<EIP712SignedMessage<ReceiptAggregateVoucher>>> {
+        self.aggregate_and_broadcast(
+            receipts,
+            |receipts| {
+                aggregate_receipts_by_bucket_(
+                    api_version,
+                    &self.wallet,
+                    &self.accepted_addresses,
+                    &self.domain_separator,
+                    receipts,
+                    previous_rav,
+                    self.require_sorted,
+                    Duration::from_nanos(bucket_ns),
+                )
+            },
+            Vec::as_slice,
+        )
+        .await
+    }
+
+    async fn aggregate_receipts_compact(
+        &self,
+        api_version: String,
+        receipts: Bytes,
+        previous_rav: Option<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+    ) -> JsonRpcResult<AggregatedRavs> {
+        let receipts = decode_receipts(&receipts).map_err(|e| {
+            AGGREGATION_FAILURE_COUNTER.inc();
+            jsonrpsee::types::ErrorObject::owned(
+                JsonRpcErrorCode::Aggregation as i32,
+                e.to_string(),
+                None::<()>,
+            )
+        })?;
+        RpcServer::aggregate_receipts(self, api_version, receipts, previous_rav).await
+    }
 
-        match aggregate_receipts_(
+    fn verify_receipts(
+        &self,
+        api_version: String,
+        receipts: Vec<EIP712SignedMessage<Receipt>>,
+    ) -> JsonRpcResult<Vec<bool>> {
+        verify_receipts_(
             api_version,
-            &self.wallet,
             &self.accepted_addresses,
             &self.domain_separator,
             receipts,
-            previous_rav,
-        ) {
-            Ok(res) => {
-                TOTAL_GRT_AGGREGATED.inc_by(receipts_grt as f64);
-                TOTAL_AGGREGATED_RECEIPTS.inc_by(receipts_count);
-                AGGREGATION_SUCCESS_COUNTER.inc();
-                Ok(res)
-            }
-            Err(e) => {
-                AGGREGATION_FAILURE_COUNTER.inc();
-                Err(e)
+        )
+    }
+
+    async fn subscribe_ravs(&self, pending: PendingSubscriptionSink) -> SubscriptionResult {
+        let mut rav_events = self.rav_events.subscribe();
+        let sink = pending.accept().await?;
+
+        loop {
+            tokio::select! {
+                _ = sink.closed() => break,
+                rav = rav_events.recv() => match rav {
+                    Ok(rav) => sink.send(SubscriptionMessage::from_json(&rav)?).await?,
+                    // The subscriber is too slow, skip the RAVs it missed
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
             }
         }
+        Ok(())
+    }
+}
+
+/// HTTP middleware wrapping the aggregator's JSON-RPC service, as a boxed [`tower::Layer`]. Any
+/// layer with compatible request, response and error types can be boxed with [`BoxLayer::new`],
+/// e.g. for authentication, logging or tracing. See [`ServerConfig::with_middleware`].
+pub type RpcMiddleware = BoxLayer<
+    RpcService,
+    hyper::Request<hyper::Body>,
+    hyper::Response<hyper::Body>,
+    Box<dyn std::error::Error + Send + Sync + 'static>,
+>;
+
+/// The aggregator's JSON-RPC service, as wrapped by the [`RpcMiddleware`]. It can be cloned, e.g.
+/// to be called again while a previous call is still being processed.
+pub type RpcService = BoxCloneService<
+    hyper::Request<hyper::Body>,
+    hyper::Response<hyper::Body>,
+    Box<dyn std::error::Error + Send + Sync + 'static>,
+>;
+
+/// Settings of the aggregator's JSON-RPC server, see [`run_server_with_config`].
+///
+/// Created with the signing wallet and the accepted signers, every other setting starts at the
+/// default of the `tap_aggregator` binary and can be changed through the `with_*` methods.
+pub struct ServerConfig {
+    port: u16,
+    wallet: LocalWallet,
+    accepted_addresses: HashSet<Address>,
+    allowed_senders: Option<HashSet<Address>>,
+    domain_separator: Eip712Domain,
+    max_request_body_size: u32,
+    max_response_body_size: u32,
+    max_concurrent_connections: u32,
+    max_concurrent_aggregations: u32,
+    require_sorted: bool,
+    method_prefix: String,
+    coalesce_requests: bool,
+    middleware: RpcMiddleware,
+    size_limits: Option<HttpSizeLimits>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ServerConfig {
+    pub fn new(
+        port: u16,
+        wallet: LocalWallet,
+        accepted_addresses: HashSet<Address>,
+        domain_separator: Eip712Domain,
+    ) -> Self {
+        Self {
+            port,
+            wallet,
+            accepted_addresses,
+            allowed_senders: None,
+            domain_separator,
+            max_request_body_size: 10 * 1024 * 1024,
+            max_response_body_size: 100 * 1024,
+            max_concurrent_connections: 32,
+            max_concurrent_aggregations: 8,
+            require_sorted: false,
+            method_prefix: String::new(),
+            coalesce_requests: false,
+            middleware: BoxLayer::new(Identity::new()),
+            size_limits: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Same as [`ServerConfig::new`], with the signing wallet decrypted from a keystore file using
+    /// [`load_wallet_from_keystore`].
+    pub fn from_keystore(
+        port: u16,
+        keystore_path: impl AsRef<Path>,
+        password: impl AsRef<[u8]>,
+        accepted_addresses: HashSet<Address>,
+        domain_separator: Eip712Domain,
+    ) -> Result<Self> {
+        let wallet = load_wallet_from_keystore(keystore_path, password)?;
+        Ok(Self::new(
+            port,
+            wallet,
+            accepted_addresses,
+            domain_separator,
+        ))
+    }
+
+    /// Has `aggregate_receipts` leave the receipts whose signer is not one of `allowed_senders` out
+    /// of the RAV, and report their indices with a [`JsonRpcWarningCode::ExcludedReceipts`]
+    /// warning. Without it, every receipt from an accepted address is aggregated.
+    pub fn with_allowed_senders(mut self, allowed_senders: HashSet<Address>) -> Self {
+        self.allowed_senders = Some(allowed_senders);
+        self
+    }
+
+    /// Sets the maximum HTTP request and response body sizes, in bytes. Ignored with
+    /// [`ServerConfig::with_http_size_limits`].
+    pub fn with_body_size_limits(
+        mut self,
+        max_request_body_size: u32,
+        max_response_body_size: u32,
+    ) -> Self {
+        self.max_request_body_size = max_request_body_size;
+        self.max_response_body_size = max_response_body_size;
+        self
+    }
+
+    /// Sets HTTP body size limits that can be changed through `size_limits` while the server runs,
+    /// e.g. to let larger batches in without a restart. The server is built with the hard caps of
+    /// `size_limits`.
+    pub fn with_http_size_limits(mut self, size_limits: HttpSizeLimits) -> Self {
+        self.size_limits = Some(size_limits);
+        self
+    }
+
+    /// `max_concurrent_connections` caps the number of open connections, while
+    /// `max_concurrent_aggregations` caps the number of `aggregate_receipts*` calls being
+    /// processed at once, across all connections. Calls above that limit wait for a running
+    /// aggregation to finish.
+    pub fn with_concurrency_limits(
+        mut self,
+        max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
+    ) -> Self {
+        self.max_concurrent_connections = max_concurrent_connections;
+        self.max_concurrent_aggregations = max_concurrent_aggregations;
+        self
+    }
+
+    /// With `require_sorted`, aggregation requests whose receipts are not sorted by timestamp are
+    /// rejected.
+    pub fn with_require_sorted(mut self, require_sorted: bool) -> Self {
+        self.require_sorted = require_sorted;
+        self
+    }
+
+    /// Prepends `method_prefix` to every method name, e.g. `tap_v2_aggregate_receipts` for a
+    /// `tap_v2_` prefix, such that aggregators of several versions can be served behind a single
+    /// endpoint. The unprefixed names are not served, except for `api_versions`, which is served
+    /// under both names and returns the prefix. An empty prefix leaves the names unchanged.
+    pub fn with_method_prefix(mut self, method_prefix: impl Into<String>) -> Self {
+        self.method_prefix = method_prefix.into();
+        self
+    }
+
+    /// With `coalesce_requests`, identical `aggregate_receipts` calls are coalesced: a call made
+    /// while an aggregation of the same parameters is in progress waits for it and gets the same
+    /// RAV, instead of having it signed again. This spares the signer when senders retry eagerly.
+    pub fn with_request_coalescing(mut self, coalesce_requests: bool) -> Self {
+        self.coalesce_requests = coalesce_requests;
+        self
+    }
+
+    /// Replaces the system clock read by `server_time` with `clock`, e.g. to control time in tests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Has every HTTP request go through `middleware` before reaching the JSON-RPC service.
+    /// Several layers can be stacked into one with [`tower::ServiceBuilder`] before being boxed.
+    pub fn with_middleware(mut self, middleware: RpcMiddleware) -> Self {
+        self.middleware = middleware;
+        self
     }
 }
 
+/// Starts the aggregator's JSON-RPC server with the default [`ServerConfig`] settings but for the
+/// given ones. Kept for compatibility, see [`run_server_with_config`] for the other settings.
+#[allow(clippy::too_many_arguments)]
 pub async fn run_server(
     port: u16,
     wallet: LocalWallet,
@@ -216,44 +813,108 @@ pub async fn run_server(
     max_request_body_size: u32,
     max_response_body_size: u32,
     max_concurrent_connections: u32,
+    max_concurrent_aggregations: u32,
+    require_sorted: bool,
+) -> Result<(ServerHandle, std::net::SocketAddr)> {
+    run_server_with_config(
+        ServerConfig::new(port, wallet, accepted_addresses, domain_separator)
+            .with_body_size_limits(max_request_body_size, max_response_body_size)
+            .with_concurrency_limits(max_concurrent_connections, max_concurrent_aggregations)
+            .with_require_sorted(require_sorted),
+    )
+    .await
+}
+
+/// Starts the aggregator's JSON-RPC server. With HTTP size limits, HTTP requests go through the
+/// middleware before the [`HttpSizeLimits`] layer.
+pub async fn run_server_with_config(
+    config: ServerConfig,
 ) -> Result<(ServerHandle, std::net::SocketAddr)> {
+    let (max_request_body_size, max_response_body_size) = match &config.size_limits {
+        Some(size_limits) => (
+            size_limits.max_request_limit(),
+            size_limits.max_response_limit(),
+        ),
+        None => (config.max_request_body_size, config.max_response_body_size),
+    };
+
     // Setting up the JSON RPC server
     println!("Starting server...");
+    // Serves both HTTP and WebSocket, the latter being needed for subscriptions
     let server = ServerBuilder::new()
         .max_request_body_size(max_request_body_size)
         .max_response_body_size(max_response_body_size)
-        .max_connections(max_concurrent_connections)
-        .http_only()
-        .build(format!("0.0.0.0:{}", port))
+        .max_connections(config.max_concurrent_connections)
+        .set_middleware(
+            ServiceBuilder::new()
+                .layer(config.middleware)
+                .layer(BoxCloneService::layer())
+                .option_layer(config.size_limits),
+        )
+        .build(format!("0.0.0.0:{}", config.port))
         .await?;
     let addr = server.local_addr()?;
     println!("Listening on: {}", addr);
     let rpc_impl = RpcImpl {
-        wallet,
-        accepted_addresses,
-        domain_separator,
+        wallet: config.wallet,
+        accepted_addresses: config.accepted_addresses,
+        allowed_senders: config.allowed_senders,
+        domain_separator: config.domain_separator,
+        rav_events: broadcast::channel(RAV_EVENTS_CAPACITY).0,
+        aggregation_permits: Arc::new(Semaphore::new(config.max_concurrent_aggregations as usize)),
+        require_sorted: config.require_sorted,
+        method_prefix: config.method_prefix,
+        in_flight_aggregations: config.coalesce_requests.then(Default::default),
+        clock: config.clock,
     };
-    let handle = server.start(rpc_impl.into_rpc())?;
+    let handle = server.start(rpc_impl.into_rpc_module()?)?;
     Ok((handle, addr))
 }
 
+/// Decrypts the signing wallet from an encrypted JSON keystore file (as created by e.g. `geth` or
+/// [`LocalWallet::new_keystore`]), so that the private key doesn't have to be handled in clear.
+pub fn load_wallet_from_keystore(
+    keystore_path: impl AsRef<Path>,
+    password: impl AsRef<[u8]>,
+) -> Result<LocalWallet> {
+    Ok(LocalWallet::decrypt_keystore(keystore_path, password)?)
+}
+
 #[cfg(test)]
 #[allow(clippy::too_many_arguments)]
 mod tests {
     use std::collections::HashSet;
+    use std::future::Future;
+    use std::pin::Pin;
     use std::str::FromStr;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
 
-    use alloy_primitives::Address;
+    use alloy_primitives::{Address, Bytes};
     use alloy_sol_types::Eip712Domain;
     use ethers_signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
-    use jsonrpsee::{core::client::ClientT, http_client::HttpClientBuilder, rpc_params};
+    use jsonrpsee::{
+        core::client::{ClientT, SubscriptionClientT},
+        http_client::{HeaderMap, HeaderValue, HttpClientBuilder},
+        rpc_params,
+        ws_client::WsClientBuilder,
+    };
     use rand::prelude::*;
     use rand::seq::SliceRandom;
     use rstest::*;
+    use tokio::sync::{broadcast, Semaphore};
+    use tower::{util::BoxLayer, Layer, Service};
 
-    use crate::server;
+    use crate::http_limits::HttpSizeLimits;
+    use crate::jsonrpsee_helpers::RawJsonRpcResponse;
+    use crate::server::{self, RpcServer, ServerConfig};
     use tap_core::{
-        rav::ReceiptAggregateVoucher, receipt::Receipt, signed_message::EIP712SignedMessage,
+        clock::{ManualClock, SystemClock},
+        ethers_compat::convert_address,
+        rav::ReceiptAggregateVoucher,
+        receipt::Receipt,
+        signed_message::EIP712SignedMessage,
         tap_eip712_domain,
     };
 
@@ -270,14 +931,9 @@ mod tests {
          .unwrap()
          .build()
          .unwrap();
-        // Alloy library does not have feature parity with ethers library (yet) This workaround is needed to get the address
-        // to convert to an alloy Address. This will not be needed when the alloy library has wallet support.
-        let address: [u8; 20] = wallet.address().into();
+        let address = convert_address(wallet.address());
 
-        Keys {
-            wallet,
-            address: address.into(),
-        }
+        Keys { wallet, address }
     }
 
     #[fixture]
@@ -310,6 +966,11 @@ mod tests {
         1
     }
 
+    #[fixture]
+    fn max_concurrent_aggregations() -> u32 {
+        4
+    }
+
     #[rstest]
     #[tokio::test]
     async fn protocol_version(
@@ -317,19 +978,23 @@ mod tests {
         http_request_size_limit: u32,
         http_response_size_limit: u32,
         http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
     ) {
         // The keys that will be used to sign the new RAVs
         let keys_main = keys(0);
+        let clock = Arc::new(ManualClock::new(1_685_670_449_225_087_255));
 
         // Start the JSON-RPC server.
-        let (handle, local_addr) = server::run_server(
-            0,
-            keys_main.wallet,
-            HashSet::from([keys_main.address]),
-            domain_separator,
-            http_request_size_limit,
-            http_response_size_limit,
-            http_max_concurrent_connections,
+        let (handle, local_addr) = server::run_server_with_config(
+            ServerConfig::new(
+                0,
+                keys_main.wallet,
+                HashSet::from([keys_main.address]),
+                domain_separator,
+            )
+            .with_body_size_limits(http_request_size_limit, http_response_size_limit)
+            .with_concurrency_limits(http_max_concurrent_connections, max_concurrent_aggregations)
+            .with_clock(clock.clone()),
         )
         .await
         .unwrap();
@@ -343,19 +1008,374 @@ mod tests {
             .await
             .unwrap();
 
+        // The server time is read from the server's clock
+        let server_time: server::JsonRpcResponse<u64> =
+            client.request("server_time", rpc_params!()).await.unwrap();
+        assert_eq!(server_time.data, 1_685_670_449_225_087_255);
+        clock.advance(1_000);
+        let server_time: server::JsonRpcResponse<u64> =
+            client.request("server_time", rpc_params!()).await.unwrap();
+        assert_eq!(server_time.data, 1_685_670_449_225_088_255);
+
         handle.stop().unwrap();
         handle.stopped().await;
     }
 
-    #[rstest]
-    #[case::basic_rav_test (vec![45,56,34,23])]
-    #[case::rav_from_zero_valued_receipts (vec![0,0,0,0])]
-    #[tokio::test]
-    async fn signed_rav_is_valid_with_no_previous_rav(
-        domain_separator: Eip712Domain,
-        http_request_size_limit: u32,
-        http_response_size_limit: u32,
+    /// Example authentication middleware, rejecting the requests without the expected bearer token.
+    #[derive(Clone)]
+    struct BearerAuthLayer {
+        token: String,
+    }
+
+    impl<S> Layer<S> for BearerAuthLayer {
+        type Service = BearerAuth<S>;
+
+        fn layer(&self, inner: S) -> Self::Service {
+            BearerAuth {
+                inner,
+                token: self.token.clone(),
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct BearerAuth<S> {
+        inner: S,
+        token: String,
+    }
+
+    impl<S> Service<hyper::Request<hyper::Body>> for BearerAuth<S>
+    where
+        S: Service<hyper::Request<hyper::Body>, Response = hyper::Response<hyper::Body>>,
+        S::Future: Send + 'static,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+            let authorized = request
+                .headers()
+                .get(hyper::header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                == Some(format!("Bearer {}", self.token).as_str());
+            if authorized {
+                Box::pin(self.inner.call(request))
+            } else {
+                Box::pin(async {
+                    Ok(hyper::Response::builder()
+                        .status(hyper::StatusCode::UNAUTHORIZED)
+                        .body(hyper::Body::empty())
+                        .unwrap())
+                })
+            }
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn middleware_auth(
+        domain_separator: Eip712Domain,
+        http_request_size_limit: u32,
+        http_response_size_limit: u32,
+        max_concurrent_aggregations: u32,
+    ) {
+        // The keys that will be used to sign the new RAVs
+        let keys_main = keys(0);
+
+        // Start the JSON-RPC server behind the authentication middleware.
+        let (handle, local_addr) = server::run_server_with_config(
+            ServerConfig::new(
+                0,
+                keys_main.wallet,
+                HashSet::from([keys_main.address]),
+                domain_separator,
+            )
+            .with_body_size_limits(http_request_size_limit, http_response_size_limit)
+            .with_concurrency_limits(2, max_concurrent_aggregations)
+            .with_middleware(BoxLayer::new(BearerAuthLayer {
+                token: "secret".to_string(),
+            })),
+        )
+        .await
+        .unwrap();
+
+        // Requests without the token are rejected before reaching the RPC methods
+        let client = HttpClientBuilder::default()
+            .build(format!("http://127.0.0.1:{}", local_addr.port()))
+            .unwrap();
+        let res: Result<server::JsonRpcResponse<server::TapRpcApiVersionsInfo>, _> = client
+            .request("api_versions", rpc_params!(None::<()>))
+            .await;
+        assert!(res.is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        let client = HttpClientBuilder::default()
+            .set_headers(headers)
+            .build(format!("http://127.0.0.1:{}", local_addr.port()))
+            .unwrap();
+        let _: server::JsonRpcResponse<server::TapRpcApiVersionsInfo> = client
+            .request("api_versions", rpc_params!(None::<()>))
+            .await
+            .unwrap();
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn concurrent_aggregations_limit(
+        domain_separator: Eip712Domain,
+        allocation_ids: Vec<Address>,
+        max_concurrent_aggregations: u32,
+    ) {
+        let keys_main = keys(0);
+        let rpc_impl = Arc::new(server::RpcImpl {
+            wallet: keys_main.wallet.clone(),
+            accepted_addresses: HashSet::from([keys_main.address]),
+            allowed_senders: None,
+            domain_separator: domain_separator.clone(),
+            rav_events: broadcast::channel(1).0,
+            aggregation_permits: Arc::new(Semaphore::new(max_concurrent_aggregations as usize)),
+            require_sorted: false,
+            method_prefix: String::new(),
+            in_flight_aggregations: None,
+            clock: Arc::new(SystemClock),
+        });
+
+        let receipts = vec![EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys_main.wallet,
+        )
+        .unwrap()];
+
+        // Occupy every aggregation slot, as long running aggregations would
+        let permits = rpc_impl
+            .aggregation_permits
+            .acquire_many(max_concurrent_aggregations)
+            .await
+            .unwrap();
+
+        // Fire many more aggregations than there are slots
+        let aggregations = (0..4 * max_concurrent_aggregations)
+            .map(|_| {
+                let rpc_impl = rpc_impl.clone();
+                let receipts = receipts.clone();
+                tokio::spawn(async move {
+                    rpc_impl
+                        .aggregate_receipts("0.0".to_string(), receipts, None)
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // None of them may run while all the slots are taken
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(aggregations
+            .iter()
+            .all(|aggregation| !aggregation.is_finished()));
+
+        // Once the slots are freed, the queued aggregations all complete
+        drop(permits);
+        for aggregation in aggregations {
+            assert!(aggregation.await.unwrap().is_ok());
+        }
+        assert_eq!(
+            rpc_impl.aggregation_permits.available_permits(),
+            max_concurrent_aggregations as usize
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn aggregate_receipts_per_sender(
+        domain_separator: Eip712Domain,
+        allocation_ids: Vec<Address>,
+        max_concurrent_aggregations: u32,
+    ) {
+        let keys_0 = keys(0);
+        let keys_1 = keys(1);
+        let rpc_impl = server::RpcImpl {
+            wallet: keys_0.wallet.clone(),
+            accepted_addresses: HashSet::from([keys_0.address, keys_1.address]),
+            allowed_senders: None,
+            domain_separator: domain_separator.clone(),
+            rav_events: broadcast::channel(1).0,
+            aggregation_permits: Arc::new(Semaphore::new(max_concurrent_aggregations as usize)),
+            require_sorted: false,
+            method_prefix: String::new(),
+            in_flight_aggregations: None,
+            clock: Arc::new(SystemClock),
+        };
+
+        // Receipts of two senders mixed in one batch
+        let receipts = [(&keys_0, 10), (&keys_1, 20), (&keys_0, 30)]
+            .into_iter()
+            .map(|(keys, value)| {
+                EIP712SignedMessage::new(
+                    &domain_separator,
+                    Receipt::new(allocation_ids[0], value).unwrap(),
+                    &keys.wallet,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        // Version 0.0 keeps aggregating them into a single RAV
+        let res = rpc_impl
+            .aggregate_receipts("0.0".to_string(), receipts.clone(), None)
+            .await
+            .unwrap();
+        let server::AggregatedRavs::Single(rav) = res.data else {
+            panic!("Expected a single RAV");
+        };
+        assert_eq!(rav.message.valueAggregate, 60);
+
+        // Version 0.1 aggregates them into one RAV per sender
+        let res = rpc_impl
+            .aggregate_receipts("0.1".to_string(), receipts, None)
+            .await
+            .unwrap();
+        let server::AggregatedRavs::PerSender(ravs) = res.data else {
+            panic!("Expected one RAV per sender");
+        };
+        let mut values = ravs
+            .iter()
+            .map(|rav| rav.message.valueAggregate)
+            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, vec![20, 40]);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn coalescing_survives_poisoned_lock(
+        domain_separator: Eip712Domain,
+        allocation_ids: Vec<Address>,
+    ) {
+        let keys_main = keys(0);
+        let rpc_impl = Arc::new(server::RpcImpl {
+            wallet: keys_main.wallet.clone(),
+            accepted_addresses: HashSet::from([keys_main.address]),
+            allowed_senders: None,
+            domain_separator: domain_separator.clone(),
+            rav_events: broadcast::channel(1).0,
+            aggregation_permits: Arc::new(Semaphore::new(1)),
+            require_sorted: false,
+            method_prefix: String::new(),
+            in_flight_aggregations: Some(Default::default()),
+            clock: Arc::new(SystemClock),
+        });
+
+        // Poison the lock of the in-flight aggregations, as a panicking request would
+        let in_flight_aggregations = rpc_impl.in_flight_aggregations.clone().unwrap();
+        std::thread::spawn(move || {
+            let _guard = in_flight_aggregations.lock().unwrap();
+            panic!("Poisoning the lock");
+        })
+        .join()
+        .unwrap_err();
+
+        let receipts = vec![EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys_main.wallet,
+        )
+        .unwrap()];
+        assert!(rpc_impl
+            .aggregate_receipts("0.0".to_string(), receipts, None)
+            .await
+            .is_ok());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn identical_aggregations_coalesced(
+        domain_separator: Eip712Domain,
+        allocation_ids: Vec<Address>,
+    ) {
+        let keys_main = keys(0);
+        let rpc_impl = Arc::new(server::RpcImpl {
+            wallet: keys_main.wallet.clone(),
+            accepted_addresses: HashSet::from([keys_main.address]),
+            allowed_senders: None,
+            domain_separator: domain_separator.clone(),
+            rav_events: broadcast::channel(4).0,
+            aggregation_permits: Arc::new(Semaphore::new(1)),
+            require_sorted: false,
+            method_prefix: String::new(),
+            in_flight_aggregations: Some(Default::default()),
+            clock: Arc::new(SystemClock),
+        });
+        // Every signed RAV is published, which tells how many times the signer ran
+        let mut rav_events = rpc_impl.rav_events.subscribe();
+
+        let receipts = vec![EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys_main.wallet,
+        )
+        .unwrap()];
+
+        // Hold the only aggregation slot so that both calls are in flight at the same time
+        let permit = rpc_impl.aggregation_permits.acquire().await.unwrap();
+        let aggregations = (0..2)
+            .map(|_| {
+                let rpc_impl = rpc_impl.clone();
+                let receipts = receipts.clone();
+                tokio::spawn(async move {
+                    rpc_impl
+                        .aggregate_receipts("0.0".to_string(), receipts, None)
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(permit);
+
+        let mut ravs = Vec::new();
+        for aggregation in aggregations {
+            ravs.push(aggregation.await.unwrap().unwrap().data);
+        }
+        assert_eq!(ravs[0], ravs[1]);
+
+        // The signer ran once
+        assert_eq!(
+            server::AggregatedRavs::Single(rav_events.try_recv().unwrap()),
+            ravs[0]
+        );
+        assert!(rav_events.try_recv().is_err());
+
+        // Nothing is left in flight
+        assert!(rpc_impl
+            .in_flight_aggregations
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[rstest]
+    #[case::basic_rav_test (vec![45,56,34,23])]
+    #[case::rav_from_zero_valued_receipts (vec![0,0,0,0])]
+    #[tokio::test]
+    async fn signed_rav_is_valid_with_no_previous_rav(
+        domain_separator: Eip712Domain,
+        http_request_size_limit: u32,
+        http_response_size_limit: u32,
         http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
         allocation_ids: Vec<Address>,
         #[case] values: Vec<u128>,
         #[values("0.0")] api_version: &str,
@@ -380,6 +1400,8 @@ mod tests {
             http_request_size_limit,
             http_response_size_limit,
             http_max_concurrent_connections,
+            max_concurrent_aggregations,
+            false,
         )
         .await
         .unwrap();
@@ -428,6 +1450,77 @@ mod tests {
         handle.stopped().await;
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn aggregate_compact_receipts(
+        domain_separator: Eip712Domain,
+        http_request_size_limit: u32,
+        http_response_size_limit: u32,
+        http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
+        allocation_ids: Vec<Address>,
+    ) {
+        let keys_main = keys(0);
+        let (handle, local_addr) = server::run_server(
+            0,
+            keys_main.wallet.clone(),
+            HashSet::from([keys_main.address]),
+            domain_separator.clone(),
+            http_request_size_limit,
+            http_response_size_limit,
+            http_max_concurrent_connections,
+            max_concurrent_aggregations,
+            false,
+        )
+        .await
+        .unwrap();
+        let client = HttpClientBuilder::default()
+            .build(format!("http://127.0.0.1:{}", local_addr.port()))
+            .unwrap();
+
+        let receipts = [45, 56, 34, 23]
+            .into_iter()
+            .map(|value| {
+                EIP712SignedMessage::new(
+                    &domain_separator,
+                    Receipt::new(allocation_ids[0], value).unwrap(),
+                    &keys_main.wallet,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let compact_receipts = Bytes::from(crate::compact::encode_receipts(&receipts).unwrap());
+
+        let res: server::JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>> = client
+            .request(
+                "aggregate_receipts_compact",
+                rpc_params!("0.0", compact_receipts, None::<()>),
+            )
+            .await
+            .unwrap();
+        let local_rav =
+            ReceiptAggregateVoucher::aggregate_receipts(allocation_ids[0], &receipts, None)
+                .unwrap();
+        assert_eq!(res.data.message, local_rav);
+        assert_eq!(
+            res.data.recover_signer(&domain_separator).unwrap(),
+            keys_main.address
+        );
+
+        // Malformed receipts are rejected
+        let res: Result<server::JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>>, _> =
+            client
+                .request(
+                    "aggregate_receipts_compact",
+                    rpc_params!("0.0", Bytes::from(vec![0xffu8]), None::<()>),
+                )
+                .await;
+        assert!(res.is_err());
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
     #[rstest]
     #[case::basic_rav_test (vec![45,56,34,23])]
     #[case::rav_from_zero_valued_receipts (vec![0,0,0,0])]
@@ -437,6 +1530,7 @@ mod tests {
         http_request_size_limit: u32,
         http_response_size_limit: u32,
         http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
         allocation_ids: Vec<Address>,
         #[case] values: Vec<u128>,
         #[values("0.0")] api_version: &str,
@@ -461,6 +1555,8 @@ mod tests {
             http_request_size_limit,
             http_response_size_limit,
             http_max_concurrent_connections,
+            max_concurrent_aggregations,
+            false,
         )
         .await
         .unwrap();
@@ -525,6 +1621,7 @@ mod tests {
         http_request_size_limit: u32,
         http_response_size_limit: u32,
         http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
         allocation_ids: Vec<Address>,
     ) {
         // The keys that will be used to sign the new RAVs
@@ -539,6 +1636,8 @@ mod tests {
             http_request_size_limit,
             http_response_size_limit,
             http_max_concurrent_connections,
+            max_concurrent_aggregations,
+            false,
         )
         .await
         .unwrap();
@@ -607,6 +1706,7 @@ mod tests {
         domain_separator: Eip712Domain,
         http_response_size_limit: u32,
         http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
         allocation_ids: Vec<Address>,
         #[values("0.0")] api_version: &str,
     ) {
@@ -631,6 +1731,8 @@ mod tests {
             http_request_size_limit,
             http_response_size_limit,
             http_max_concurrent_connections,
+            max_concurrent_aggregations,
+            false,
         )
         .await
         .unwrap();
@@ -690,4 +1792,380 @@ mod tests {
         handle.stop().unwrap();
         handle.stopped().await;
     }
+
+    #[rstest]
+    #[tokio::test]
+    async fn request_size_limit_at_runtime(
+        domain_separator: Eip712Domain,
+        http_response_size_limit: u32,
+        http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
+        allocation_ids: Vec<Address>,
+        #[values("0.0")] api_version: &str,
+    ) {
+        // The keys that will be used to sign the new RAVs
+        let keys_main = keys(0);
+
+        // About 250 receipts fit in 100 kB, see `request_size_limit`
+        let size_limits = HttpSizeLimits::new(
+            100 * 1024,
+            http_response_size_limit,
+            200 * 1024,
+            http_response_size_limit,
+        )
+        .unwrap();
+        assert!(size_limits.set_request_limit(200 * 1024 + 1).is_err());
+
+        // Start the JSON-RPC server.
+        let (handle, local_addr) = server::run_server_with_config(
+            ServerConfig::new(
+                0,
+                keys_main.wallet.clone(),
+                HashSet::from([keys_main.address]),
+                domain_separator.clone(),
+            )
+            .with_http_size_limits(size_limits.clone())
+            .with_concurrency_limits(http_max_concurrent_connections, max_concurrent_aggregations),
+        )
+        .await
+        .unwrap();
+
+        // Start the JSON-RPC client.
+        let client = HttpClientBuilder::default()
+            .build(format!("http://127.0.0.1:{}", local_addr.port()))
+            .unwrap();
+
+        // Create receipts
+        let mut receipts = Vec::new();
+        for _ in 0..300 {
+            receipts.push(
+                EIP712SignedMessage::new(
+                    &domain_separator,
+                    Receipt::new(allocation_ids[0], u128::MAX / 1000).unwrap(),
+                    &keys_main.wallet,
+                )
+                .unwrap(),
+            );
+        }
+
+        let res: Result<
+            server::JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+            jsonrpsee::core::Error,
+        > = client
+            .request(
+                "aggregate_receipts",
+                rpc_params!(api_version, &receipts, None::<()>),
+            )
+            .await;
+        assert!(res.unwrap_err().to_string().contains("413"));
+
+        // The same request goes through once the limit is raised, without restarting the server
+        size_limits.set_request_limit(200 * 1024).unwrap();
+        let res: Result<
+            server::JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+            jsonrpsee::core::Error,
+        > = client
+            .request(
+                "aggregate_receipts",
+                rpc_params!(api_version, &receipts, None::<()>),
+            )
+            .await;
+        assert!(res.is_ok());
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn method_prefix(
+        domain_separator: Eip712Domain,
+        http_request_size_limit: u32,
+        http_response_size_limit: u32,
+        http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
+        allocation_ids: Vec<Address>,
+        #[values("0.0")] api_version: &str,
+    ) {
+        // The keys that will be used to sign the new RAVs
+        let keys_main = keys(0);
+
+        // Start the JSON-RPC server.
+        let (handle, local_addr) = server::run_server_with_config(
+            ServerConfig::new(
+                0,
+                keys_main.wallet.clone(),
+                HashSet::from([keys_main.address]),
+                domain_separator.clone(),
+            )
+            .with_body_size_limits(http_request_size_limit, http_response_size_limit)
+            .with_concurrency_limits(http_max_concurrent_connections, max_concurrent_aggregations)
+            .with_method_prefix("tap_v2_"),
+        )
+        .await
+        .unwrap();
+
+        // Start the JSON-RPC client.
+        let client = HttpClientBuilder::default()
+            .build(format!("http://127.0.0.1:{}", local_addr.port()))
+            .unwrap();
+
+        // The prefix is advertised by the (prefixed) version method
+        let versions: server::JsonRpcResponse<server::TapRpcApiVersionsInfo> = client
+            .request("tap_v2_api_versions", rpc_params!())
+            .await
+            .unwrap();
+        assert_eq!(versions.data.method_prefix.as_deref(), Some("tap_v2_"));
+
+        // As well as by its unprefixed alias, for clients that don't know the prefix yet
+        let versions: server::JsonRpcResponse<server::TapRpcApiVersionsInfo> =
+            client.request("api_versions", rpc_params!()).await.unwrap();
+        assert_eq!(versions.data.method_prefix.as_deref(), Some("tap_v2_"));
+
+        let receipts = vec![EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys_main.wallet,
+        )
+        .unwrap()];
+
+        let res: server::JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>> = client
+            .request(
+                "tap_v2_aggregate_receipts",
+                rpc_params!(api_version, &receipts, None::<()>),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.data.message.valueAggregate, 42);
+
+        // The other unprefixed names are not served
+        let res: Result<
+            server::JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>>,
+            jsonrpsee::core::Error,
+        > = client
+            .request(
+                "aggregate_receipts",
+                rpc_params!(api_version, &receipts, None::<()>),
+            )
+            .await;
+        assert!(res.is_err());
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn response_checksum(
+        domain_separator: Eip712Domain,
+        http_request_size_limit: u32,
+        http_response_size_limit: u32,
+        http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
+        allocation_ids: Vec<Address>,
+    ) {
+        let keys_main = keys(0);
+        let (handle, local_addr) = server::run_server(
+            0,
+            keys_main.wallet.clone(),
+            HashSet::from([keys_main.address]),
+            domain_separator.clone(),
+            http_request_size_limit,
+            http_response_size_limit,
+            http_max_concurrent_connections,
+            max_concurrent_aggregations,
+            false,
+        )
+        .await
+        .unwrap();
+        let client = HttpClientBuilder::default()
+            .build(format!("http://127.0.0.1:{}", local_addr.port()))
+            .unwrap();
+
+        let receipts = vec![EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys_main.wallet,
+        )
+        .unwrap()];
+        // Keep the response as sent by the server, the checksum covers its exact bytes
+        let res: Box<serde_json::value::RawValue> = client
+            .request(
+                "aggregate_receipts",
+                rpc_params!("0.0", &receipts, None::<()>),
+            )
+            .await
+            .unwrap();
+        let res = res.get().to_owned();
+
+        let intact: RawJsonRpcResponse = serde_json::from_str(&res).unwrap();
+        assert!(intact.checksum.is_some());
+        let intact = intact
+            .verify_checksum::<EIP712SignedMessage<ReceiptAggregateVoucher>>()
+            .unwrap();
+
+        // Flip a digit of the RAV timestamp, the response still parses but fails the checksum
+        let start = res.find("\"timestampNs\":").unwrap() + "\"timestampNs\":".len();
+        let digit = start + res[start..].find(|c: char| !c.is_ascii_digit()).unwrap() - 1;
+        let mut corrupted = res.into_bytes();
+        corrupted[digit] = if corrupted[digit] == b'9' {
+            b'0'
+        } else {
+            corrupted[digit] + 1
+        };
+        let corrupted: RawJsonRpcResponse = serde_json::from_slice(&corrupted).unwrap();
+        let corrupted_rav: EIP712SignedMessage<ReceiptAggregateVoucher> =
+            serde_json::from_str(corrupted.data.get()).unwrap();
+        assert_ne!(corrupted_rav.message, intact.data.message);
+        assert!(corrupted
+            .verify_checksum::<EIP712SignedMessage<ReceiptAggregateVoucher>>()
+            .is_err());
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn subscribe_ravs(
+        domain_separator: Eip712Domain,
+        http_request_size_limit: u32,
+        http_response_size_limit: u32,
+        http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
+        allocation_ids: Vec<Address>,
+    ) {
+        let keys_main = keys(0);
+
+        // Start the JSON-RPC server.
+        let (handle, local_addr) = server::run_server(
+            0,
+            keys_main.wallet.clone(),
+            HashSet::from([keys_main.address]),
+            domain_separator.clone(),
+            http_request_size_limit,
+            http_response_size_limit,
+            http_max_concurrent_connections,
+            max_concurrent_aggregations,
+            false,
+        )
+        .await
+        .unwrap();
+
+        // Start the JSON-RPC client, over WebSocket to support subscriptions.
+        let client = WsClientBuilder::default()
+            .build(format!("ws://127.0.0.1:{}", local_addr.port()))
+            .await
+            .unwrap();
+        let mut subscription = client
+            .subscribe::<EIP712SignedMessage<ReceiptAggregateVoucher>, _>(
+                "subscribe_ravs",
+                rpc_params!(),
+                "unsubscribe_ravs",
+            )
+            .await
+            .unwrap();
+
+        let receipts = vec![EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys_main.wallet,
+        )
+        .unwrap()];
+        let res: server::JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>> = client
+            .request(
+                "aggregate_receipts",
+                rpc_params!("0.0", &receipts, None::<()>),
+            )
+            .await
+            .unwrap();
+
+        // The produced RAV is pushed to the subscriber
+        let rav_event = subscription.next().await.unwrap().unwrap();
+        assert_eq!(rav_event, res.data);
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn verify_receipts(
+        domain_separator: Eip712Domain,
+        http_request_size_limit: u32,
+        http_response_size_limit: u32,
+        http_max_concurrent_connections: u32,
+        max_concurrent_aggregations: u32,
+        allocation_ids: Vec<Address>,
+    ) {
+        let keys_main = keys(0);
+        // Keys that are not accepted by the server
+        let keys_unknown = keys(1);
+
+        // Start the JSON-RPC server.
+        let (handle, local_addr) = server::run_server(
+            0,
+            keys_main.wallet.clone(),
+            HashSet::from([keys_main.address]),
+            domain_separator.clone(),
+            http_request_size_limit,
+            http_response_size_limit,
+            http_max_concurrent_connections,
+            max_concurrent_aggregations,
+            false,
+        )
+        .await
+        .unwrap();
+
+        // Start the JSON-RPC client.
+        let client = HttpClientBuilder::default()
+            .build(format!("http://127.0.0.1:{}", local_addr.port()))
+            .unwrap();
+
+        let sign = |value: u128, keys: &Keys| {
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_ids[0], value).unwrap(),
+                &keys.wallet,
+            )
+            .unwrap()
+        };
+        // A receipt whose value was changed after signing
+        let mut tampered_receipt = sign(30, &keys_main);
+        tampered_receipt.message.value = 3000;
+
+        let receipts = vec![
+            sign(10, &keys_main),
+            tampered_receipt,
+            sign(20, &keys_unknown),
+            sign(40, &keys_main),
+        ];
+        let res: server::JsonRpcResponse<Vec<bool>> = client
+            .request("verify_receipts", rpc_params!("0.0", &receipts))
+            .await
+            .unwrap();
+
+        assert_eq!(res.data, vec![true, false, false, true]);
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
+    #[test]
+    fn load_wallet_from_keystore() {
+        let keystore_dir =
+            std::env::temp_dir().join(format!("tap_aggregator_keystore_{}", random::<u64>()));
+        std::fs::create_dir_all(&keystore_dir).unwrap();
+        let (wallet, keystore_name) =
+            LocalWallet::new_keystore(&keystore_dir, &mut thread_rng(), "password", None).unwrap();
+        let keystore_path = keystore_dir.join(keystore_name);
+
+        let loaded_wallet = server::load_wallet_from_keystore(&keystore_path, "password").unwrap();
+        assert_eq!(loaded_wallet.address(), wallet.address());
+
+        // A wrong password fails to decrypt the keystore
+        assert!(server::load_wallet_from_keystore(&keystore_path, "wrong password").is_err());
+
+        std::fs::remove_dir_all(&keystore_dir).unwrap();
+    }
 }
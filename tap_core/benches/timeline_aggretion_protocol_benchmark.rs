@@ -13,12 +13,15 @@ use std::str::FromStr;
 use alloy_primitives::Address;
 use alloy_sol_types::Eip712Domain;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ethers::core::k256::ecdsa::SigningKey;
 use ethers::signers::{LocalWallet, Signer, Wallet};
-use ethers_core::k256::ecdsa::SigningKey;
 use rand_core::OsRng;
 use tap_core::tap_eip712_domain;
 use tap_core::{
-    rav::ReceiptAggregateVoucher, receipt::Receipt, signed_message::EIP712SignedMessage,
+    ethers_compat::convert_address,
+    rav::ReceiptAggregateVoucher,
+    receipt::Receipt,
+    signed_message::{cached_eip712_signing_hash, EIP712SignedMessage, Eip712Message},
 };
 
 pub fn create_and_sign_receipt(
@@ -39,8 +42,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     let domain_seperator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
 
     let wallet = LocalWallet::new(&mut OsRng);
-    let address: [u8; 20] = wallet.address().into();
-    let address: Address = address.into();
+    let address: Address = convert_address(wallet.address());
 
     // Arbitrary values wrapped in black box to avoid compiler optimizing them out
     let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
@@ -57,6 +59,79 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         })
     });
 
+    let mut sign_group = c.benchmark_group("Sign 1024 receipts");
+    let receipts = (0..1024)
+        .map(|_| Receipt::new(allocation_id, value).unwrap())
+        .collect::<Vec<_>>();
+
+    sign_group.bench_function("One by one", |b| {
+        b.iter(|| {
+            black_box(&receipts)
+                .iter()
+                .map(|receipt| {
+                    EIP712SignedMessage::new(&domain_seperator, receipt.clone(), &wallet).unwrap()
+                })
+                .collect::<Vec<_>>()
+        })
+    });
+
+    sign_group.bench_function("Batch", |b| {
+        b.iter(|| {
+            EIP712SignedMessage::sign_batch(&domain_seperator, black_box(&receipts), &wallet)
+                .unwrap()
+        })
+    });
+
+    sign_group.finish();
+
+    let mut hash_group = c.benchmark_group("Signing hash of 1024 receipts");
+
+    hash_group.bench_function("Domain hashed per receipt", |b| {
+        b.iter(|| {
+            black_box(&receipts)
+                .iter()
+                .map(|receipt| receipt.eip712_signing_hash(&domain_seperator))
+                .collect::<Vec<_>>()
+        })
+    });
+
+    hash_group.bench_function("Cached domain hash", |b| {
+        b.iter(|| {
+            black_box(&receipts)
+                .iter()
+                .map(|receipt| cached_eip712_signing_hash(receipt, &domain_seperator))
+                .collect::<Vec<_>>()
+        })
+    });
+
+    hash_group.finish();
+
+    // Receipts verified under two domains alternately, e.g. by a receiver serving two chains
+    let other_domain_seperator = tap_eip712_domain(2, Address::from([0x11u8; 20]));
+    let mut alternating_group =
+        c.benchmark_group("Signing hash of 1024 receipts under alternating domains");
+
+    alternating_group.bench_function("Domain hashed per receipt", |b| {
+        b.iter(|| {
+            black_box(&receipts)
+                .iter()
+                .zip([&domain_seperator, &other_domain_seperator].iter().cycle())
+                .map(|(receipt, domain)| receipt.eip712_signing_hash(domain))
+                .collect::<Vec<_>>()
+        })
+    });
+    alternating_group.bench_function("Cached domain hash", |b| {
+        b.iter(|| {
+            black_box(&receipts)
+                .iter()
+                .zip([&domain_seperator, &other_domain_seperator].iter().cycle())
+                .map(|(receipt, domain)| cached_eip712_signing_hash(receipt, domain))
+                .collect::<Vec<_>>()
+        })
+    });
+
+    alternating_group.finish();
+
     let receipt = create_and_sign_receipt(&domain_seperator, allocation_id, value, &wallet);
 
     c.bench_function("Validate Receipt", |b| {
@@ -75,7 +150,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
             .collect::<Vec<_>>();
 
         rav_group.bench_function(
-            &format!("Create RAV w/ 2^{} receipt's", log_number_of_receipts),
+            format!("Create RAV w/ 2^{} receipt's", log_number_of_receipts),
             |b| {
                 b.iter(|| {
                     ReceiptAggregateVoucher::aggregate_receipts(
@@ -95,7 +170,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         .unwrap();
 
         rav_group.bench_function(
-            &format!("Validate RAV w/ 2^{} receipt's", log_number_of_receipts),
+            format!("Validate RAV w/ 2^{} receipt's", log_number_of_receipts),
             |b| b.iter(|| black_box(&signed_rav).verify(&domain_seperator, black_box(address))),
         );
     }
@@ -0,0 +1,56 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Module containing the time source used by the [`crate::manager::Manager`]
+//!
+//! The manager reads the current time through a [`Clock`], so that time-dependent behavior (such as
+//! the RAV request timestamp window) can be driven deterministically in tests.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::Result;
+
+/// Source of the current time, as a Unix Epoch timestamp in nanoseconds.
+pub trait Clock: Send + Sync {
+    fn now_ns(&self) -> Result<u64>;
+}
+
+/// [`Clock`] reading the system wall-clock. This is the default clock of the manager.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ns(&self) -> Result<u64> {
+        crate::get_current_timestamp_u64_ns()
+    }
+}
+
+/// [`Clock`] that only moves when told to, for tests.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now_ns: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now_ns: u64) -> Self {
+        Self {
+            now_ns: AtomicU64::new(now_ns),
+        }
+    }
+
+    /// Sets the current time to `now_ns`.
+    pub fn set(&self, now_ns: u64) {
+        self.now_ns.store(now_ns, Ordering::SeqCst);
+    }
+
+    /// Moves the current time forward by `duration_ns`.
+    pub fn advance(&self, duration_ns: u64) {
+        self.now_ns.fetch_add(duration_ns, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ns(&self) -> Result<u64> {
+        Ok(self.now_ns.load(Ordering::SeqCst))
+    }
+}
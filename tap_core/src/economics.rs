@@ -0,0 +1,49 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Module containing helpers to weigh the value of a RAV against the cost of redeeming it on-chain
+//!
+//! Prices are passed in by the caller, so that the computation stays pure and can be tested with
+//! fixed values.
+
+use alloy_primitives::U256;
+
+use crate::rav::SignedRAV;
+
+/// Number of base units in one GRT.
+pub const GRT_BASE_UNITS: u128 = 1_000_000_000_000_000_000;
+
+/// Returns whether redeeming `rav` is worth its gas cost.
+///
+/// The redemption costs `gas_price * gas_estimate` wei. The RAV's `valueAggregate` (in GRT base
+/// units) is converted to wei with `token_price`, the price in wei of one GRT. The RAV is
+/// economical if its value covers the redemption cost, break-even included.
+///
+/// A redemption cost that overflows `U256` is never economical.
+pub fn rav_is_economical(
+    rav: &SignedRAV,
+    gas_price: U256,
+    gas_estimate: u64,
+    token_price: U256,
+) -> bool {
+    // Both sides are scaled by `GRT_BASE_UNITS` to compare without rounding
+    let Some(redemption_cost) = gas_price
+        .checked_mul(U256::from(gas_estimate))
+        .and_then(|cost| cost.checked_mul(U256::from(GRT_BASE_UNITS)))
+    else {
+        return false;
+    };
+    // A RAV value that overflows exceeds any cost that does not
+    U256::from(rav.message.valueAggregate)
+        .checked_mul(token_price)
+        .is_none_or(|rav_value| rav_value >= redemption_cost)
+}
+
+/// Returns the value of `latest_rav` that can still be claimed, given the value already redeemed
+/// on-chain for its allocation.
+///
+/// Clamped at zero when the redeemed value exceeds the RAV's `valueAggregate`, e.g. when an older
+/// RAV was redeemed after the latest one was stored.
+pub fn unredeemed_value(latest_rav: &SignedRAV, redeemed_on_chain: U256) -> U256 {
+    U256::from(latest_rav.message.valueAggregate).saturating_sub(redeemed_on_chain)
+}
@@ -4,10 +4,14 @@
 //! Module containing Error type and Result typedef
 //!
 
-use crate::{rav::ReceiptAggregateVoucher, receipt::ReceiptError};
+use crate::{
+    rav::ReceiptAggregateVoucher,
+    receipt::ReceiptError,
+    signed_message::{SignatureError, SignatureScheme},
+};
 use alloy_primitives::Address;
+#[cfg(feature = "ethers")]
 use ethers::signers::WalletError;
-use ethers_core::types::SignatureError;
 use std::result::Result as StdResult;
 use thiserror::Error as ThisError;
 
@@ -25,10 +29,21 @@ pub enum Error {
     InvalidStateForRequestedAction { state: String },
     #[error("Failed to get current system time: {source_error_message} ")]
     InvalidSystemTime { source_error_message: String },
+    #[cfg(feature = "ethers")]
     #[error(transparent)]
     WalletError(#[from] WalletError),
+    #[error("Signer failed to sign message: {source_error_message}")]
+    SignerError { source_error_message: String },
     #[error(transparent)]
     SignatureError(#[from] SignatureError),
+    #[error(
+        "Signed message encoding version {version} is not supported, expected version {supported}"
+    )]
+    UnsupportedVersion { version: u8, supported: u8 },
+    #[error("Failed to decode signed message:\n{source_error_message}")]
+    SignedMessageDecodeError { source_error_message: String },
+    #[error("Signature scheme {scheme:?} is not supported")]
+    UnsupportedSignatureScheme { scheme: SignatureScheme },
     #[error("Recovered sender address invalid {address}")]
     InvalidRecoveredSigner { address: Address },
     #[error("Received RAV does not match expexted RAV")]
@@ -38,23 +53,57 @@ pub enum Error {
     },
     #[error("Error from adapter.\n Caused by: {source_error}")]
     AdapterError { source_error: anyhow::Error },
+    #[error("Received RAV is older than the stored RAV of the same allocation")]
+    RavRegression {
+        stored_rav: ReceiptAggregateVoucher,
+        received_rav: ReceiptAggregateVoucher,
+    },
     #[error("Failed to produce rav request, no valid receipts")]
     NoValidReceiptsForRAVRequest,
+    #[error("Not enough receipts to aggregate yet: {receipts_count} receipts worth {value}")]
+    NotEnoughReceiptsForRAVRequest { receipts_count: usize, value: u128 },
     #[error("Previous RAV allocation id ({prev_id}) doesn't match the allocation id from the new receipt ({new_id}).")]
     RavAllocationIdMismatch { prev_id: String, new_id: String },
     #[error("All receipts should have the same allocation id, but they don't")]
     RavAllocationIdNotUniform,
+    #[error(
+        "A previous RAV can only be provided when all receipts share one sender and allocation id"
+    )]
+    PreviousRavForMultipleSenders,
+    #[error("Receipt {index} is signed by {received}, other receipts of the batch by {expected}")]
+    MixedSenders {
+        index: usize,
+        expected: Address,
+        received: Address,
+    },
     #[error("Duplicate receipt signature: {0}")]
     DuplicateReceiptSignature(String),
     #[error(
         "Receipt timestamp ({receipt_ts}) is less or equal than previous rav timestamp ({rav_ts})"
     )]
     ReceiptTimestampLowerThanRav { rav_ts: u64, receipt_ts: u64 },
+    #[error("Receipts are not sorted by timestamp: receipt {index} timestamp ({receipt_ts}) is lower than the previous one ({previous_ts})")]
+    UnsortedReceipts {
+        index: usize,
+        previous_ts: u64,
+        receipt_ts: u64,
+    },
     #[error("Timestamp range error: min_timestamp_ns: {min_timestamp_ns}, max_timestamp_ns: {max_timestamp_ns}. Adjust timestamp buffer.")]
     TimestampRangeError {
         min_timestamp_ns: u64,
         max_timestamp_ns: u64,
     },
+    #[error("Timestamp buffer ({timestamp_buffer_ns} ns) is greater than the current time ({now_ns} ns). Adjust timestamp buffer.")]
+    TimestampBufferTooLarge {
+        timestamp_buffer_ns: u64,
+        now_ns: u64,
+    },
+
+    #[error("Allocation {allocation_id} was finalized, no further receipts are accepted for it")]
+    AllocationClosed { allocation_id: Address },
+
+    #[error("Receipts are not accepted at the moment, retry later")]
+    ServiceUnavailable,
 
     #[error("Receipt error: {0}")]
     ReceiptError(#[from] ReceiptError),
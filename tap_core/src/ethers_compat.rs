@@ -0,0 +1,39 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bridges between the [`ethers`] types still used for signing and their [`alloy_primitives`]
+//! counterparts used everywhere else.
+
+/// Converts an address between its ethers and alloy types, in either direction, e.g. to use the
+/// address of an ethers wallet as an alloy [`Address`](alloy_primitives::Address):
+///
+/// ```
+/// # use alloy_primitives::Address;
+/// # use ethers::signers::{LocalWallet, Signer};
+/// # use tap_core::ethers_compat::convert_address;
+/// let wallet = LocalWallet::new(&mut rand::thread_rng());
+/// let address: Address = convert_address(wallet.address());
+/// ```
+pub fn convert_address<T: From<[u8; 20]>>(address: impl Into<[u8; 20]>) -> T {
+    T::from(address.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::Address;
+    use ethers::types::H160;
+
+    use super::convert_address;
+
+    #[test]
+    fn convert_address_round_trip() {
+        let alloy_address = Address::from([0xabu8; 20]);
+        let ethers_address: H160 = convert_address(alloy_address);
+        assert_eq!(ethers_address.0, alloy_address.0 .0);
+        assert_eq!(convert_address::<Address>(ethers_address), alloy_address);
+
+        let ethers_address = H160::from([0xcdu8; 20]);
+        let alloy_address: Address = convert_address(ethers_address);
+        assert_eq!(convert_address::<H160>(alloy_address), ethers_address);
+    }
+}
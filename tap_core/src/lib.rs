@@ -11,11 +11,17 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use alloy_sol_types::eip712_domain;
 use thiserror::Error;
 
+pub mod clock;
+pub mod economics;
 mod error;
+#[cfg(feature = "ethers-compat")]
+pub mod ethers_compat;
 pub mod manager;
 pub mod rav;
 pub mod receipt;
 pub mod signed_message;
+pub mod test_vectors;
+pub mod timestamp;
 
 pub use error::{Error, Result};
 
@@ -28,30 +34,66 @@ fn get_current_timestamp_u64_ns() -> Result<u64> {
         .as_nanos() as u64)
 }
 
+/// Version of the TAP EIP-712 domain.
+pub const TAP_EIP712_DOMAIN_VERSION: &str = "1";
+
 pub fn tap_eip712_domain(
     chain_id: u64,
     verifying_contract_address: alloy_primitives::Address,
 ) -> alloy_sol_types::Eip712Domain {
-    eip712_domain! {
+    tap_eip712_domain_with_salt(chain_id, verifying_contract_address, None)
+}
+
+/// Same as [`tap_eip712_domain`], with an optional EIP-712 `salt`.
+///
+/// The salt is part of the domain separator, so messages signed with a salted domain
+/// only validate against that same salt. Use it to tell apart deployments sharing a chain id and
+/// verifying contract.
+pub fn tap_eip712_domain_with_salt(
+    chain_id: u64,
+    verifying_contract_address: alloy_primitives::Address,
+    salt: Option<[u8; 32]>,
+) -> alloy_sol_types::Eip712Domain {
+    let mut domain = eip712_domain! {
         name: "TAP",
-        version: "1",
+        version: TAP_EIP712_DOMAIN_VERSION,
         chain_id: chain_id,
         verifying_contract: verifying_contract_address,
-    }
+    };
+    domain.salt = salt.map(alloy_primitives::B256::from);
+    domain
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "ethers", feature = "ethers-compat"))]
 mod tap_tests {
-    use std::str::FromStr;
+    use std::{
+        str::FromStr,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
 
-    use alloy_primitives::Address;
-    use alloy_sol_types::Eip712Domain;
-    use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
+    use alloy_primitives::{keccak256, Address, U256};
+    use alloy_sol_types::{Eip712Domain, SolStruct};
+    use async_trait::async_trait;
+    use ethers::{
+        signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer, WalletError},
+        types::{
+            transaction::{eip2718::TypedTransaction, eip712::Eip712},
+            Signature,
+        },
+    };
+    use proptest::prelude::*;
     use rstest::*;
 
     use crate::{
-        rav::ReceiptAggregateVoucher, receipt::Receipt, signed_message::EIP712SignedMessage,
-        tap_eip712_domain,
+        ethers_compat::convert_address,
+        rav::{ReceiptAggregateVoucher, RAV_EIP712_TYPE},
+        receipt::Receipt,
+        signed_message::{
+            EIP712SignedMessage, Eip712Message, Hasher, Keccak256Hasher, SignatureScheme,
+            SIGNED_MESSAGE_VERSION,
+        },
+        tap_eip712_domain, tap_eip712_domain_with_salt, Error,
     };
 
     #[fixture]
@@ -60,11 +102,9 @@ mod tap_tests {
          .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
          .build()
          .unwrap();
-        // Alloy library does not have feature parity with ethers library (yet) This workaround is needed to get the address
-        // to convert to an alloy Address. This will not be needed when the alloy library has wallet support.
-        let address: [u8; 20] = wallet.address().into();
+        let address = convert_address(wallet.address());
 
-        (wallet, address.into())
+        (wallet, address)
     }
 
     #[fixture]
@@ -158,6 +198,96 @@ mod tap_tests {
         assert!(signed_rav.recover_signer(&domain_separator).unwrap() == keys.1);
     }
 
+    #[rstest]
+    #[test]
+    fn sign_batch_matches_individual_signatures(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let receipts = (0..100)
+            .map(|value| Receipt::new(allocation_ids[0], value).unwrap())
+            .collect::<Vec<_>>();
+
+        let signed_receipts =
+            EIP712SignedMessage::sign_batch(&domain_separator, &receipts, &keys.0).unwrap();
+
+        assert_eq!(signed_receipts.len(), receipts.len());
+        for (signed_receipt, receipt) in signed_receipts.iter().zip(receipts) {
+            assert_eq!(
+                *signed_receipt,
+                EIP712SignedMessage::new(&domain_separator, receipt, &keys.0).unwrap()
+            );
+        }
+        assert!(
+            EIP712SignedMessage::<Receipt>::sign_batch(&domain_separator, &[], &keys.0)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[rstest]
+    #[test]
+    fn rav_type_matches_escrow_contract(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        assert_eq!(
+            ReceiptAggregateVoucher::eip712_encode_type(),
+            RAV_EIP712_TYPE
+        );
+
+        // The signing hash is the one rebuilt by the contract from its RAV type
+        let rav = ReceiptAggregateVoucher {
+            allocationId: allocation_ids[0],
+            timestampNs: 1000,
+            valueAggregate: 42,
+        };
+        let mut encoded_data = keccak256(RAV_EIP712_TYPE.as_bytes()).to_vec();
+        encoded_data.extend(rav.allocationId.into_word());
+        encoded_data.extend(U256::from(rav.timestampNs).to_be_bytes::<32>());
+        encoded_data.extend(U256::from(rav.valueAggregate).to_be_bytes::<32>());
+        let mut digest_input = vec![0x19, 0x01];
+        digest_input.extend(domain_separator.hash_struct());
+        digest_input.extend(keccak256(encoded_data));
+        let digest = keccak256(digest_input);
+        assert_eq!(rav.eip712_signing_hash(&domain_separator), digest);
+
+        let signed_rav = EIP712SignedMessage::new(&domain_separator, rav, &keys.0).unwrap();
+        assert_eq!(signed_rav.signature.recover(digest).unwrap(), keys.1);
+    }
+
+    #[rstest]
+    #[test]
+    fn rav_rejects_receipts_from_other_allocations(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let receipts = [allocation_ids[0], allocation_ids[1], allocation_ids[0]]
+            .into_iter()
+            .map(|allocation_id| {
+                EIP712SignedMessage::new(
+                    &domain_separator,
+                    Receipt::new(allocation_id, 42).unwrap(),
+                    &keys.0,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            ReceiptAggregateVoucher::aggregate_receipts(allocation_ids[0], &receipts, None),
+            Err(Error::RavAllocationIdNotUniform)
+        ));
+        // Even if the receipts agree with each other, they must match the RAV's allocation id
+        assert!(matches!(
+            ReceiptAggregateVoucher::aggregate_receipts(allocation_ids[2], &receipts[..1], None),
+            Err(Error::RavAllocationIdNotUniform)
+        ));
+    }
+
     #[rstest]
     #[test]
     fn verify_signature(
@@ -180,4 +310,278 @@ mod tap_tests {
             )
             .is_err());
     }
+
+    #[rstest]
+    #[test]
+    fn verify_signature_with_salted_domain(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let salted_domain_separator =
+            tap_eip712_domain_with_salt(1, Address::from([0x11u8; 20]), Some([0x22u8; 32]));
+        let signed_message = EIP712SignedMessage::new(
+            &salted_domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+
+        assert!(signed_message
+            .verify(&salted_domain_separator, keys.1)
+            .is_ok());
+        assert!(signed_message.verify(&domain_separator, keys.1).is_err());
+        assert_eq!(
+            tap_eip712_domain_with_salt(1, Address::from([0x11u8; 20]), None),
+            domain_separator
+        );
+    }
+
+    #[rstest]
+    #[test]
+    fn verify_signature_scheme(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let signed_message = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+
+        // Messages are signed with secp256k1 by default, which is left out of their serialization
+        assert_eq!(signed_message.scheme, SignatureScheme::Secp256k1);
+        assert!(signed_message.verify(&domain_separator, keys.1).is_ok());
+        let serialized = serde_json::to_value(&signed_message).unwrap();
+        assert!(serialized.get("scheme").is_none());
+        assert_eq!(
+            serde_json::from_value::<EIP712SignedMessage<Receipt>>(serialized).unwrap(),
+            signed_message
+        );
+
+        // Recovery goes through the same hash as verification
+        assert_eq!(
+            signed_message.recover_signer(&domain_separator).unwrap(),
+            keys.1
+        );
+
+        // Unknown schemes are rejected rather than verified as secp256k1
+        let mut relabeled = serde_json::to_value(&signed_message).unwrap();
+        relabeled["scheme"] = serde_json::json!("ed25519");
+        assert!(serde_json::from_value::<EIP712SignedMessage<Receipt>>(relabeled).is_err());
+
+        // The scheme is folded into the signed hash, a secp256k1 signature can't pass for another
+        // scheme
+        #[cfg(feature = "p256")]
+        {
+            let eip712_hash = signed_message
+                .message
+                .eip712_signing_hash(&domain_separator);
+            assert_ne!(
+                SignatureScheme::Secp256r1.signing_hash(eip712_hash),
+                SignatureScheme::Secp256k1.signing_hash(eip712_hash)
+            );
+            let relabeled_message = EIP712SignedMessage {
+                scheme: SignatureScheme::Secp256r1,
+                ..signed_message.clone()
+            };
+            assert!(matches!(
+                relabeled_message.verify(&domain_separator, keys.1),
+                Err(Error::UnsupportedSignatureScheme { .. })
+            ));
+            assert!(relabeled_message.recover_signer(&domain_separator).is_err());
+            let serialized = serde_json::to_value(&relabeled_message).unwrap();
+            assert_eq!(serialized["scheme"], "secp256r1");
+        }
+    }
+
+    #[rstest]
+    #[test]
+    fn signed_message_bytes_version(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let signed_message = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+
+        let bytes = signed_message.to_bytes().unwrap();
+        assert_eq!(bytes[0], SIGNED_MESSAGE_VERSION);
+        assert_eq!(
+            EIP712SignedMessage::<Receipt>::from_bytes(&bytes).unwrap(),
+            signed_message
+        );
+
+        // A blob from a future encoding version is rejected, not parsed as the current version
+        let mut future_bytes = bytes.clone();
+        future_bytes[0] = SIGNED_MESSAGE_VERSION + 1;
+        assert!(matches!(
+            EIP712SignedMessage::<Receipt>::from_bytes(&future_bytes),
+            Err(Error::UnsupportedVersion { version, supported })
+                if version == SIGNED_MESSAGE_VERSION + 1 && supported == SIGNED_MESSAGE_VERSION
+        ));
+
+        assert!(matches!(
+            EIP712SignedMessage::<Receipt>::from_bytes(&[]),
+            Err(Error::SignedMessageDecodeError { .. })
+        ));
+        assert!(matches!(
+            EIP712SignedMessage::<Receipt>::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(Error::SignedMessageDecodeError { .. })
+        ));
+    }
+
+    /// Stands in for a hardware wallet, signing with a local key after a slow device roundtrip.
+    #[derive(Debug)]
+    struct SlowSigner {
+        wallet: LocalWallet,
+        device_interactions: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Signer for SlowSigner {
+        type Error = WalletError;
+
+        async fn sign_message<S: Send + Sync + AsRef<[u8]>>(
+            &self,
+            message: S,
+        ) -> Result<Signature, Self::Error> {
+            self.wallet.sign_message(message).await
+        }
+
+        async fn sign_transaction(
+            &self,
+            message: &TypedTransaction,
+        ) -> Result<Signature, Self::Error> {
+            self.wallet.sign_transaction(message).await
+        }
+
+        async fn sign_typed_data<T: Eip712 + Send + Sync>(
+            &self,
+            payload: &T,
+        ) -> Result<Signature, Self::Error> {
+            tokio::task::spawn_blocking(|| std::thread::sleep(Duration::from_millis(10)))
+                .await
+                .unwrap();
+            self.device_interactions.fetch_add(1, Ordering::SeqCst);
+            self.wallet.sign_typed_data(payload).await
+        }
+
+        fn address(&self) -> ethers::types::Address {
+            self.wallet.address()
+        }
+
+        fn chain_id(&self) -> u64 {
+            self.wallet.chain_id()
+        }
+
+        fn with_chain_id<T: Into<u64>>(self, chain_id: T) -> Self {
+            Self {
+                wallet: self.wallet.with_chain_id(chain_id),
+                ..self
+            }
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn sign_with_generic_signer(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        let signer = SlowSigner {
+            wallet: keys.0.clone(),
+            device_interactions: AtomicUsize::new(0),
+        };
+        let receipt = Receipt::new(allocation_ids[0], 42).unwrap();
+
+        let signed_message =
+            EIP712SignedMessage::new_with_signer(&domain_separator, receipt.clone(), &signer)
+                .await
+                .unwrap();
+
+        assert!(signed_message.verify(&domain_separator, keys.1).is_ok());
+        assert_eq!(signer.device_interactions.load(Ordering::SeqCst), 1);
+        // Same signature as with the local signing path
+        assert_eq!(
+            signed_message,
+            EIP712SignedMessage::new(&domain_separator, receipt, &keys.0).unwrap()
+        );
+    }
+
+    #[rstest]
+    #[test]
+    fn unique_hash_with_custom_hasher(
+        keys: (LocalWallet, Address),
+        allocation_ids: Vec<Address>,
+        domain_separator: Eip712Domain,
+    ) {
+        struct ReversedKeccak256Hasher;
+        impl Hasher for ReversedKeccak256Hasher {
+            fn hash(&self, data: &[u8]) -> [u8; 32] {
+                let mut hash = Keccak256Hasher.hash(data);
+                hash.reverse();
+                hash
+            }
+        }
+
+        let signed_message = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 42).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+
+        // The default hasher still produces the EIP712 struct hash
+        assert_eq!(
+            signed_message.unique_hash().0,
+            <[u8; 32]>::from(signed_message.message.hash_struct())
+        );
+        assert_ne!(
+            signed_message.unique_hash_with(&ReversedKeccak256Hasher),
+            signed_message.unique_hash()
+        );
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        #[test]
+        fn rav_aggregates_arbitrary_receipts(
+            parts in prop::collection::vec((any::<u64>(), any::<u64>(), any::<u64>()), 1..10)
+        ) {
+            let (wallet, _) = keys();
+            let domain_separator = domain_separator();
+            let allocation_id = allocation_ids()[0];
+
+            let receipts = parts
+                .iter()
+                .map(|&(timestamp_ns, nonce, value)| {
+                    EIP712SignedMessage::new(
+                        &domain_separator,
+                        Receipt::from_parts(allocation_id, timestamp_ns, nonce, value.into()),
+                        &wallet,
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>();
+
+            let rav =
+                ReceiptAggregateVoucher::aggregate_receipts(allocation_id, &receipts, None).unwrap();
+            prop_assert_eq!(
+                rav.valueAggregate,
+                parts.iter().map(|&(_, _, value)| u128::from(value)).sum::<u128>()
+            );
+            prop_assert_eq!(
+                rav.timestampNs,
+                parts.iter().map(|&(timestamp_ns, _, _)| timestamp_ns).max().unwrap()
+            );
+        }
+    }
 }
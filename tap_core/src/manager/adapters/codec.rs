@@ -0,0 +1,65 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::receipt::SignedReceipt;
+
+/// `ReceiptCodec` defines how a `SignedReceipt` is turned into a blob of bytes for storage, and back.
+///
+/// This trait is designed to be shared by storage adapters that would rather store receipts as
+/// opaque blobs, such that the storage format (and compression) can be changed without changing
+/// the adapter. Decoding a blob must return a receipt identical to the one that was encoded.
+///
+/// # Example
+///
+/// For example code see [crate::manager::context::memory::InMemoryContext::with_codec]
+pub trait ReceiptCodec: Send + Sync {
+    /// Encodes `receipt` into a blob of bytes suitable for storage.
+    fn encode(&self, receipt: &SignedReceipt) -> anyhow::Result<Vec<u8>>;
+
+    /// Decodes a blob of bytes produced by [`ReceiptCodec::encode`] back into a `SignedReceipt`.
+    fn decode(&self, blob: &[u8]) -> anyhow::Result<SignedReceipt>;
+}
+
+/// Stores receipts as uncompressed JSON.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl ReceiptCodec for JsonCodec {
+    fn encode(&self, receipt: &SignedReceipt) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(receipt)?)
+    }
+
+    fn decode(&self, blob: &[u8]) -> anyhow::Result<SignedReceipt> {
+        Ok(serde_json::from_slice(blob)?)
+    }
+}
+
+/// Stores receipts as zstd-compressed JSON.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct ZstdCodec {
+    /// zstd compression level, `0` meaning zstd's default level.
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCodec {
+    fn default() -> Self {
+        Self {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl ReceiptCodec for ZstdCodec {
+    fn encode(&self, receipt: &SignedReceipt) -> anyhow::Result<Vec<u8>> {
+        let json = JsonCodec.encode(receipt)?;
+        Ok(zstd::encode_all(json.as_slice(), self.level)?)
+    }
+
+    fn decode(&self, blob: &[u8]) -> anyhow::Result<SignedReceipt> {
+        let json = zstd::decode_all(blob)?;
+        JsonCodec.decode(&json)
+    }
+}
@@ -7,7 +7,8 @@ use async_trait::async_trait;
 
 use crate::{
     rav::SignedRAV,
-    receipt::{AwaitingReserve, ReceiptError, ReceiptResult, ReceiptWithState},
+    receipt::{AwaitingReserve, ReceiptError, ReceiptResult, ReceiptWithState, Reserved},
+    signed_message::MessageId,
     Error,
 };
 
@@ -28,6 +29,12 @@ use crate::{
 /// of available escrow of a specified sender. Any errors during this operation should be captured
 /// and returned as an `AdapterError`.
 ///
+/// The `deposit` and `withdraw` methods are used to keep the local accounting in sync with the
+/// on-chain escrow, e.g. when processing the escrow contract's deposit and withdrawal events.
+///
+/// The `reclaim_expired_reservations` method gives back the escrow reserved for receipts that never
+/// made it into a RAV, once their reservation is older than the adapter's time-to-live.
+///
 /// This trait is utilized by [crate::tap_manager], which relies on these
 /// operations for managing escrow.
 ///
@@ -61,8 +68,32 @@ pub trait EscrowHandler: Send + Sync {
         value: u128,
     ) -> Result<(), Self::AdapterError>;
 
+    /// Adds a specified value to the local accounting of available escrow for a specified sender.
+    ///
+    /// This method should be implemented to credit the available escrow of a specified sender in your
+    /// system, creating the sender's account if needed. Any errors that occur during this process
+    /// (e.g. an overflowing balance) should be captured and returned as an `AdapterError`.
+    ///
+    /// There is no default, as escrow can't be credited through the other methods of this trait.
+    async fn deposit(&self, sender_id: Address, value: u128) -> Result<(), Self::AdapterError>;
+
+    /// Removes a specified value from the local accounting of available escrow for a specified sender.
+    ///
+    /// If `value` is greater than the available escrow, the balance must be left untouched and an
+    /// `AdapterError` returned. Uses [`EscrowHandler::subtract_escrow`] by default, override it if
+    /// withdrawals must be told apart from the escrow spent on receipts.
+    async fn withdraw(&self, sender_id: Address, value: u128) -> Result<(), Self::AdapterError> {
+        self.subtract_escrow(sender_id, value).await
+    }
+
     async fn verify_signer(&self, signer_address: Address) -> Result<bool, Self::AdapterError>;
 
+    /// Subtracts the value of `received_receipt` from its sender's escrow with
+    /// [`EscrowHandler::subtract_escrow`].
+    ///
+    /// Returns [`ReceiptError::InsufficientEscrow`], with the amount the sender has to deposit, if
+    /// the available escrow doesn't cover the receipt's value, and
+    /// [`ReceiptError::SubtractEscrowFailed`] if the subtraction failed for another reason.
     async fn check_and_reserve_escrow(
         &self,
         received_receipt: &ReceiptWithState<AwaitingReserve>,
@@ -70,18 +101,92 @@ pub trait EscrowHandler: Send + Sync {
     ) -> ReceiptResult<()> {
         let signed_receipt = &received_receipt.signed_receipt;
         let receipt_signer_address =
-            signed_receipt
+            received_receipt
+                .recover_signer(domain_separator)
+                .map_err(|err| ReceiptError::InvalidSignature {
+                    source_error_message: err.to_string(),
+                })?;
+
+        let required = signed_receipt.message.value;
+        if self
+            .subtract_escrow(receipt_signer_address, required)
+            .await
+            .is_err()
+        {
+            // Tell the sender how much escrow is missing, if that's why the subtraction failed
+            return Err(
+                match self.get_available_escrow(receipt_signer_address).await {
+                    Ok(available) if available < required => ReceiptError::InsufficientEscrow {
+                        required,
+                        available,
+                        shortfall: required - available,
+                    },
+                    _ => ReceiptError::SubtractEscrowFailed,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Gives back the escrow of the reservations made by [`EscrowHandler::check_and_reserve_escrow`]
+    /// that are older than the adapter's reservation time-to-live as of `now_ns`, and were not committed
+    /// in the meantime, i.e. no RAV aggregating their receipt was stored. This happens when a RAV request
+    /// is abandoned, e.g. because the indexer crashed before receiving the RAV. Returns the total value
+    /// given back.
+    ///
+    /// This is a no-op by default, for adapters that don't track reservations.
+    async fn reclaim_expired_reservations(&self, _now_ns: u64) -> Result<u128, Self::AdapterError> {
+        Ok(0)
+    }
+
+    /// Hands the escrow reserved for the receipt with `receipt_id` (see
+    /// [`crate::signed_message::EIP712SignedMessage::unique_hash`]) when it was stored with
+    /// [`crate::manager::Manager::verify_and_store_receipt_with_state`] over to the RAV request
+    /// collecting the receipt, such that it isn't reserved twice. Returns whether there was such a
+    /// reservation, not handed over to a RAV request yet.
+    ///
+    /// Returns `false` by default, for adapters that don't track reservations.
+    async fn collect_reserved_escrow(
+        &self,
+        _receipt_id: MessageId,
+    ) -> Result<bool, Self::AdapterError> {
+        Ok(false)
+    }
+
+    /// Returns whether escrow is reserved for the receipt with `receipt_id` (see
+    /// [`crate::signed_message::EIP712SignedMessage::unique_hash`]), i.e. its value was deducted
+    /// from the available escrow by [`EscrowHandler::check_and_reserve_escrow`], and neither
+    /// released nor committed by a stored RAV since.
+    ///
+    /// Returns `false` by default, for adapters that don't track reservations.
+    async fn is_escrow_reserved(&self, _receipt_id: MessageId) -> Result<bool, Self::AdapterError> {
+        Ok(false)
+    }
+
+    /// Gives back to the sender the escrow reserved for `received_receipt` by
+    /// [`EscrowHandler::check_and_reserve_escrow`], using [`EscrowHandler::deposit`].
+    async fn release_escrow(
+        &self,
+        received_receipt: &ReceiptWithState<Reserved>,
+        domain_separator: &Eip712Domain,
+    ) -> ReceiptResult<()> {
+        let receipt_signer_address =
+            received_receipt
                 .recover_signer(domain_separator)
                 .map_err(|err| ReceiptError::InvalidSignature {
                     source_error_message: err.to_string(),
                 })?;
 
         if self
-            .subtract_escrow(receipt_signer_address, signed_receipt.message.value)
+            .deposit(
+                receipt_signer_address,
+                received_receipt.signed_receipt.message.value,
+            )
             .await
             .is_err()
         {
-            return Err(ReceiptError::SubtractEscrowFailed);
+            return Err(ReceiptError::ReleaseEscrowFailed);
         }
 
         Ok(())
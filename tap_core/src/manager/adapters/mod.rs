@@ -8,17 +8,25 @@
 //! of use cases.
 //!
 //! The following adapters are defined:
+//! - `codec`: An interface for encoding receipts into (optionally compressed) blobs for storage.
 //! - `escrow_adapter`: An interface for checking and updating escrow availability.
 //! - `rav_storage_adapter`: An interface for storing and retrieving/replacing RAVs.
 //! - `receipt_checks_adapter`: An interface for verifying TAP receipts.
 //! - `receipt_storage_adapter`: An interface for storing, retrieving, updating, and removing TAP receipts.
+//! - `transaction`: An interface for applying several adapter operations atomically.
 //!
 //! In addition, this module also includes mock implementations of each adapter for testing and example purposes.
 
+mod codec;
 mod escrow;
 mod rav;
 mod receipt;
+mod transaction;
 
+#[cfg(feature = "zstd")]
+pub use codec::ZstdCodec;
+pub use codec::{JsonCodec, ReceiptCodec};
 pub use escrow::EscrowHandler;
 pub use rav::*;
 pub use receipt::*;
+pub use transaction::Transaction;
@@ -1,6 +1,9 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
+
+use alloy_primitives::Address;
 use async_trait::async_trait;
 
 use crate::rav::SignedRAV;
@@ -15,8 +18,8 @@ use crate::rav::SignedRAV;
 /// # Usage
 ///
 /// The `update_last_rav` method should be used to update the last validated `SignedRAV`
-/// in the storage managed by the adapter. Errors during this operation should be
-/// captured and returned in the `AdapterError` format.
+/// of its allocation in the storage managed by the adapter. Errors during this operation
+/// should be captured and returned in the `AdapterError` format.
 ///
 /// This trait is utilized by [crate::tap_manager], which relies on these
 /// operations for working with `SignedRAV` data.
@@ -33,9 +36,10 @@ pub trait RAVStore {
     /// Errors of this type are returned to the user when an operation fails.
     type AdapterError: std::error::Error + std::fmt::Debug + Send + Sync + 'static;
 
-    /// Updates the storage with the latest validated `SignedRAV`.
+    /// Updates the storage with the latest validated `SignedRAV` of its allocation.
     ///
-    /// This method should be implemented to store the most recent validated `SignedRAV` into your chosen storage system.
+    /// This method should be implemented to store the most recent validated `SignedRAV` into your chosen storage system,
+    /// replacing the stored `SignedRAV` of the same allocation only.
     /// Any errors that occur during this process should be captured and returned as an `AdapterError`.
     async fn update_last_rav(&self, rav: SignedRAV) -> Result<(), Self::AdapterError>;
 }
@@ -49,9 +53,12 @@ pub trait RAVStore {
 ///
 /// # Usage
 ///
-/// The `last_rav` method is designed to fetch the latest `SignedRAV` from the storage.
-/// If there is no `SignedRAV` available, it should return `None`. Any errors during
-/// this operation should be captured and returned as an `AdapterError`.
+/// The `last_rav` method is designed to fetch the latest `SignedRAV` of an allocation from
+/// the storage. If there is no `SignedRAV` available, it should return `None`. Any errors
+/// during this operation should be captured and returned as an `AdapterError`.
+///
+/// The `load_latest_ravs` method returns the newest `SignedRAV` of every allocation at
+/// once, e.g. to warm up caches when restarting.
 ///
 /// This trait is utilized by [crate::tap_manager], which relies on these
 /// operations for working with `SignedRAV` data.
@@ -68,10 +75,22 @@ pub trait RAVRead {
     /// Errors of this type are returned to the user when an operation fails.
     type AdapterError: std::error::Error + std::fmt::Debug + Send + Sync + 'static;
 
-    /// Retrieves the latest `SignedRAV` from the storage.
+    /// Retrieves the latest `SignedRAV` of `allocation_id` from the storage.
+    ///
+    /// This method should be implemented to fetch the latest `SignedRAV` of the allocation from your storage system.
+    /// If no `SignedRAV` is available for the allocation, this method should return `None`.
+    /// Any errors that occur during this process should be captured and returned as an `AdapterError`.
+    async fn last_rav(
+        &self,
+        allocation_id: Address,
+    ) -> Result<Option<SignedRAV>, Self::AdapterError>;
+
+    /// Retrieves the newest `SignedRAV` of every allocation, keyed by allocation id.
     ///
-    /// This method should be implemented to fetch the latest `SignedRAV` from your storage system.
-    /// If no `SignedRAV` is available, this method should return `None`.
+    /// This method should be implemented to load all of them in a single pass over your storage system
+    /// (e.g. with a single query), rather than one allocation at a time.
     /// Any errors that occur during this process should be captured and returned as an `AdapterError`.
-    async fn last_rav(&self) -> Result<Option<SignedRAV>, Self::AdapterError>;
+    ///
+    /// There is no default, as [`RAVRead::last_rav`] can't tell which allocations have a `SignedRAV`.
+    async fn load_latest_ravs(&self) -> Result<HashMap<Address, SignedRAV>, Self::AdapterError>;
 }
@@ -1,11 +1,13 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::ops::RangeBounds;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
 
+use alloy_primitives::Address;
 use async_trait::async_trait;
 
-use crate::receipt::{Checking, ReceiptState, ReceiptWithState};
+use crate::receipt::{Checking, Failed, ReceiptState, ReceiptWithState};
 
 /// `ReceiptStore` defines a trait for write storage adapters to manage `ReceivedReceipt` data.
 ///
@@ -20,6 +22,12 @@ use crate::receipt::{Checking, ReceiptState, ReceiptWithState};
 /// managed by the adapter. It returns a unique receipt_id associated with the stored receipt.
 /// Any errors during this operation should be captured and returned in the `AdapterError` format.
 ///
+/// The `check_and_store_unique` method stores a new `ReceivedReceipt` only if no receipt with the same
+/// signature is already stored, the check and the insertion being a single atomic operation.
+///
+/// The `replay_watermark` method returns the timestamp up to which the receipts of an allocation
+/// count as stored, even once removed from the storage, such that they can't be replayed.
+///
 /// The `update_receipt_by_id` method is designed to update a specific `ReceivedReceipt` identified by a unique
 /// receipt_id. Any errors during this operation should be captured and returned as an `AdapterError`.
 ///
@@ -50,6 +58,53 @@ pub trait ReceiptStore {
         &self,
         receipt: ReceiptWithState<Checking>,
     ) -> Result<u64, Self::AdapterError>;
+
+    /// Stores a new `ReceivedReceipt` into the storage, unless a receipt with the same signature is
+    /// already stored. A receipt with a timestamp up to the [`ReceiptStore::replay_watermark`] of its
+    /// allocation counts as already stored.
+    ///
+    /// This method should be implemented such that the uniqueness check and the insertion are atomic
+    /// (e.g. a unique index in a SQL database), otherwise two concurrent calls could both store the
+    /// same receipt. It returns the unique receipt_id associated with the stored receipt, or `None` if
+    /// the receipt is a duplicate. Any errors that occur during this process should be captured and
+    /// returned as an `AdapterError`.
+    ///
+    /// By default, the receipt is stored with [`ReceiptStore::store_receipt`] if it is above the
+    /// replay watermark, without any other uniqueness check, such that duplicates are only left out
+    /// of the RAV requests by [`crate::receipt::checks::UniqueCheck`].
+    async fn check_and_store_unique(
+        &self,
+        receipt: ReceiptWithState<Checking>,
+    ) -> Result<Option<u64>, Self::AdapterError>
+    where
+        Self: Sync,
+    {
+        let signed_receipt = receipt.signed_receipt();
+        let replay_watermark = self
+            .replay_watermark(signed_receipt.message.allocation_id)
+            .await?;
+        if signed_receipt.message.timestamp_ns.as_nanos() <= replay_watermark {
+            return Ok(None);
+        }
+        self.store_receipt(receipt).await.map(Some)
+    }
+
+    /// Returns the replay watermark of `allocation_id`: the receipts of the allocation with a
+    /// timestamp up to it (inclusive) were seen already, even if they are no longer stored.
+    ///
+    /// This method should be implemented to return at least the timestamp of the allocation's last
+    /// RAV, as the receipts it aggregates are removed from the storage, and the newest timestamp of
+    /// the receipts of the allocation removed from the storage otherwise. It must be persisted along
+    /// with the receipts, such that receipts can't be replayed after a restart. Any errors that occur
+    /// during this process should be captured and returned as an `AdapterError`.
+    ///
+    /// By default, it returns 0, such that only the stored receipts are seen.
+    async fn replay_watermark(&self, _allocation_id: Address) -> Result<u64, Self::AdapterError>
+    where
+        Self: Sync,
+    {
+        Ok(0)
+    }
 }
 
 #[async_trait]
@@ -70,6 +125,58 @@ pub trait ReceiptDelete {
     ) -> Result<(), Self::AdapterError>;
 }
 
+/// `FailedReceiptStore` defines a trait for storage adapters keeping the receipts that failed their
+/// checks, e.g. to audit what was rejected and why.
+///
+/// # Usage
+///
+/// The `store_failed_receipt` method stores a failed receipt along with its error.
+///
+/// The `retrieve_failed_receipts_in_timestamp_range` method fetches the failed receipts within a
+/// specific timestamp range.
+///
+/// The `remove_failed_receipts_in_timestamp_range` method removes the failed receipts within a
+/// specific timestamp range, such that the storage can be bounded to a retention window with
+/// [`crate::manager::Manager::prune_failed_receipts`].
+///
+/// The [`crate::manager::Manager`] never stores failed receipts itself: callers own this storage,
+/// and store the failed receipts they get back, i.e. [`crate::receipt::ReceiptOutcome::Failed`]
+/// from [`crate::manager::Manager::verify_and_store_receipt_with_state`] and
+/// [`crate::rav::RAVRequest::invalid_receipts`] from the RAV requests.
+#[async_trait]
+pub trait FailedReceiptStore {
+    /// Defines the user-specified error type.
+    ///
+    /// This error type should implement the `Error` and `Debug` traits from the standard library.
+    /// Errors of this type are returned to the user when an operation fails.
+    type AdapterError: std::error::Error + std::fmt::Debug + Send + Sync + 'static;
+
+    /// Stores a receipt that failed its checks.
+    ///
+    /// Any errors that occur during this process should be captured and returned as an `AdapterError`.
+    async fn store_failed_receipt(
+        &self,
+        receipt: ReceiptWithState<Failed>,
+    ) -> Result<(), Self::AdapterError>;
+
+    /// Retrieves the failed receipts within a specific timestamp range.
+    ///
+    /// Any errors that occur during this process should be captured and returned as an `AdapterError`.
+    async fn retrieve_failed_receipts_in_timestamp_range<R: RangeBounds<u64> + std::marker::Send>(
+        &self,
+        timestamp_range_ns: R,
+    ) -> Result<Vec<ReceiptWithState<Failed>>, Self::AdapterError>;
+
+    /// Removes the failed receipts within a specific timestamp range, and returns how many were
+    /// removed.
+    ///
+    /// Any errors that occur during this process should be captured and returned as an `AdapterError`.
+    async fn remove_failed_receipts_in_timestamp_range<R: RangeBounds<u64> + std::marker::Send>(
+        &self,
+        timestamp_range_ns: R,
+    ) -> Result<u64, Self::AdapterError>;
+}
+
 /// `ReceiptRead` defines a trait for read storage adapters to manage `ReceivedReceipt` data.
 ///
 /// This trait is designed to be implemented by users of this library who want to
@@ -82,6 +189,13 @@ pub trait ReceiptDelete {
 /// The `retrieve_receipts_in_timestamp_range` method should be implemented to fetch all `ReceivedReceipts`
 /// within a specific timestamp range from the storage. The returned receipts should be in the form of a vector
 /// of tuples where each tuple contains the unique receipt_id and the corresponding `ReceivedReceipt`.
+///
+/// The `count_receipts` method returns the number of stored receipts for a given allocation,
+/// without having to retrieve them.
+///
+/// The `retrieve_receipts_paginated` method fetches the receipts of an allocation one page at a time,
+/// such that large sets of receipts never have to be held in memory at once. RAV requests collect
+/// their receipts with it, so it should be overridden for large receipt stores.
 #[async_trait]
 pub trait ReceiptRead {
     /// Defines the user-specified error type.
@@ -116,6 +230,103 @@ pub trait ReceiptRead {
         timestamp_range_ns: R,
         limit: Option<u64>,
     ) -> Result<Vec<ReceiptWithState<Checking>>, Self::AdapterError>;
+
+    /// Counts the `ReceivedReceipts` stored for a specific allocation.
+    ///
+    /// This method should be implemented to be cheaper than retrieving the receipts, for example
+    /// with a `COUNT` query. It is meant to decide when to request a RAV, or to report metrics.
+    /// By default, it retrieves all the stored receipts with
+    /// [`ReceiptRead::retrieve_receipts_in_timestamp_range`] and counts those of the allocation.
+    ///
+    /// Any errors that occur during this process should be captured and returned as an `AdapterError`.
+    async fn count_receipts(&self, allocation_id: Address) -> Result<u64, Self::AdapterError>
+    where
+        Self: Sync,
+    {
+        Ok(self
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await?
+            .iter()
+            .filter(|rx_receipt| rx_receipt.signed_receipt().message.allocation_id == allocation_id)
+            .count() as u64)
+    }
+
+    /// Retrieves a page of the `ReceivedReceipts` of a specific allocation, ordered by timestamp.
+    ///
+    /// The page holds the receipts with a timestamp strictly greater than `after` (all of them if
+    /// `None`), up to `limit` receipts. As with `retrieve_receipts_in_timestamp_range`, a page must
+    /// never leave behind receipts of a timestamp it returns, such that the next page can start after
+    /// the last timestamp of the page (see [`safe_truncate_receipts()`]). If the receipts of the first
+    /// timestamp are more than `limit`, they must all be returned anyway, for the pagination to make
+    /// progress.
+    ///
+    /// The returned [`ReceiptsPage::next_cursor`] is the `after` to pass to get the next page, or
+    /// `None` once all the receipts have been returned.
+    ///
+    /// By default, it retrieves all the receipts after `after` with
+    /// [`ReceiptRead::retrieve_receipts_in_timestamp_range`] and pages those of the allocation with
+    /// [`paginate_receipts()`], which defeats the purpose of paginating. It should be overridden to
+    /// only fetch the page from your storage system.
+    ///
+    /// Any errors that occur during this process should be captured and returned as an `AdapterError`.
+    async fn retrieve_receipts_paginated(
+        &self,
+        allocation_id: Address,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<ReceiptsPage, Self::AdapterError>
+    where
+        Self: Sync,
+    {
+        let after = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let receipts = self
+            .retrieve_receipts_in_timestamp_range((after, Bound::Unbounded), None)
+            .await?
+            .into_iter()
+            .filter(|rx_receipt| {
+                rx_receipt.signed_receipt().message.allocation_id == allocation_id
+            });
+        Ok(paginate_receipts(receipts, limit))
+    }
+}
+
+/// Page of receipts returned by [`ReceiptRead::retrieve_receipts_paginated`].
+#[derive(Debug)]
+pub struct ReceiptsPage {
+    pub receipts: Vec<ReceiptWithState<Checking>>,
+    /// Timestamp of the last receipt of the page, if more receipts may follow.
+    pub next_cursor: Option<u64>,
+}
+
+/// Returns the first page of `receipts`, with up to `limit` receipts ordered by timestamp, see
+/// [`ReceiptRead::retrieve_receipts_paginated`] for details.
+pub fn paginate_receipts(
+    receipts: impl IntoIterator<Item = ReceiptWithState<Checking>>,
+    limit: usize,
+) -> ReceiptsPage {
+    let mut receipts_by_timestamp: BTreeMap<u64, Vec<ReceiptWithState<Checking>>> = BTreeMap::new();
+    for rx_receipt in receipts {
+        receipts_by_timestamp
+            .entry(rx_receipt.signed_receipt().message.timestamp_ns.as_nanos())
+            .or_default()
+            .push(rx_receipt);
+    }
+
+    // Whole timestamps only, and at least one for the pagination to make progress
+    let mut receipts = Vec::new();
+    let mut last_timestamp = None;
+    let mut timestamps = receipts_by_timestamp.into_iter().peekable();
+    while let Some((timestamp, timestamp_receipts)) =
+        timestamps.next_if(|(_, next)| receipts.is_empty() || receipts.len() + next.len() <= limit)
+    {
+        receipts.extend(timestamp_receipts);
+        last_timestamp = Some(timestamp);
+    }
+
+    ReceiptsPage {
+        receipts,
+        next_cursor: timestamps.peek().and(last_timestamp),
+    }
 }
 
 /// See [`ReceiptStorageAdapter::retrieve_receipts_in_timestamp_range()`] for details.
@@ -0,0 +1,51 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use async_trait::async_trait;
+
+use super::{EscrowHandler, ReceiptStore};
+
+/// `Transaction` defines a trait for adapters that can apply several of their operations atomically,
+/// such as storing a receipt and reserving escrow for it.
+///
+/// This trait is designed to be implemented by users of this library alongside the other adapters,
+/// such that a failure (or a crash) in the middle of a multi-step operation of the manager doesn't
+/// leave a partial state behind, e.g. escrow reserved for a receipt that was never stored.
+///
+/// # Usage
+///
+/// The `begin_transaction` method starts a transaction, and returns a handle on it. The receipt and
+/// escrow operations made through the handle are part of the transaction, until `commit_transaction`
+/// applies them, or `rollback_transaction` undoes them. A SQL storage would typically map these onto
+/// `BEGIN`, `COMMIT` and `ROLLBACK`, on a connection held by the handle.
+///
+/// The operations made outside of the handle, e.g. by concurrent calls to the other adapters, are not
+/// part of the transaction, and must not be undone by `rollback_transaction`.
+///
+/// # Example
+///
+/// For example code see [crate::manager::context::memory::InMemoryContext]
+#[async_trait]
+pub trait Transaction {
+    /// Defines the user-specified error type.
+    ///
+    /// This error type should implement the `Error` and `Debug` traits from the standard library.
+    /// Errors of this type are returned to the user when an operation fails.
+    type AdapterError: std::error::Error + std::fmt::Debug + Send + Sync + 'static;
+
+    /// Handle on a transaction in progress, through which the operations of the transaction are made.
+    type Handle: ReceiptStore + EscrowHandler + Send + Sync;
+
+    /// Starts a transaction.
+    async fn begin_transaction(&self) -> Result<Self::Handle, Self::AdapterError>;
+
+    /// Applies the operations made through `transaction`, and ends it.
+    async fn commit_transaction(&self, transaction: Self::Handle)
+        -> Result<(), Self::AdapterError>;
+
+    /// Undoes the operations made through `transaction`, and ends it.
+    async fn rollback_transaction(
+        &self,
+        transaction: Self::Handle,
+    ) -> Result<(), Self::AdapterError>;
+}
@@ -0,0 +1,211 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::future::Future;
+
+use alloy_primitives::Address;
+
+use super::{
+    adapters::{EscrowHandler, RAVRead, RAVStore, ReceiptDelete, ReceiptRead, ReceiptStore},
+    Manager,
+};
+use crate::{
+    rav::{RAVRequest, SignedRAV},
+    receipt::{ReceiptError, SignedReceipt},
+    timestamp::TimestampNs,
+    Error,
+};
+
+/// [`Manager`] scoped to a single allocation, as returned by [`Manager::for_allocation`], such that
+/// the allocation id doesn't have to be passed to every call.
+///
+/// ```
+/// # use std::{collections::HashMap, sync::{Arc, RwLock}, time::Duration};
+/// # use alloy_primitives::Address;
+/// # use ethers::signers::{LocalWallet, Signer};
+/// # use tap_core::{
+/// #     manager::{context::memory::InMemoryContext, Manager},
+/// #     receipt::{checks::{Checks, TimestampCheck}, Receipt},
+/// #     signed_message::EIP712SignedMessage,
+/// #     tap_eip712_domain,
+/// # };
+/// # #[cfg(not(feature = "ethers"))]
+/// # fn main() {}
+/// # #[cfg(feature = "ethers")]
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+/// # let wallet: LocalWallet =
+/// #     "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318".parse()?;
+/// # let sender_id = Address::from(wallet.address().0);
+/// # let mut context = InMemoryContext::new(
+/// #     Arc::new(RwLock::new(HashMap::new())),
+/// #     Arc::new(RwLock::new(HashMap::new())),
+/// #     Arc::new(RwLock::new(HashMap::new())),
+/// #     Arc::new(TimestampCheck::new(0)),
+/// # );
+/// # context.increase_escrow(sender_id, 100);
+/// # let manager = Manager::new(domain_separator.clone(), context, Checks::empty());
+/// let allocation_id = Address::from([0xab; 20]);
+/// let signed_receipt = EIP712SignedMessage::new(
+///     &domain_separator,
+///     Receipt::new(allocation_id, 42)?,
+///     &wallet,
+/// )?;
+///
+/// // Passing the allocation id to each call
+/// assert_eq!(manager.count_receipts(allocation_id).await?, 0);
+/// assert!(manager.export_pending_receipts(allocation_id).await?.is_empty());
+///
+/// // Scoping the calls to the allocation once
+/// let allocation = manager.for_allocation(allocation_id);
+/// allocation.store_receipt(signed_receipt).await?;
+/// assert_eq!(allocation.count_receipts().await?, 1);
+/// assert_eq!(allocation.export_pending_receipts().await?.len(), 1);
+/// let rav_request = allocation.create_rav_request(Duration::ZERO, None).await?;
+/// assert_eq!(rav_request.expected_rav.valueAggregate, 42);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The escrow is held by senders rather than allocations, so [`AllocationView::remaining_escrow`]
+/// only deducts the receipts of the allocation from a sender's escrow.
+pub struct AllocationView<'a, E> {
+    manager: &'a Manager<E>,
+    allocation_id: Address,
+}
+
+impl<E> Manager<E> {
+    /// Returns a view of the manager scoped to `allocation_id`, see [`AllocationView`].
+    pub fn for_allocation(&self, allocation_id: Address) -> AllocationView<'_, E> {
+        AllocationView {
+            manager: self,
+            allocation_id,
+        }
+    }
+}
+
+impl<'a, E> AllocationView<'a, E> {
+    pub fn allocation_id(&self) -> Address {
+        self.allocation_id
+    }
+
+    pub fn manager(&self) -> &'a Manager<E> {
+        self.manager
+    }
+}
+
+impl<E> AllocationView<'_, E>
+where
+    E: ReceiptStore + Sync,
+{
+    /// Same as [`Manager::verify_and_store_receipt`], for receipts of the allocation only.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ReceiptError`] with [`ReceiptError::InvalidAllocationID`] if
+    /// `signed_receipt` is for another allocation
+    ///
+    /// Returns the errors of [`Manager::verify_and_store_receipt`]
+    ///
+    pub async fn store_receipt(&self, signed_receipt: SignedReceipt) -> Result<(), Error> {
+        let received_allocation_id = signed_receipt.message.allocation_id;
+        if received_allocation_id != self.allocation_id {
+            return Err(ReceiptError::InvalidAllocationID {
+                received_allocation_id,
+            }
+            .into());
+        }
+        self.manager.verify_and_store_receipt(signed_receipt).await
+    }
+}
+
+impl<E> AllocationView<'_, E>
+where
+    E: ReceiptRead,
+{
+    /// Same as [`Manager::count_receipts`] for the allocation.
+    pub async fn count_receipts(&self) -> Result<u64, Error>
+    where
+        E: Sync,
+    {
+        self.manager.count_receipts(self.allocation_id).await
+    }
+}
+
+impl<E> AllocationView<'_, E>
+where
+    E: ReceiptRead + RAVRead,
+{
+    /// Same as [`Manager::export_pending_receipts`] for the allocation.
+    pub async fn export_pending_receipts(&self) -> Result<Vec<SignedReceipt>, Error> {
+        self.manager
+            .export_pending_receipts(self.allocation_id)
+            .await
+    }
+}
+
+impl<E> AllocationView<'_, E>
+where
+    E: ReceiptRead + RAVRead + EscrowHandler,
+{
+    /// Same as [`Manager::create_rav_request`], with the receipts of the allocation only. The
+    /// receipts of other allocations are left pending, and no escrow is reserved for them.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`Manager::create_rav_request`]
+    ///
+    pub async fn create_rav_request(
+        &self,
+        timestamp_buffer: impl Into<TimestampNs>,
+        receipts_limit: Option<u64>,
+    ) -> Result<RAVRequest, Error> {
+        self.manager
+            .create_allocation_rav_request(
+                self.allocation_id,
+                timestamp_buffer.into(),
+                receipts_limit,
+            )
+            .await
+    }
+
+    /// Returns the escrow of `sender_id` left once the pending receipts it signed for the
+    /// allocation are paid, i.e. how much more it can spend on the allocation without a top-up. The
+    /// escrow is shared by all the allocations of the sender, so their receipts may use it up too.
+    /// The receipts whose escrow is reserved, e.g. by a RAV request in flight, are already deducted
+    /// from the available escrow, see [`EscrowHandler::is_escrow_reserved`]. The signers of the
+    /// pending receipts are recovered on the first call only, and kept by the manager until the
+    /// receipts are aggregated.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if the available escrow of `sender_id` cannot be read, or
+    /// there are any errors while retrieving the stored RAV of the allocation or the receipts
+    ///
+    pub async fn remaining_escrow(&self, sender_id: Address) -> Result<u128, Error> {
+        self.manager
+            .allocation_remaining_escrow(self.allocation_id, sender_id)
+            .await
+    }
+}
+
+impl<E> AllocationView<'_, E>
+where
+    E: ReceiptRead + ReceiptDelete + RAVRead + RAVStore + EscrowHandler,
+{
+    /// Same as [`Manager::finalize_allocation`] for the allocation.
+    pub async fn finalize<F, Fut, AggregatorError>(
+        &self,
+        aggregator_client: F,
+    ) -> Result<Option<SignedRAV>, Error>
+    where
+        F: FnOnce(RAVRequest) -> Fut,
+        Fut: Future<Output = Result<SignedRAV, AggregatorError>>,
+        AggregatorError: Into<anyhow::Error>,
+    {
+        self.manager
+            .finalize_allocation(self.allocation_id, aggregator_client)
+            .await
+    }
+}
@@ -2,21 +2,37 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{
+    clock::{Clock, SystemClock},
     manager::adapters::*,
     rav::SignedRAV,
-    receipt::{checks::TimestampCheck, Checking, ReceiptWithState},
-    signed_message::MessageId,
+    receipt::{
+        checks::TimestampCheck, AwaitingReserve, Checking, Failed, ReceiptError, ReceiptResult,
+        ReceiptState, ReceiptWithState, Reserved, SignedReceipt,
+    },
+    signed_message::{MessageId, Signature},
 };
 use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
 use async_trait::async_trait;
-use std::ops::RangeBounds;
-use std::sync::RwLock;
-use std::{collections::HashMap, sync::Arc};
+use std::collections::hash_map::Entry;
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
 
 pub type EscrowStorage = Arc<RwLock<HashMap<Address, u128>>>;
 pub type QueryAppraisals = Arc<RwLock<HashMap<MessageId, u128>>>;
-pub type ReceiptStorage = Arc<RwLock<HashMap<u64, ReceiptWithState<Checking>>>>;
-pub type RAVStorage = Arc<RwLock<Option<SignedRAV>>>;
+/// Receipts are stored as blobs encoded with the context's [`ReceiptCodec`]
+pub type ReceiptStorage = Arc<RwLock<HashMap<u64, Vec<u8>>>>;
+pub type RAVStorage = Arc<RwLock<HashMap<Address, SignedRAV>>>;
+/// Newest timestamp of the receipts of each allocation removed from the receipt storage, see
+/// [`ReceiptStore::replay_watermark`]
+pub type ReplayWatermarkStorage = Arc<RwLock<HashMap<Address, u64>>>;
+/// Escrow deposits of each sender, by block number
+type BlockDeposits = BTreeMap<u64, Vec<BlockDeposit>>;
 
 use thiserror::Error;
 
@@ -26,15 +42,94 @@ pub enum InMemoryError {
     AdapterError { error: String },
 }
 
+/// Deep copy of the state of an [`InMemoryContext`], see [`InMemoryContext::snapshot`].
+#[derive(Debug, Clone)]
+pub struct ContextSnapshot {
+    ravs: HashMap<Address, SignedRAV>,
+    receipts: HashMap<u64, Vec<u8>>,
+    unique_id: u64,
+    replay_watermarks: HashMap<Address, u64>,
+    sender_escrows: HashMap<Address, u128>,
+    escrow_reservations: Vec<EscrowReservation>,
+    evicted_timestamps: BTreeSet<u64>,
+    block_deposits: BlockDeposits,
+    escrow_grace_used: HashMap<Address, u128>,
+    failed_receipts: Vec<ReceiptWithState<Failed>>,
+}
+
+/// Deposit made with [`InMemoryContext::deposit_at_block`], split between the escrow it paid back
+/// and the escrow it credited, such that both can be undone by [`InMemoryContext::revert_to_block`].
+#[derive(Debug, Clone)]
+struct BlockDeposit {
+    sender_id: Address,
+    /// Escrow overdrawn by the sender that the deposit paid back, see
+    /// [`InMemoryContext::with_escrow_grace`]
+    paid_back: u128,
+    /// Escrow credited to the sender by the deposit
+    credited: u128,
+}
+
+/// Deposits discarded by [`InMemoryContext::revert_to_block`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RevertedDeposits {
+    /// Total value of the discarded deposits
+    pub value: u128,
+    /// Escrow credited by the discarded deposits that its sender had already reserved, by sender.
+    /// It is counted as overdrawn by the sender, see [`InMemoryContext::escrow_grace_used`].
+    pub overdrawn: HashMap<Address, u128>,
+}
+
+/// Escrow reserved for a receipt, until a RAV aggregating the receipt is stored, or the reservation
+/// expires.
+#[derive(Debug, Clone)]
+struct EscrowReservation {
+    sender_id: Address,
+    allocation_id: Address,
+    value: u128,
+    receipt_id: MessageId,
+    receipt_timestamp_ns: u64,
+    reserved_at_ns: u64,
+    /// Whether a RAV request collected the receipt, see [`EscrowHandler::collect_reserved_escrow`]
+    collected: bool,
+}
+
 #[derive(Clone)]
 pub struct InMemoryContext {
-    /// local RAV store with rwlocks to allow sharing with other compenents as needed
+    /// local RAV store, with the last RAV of each allocation, with rwlocks to allow sharing with
+    /// other compenents as needed
     rav_storage: RAVStorage,
     receipt_storage: ReceiptStorage,
+    /// Decoded receipts of the receipt storage, by timestamp then ID, such that reads only go
+    /// through the receipts in the requested range, without decoding the storage. It holds every
+    /// stored receipt uncompressed, whatever the codec, see [`InMemoryContext::with_codec`]
+    receipt_index: Arc<RwLock<BTreeMap<(u64, u64), SignedReceipt>>>,
+    /// Signatures of the stored receipts, with the number of stored receipts having each, such
+    /// that checking a receipt's uniqueness doesn't go through every stored receipt
+    receipt_signatures: Arc<RwLock<HashMap<Signature, usize>>>,
     unique_id: Arc<RwLock<u64>>,
+    /// Newest timestamp of the removed receipts of each allocation, such that they can't be replayed
+    replay_watermarks: ReplayWatermarkStorage,
     sender_escrow_storage: EscrowStorage,
     timestamp_check: Arc<TimestampCheck>,
     sender_address: Option<Address>,
+    codec: Arc<dyn ReceiptCodec>,
+    /// Maximum number of receipts kept in the receipt storage, unbounded if `None`
+    receipt_capacity: Option<usize>,
+    /// Timestamps of the receipts evicted to stay within `receipt_capacity`
+    evicted_timestamps: Arc<RwLock<BTreeSet<u64>>>,
+    escrow_reservations: Arc<RwLock<Vec<EscrowReservation>>>,
+    /// Time after which an uncommitted escrow reservation can be reclaimed, never if `None`
+    reservation_ttl: Option<Duration>,
+    /// Source of the reservation times
+    clock: Arc<dyn Clock>,
+    /// Escrow deposits by block number, such that they can be reverted on a chain reorg
+    block_deposits: Arc<RwLock<BlockDeposits>>,
+    /// Escrow a sender can overdraw when reserving escrow for receipts
+    escrow_grace: u128,
+    /// Escrow overdrawn by each sender, paid back by their next credits
+    escrow_grace_used: Arc<RwLock<HashMap<Address, u128>>>,
+    /// Receipts that failed their checks, kept for auditing
+    failed_receipt_storage: Arc<RwLock<Vec<ReceiptWithState<Failed>>>>,
 }
 
 impl InMemoryContext {
@@ -44,14 +139,43 @@ impl InMemoryContext {
         sender_escrow_storage: EscrowStorage,
         timestamp_check: Arc<TimestampCheck>,
     ) -> Self {
-        InMemoryContext {
+        // The storages may outlive a context, e.g. across a restart. Receipts up to the last RAV of
+        // their allocation are already aggregated, so they must not be accepted again, and the IDs of
+        // new receipts must not collide with the stored ones.
+        for (&allocation_id, rav) in rav_storage.read().unwrap().iter() {
+            timestamp_check
+                .update_allocation_min_timestamp_ns(allocation_id, rav.message.timestampNs);
+        }
+        let unique_id = receipt_storage
+            .read()
+            .unwrap()
+            .keys()
+            .max()
+            .map_or(0, |id| id + 1);
+
+        let context = InMemoryContext {
             rav_storage,
             receipt_storage,
-            unique_id: Arc::new(RwLock::new(0)),
+            receipt_index: Arc::new(RwLock::new(BTreeMap::new())),
+            receipt_signatures: Arc::new(RwLock::new(HashMap::new())),
+            unique_id: Arc::new(RwLock::new(unique_id)),
+            replay_watermarks: Arc::new(RwLock::new(HashMap::new())),
             sender_escrow_storage,
             timestamp_check,
             sender_address: None,
-        }
+            codec: Arc::new(JsonCodec),
+            receipt_capacity: None,
+            evicted_timestamps: Arc::new(RwLock::new(BTreeSet::new())),
+            escrow_reservations: Arc::new(RwLock::new(Vec::new())),
+            reservation_ttl: None,
+            clock: Arc::new(SystemClock),
+            block_deposits: Arc::new(RwLock::new(BTreeMap::new())),
+            escrow_grace: 0,
+            escrow_grace_used: Arc::new(RwLock::new(HashMap::new())),
+            failed_receipt_storage: Arc::new(RwLock::new(Vec::new())),
+        };
+        context.index_receipts();
+        context
     }
 
     pub fn with_sender_address(mut self, sender_address: Address) -> Self {
@@ -59,31 +183,262 @@ impl InMemoryContext {
         self
     }
 
+    /// Sets the storage of the replay watermarks, see [`ReceiptStore::replay_watermark`]. Like the
+    /// receipt storage, it must outlive the context, such that the receipts removed from the
+    /// storage can't be replayed after a restart.
+    pub fn with_replay_watermark_storage(
+        mut self,
+        replay_watermarks: ReplayWatermarkStorage,
+    ) -> Self {
+        self.replay_watermarks = replay_watermarks;
+        self
+    }
+
+    /// Sets the codec used to encode the receipts in the receipt storage (JSON by default). The
+    /// receipts already in the storage must be encoded with it.
+    ///
+    /// Only the receipt storage holds encoded receipts. The context also keeps every stored receipt
+    /// decoded in memory, to serve reads by timestamp without decoding the storage, so a
+    /// compressing codec such as `ZstdCodec` does not reduce the memory used by the context. It
+    /// shows how an adapter backed by an actual database would use the codec.
+    pub fn with_codec(mut self, codec: impl ReceiptCodec + 'static) -> Self {
+        self.codec = Arc::new(codec);
+        self.index_receipts();
+        self
+    }
+
+    /// Bounds the receipt storage to `capacity` receipts. Once exceeded, the oldest receipts (by
+    /// timestamp) are evicted, and retrieving a timestamp range that contained an evicted receipt
+    /// returns an error, as a RAV can no longer be created for it.
+    pub fn with_receipt_capacity(mut self, capacity: usize) -> Self {
+        self.receipt_capacity = Some(capacity);
+        self
+    }
+
+    /// Lets the escrow reserved for receipts be reclaimed with
+    /// [`EscrowHandler::reclaim_expired_reservations`] once `ttl` has elapsed since the reservation,
+    /// unless a RAV aggregating the receipts was stored in the meantime.
+    pub fn with_reservation_ttl(mut self, ttl: Duration) -> Self {
+        self.reservation_ttl = Some(ttl);
+        self
+    }
+
+    /// Lets a sender overdraw up to `grace` of escrow when reserving escrow for receipts, such that
+    /// a few receipts over the limit are absorbed, rather than failing in-flight queries as soon as
+    /// the escrow runs out. The overdrawn escrow is paid back by the sender's next credits, and
+    /// receipts beyond the grace are rejected. No grace is given by default.
+    pub fn with_escrow_grace(mut self, grace: u128) -> Self {
+        self.escrow_grace = grace;
+        self
+    }
+
+    /// Returns the escrow overdrawn by `sender_id` within the grace given with
+    /// [`InMemoryContext::with_escrow_grace`], e.g. to ask the sender to top up its escrow.
+    pub fn escrow_grace_used(&self, sender_id: Address) -> u128 {
+        self.escrow_grace_used
+            .read()
+            .unwrap()
+            .get(&sender_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Sets the clock used to time the escrow reservations (the system clock by default).
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Captures the RAV, receipt and escrow storages, such that multi-step test scenarios can be
+    /// replayed from that state with [`InMemoryContext::restore`].
+    pub fn snapshot(&self) -> ContextSnapshot {
+        ContextSnapshot {
+            ravs: self.rav_storage.read().unwrap().clone(),
+            receipts: self.receipt_storage.read().unwrap().clone(),
+            unique_id: *self.unique_id.read().unwrap(),
+            replay_watermarks: self.replay_watermarks.read().unwrap().clone(),
+            sender_escrows: self.sender_escrow_storage.read().unwrap().clone(),
+            escrow_reservations: self.escrow_reservations.read().unwrap().clone(),
+            evicted_timestamps: self.evicted_timestamps.read().unwrap().clone(),
+            block_deposits: self.block_deposits.read().unwrap().clone(),
+            escrow_grace_used: self.escrow_grace_used.read().unwrap().clone(),
+            failed_receipts: self.failed_receipt_storage.read().unwrap().clone(),
+        }
+    }
+
+    /// Restores the storages to the state captured by `snapshot`. The storages are shared, so this
+    /// also affects any clone of this context.
+    pub fn restore(&self, snapshot: &ContextSnapshot) {
+        *self.rav_storage.write().unwrap() = snapshot.ravs.clone();
+        *self.receipt_storage.write().unwrap() = snapshot.receipts.clone();
+        self.index_receipts();
+        *self.unique_id.write().unwrap() = snapshot.unique_id;
+        *self.replay_watermarks.write().unwrap() = snapshot.replay_watermarks.clone();
+        *self.sender_escrow_storage.write().unwrap() = snapshot.sender_escrows.clone();
+        *self.escrow_reservations.write().unwrap() = snapshot.escrow_reservations.clone();
+        *self.evicted_timestamps.write().unwrap() = snapshot.evicted_timestamps.clone();
+        *self.block_deposits.write().unwrap() = snapshot.block_deposits.clone();
+        *self.escrow_grace_used.write().unwrap() = snapshot.escrow_grace_used.clone();
+        *self.failed_receipt_storage.write().unwrap() = snapshot.failed_receipts.clone();
+    }
+
+    /// Rebuilds the receipt and signature indexes from the receipt storage. Blobs that can't be
+    /// decoded with the context's codec are left out, they fail to be retrieved anyway.
+    fn index_receipts(&self) {
+        let receipt_storage = self.receipt_storage.read().unwrap();
+        let mut receipt_index = BTreeMap::new();
+        let mut receipt_signatures = HashMap::new();
+        for (&id, blob) in receipt_storage.iter() {
+            if let Ok(signed_receipt) = self.codec.decode(blob) {
+                index_receipt(
+                    &mut receipt_index,
+                    &mut receipt_signatures,
+                    id,
+                    signed_receipt,
+                );
+            }
+        }
+        *self.receipt_index.write().unwrap() = receipt_index;
+        *self.receipt_signatures.write().unwrap() = receipt_signatures;
+    }
+
+    /// Stores the encoded receipt with `id`, and indexes it.
+    fn insert_receipt(&self, id: u64, blob: Vec<u8>, signed_receipt: SignedReceipt) {
+        let mut receipt_storage = self.receipt_storage.write().unwrap();
+        receipt_storage.insert(id, blob);
+        index_receipt(
+            &mut self.receipt_index.write().unwrap(),
+            &mut self.receipt_signatures.write().unwrap(),
+            id,
+            signed_receipt,
+        );
+    }
+
+    /// Returns the IDs of the stored receipts in `timestamp_range_ns` with the receipts, ordered by
+    /// timestamp.
+    fn receipts_in_range(
+        &self,
+        timestamp_range_ns: &impl RangeBounds<u64>,
+    ) -> Vec<(u64, SignedReceipt)> {
+        let Some(key_range) = index_key_range(timestamp_range_ns) else {
+            return Vec::new();
+        };
+        self.receipt_index
+            .read()
+            .unwrap()
+            .range(key_range)
+            .map(|(&(_, id), signed_receipt)| (id, signed_receipt.clone()))
+            .collect()
+    }
+
+    /// Evicts the oldest receipts until at most `capacity` remain. The receipt index is ordered by
+    /// timestamp, so this only goes through the evicted receipts.
+    fn evict_oldest_receipts(&self, capacity: usize) {
+        let mut receipt_storage = self.receipt_storage.write().unwrap();
+        let mut receipt_index = self.receipt_index.write().unwrap();
+        let mut receipt_signatures = self.receipt_signatures.write().unwrap();
+        let mut evicted_timestamps = self.evicted_timestamps.write().unwrap();
+        let mut replay_watermarks = self.replay_watermarks.write().unwrap();
+        while receipt_index.len() > capacity {
+            let Some(((timestamp_ns, id), signed_receipt)) = receipt_index.pop_first() else {
+                break;
+            };
+            receipt_storage.remove(&id);
+            unindex_signature(&mut receipt_signatures, &signed_receipt.signature);
+            evicted_timestamps.insert(timestamp_ns);
+            raise_replay_watermark(&mut replay_watermarks, &signed_receipt);
+        }
+    }
+
+    /// Removes the receipt stored with `receipt_id`, along with it from the indexes. Returns whether
+    /// a receipt was stored with that ID.
+    fn remove_receipt(&self, receipt_id: u64) -> bool {
+        let mut receipt_storage = self.receipt_storage.write().unwrap();
+        let Some(blob) = receipt_storage.remove(&receipt_id) else {
+            return false;
+        };
+        if let Ok(signed_receipt) = self.codec.decode(&blob) {
+            unindex_receipt(
+                &mut self.receipt_index.write().unwrap(),
+                &mut self.receipt_signatures.write().unwrap(),
+                receipt_id,
+                &signed_receipt,
+            );
+            raise_replay_watermark(
+                &mut self.replay_watermarks.write().unwrap(),
+                &signed_receipt,
+            );
+        }
+        true
+    }
+
+    /// Returns the replay watermark of `allocation_id`, i.e. the newest of the timestamp of its last
+    /// RAV and the timestamps of its removed receipts.
+    fn replay_watermark_of(&self, allocation_id: Address) -> u64 {
+        let rav_timestamp_ns = self
+            .rav_storage
+            .read()
+            .unwrap()
+            .get(&allocation_id)
+            .map_or(0, |rav| rav.message.timestampNs);
+        let removed_timestamp_ns = self
+            .replay_watermarks
+            .read()
+            .unwrap()
+            .get(&allocation_id)
+            .copied()
+            .unwrap_or(0);
+        rav_timestamp_ns.max(removed_timestamp_ns)
+    }
+
+    /// Returns whether `signed_receipt` is at or below the replay watermark of its allocation.
+    fn is_below_replay_watermark(&self, signed_receipt: &SignedReceipt) -> bool {
+        signed_receipt.message.timestamp_ns.as_nanos()
+            <= self.replay_watermark_of(signed_receipt.message.allocation_id)
+    }
+
+    fn encode_receipt(
+        &self,
+        receipt: &ReceiptWithState<Checking>,
+    ) -> Result<Vec<u8>, InMemoryError> {
+        self.codec
+            .encode(receipt.signed_receipt())
+            .map_err(|e| InMemoryError::AdapterError {
+                error: e.to_string(),
+            })
+    }
+
+    fn decode_receipt(&self, blob: &[u8]) -> Result<ReceiptWithState<Checking>, InMemoryError> {
+        self.codec
+            .decode(blob)
+            .map(ReceiptWithState::new)
+            .map_err(|e| InMemoryError::AdapterError {
+                error: e.to_string(),
+            })
+    }
+
     pub async fn retrieve_receipt_by_id(
         &self,
         receipt_id: u64,
     ) -> Result<ReceiptWithState<Checking>, InMemoryError> {
         let receipt_storage = self.receipt_storage.read().unwrap();
 
-        receipt_storage
+        let blob = receipt_storage
             .get(&receipt_id)
-            .cloned()
             .ok_or(InMemoryError::AdapterError {
                 error: "No receipt found with ID".to_owned(),
-            })
+            })?;
+        self.decode_receipt(blob)
     }
 
     pub async fn retrieve_receipts_by_timestamp(
         &self,
         timestamp_ns: u64,
     ) -> Result<Vec<(u64, ReceiptWithState<Checking>)>, InMemoryError> {
-        let receipt_storage = self.receipt_storage.read().unwrap();
-        Ok(receipt_storage
-            .iter()
-            .filter(|(_, rx_receipt)| {
-                rx_receipt.signed_receipt().message.timestamp_ns == timestamp_ns
-            })
-            .map(|(&id, rx_receipt)| (id, rx_receipt.clone()))
+        Ok(self
+            .receipts_in_range(&(timestamp_ns..=timestamp_ns))
+            .into_iter()
+            .map(|(id, signed_receipt)| (id, ReceiptWithState::new(signed_receipt)))
             .collect())
     }
 
@@ -96,13 +451,13 @@ impl InMemoryContext {
     }
 
     pub async fn remove_receipt_by_id(&mut self, receipt_id: u64) -> Result<(), InMemoryError> {
-        let mut receipt_storage = self.receipt_storage.write().unwrap();
-        receipt_storage
-            .remove(&receipt_id)
-            .map(|_| ())
-            .ok_or(InMemoryError::AdapterError {
+        if self.remove_receipt(receipt_id) {
+            Ok(())
+        } else {
+            Err(InMemoryError::AdapterError {
                 error: "No receipt found with ID".to_owned(),
             })
+        }
     }
     pub async fn remove_receipts_by_ids(
         &mut self,
@@ -115,15 +470,92 @@ impl InMemoryContext {
     }
 }
 
+/// Adds the receipt stored with `id` to the receipt and signature indexes.
+fn index_receipt(
+    receipt_index: &mut BTreeMap<(u64, u64), SignedReceipt>,
+    receipt_signatures: &mut HashMap<Signature, usize>,
+    id: u64,
+    signed_receipt: SignedReceipt,
+) {
+    *receipt_signatures
+        .entry(signed_receipt.signature)
+        .or_default() += 1;
+    receipt_index.insert(
+        (signed_receipt.message.timestamp_ns.as_nanos(), id),
+        signed_receipt,
+    );
+}
+
+/// Raises the replay watermark of the allocation of `signed_receipt`, removed from the storage, to
+/// its timestamp.
+fn raise_replay_watermark(
+    replay_watermarks: &mut HashMap<Address, u64>,
+    signed_receipt: &SignedReceipt,
+) {
+    let timestamp_ns = signed_receipt.message.timestamp_ns.as_nanos();
+    replay_watermarks
+        .entry(signed_receipt.message.allocation_id)
+        .and_modify(|watermark| *watermark = (*watermark).max(timestamp_ns))
+        .or_insert(timestamp_ns);
+}
+
+/// Removes the receipt stored with `id` from the receipt and signature indexes.
+fn unindex_receipt(
+    receipt_index: &mut BTreeMap<(u64, u64), SignedReceipt>,
+    receipt_signatures: &mut HashMap<Signature, usize>,
+    id: u64,
+    signed_receipt: &SignedReceipt,
+) {
+    receipt_index.remove(&(signed_receipt.message.timestamp_ns.as_nanos(), id));
+    unindex_signature(receipt_signatures, &signed_receipt.signature);
+}
+
+/// Removes one receipt with `signature` from the signature index.
+fn unindex_signature(receipt_signatures: &mut HashMap<Signature, usize>, signature: &Signature) {
+    if let Entry::Occupied(mut entry) = receipt_signatures.entry(*signature) {
+        *entry.get_mut() -= 1;
+        if *entry.get() == 0 {
+            entry.remove();
+        }
+    }
+}
+
+/// Returns the range of receipt index keys covering `timestamp_range_ns`, `None` if it is empty.
+fn index_key_range(
+    timestamp_range_ns: &impl RangeBounds<u64>,
+) -> Option<RangeInclusive<(u64, u64)>> {
+    let start = match timestamp_range_ns.start_bound() {
+        Bound::Included(&timestamp) => timestamp,
+        Bound::Excluded(&timestamp) => timestamp.checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let end = match timestamp_range_ns.end_bound() {
+        Bound::Included(&timestamp) => timestamp,
+        Bound::Excluded(&timestamp) => timestamp.checked_sub(1)?,
+        Bound::Unbounded => u64::MAX,
+    };
+    (start <= end).then_some((start, 0)..=(end, u64::MAX))
+}
+
 #[async_trait]
 impl RAVStore for InMemoryContext {
     type AdapterError = InMemoryError;
 
     async fn update_last_rav(&self, rav: SignedRAV) -> Result<(), Self::AdapterError> {
         let mut rav_storage = self.rav_storage.write().unwrap();
+        let allocation_id = rav.message.allocationId;
         let timestamp = rav.message.timestampNs;
-        *rav_storage = Some(rav);
-        self.timestamp_check.update_min_timestamp_ns(timestamp);
+        rav_storage.insert(allocation_id, rav);
+        self.timestamp_check
+            .update_allocation_min_timestamp_ns(allocation_id, timestamp);
+        // The reservations of the aggregated receipts are committed
+        self.escrow_reservations
+            .write()
+            .unwrap()
+            .retain(|reservation| {
+                reservation.allocation_id != allocation_id
+                    || reservation.receipt_timestamp_ns > timestamp
+            });
         Ok(())
     }
 }
@@ -132,7 +564,19 @@ impl RAVStore for InMemoryContext {
 impl RAVRead for InMemoryContext {
     type AdapterError = InMemoryError;
 
-    async fn last_rav(&self) -> Result<Option<SignedRAV>, Self::AdapterError> {
+    async fn last_rav(
+        &self,
+        allocation_id: Address,
+    ) -> Result<Option<SignedRAV>, Self::AdapterError> {
+        Ok(self
+            .rav_storage
+            .read()
+            .unwrap()
+            .get(&allocation_id)
+            .cloned())
+    }
+
+    async fn load_latest_ravs(&self) -> Result<HashMap<Address, SignedRAV>, Self::AdapterError> {
         Ok(self.rav_storage.read().unwrap().clone())
     }
 }
@@ -145,13 +589,439 @@ impl ReceiptStore for InMemoryContext {
         &self,
         receipt: ReceiptWithState<Checking>,
     ) -> Result<u64, Self::AdapterError> {
+        let blob = self.encode_receipt(&receipt)?;
         let mut id_pointer = self.unique_id.write().unwrap();
         let id_previous = *id_pointer;
-        let mut receipt_storage = self.receipt_storage.write().unwrap();
-        receipt_storage.insert(*id_pointer, receipt);
+        self.insert_receipt(id_previous, blob, receipt.signed_receipt().clone());
         *id_pointer += 1;
+        drop(id_pointer);
+
+        if let Some(capacity) = self.receipt_capacity {
+            self.evict_oldest_receipts(capacity);
+        }
         Ok(id_previous)
     }
+
+    async fn check_and_store_unique(
+        &self,
+        receipt: ReceiptWithState<Checking>,
+    ) -> Result<Option<u64>, Self::AdapterError> {
+        let blob = self.encode_receipt(&receipt)?;
+        let mut id_pointer = self.unique_id.write().unwrap();
+        // The uniqueness check and the insertion happen under the same write lock
+        let mut receipt_storage = self.receipt_storage.write().unwrap();
+        let mut receipt_index = self.receipt_index.write().unwrap();
+        let mut receipt_signatures = self.receipt_signatures.write().unwrap();
+        if receipt_signatures.contains_key(&receipt.signed_receipt().signature)
+            || self.is_below_replay_watermark(receipt.signed_receipt())
+        {
+            return Ok(None);
+        }
+        let id = *id_pointer;
+        receipt_storage.insert(id, blob);
+        index_receipt(
+            &mut receipt_index,
+            &mut receipt_signatures,
+            id,
+            receipt.signed_receipt().clone(),
+        );
+        *id_pointer += 1;
+        drop(receipt_signatures);
+        drop(receipt_index);
+        drop(receipt_storage);
+        drop(id_pointer);
+
+        if let Some(capacity) = self.receipt_capacity {
+            self.evict_oldest_receipts(capacity);
+        }
+        Ok(Some(id))
+    }
+
+    async fn replay_watermark(&self, allocation_id: Address) -> Result<u64, Self::AdapterError> {
+        Ok(self.replay_watermark_of(allocation_id))
+    }
+}
+
+/// Transactions stage their operations, checking that each applies on top of the staged ones, and
+/// apply them all on commit, with every storage they touch locked at once. Readers of the context
+/// never see staged operations, and a rollback just drops them. Committing fails, applying nothing,
+/// if an operation no longer applies, e.g. because the receipt or the escrow was taken meanwhile.
+#[async_trait]
+impl Transaction for InMemoryContext {
+    type AdapterError = InMemoryError;
+    type Handle = InMemoryTransaction;
+
+    async fn begin_transaction(&self) -> Result<Self::Handle, Self::AdapterError> {
+        Ok(InMemoryTransaction {
+            context: self.clone(),
+            staged_operations: Mutex::new(Vec::new()),
+        })
+    }
+
+    async fn commit_transaction(
+        &self,
+        transaction: Self::Handle,
+    ) -> Result<(), Self::AdapterError> {
+        let context = transaction.context;
+        let staged_operations = transaction.staged_operations.into_inner().unwrap();
+        {
+            let mut receipt_storage = context.receipt_storage.write().unwrap();
+            let mut receipt_index = context.receipt_index.write().unwrap();
+            let mut receipt_signatures = context.receipt_signatures.write().unwrap();
+            let mut sender_escrows = context.sender_escrow_storage.write().unwrap();
+            let mut escrow_grace_used = context.escrow_grace_used.write().unwrap();
+            let mut escrow_reservations = context.escrow_reservations.write().unwrap();
+
+            // Every operation is checked before any is applied
+            let mut escrow = EscrowState {
+                sender_escrows: sender_escrows.clone(),
+                escrow_grace_used: escrow_grace_used.clone(),
+                escrow_reservations: escrow_reservations.clone(),
+            };
+            let mut stored_signatures = HashSet::new();
+            for operation in &staged_operations {
+                if let StagedOperation::StoreReceipt {
+                    receipt,
+                    unique: true,
+                    ..
+                } = operation
+                {
+                    if receipt_signatures.contains_key(&receipt.signature)
+                        || !stored_signatures.insert(receipt.signature)
+                    {
+                        return Err(InMemoryError::AdapterError {
+                            error: "Receipt was stored outside of the transaction.".to_owned(),
+                        });
+                    }
+                }
+                escrow.apply(operation, context.escrow_grace)?;
+            }
+
+            for (&sender_id, &grace_used) in &escrow.escrow_grace_used {
+                if grace_used > escrow_grace_used.get(&sender_id).copied().unwrap_or(0) {
+                    log_overdraft(sender_id, grace_used, context.escrow_grace);
+                }
+            }
+            for operation in staged_operations {
+                if let StagedOperation::StoreReceipt {
+                    receipt_id,
+                    blob,
+                    receipt,
+                    ..
+                } = operation
+                {
+                    receipt_storage.insert(receipt_id, blob);
+                    index_receipt(
+                        &mut receipt_index,
+                        &mut receipt_signatures,
+                        receipt_id,
+                        receipt,
+                    );
+                }
+            }
+            *sender_escrows = escrow.sender_escrows;
+            *escrow_grace_used = escrow.escrow_grace_used;
+            *escrow_reservations = escrow.escrow_reservations;
+        }
+
+        if let Some(capacity) = context.receipt_capacity {
+            context.evict_oldest_receipts(capacity);
+        }
+        Ok(())
+    }
+
+    async fn rollback_transaction(
+        &self,
+        _transaction: Self::Handle,
+    ) -> Result<(), Self::AdapterError> {
+        Ok(())
+    }
+}
+
+/// Operation of an [`InMemoryTransaction`], applied to the context on commit.
+enum StagedOperation {
+    /// Stores the encoded receipt with this ID. If `unique`, fails if a receipt with the same
+    /// signature is stored.
+    StoreReceipt {
+        receipt_id: u64,
+        blob: Vec<u8>,
+        receipt: SignedReceipt,
+        unique: bool,
+    },
+    /// Credits escrow to the sender
+    Credit(Address, u128),
+    /// Debits escrow from the sender
+    Debit(Address, u128),
+    /// Reserves escrow of the sender for a receipt
+    ReserveEscrow(EscrowReservation),
+    /// Releases the escrow reserved by the sender for the receipt
+    ReleaseEscrow(Address, SignedReceipt),
+}
+
+/// Copy of the escrow of an [`InMemoryContext`], which the operations of a transaction are applied
+/// to before they reach the context.
+struct EscrowState {
+    sender_escrows: HashMap<Address, u128>,
+    escrow_grace_used: HashMap<Address, u128>,
+    escrow_reservations: Vec<EscrowReservation>,
+}
+
+impl EscrowState {
+    fn apply(
+        &mut self,
+        operation: &StagedOperation,
+        escrow_grace: u128,
+    ) -> Result<(), InMemoryError> {
+        match operation {
+            StagedOperation::StoreReceipt { .. } => Ok(()),
+            StagedOperation::Credit(sender_id, value) => credit_escrow(
+                &mut self.sender_escrows,
+                &mut self.escrow_grace_used,
+                *sender_id,
+                *value,
+            )
+            .map(|_| ()),
+            StagedOperation::Debit(sender_id, value) => {
+                debit_escrow(&mut self.sender_escrows, *sender_id, *value)
+            }
+            StagedOperation::ReserveEscrow(reservation) => {
+                debit_escrow_with_grace(
+                    &mut self.sender_escrows,
+                    &mut self.escrow_grace_used,
+                    escrow_grace,
+                    reservation.sender_id,
+                    reservation.value,
+                )?;
+                self.escrow_reservations.push(reservation.clone());
+                Ok(())
+            }
+            StagedOperation::ReleaseEscrow(sender_id, signed_receipt) => {
+                credit_escrow(
+                    &mut self.sender_escrows,
+                    &mut self.escrow_grace_used,
+                    *sender_id,
+                    signed_receipt.message.value,
+                )?;
+                let receipt_id = signed_receipt.unique_hash();
+                if let Some(index) = self
+                    .escrow_reservations
+                    .iter()
+                    .position(|reservation| reservation.receipt_id == receipt_id)
+                {
+                    self.escrow_reservations.remove(index);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Escrow `sender_id` can still reserve, including the grace it has left
+    fn available_with_grace(&self, sender_id: Address, escrow_grace: u128) -> u128 {
+        let grace_used = self.escrow_grace_used.get(&sender_id).copied().unwrap_or(0);
+        self.sender_escrows
+            .get(&sender_id)
+            .copied()
+            .unwrap_or(0)
+            .saturating_add(escrow_grace.saturating_sub(grace_used))
+    }
+}
+
+/// Handle on a transaction of an [`InMemoryContext`], see [`Transaction`].
+pub struct InMemoryTransaction {
+    context: InMemoryContext,
+    staged_operations: Mutex<Vec<StagedOperation>>,
+}
+
+impl InMemoryTransaction {
+    /// Returns the escrow of the context with the operations staged so far applied.
+    fn escrow_state(
+        &self,
+        staged_operations: &[StagedOperation],
+    ) -> Result<EscrowState, InMemoryError> {
+        let mut escrow = self.context.escrow_state();
+        for operation in staged_operations {
+            escrow.apply(operation, self.context.escrow_grace)?;
+        }
+        Ok(escrow)
+    }
+
+    /// Stages `operation`, unless it doesn't apply on top of the operations staged so far.
+    fn stage(&self, operation: StagedOperation) -> Result<(), InMemoryError> {
+        let mut staged_operations = self.staged_operations.lock().unwrap();
+        self.escrow_state(&staged_operations)?
+            .apply(&operation, self.context.escrow_grace)?;
+        staged_operations.push(operation);
+        Ok(())
+    }
+
+    fn stage_receipt(
+        &self,
+        receipt: &ReceiptWithState<Checking>,
+        unique: bool,
+    ) -> Result<u64, InMemoryError> {
+        let blob = self.context.encode_receipt(receipt)?;
+        let receipt_id = self.context.next_receipt_id();
+        self.stage(StagedOperation::StoreReceipt {
+            receipt_id,
+            blob,
+            receipt: receipt.signed_receipt().clone(),
+            unique,
+        })?;
+        Ok(receipt_id)
+    }
+}
+
+#[async_trait]
+impl ReceiptStore for InMemoryTransaction {
+    type AdapterError = InMemoryError;
+
+    async fn store_receipt(
+        &self,
+        receipt: ReceiptWithState<Checking>,
+    ) -> Result<u64, Self::AdapterError> {
+        self.stage_receipt(&receipt, false)
+    }
+
+    async fn check_and_store_unique(
+        &self,
+        receipt: ReceiptWithState<Checking>,
+    ) -> Result<Option<u64>, Self::AdapterError> {
+        let signature = receipt.signed_receipt().signature;
+        let already_stored = self.context.is_below_replay_watermark(receipt.signed_receipt())
+            || self
+            .context
+            .receipt_signatures
+            .read()
+            .unwrap()
+            .contains_key(&signature)
+            || self.staged_operations.lock().unwrap().iter().any(|operation| {
+                matches!(
+                    operation,
+                    StagedOperation::StoreReceipt { receipt: staged, .. } if staged.signature == signature
+                )
+            });
+        if already_stored {
+            return Ok(None);
+        }
+        self.stage_receipt(&receipt, true).map(Some)
+    }
+
+    async fn replay_watermark(&self, allocation_id: Address) -> Result<u64, Self::AdapterError> {
+        Ok(self.context.replay_watermark_of(allocation_id))
+    }
+}
+
+#[async_trait]
+impl EscrowHandler for InMemoryTransaction {
+    type AdapterError = InMemoryError;
+
+    async fn get_available_escrow(&self, sender_id: Address) -> Result<u128, Self::AdapterError> {
+        let staged_operations = self.staged_operations.lock().unwrap();
+        self.escrow_state(&staged_operations)?
+            .sender_escrows
+            .get(&sender_id)
+            .copied()
+            .ok_or(InMemoryError::AdapterError {
+                error: "No escrow exists for provided sender ID.".to_owned(),
+            })
+    }
+
+    async fn subtract_escrow(
+        &self,
+        sender_id: Address,
+        value: u128,
+    ) -> Result<(), Self::AdapterError> {
+        self.stage(StagedOperation::Debit(sender_id, value))
+    }
+
+    async fn deposit(&self, sender_id: Address, value: u128) -> Result<(), Self::AdapterError> {
+        self.stage(StagedOperation::Credit(sender_id, value))
+    }
+
+    async fn check_and_reserve_escrow(
+        &self,
+        received_receipt: &ReceiptWithState<AwaitingReserve>,
+        domain_separator: &Eip712Domain,
+    ) -> ReceiptResult<()> {
+        let sender_id = InMemoryContext::recover_sender(received_receipt, domain_separator)?;
+        // Escrow is reserved in a transaction as the receipt is stored, and is left for the RAV
+        // request collecting the receipt
+        let reservation =
+            self.context
+                .escrow_reservation(sender_id, received_receipt.signed_receipt(), false)?;
+        let required = reservation.value;
+        self.stage(StagedOperation::ReserveEscrow(reservation))
+            .map_err(|_| {
+                let staged_operations = self.staged_operations.lock().unwrap();
+                let available = self
+                    .escrow_state(&staged_operations)
+                    .map(|escrow| escrow.available_with_grace(sender_id, self.context.escrow_grace))
+                    .unwrap_or(0);
+                reserve_error(required, available)
+            })
+    }
+
+    async fn release_escrow(
+        &self,
+        received_receipt: &ReceiptWithState<Reserved>,
+        domain_separator: &Eip712Domain,
+    ) -> ReceiptResult<()> {
+        let sender_id = InMemoryContext::recover_sender(received_receipt, domain_separator)?;
+        self.stage(StagedOperation::ReleaseEscrow(
+            sender_id,
+            received_receipt.signed_receipt().clone(),
+        ))
+        .map_err(|_| ReceiptError::ReleaseEscrowFailed)
+    }
+
+    async fn verify_signer(&self, signer_address: Address) -> Result<bool, Self::AdapterError> {
+        self.context.verify_signer(signer_address).await
+    }
+}
+
+#[async_trait]
+impl FailedReceiptStore for InMemoryContext {
+    type AdapterError = InMemoryError;
+
+    async fn store_failed_receipt(
+        &self,
+        receipt: ReceiptWithState<Failed>,
+    ) -> Result<(), Self::AdapterError> {
+        self.failed_receipt_storage.write().unwrap().push(receipt);
+        Ok(())
+    }
+
+    async fn retrieve_failed_receipts_in_timestamp_range<
+        R: RangeBounds<u64> + std::marker::Send,
+    >(
+        &self,
+        timestamp_range_ns: R,
+    ) -> Result<Vec<ReceiptWithState<Failed>>, Self::AdapterError> {
+        Ok(self
+            .failed_receipt_storage
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|rx_receipt| {
+                timestamp_range_ns
+                    .contains(&rx_receipt.signed_receipt().message.timestamp_ns.as_nanos())
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_failed_receipts_in_timestamp_range<R: RangeBounds<u64> + std::marker::Send>(
+        &self,
+        timestamp_range_ns: R,
+    ) -> Result<u64, Self::AdapterError> {
+        let mut failed_receipt_storage = self.failed_receipt_storage.write().unwrap();
+        let stored_count = failed_receipt_storage.len();
+        failed_receipt_storage.retain(|rx_receipt| {
+            !timestamp_range_ns
+                .contains(&rx_receipt.signed_receipt().message.timestamp_ns.as_nanos())
+        });
+        Ok((stored_count - failed_receipt_storage.len()) as u64)
+    }
 }
 
 #[async_trait]
@@ -162,10 +1032,14 @@ impl ReceiptDelete for InMemoryContext {
         &self,
         timestamp_ns: R,
     ) -> Result<(), Self::AdapterError> {
-        let mut receipt_storage = self.receipt_storage.write().unwrap();
-        receipt_storage.retain(|_, rx_receipt| {
-            !timestamp_ns.contains(&rx_receipt.signed_receipt().message.timestamp_ns)
-        });
+        for (id, _) in self.receipts_in_range(&timestamp_ns) {
+            self.remove_receipt(id);
+        }
+        // Receipts evicted in that range are not needed anymore either
+        self.evicted_timestamps
+            .write()
+            .unwrap()
+            .retain(|timestamp| !timestamp_ns.contains(timestamp));
         Ok(())
     }
 }
@@ -177,13 +1051,22 @@ impl ReceiptRead for InMemoryContext {
         timestamp_range_ns: R,
         limit: Option<u64>,
     ) -> Result<Vec<ReceiptWithState<Checking>>, Self::AdapterError> {
-        let receipt_storage = self.receipt_storage.read().unwrap();
-        let mut receipts_in_range: Vec<ReceiptWithState<Checking>> = receipt_storage
+        if self
+            .evicted_timestamps
+            .read()
+            .unwrap()
             .iter()
-            .filter(|(_, rx_receipt)| {
-                timestamp_range_ns.contains(&rx_receipt.signed_receipt().message.timestamp_ns)
-            })
-            .map(|(&_id, rx_receipt)| rx_receipt.clone())
+            .any(|timestamp| timestamp_range_ns.contains(timestamp))
+        {
+            return Err(InMemoryError::AdapterError {
+                error: "Receipts in the requested range were evicted from storage".to_owned(),
+            });
+        }
+
+        let mut receipts_in_range: Vec<ReceiptWithState<Checking>> = self
+            .receipts_in_range(&timestamp_range_ns)
+            .into_iter()
+            .map(|(_id, signed_receipt)| ReceiptWithState::new(signed_receipt))
             .collect();
 
         if limit.is_some_and(|limit| receipts_in_range.len() > limit as usize) {
@@ -191,6 +1074,32 @@ impl ReceiptRead for InMemoryContext {
         }
         Ok(receipts_in_range.into_iter().collect())
     }
+
+    async fn count_receipts(&self, allocation_id: Address) -> Result<u64, Self::AdapterError> {
+        Ok(self
+            .receipt_index
+            .read()
+            .unwrap()
+            .values()
+            .filter(|signed_receipt| signed_receipt.message.allocation_id == allocation_id)
+            .count() as u64)
+    }
+
+    async fn retrieve_receipts_paginated(
+        &self,
+        allocation_id: Address,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<ReceiptsPage, Self::AdapterError> {
+        // Receipts of the allocation after the cursor
+        let after = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let receipts = self
+            .receipts_in_range(&(after, Bound::Unbounded))
+            .into_iter()
+            .filter(|(_, signed_receipt)| signed_receipt.message.allocation_id == allocation_id)
+            .map(|(_, signed_receipt)| ReceiptWithState::new(signed_receipt));
+        Ok(paginate_receipts(receipts, limit))
+    }
 }
 
 impl InMemoryContext {
@@ -204,30 +1113,325 @@ impl InMemoryContext {
         })
     }
 
+    /// Same as [`InMemoryContext::checked_increase_escrow`], paying back the escrow overdrawn
+    /// within the grace first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the escrow of `sender_id` overflows
     pub fn increase_escrow(&mut self, sender_id: Address, value: u128) {
-        let mut sender_escrow_storage = self.sender_escrow_storage.write().unwrap();
+        self.checked_increase_escrow(sender_id, value)
+            .expect("escrow overflow");
+    }
 
-        if let Some(current_value) = sender_escrow_storage.get(&sender_id) {
-            let mut sender_escrow_storage = self.sender_escrow_storage.write().unwrap();
-            sender_escrow_storage.insert(sender_id, current_value + value);
-        } else {
-            sender_escrow_storage.insert(sender_id, value);
+    /// Credits `value` to the escrow of `sender_id`. The escrow overdrawn within the grace given
+    /// with [`InMemoryContext::with_escrow_grace`] is paid back first.
+    pub fn checked_increase_escrow(
+        &self,
+        sender_id: Address,
+        value: u128,
+    ) -> Result<(), InMemoryError> {
+        credit_escrow(
+            &mut self.sender_escrow_storage.write().unwrap(),
+            &mut self.escrow_grace_used.write().unwrap(),
+            sender_id,
+            value,
+        )
+        .map(|_| ())
+    }
+
+    /// Same as [`InMemoryContext::reduce_escrow`], overdrawing the escrow within the grace given
+    /// with [`InMemoryContext::with_escrow_grace`] if needed.
+    fn reduce_escrow_with_grace(
+        &self,
+        sender_id: Address,
+        value: u128,
+    ) -> Result<(), InMemoryError> {
+        let overdraft = debit_escrow_with_grace(
+            &mut self.sender_escrow_storage.write().unwrap(),
+            &mut self.escrow_grace_used.write().unwrap(),
+            self.escrow_grace,
+            sender_id,
+            value,
+        )?;
+        if let Some(grace_used) = overdraft {
+            log_overdraft(sender_id, grace_used, self.escrow_grace);
         }
+        Ok(())
     }
 
     pub fn reduce_escrow(&self, sender_id: Address, value: u128) -> Result<(), InMemoryError> {
+        debit_escrow(
+            &mut self.sender_escrow_storage.write().unwrap(),
+            sender_id,
+            value,
+        )
+    }
+
+    /// Copies the escrow storages, see [`EscrowState`].
+    fn escrow_state(&self) -> EscrowState {
+        EscrowState {
+            sender_escrows: self.sender_escrow_storage.read().unwrap().clone(),
+            escrow_grace_used: self.escrow_grace_used.read().unwrap().clone(),
+            escrow_reservations: self.escrow_reservations.read().unwrap().clone(),
+        }
+    }
+
+    /// Takes the ID of the next stored receipt.
+    fn next_receipt_id(&self) -> u64 {
+        let mut id_pointer = self.unique_id.write().unwrap();
+        let id = *id_pointer;
+        *id_pointer += 1;
+        id
+    }
+
+    fn recover_sender<S: ReceiptState>(
+        received_receipt: &ReceiptWithState<S>,
+        domain_separator: &Eip712Domain,
+    ) -> ReceiptResult<Address> {
+        received_receipt
+            .recover_signer(domain_separator)
+            .map_err(|err| ReceiptError::InvalidSignature {
+                source_error_message: err.to_string(),
+            })
+    }
+
+    /// Reservation of the escrow of `sender_id` for `signed_receipt`, made now.
+    fn escrow_reservation(
+        &self,
+        sender_id: Address,
+        signed_receipt: &SignedReceipt,
+        collected: bool,
+    ) -> ReceiptResult<EscrowReservation> {
+        let reserved_at_ns = self
+            .clock
+            .now_ns()
+            .map_err(|err| ReceiptError::CheckFailedToComplete(err.to_string()))?;
+        Ok(EscrowReservation {
+            sender_id,
+            allocation_id: signed_receipt.message.allocation_id,
+            value: signed_receipt.message.value,
+            receipt_id: signed_receipt.unique_hash(),
+            receipt_timestamp_ns: signed_receipt.message.timestamp_ns.as_nanos(),
+            reserved_at_ns,
+            collected,
+        })
+    }
+
+    /// Reserves the escrow of `sender_id` for `signed_receipt`, see
+    /// [`EscrowHandler::check_and_reserve_escrow`].
+    fn reserve_escrow(
+        &self,
+        sender_id: Address,
+        signed_receipt: &SignedReceipt,
+    ) -> ReceiptResult<()> {
+        let reservation = self.escrow_reservation(sender_id, signed_receipt, true)?;
+        let required = reservation.value;
+        self.reduce_escrow_with_grace(sender_id, required)
+            .map_err(|_| {
+                // The remaining grace can be drawn on as well
+                let available = self.escrow(sender_id).unwrap_or(0).saturating_add(
+                    self.escrow_grace
+                        .saturating_sub(self.escrow_grace_used(sender_id)),
+                );
+                reserve_error(required, available)
+            })?;
+        self.escrow_reservations.write().unwrap().push(reservation);
+        Ok(())
+    }
+
+    /// Releases the escrow reserved by `sender_id` for `signed_receipt`, see
+    /// [`EscrowHandler::release_escrow`].
+    fn release_reserved_escrow(
+        &self,
+        sender_id: Address,
+        signed_receipt: &SignedReceipt,
+    ) -> ReceiptResult<()> {
+        self.checked_increase_escrow(sender_id, signed_receipt.message.value)
+            .map_err(|_| ReceiptError::ReleaseEscrowFailed)?;
+        let receipt_id = signed_receipt.unique_hash();
+        let mut escrow_reservations = self.escrow_reservations.write().unwrap();
+        if let Some(index) = escrow_reservations
+            .iter()
+            .position(|reservation| reservation.receipt_id == receipt_id)
+        {
+            escrow_reservations.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Credits the escrow of `sender_id` with a deposit made at `block_number`, such that it can be
+    /// reverted with [`InMemoryContext::revert_to_block`] if the block is reorged.
+    pub fn deposit_at_block(
+        &self,
+        sender_id: Address,
+        value: u128,
+        block_number: u64,
+    ) -> Result<(), InMemoryError> {
+        let mut sender_escrow_storage = self.sender_escrow_storage.write().unwrap();
+        let mut escrow_grace_used = self.escrow_grace_used.write().unwrap();
+        let paid_back = credit_escrow(
+            &mut sender_escrow_storage,
+            &mut escrow_grace_used,
+            sender_id,
+            value,
+        )?;
+        self.block_deposits
+            .write()
+            .unwrap()
+            .entry(block_number)
+            .or_default()
+            .push(BlockDeposit {
+                sender_id,
+                paid_back,
+                credited: value - paid_back,
+            });
+        Ok(())
+    }
+
+    /// Discards the deposits made with [`InMemoryContext::deposit_at_block`] after `block_number`,
+    /// once these blocks were reorged out of the canonical chain.
+    ///
+    /// The escrow a discarded deposit paid back is overdrawn again, and the escrow it credited is
+    /// debited. The escrow reserved for receipts is kept, as the receipts are still valid, so if the
+    /// credited escrow was already reserved, the part of it left uncovered is counted as overdrawn
+    /// too, to be paid back by the sender's next credits, and is reported in
+    /// [`RevertedDeposits::overdrawn`].
+    pub fn revert_to_block(&self, block_number: u64) -> RevertedDeposits {
         let mut sender_escrow_storage = self.sender_escrow_storage.write().unwrap();
+        let mut escrow_grace_used = self.escrow_grace_used.write().unwrap();
+        let reverted_deposits = match block_number.checked_add(1) {
+            Some(first_reverted_block) => self
+                .block_deposits
+                .write()
+                .unwrap()
+                .split_off(&first_reverted_block),
+            None => BTreeMap::new(),
+        };
 
-        if let Some(current_value) = sender_escrow_storage.get(&sender_id) {
-            let checked_new_value = current_value.checked_sub(value);
-            if let Some(new_value) = checked_new_value {
-                sender_escrow_storage.insert(sender_id, new_value);
-                return Ok(());
+        let mut reverted = RevertedDeposits::default();
+        // The newest deposits are undone first, as they were made on top of the older ones
+        for deposit in reverted_deposits.into_values().flatten().rev() {
+            let escrow = sender_escrow_storage.entry(deposit.sender_id).or_default();
+            let debited = deposit.credited.min(*escrow);
+            *escrow -= debited;
+            let overdrawn = deposit.credited - debited;
+            let owed = deposit.paid_back.saturating_add(overdrawn);
+            if owed > 0 {
+                let grace_used = escrow_grace_used.entry(deposit.sender_id).or_default();
+                *grace_used = grace_used.saturating_add(owed);
             }
+            if overdrawn > 0 {
+                let sender_overdrawn = reverted.overdrawn.entry(deposit.sender_id).or_default();
+                *sender_overdrawn = sender_overdrawn.saturating_add(overdrawn);
+            }
+            reverted.value = reverted
+                .value
+                .saturating_add(deposit.paid_back + deposit.credited);
         }
-        Err(InMemoryError::AdapterError {
-            error: "Provided value is greater than existing escrow.".to_owned(),
-        })
+        for (&sender_id, &overdrawn) in &reverted.overdrawn {
+            log::warn!(
+                "Reverted deposits of sender {} were already reserved, {} of escrow is overdrawn",
+                sender_id,
+                overdrawn
+            );
+        }
+        reverted
+    }
+}
+
+/// Credits `value` to the escrow of `sender_id`, paying back the escrow it overdrew first, see
+/// [`InMemoryContext::checked_increase_escrow`]. Returns the overdrawn escrow paid back.
+fn credit_escrow(
+    sender_escrows: &mut HashMap<Address, u128>,
+    escrow_grace_used: &mut HashMap<Address, u128>,
+    sender_id: Address,
+    value: u128,
+) -> Result<u128, InMemoryError> {
+    // The overdrawn escrow is paid back first
+    let grace_used = escrow_grace_used.get(&sender_id).copied().unwrap_or(0);
+    let paid_back = grace_used.min(value);
+    let current_value = sender_escrows.get(&sender_id).copied().unwrap_or(0);
+    let new_value =
+        current_value
+            .checked_add(value - paid_back)
+            .ok_or(InMemoryError::AdapterError {
+                error: "Provided value overflows existing escrow.".to_owned(),
+            })?;
+    sender_escrows.insert(sender_id, new_value);
+    if paid_back > 0 {
+        escrow_grace_used.insert(sender_id, grace_used - paid_back);
+    }
+    Ok(paid_back)
+}
+
+/// Debits `value` from the escrow of `sender_id`, see [`InMemoryContext::reduce_escrow`].
+fn debit_escrow(
+    sender_escrows: &mut HashMap<Address, u128>,
+    sender_id: Address,
+    value: u128,
+) -> Result<(), InMemoryError> {
+    if let Some(current_value) = sender_escrows.get_mut(&sender_id) {
+        if let Some(new_value) = current_value.checked_sub(value) {
+            *current_value = new_value;
+            return Ok(());
+        }
+    }
+    Err(InMemoryError::AdapterError {
+        error: "Provided value is greater than existing escrow.".to_owned(),
+    })
+}
+
+/// Debits `value` from the escrow of `sender_id`, overdrawing it up to `escrow_grace` if needed.
+/// Returns the escrow overdrawn by the sender if it had to overdraw it.
+fn debit_escrow_with_grace(
+    sender_escrows: &mut HashMap<Address, u128>,
+    escrow_grace_used: &mut HashMap<Address, u128>,
+    escrow_grace: u128,
+    sender_id: Address,
+    value: u128,
+) -> Result<Option<u128>, InMemoryError> {
+    let Some(current_value) = sender_escrows.get_mut(&sender_id) else {
+        return Err(InMemoryError::AdapterError {
+            error: "No escrow exists for provided sender ID.".to_owned(),
+        });
+    };
+    if let Some(new_value) = current_value.checked_sub(value) {
+        *current_value = new_value;
+        return Ok(None);
+    }
+
+    let grace_used = escrow_grace_used.entry(sender_id).or_default();
+    let new_grace_used = grace_used
+        .checked_add(value - *current_value)
+        .filter(|new_grace_used| *new_grace_used <= escrow_grace)
+        .ok_or(InMemoryError::AdapterError {
+            error: "Provided value is greater than existing escrow and grace.".to_owned(),
+        })?;
+    *grace_used = new_grace_used;
+    *current_value = 0;
+    Ok(Some(new_grace_used))
+}
+
+fn log_overdraft(sender_id: Address, grace_used: u128, escrow_grace: u128) {
+    log::warn!(
+        "Sender {} overdrew its escrow by {} (grace of {}), it should top up its escrow",
+        sender_id,
+        grace_used,
+        escrow_grace
+    );
+}
+
+/// Error of a reservation of `required` escrow, when only `available` is left.
+fn reserve_error(required: u128, available: u128) -> ReceiptError {
+    if available < required {
+        ReceiptError::InsufficientEscrow {
+            required,
+            available,
+            shortfall: required - available,
+        }
+    } else {
+        ReceiptError::SubtractEscrowFailed
     }
 }
 
@@ -245,6 +1449,80 @@ impl EscrowHandler for InMemoryContext {
         self.reduce_escrow(sender_id, value)
     }
 
+    async fn deposit(&self, sender_id: Address, value: u128) -> Result<(), Self::AdapterError> {
+        self.checked_increase_escrow(sender_id, value)
+    }
+
+    async fn check_and_reserve_escrow(
+        &self,
+        received_receipt: &ReceiptWithState<AwaitingReserve>,
+        domain_separator: &Eip712Domain,
+    ) -> ReceiptResult<()> {
+        let sender_id = Self::recover_sender(received_receipt, domain_separator)?;
+        self.reserve_escrow(sender_id, received_receipt.signed_receipt())
+    }
+
+    async fn release_escrow(
+        &self,
+        received_receipt: &ReceiptWithState<Reserved>,
+        domain_separator: &Eip712Domain,
+    ) -> ReceiptResult<()> {
+        let sender_id = Self::recover_sender(received_receipt, domain_separator)?;
+        self.release_reserved_escrow(sender_id, received_receipt.signed_receipt())
+    }
+
+    async fn collect_reserved_escrow(
+        &self,
+        receipt_id: MessageId,
+    ) -> Result<bool, Self::AdapterError> {
+        let mut escrow_reservations = self.escrow_reservations.write().unwrap();
+        match escrow_reservations
+            .iter_mut()
+            .find(|reservation| reservation.receipt_id == receipt_id && !reservation.collected)
+        {
+            Some(reservation) => {
+                reservation.collected = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn is_escrow_reserved(&self, receipt_id: MessageId) -> Result<bool, Self::AdapterError> {
+        Ok(self
+            .escrow_reservations
+            .read()
+            .unwrap()
+            .iter()
+            .any(|reservation| reservation.receipt_id == receipt_id))
+    }
+
+    async fn reclaim_expired_reservations(&self, now_ns: u64) -> Result<u128, Self::AdapterError> {
+        let Some(ttl) = self.reservation_ttl else {
+            return Ok(0);
+        };
+        let ttl_ns = u64::try_from(ttl.as_nanos()).unwrap_or(u64::MAX);
+
+        let mut expired_reservations = vec![];
+        self.escrow_reservations
+            .write()
+            .unwrap()
+            .retain(|reservation| {
+                let expired = reservation.reserved_at_ns.saturating_add(ttl_ns) <= now_ns;
+                if expired {
+                    expired_reservations.push(reservation.clone());
+                }
+                !expired
+            });
+
+        let mut reclaimed_value = 0u128;
+        for reservation in expired_reservations {
+            self.checked_increase_escrow(reservation.sender_id, reservation.value)?;
+            reclaimed_value = reclaimed_value.saturating_add(reservation.value);
+        }
+        Ok(reclaimed_value)
+    }
+
     async fn verify_signer(&self, signer_address: Address) -> Result<bool, Self::AdapterError> {
         Ok(self
             .sender_address
@@ -277,7 +1555,7 @@ pub mod checks {
         vec![
             // Arc::new(UniqueCheck ),
             // Arc::new(ValueCheck { query_appraisals }),
-            Arc::new(AllocationIdCheck { allocation_ids }),
+            Arc::new(AllocationIdCheck::new(allocation_ids)),
             Arc::new(SignatureCheck {
                 domain_separator,
                 valid_signers,
@@ -285,6 +1563,7 @@ pub mod checks {
         ]
     }
 
+    #[allow(dead_code)]
     struct ValueCheck {
         query_appraisals: Arc<RwLock<HashMap<MessageId, u128>>>,
     }
@@ -292,7 +1571,6 @@ pub mod checks {
     #[async_trait::async_trait]
     impl Check for ValueCheck {
         async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
-            let value = receipt.signed_receipt().message.value;
             let query_appraisals = self.query_appraisals.read().unwrap();
             let hash = receipt.signed_receipt().unique_hash();
             let appraised_value =
@@ -302,32 +1580,61 @@ pub mod checks {
                         "Could not find query_appraisals".into(),
                     ))?;
 
-            if value != *appraised_value {
-                Err(ReceiptError::InvalidValue {
-                    received_value: value,
-                }
-                .into())
-            } else {
-                Ok(())
-            }
+            Ok(receipt.check_value_against(*appraised_value)?)
         }
     }
 
-    struct AllocationIdCheck {
+    /// Accepts receipts for active allocations only. Receipts for allocations that are known but no
+    /// longer active fail with [`ReceiptError::ClosedAllocationID`], and receipts for allocations
+    /// that never existed fail with [`ReceiptError::InvalidAllocationID`], or with
+    /// [`ReceiptError::ZeroAllocationID`] for the zero address, which points at a sender bug.
+    pub struct AllocationIdCheck {
         allocation_ids: Arc<RwLock<HashSet<Address>>>,
+        known_allocation_ids: Option<Arc<RwLock<HashSet<Address>>>>,
+    }
+
+    impl AllocationIdCheck {
+        /// Creates a check accepting the active `allocation_ids`. Without known allocation ids,
+        /// every other allocation is considered unknown.
+        pub fn new(allocation_ids: Arc<RwLock<HashSet<Address>>>) -> Self {
+            Self {
+                allocation_ids,
+                known_allocation_ids: None,
+            }
+        }
+
+        /// Sets all the allocation ids that ever existed, active or closed.
+        pub fn with_known_allocation_ids(
+            mut self,
+            known_allocation_ids: Arc<RwLock<HashSet<Address>>>,
+        ) -> Self {
+            self.known_allocation_ids = Some(known_allocation_ids);
+            self
+        }
     }
 
     #[async_trait::async_trait]
     impl Check for AllocationIdCheck {
         async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
             let received_allocation_id = receipt.signed_receipt().message.allocation_id;
-            if self
+            if received_allocation_id == Address::ZERO {
+                Err(ReceiptError::ZeroAllocationID.into())
+            } else if self
                 .allocation_ids
                 .read()
                 .unwrap()
                 .contains(&received_allocation_id)
             {
                 Ok(())
+            } else if self
+                .known_allocation_ids
+                .as_ref()
+                .is_some_and(|known| known.read().unwrap().contains(&received_allocation_id))
+            {
+                Err(ReceiptError::ClosedAllocationID {
+                    received_allocation_id,
+                }
+                .into())
             } else {
                 Err(ReceiptError::InvalidAllocationID {
                     received_allocation_id,
@@ -345,12 +1652,12 @@ pub mod checks {
     #[async_trait::async_trait]
     impl Check for SignatureCheck {
         async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
-            let recovered_address = receipt
-                .signed_receipt()
-                .recover_signer(&self.domain_separator)
-                .map_err(|e| ReceiptError::InvalidSignature {
-                    source_error_message: e.to_string(),
-                })?;
+            let recovered_address =
+                receipt
+                    .recover_signer(&self.domain_separator)
+                    .map_err(|e| ReceiptError::InvalidSignature {
+                        source_error_message: e.to_string(),
+                    })?;
             if !self.valid_signers.contains(&recovered_address) {
                 Err(ReceiptError::InvalidSignature {
                     source_error_message: "Invalid signer".to_string(),
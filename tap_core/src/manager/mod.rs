@@ -11,8 +11,10 @@
 //! This design offers a high degree of flexibility, letting the user define their own behavior for these critical operations.
 
 pub mod adapters;
+mod allocation_view;
 #[cfg(feature = "in_memory")]
 pub mod context;
 mod tap_manager;
 
-pub use tap_manager::Manager;
+pub use allocation_view::AllocationView;
+pub use tap_manager::{Manager, ReconcileReport};
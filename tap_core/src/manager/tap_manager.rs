@@ -1,18 +1,41 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    future::Future,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use alloy_primitives::Address;
 use alloy_sol_types::Eip712Domain;
+use futures_util::{stream, StreamExt};
 
-use super::adapters::{EscrowHandler, RAVRead, RAVStore, ReceiptDelete, ReceiptRead, ReceiptStore};
+use super::adapters::{
+    safe_truncate_receipts, EscrowHandler, FailedReceiptStore, RAVRead, RAVStore, ReceiptDelete,
+    ReceiptRead, ReceiptStore, Transaction,
+};
 use crate::{
+    clock::{Clock, SystemClock},
     rav::{RAVRequest, ReceiptAggregateVoucher, SignedRAV},
     receipt::{
-        checks::{BatchTimestampCheck, CheckBatch, Checks, UniqueCheck},
-        Failed, ReceiptWithState, Reserved, SignedReceipt,
+        checks::{BatchTimestampCheck, CheckBatch, Checks, ReceiptCheck, UniqueCheck},
+        AwaitingReserve, Checking, Failed, ReceiptError, ReceiptOutcome, ReceiptState,
+        ReceiptWithState, Reserved, SignedReceipt,
     },
+    signed_message::MessageId,
+    timestamp::TimestampNs,
     Error,
 };
 
+/// Number of receipts retrieved at once when collecting the receipts of a RAV request.
+const RECEIPTS_PAGE_SIZE: usize = 1_000;
+
 pub struct Manager<E> {
     /// Context that implements adapters
     context: E,
@@ -20,9 +43,64 @@ pub struct Manager<E> {
     /// Checks that must be completed for each receipt before being confirmed or denied for rav request
     checks: Checks,
 
+    /// Checks skipped even though they are part of `checks`, see [`Manager::with_disabled_checks`]
+    disabled_checks: HashSet<ReceiptCheck>,
+
     /// Struct responsible for doing checks for receipt. Ownership stays with manager allowing manager
     /// to update configuration ( like minimum timestamp ).
     domain_separator: Eip712Domain,
+
+    /// Minimum number of valid receipts needed to create a RAV request
+    min_receipts: usize,
+
+    /// Minimum aggregated value of the valid receipts needed to create a RAV request
+    min_value: u128,
+
+    /// Source of the current time, used to bound the receipts included in a RAV request
+    clock: Arc<dyn Clock>,
+
+    /// Allocations finalized with [`Manager::finalize_allocation`], for which receipts are rejected
+    closed_allocations: RwLock<HashSet<Address>>,
+
+    /// Whether new receipts are accepted, see [`Manager::set_accepting`]
+    accepting: AtomicBool,
+
+    /// Signers of the pending receipts of each allocation, recovered once rather than on each
+    /// [`super::AllocationView::remaining_escrow`] call
+    receipt_signers: RwLock<HashMap<Address, HashMap<MessageId, Address>>>,
+
+    /// Maximum number of receipts stored at once, see [`Manager::with_store_concurrency`]
+    store_concurrency: usize,
+}
+
+/// Comparison of a RAV's value with the receipts held for its allocation, see
+/// [`Manager::reconcile_rav`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconcileReport {
+    pub allocation_id: Address,
+    /// Value claimed by the RAV
+    pub rav_value: u128,
+    /// Number of receipts held for the allocation, up to the RAV timestamp
+    pub receipts_count: usize,
+    /// Aggregated value of the receipts held for the allocation, up to the RAV timestamp
+    pub receipts_value: u128,
+}
+
+impl ReconcileReport {
+    /// Value claimed by the RAV that is not backed by any held receipt, e.g. fabricated receipts.
+    pub fn surplus(&self) -> u128 {
+        self.rav_value.saturating_sub(self.receipts_value)
+    }
+
+    /// Value of the held receipts that the RAV doesn't account for.
+    pub fn shortfall(&self) -> u128 {
+        self.receipts_value.saturating_sub(self.rav_value)
+    }
+
+    /// Whether the RAV value matches the held receipts exactly.
+    pub fn is_balanced(&self) -> bool {
+        self.rav_value == self.receipts_value
+    }
 }
 
 impl<E> Manager<E> {
@@ -35,29 +113,128 @@ impl<E> Manager<E> {
             context,
             domain_separator,
             checks: checks.into(),
+            disabled_checks: HashSet::new(),
+            min_receipts: 0,
+            min_value: 0,
+            clock: Arc::new(SystemClock),
+            closed_allocations: RwLock::new(HashSet::new()),
+            accepting: AtomicBool::new(true),
+            receipt_signers: RwLock::new(HashMap::new()),
+            store_concurrency: 1,
         }
     }
+
+    /// Replaces the system clock used by the manager with `clock`, e.g. to control time in tests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the maximum number of receipts verified and stored at once by
+    /// [`Manager::verify_and_store_receipts`], e.g. to keep a storage adapter with high latency busy.
+    /// Defaults to 1, i.e. one receipt after the other. A value of 0 is treated as 1.
+    pub fn with_store_concurrency(mut self, store_concurrency: usize) -> Self {
+        self.store_concurrency = store_concurrency.max(1);
+        self
+    }
+
+    /// Sets the minimum number of valid receipts and their minimum aggregated value needed for
+    /// [`Manager::create_rav_request`] to produce a RAV request. Below these, receipts are kept
+    /// in storage until enough are collected for the RAV to be worth redeeming.
+    pub fn with_min_rav_thresholds(mut self, min_receipts: usize, min_value: u128) -> Self {
+        self.min_receipts = min_receipts;
+        self.min_value = min_value;
+        self
+    }
+
+    /// Disables the `disabled_checks`, such that they are never executed, even though they are part
+    /// of the checks provided to [`Manager::new`]. E.g. to disable a value check in a deployment
+    /// without query pricing. Checks are matched by [`crate::receipt::checks::Check::name`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidCheckError`] if one of the `disabled_checks` is not part of the checks
+    /// of the manager, as it would most likely be a misconfiguration.
+    pub fn with_disabled_checks(
+        mut self,
+        disabled_checks: HashSet<ReceiptCheck>,
+    ) -> Result<Self, Error> {
+        if let Some(unknown) = disabled_checks
+            .iter()
+            .find(|disabled| !self.checks.contains(disabled))
+        {
+            return Err(Error::InvalidCheckError {
+                check_string: unknown.name().to_string(),
+            });
+        }
+        self.disabled_checks = disabled_checks;
+        Ok(self)
+    }
+
+    /// Returns the checks of the manager that are not disabled, logging the skipped ones.
+    fn enabled_checks(&self) -> Vec<ReceiptCheck> {
+        self.checks
+            .iter()
+            .filter(|check| {
+                let disabled = self.disabled_checks.contains(*check);
+                if disabled {
+                    log::debug!("Receipt check {} is disabled, skipping it", check.name());
+                }
+                !disabled
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Pauses (`false`) or resumes (`true`) the acceptance of new receipts at runtime, e.g. for
+    /// maintenance. While paused, receipts are rejected with [`Error::ServiceUnavailable`], such that
+    /// senders can retry them later. Receipts already being verified and stored are not affected.
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::SeqCst);
+    }
+
+    /// Returns whether new receipts are accepted, see [`Manager::set_accepting`].
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::SeqCst)
+    }
+
+    fn check_allocation_open(&self, signed_receipt: &SignedReceipt) -> Result<(), Error> {
+        let allocation_id = signed_receipt.message.allocation_id;
+        if self
+            .closed_allocations
+            .read()
+            .unwrap()
+            .contains(&allocation_id)
+        {
+            return Err(Error::AllocationClosed { allocation_id });
+        }
+        Ok(())
+    }
 }
 
 impl<E> Manager<E>
 where
-    E: RAVStore + EscrowHandler,
+    E: RAVStore + RAVRead + EscrowHandler,
 {
-    /// Verify `signed_rav` matches all values on `expected_rav`, and that `signed_rav` has a valid signer.
+    /// Verify `signed_rav` matches all values on `expected_rav`, and that `signed_rav` is signed by a valid signer
+    /// over the EIP712 hash of `expected_rav`.
     ///
     /// # Errors
     ///
-    /// Returns [`Error::AdapterError`] if there are any errors while storing RAV
+    /// Returns [`Error::InvalidReceivedRAV`] if `signed_rav` does not match `expected_rav`
+    ///
+    /// Returns [`Error::InvalidRecoveredSigner`] if the signature does not recover to a valid signer
+    ///
+    /// Returns [`Error::RavRegression`] if the stored RAV for the same allocation has a higher value
+    /// or a later timestamp, e.g. when an older RAV is replayed
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while retrieving or storing RAV
     ///
     pub async fn verify_and_store_rav(
         &self,
         expected_rav: ReceiptAggregateVoucher,
         signed_rav: SignedRAV,
     ) -> std::result::Result<(), Error> {
-        self.context
-            .check_rav_signature(&signed_rav, &self.domain_separator)
-            .await?;
-
         if signed_rav.message != expected_rav {
             return Err(Error::InvalidReceivedRAV {
                 received_rav: signed_rav.message,
@@ -65,6 +242,47 @@ where
             });
         }
 
+        self.check_and_store_rav(signed_rav).await
+    }
+
+    /// Adopts an externally obtained `signed_rav` (e.g. when migrating or recovering from a crash)
+    /// as the last RAV, which future RAV requests will build upon.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidRecoveredSigner`] if `signed_rav` is not signed by a valid signer
+    ///
+    /// Returns [`Error::RavRegression`] if the stored RAV for the same allocation has a higher value
+    /// or a later timestamp
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while retrieving or storing RAV
+    ///
+    pub async fn adopt_rav(&self, signed_rav: SignedRAV) -> Result<(), Error> {
+        self.check_and_store_rav(signed_rav).await
+    }
+
+    /// Stores `signed_rav` as the last RAV of its allocation, once checked that it is signed by a
+    /// valid signer and doesn't regress from the stored RAV.
+    async fn check_and_store_rav(&self, signed_rav: SignedRAV) -> Result<(), Error> {
+        self.context
+            .check_rav_signature(&signed_rav, &self.domain_separator)
+            .await?;
+
+        if let Some(stored_rav) = self
+            .get_previous_rav(signed_rav.message.allocationId)
+            .await?
+        {
+            let stored_rav = stored_rav.message;
+            if stored_rav.valueAggregate > signed_rav.message.valueAggregate
+                || stored_rav.timestampNs > signed_rav.message.timestampNs
+            {
+                return Err(Error::RavRegression {
+                    stored_rav,
+                    received_rav: signed_rav.message,
+                });
+            }
+        }
+
         self.context
             .update_last_rav(signed_rav)
             .await
@@ -80,27 +298,85 @@ impl<E> Manager<E>
 where
     E: RAVRead,
 {
-    async fn get_previous_rav(&self) -> Result<Option<SignedRAV>, Error> {
-        let previous_rav = self
-            .context
-            .last_rav()
-            .await
-            .map_err(|err| Error::AdapterError {
-                source_error: anyhow::Error::new(err),
-            })?;
+    async fn get_previous_rav(&self, allocation_id: Address) -> Result<Option<SignedRAV>, Error> {
+        let previous_rav =
+            self.context
+                .last_rav(allocation_id)
+                .await
+                .map_err(|err| Error::AdapterError {
+                    source_error: anyhow::Error::new(err),
+                })?;
         Ok(previous_rav)
     }
+
+    /// Splits `receipts` into the receipts that are not aggregated into the stored RAV of their
+    /// allocation yet, and the ones that are.
+    async fn partition_pending(
+        &self,
+        receipts: Vec<ReceiptWithState<Checking>>,
+    ) -> Result<
+        (
+            Vec<ReceiptWithState<Checking>>,
+            Vec<ReceiptWithState<Checking>>,
+        ),
+        Error,
+    > {
+        let mut rav_timestamps_ns = HashMap::new();
+        let mut pending_receipts = vec![];
+        let mut aggregated_receipts = vec![];
+        for receipt in receipts {
+            let message = &receipt.signed_receipt().message;
+            let rav_timestamp_ns = match rav_timestamps_ns.get(&message.allocation_id) {
+                Some(&rav_timestamp_ns) => rav_timestamp_ns,
+                None => {
+                    let rav_timestamp_ns = self
+                        .get_previous_rav(message.allocation_id)
+                        .await?
+                        .map(|rav| rav.message.timestamp());
+                    rav_timestamps_ns.insert(message.allocation_id, rav_timestamp_ns);
+                    rav_timestamp_ns
+                }
+            };
+            // without a RAV (`None`), every receipt of the allocation is pending
+            if rav_timestamp_ns < Some(message.timestamp_ns) {
+                pending_receipts.push(receipt);
+            } else {
+                aggregated_receipts.push(receipt);
+            }
+        }
+        Ok((pending_receipts, aggregated_receipts))
+    }
+}
+
+/// Sorts receipts by timestamp, then by unique hash, such that the receipts (and the RAV built
+/// from them) do not depend on the order in which the storage returns them.
+fn sorted_receipts<S: ReceiptState>(
+    mut receipts: Vec<ReceiptWithState<S>>,
+) -> Vec<ReceiptWithState<S>> {
+    receipts.sort_by_cached_key(|receipt| {
+        let signed_receipt = receipt.signed_receipt();
+        (
+            signed_receipt.message.timestamp_ns,
+            signed_receipt.unique_hash(),
+        )
+    });
+    receipts
 }
 
 impl<E> Manager<E>
 where
     E: ReceiptRead + EscrowHandler,
 {
+    #[allow(clippy::too_many_arguments)]
     async fn collect_receipts(
         &self,
-        timestamp_buffer_ns: u64,
+        allocation_id: Address,
+        timestamp_buffer: TimestampNs,
         min_timestamp_ns: u64,
+        cutoff_ns: Option<u64>,
         limit: Option<u64>,
+        min_receipts: usize,
+        min_value: u128,
     ) -> Result<
         (
             Vec<ReceiptWithState<Reserved>>,
@@ -108,7 +384,17 @@ where
         ),
         Error,
     > {
-        let max_timestamp_ns = crate::get_current_timestamp_u64_ns()? - timestamp_buffer_ns;
+        let now_ns = self.clock.now_ns()?;
+        let mut max_timestamp_ns = now_ns.checked_sub(timestamp_buffer.as_nanos()).ok_or(
+            Error::TimestampBufferTooLarge {
+                timestamp_buffer_ns: timestamp_buffer.as_nanos(),
+                now_ns,
+            },
+        )?;
+        if let Some(cutoff_ns) = cutoff_ns {
+            // The cutoff is inclusive, while the range end is not
+            max_timestamp_ns = max_timestamp_ns.min(cutoff_ns.saturating_add(1));
+        }
 
         if min_timestamp_ns > max_timestamp_ns {
             return Err(Error::TimestampRangeError {
@@ -117,12 +403,8 @@ where
             });
         }
         let checking_receipts = self
-            .context
-            .retrieve_receipts_in_timestamp_range(min_timestamp_ns..max_timestamp_ns, limit)
-            .await
-            .map_err(|err| Error::AdapterError {
-                source_error: anyhow::Error::new(err),
-            })?;
+            .retrieve_receipts_in_range(allocation_id, min_timestamp_ns, max_timestamp_ns, limit)
+            .await?;
 
         let mut awaiting_reserve_receipts = vec![];
         let mut failed_receipts = vec![];
@@ -137,15 +419,46 @@ where
         let (checking_receipts, already_failed) = UniqueCheck.check_batch(checking_receipts);
         failed_receipts.extend(already_failed);
 
+        let checks = self.enabled_checks();
         for receipt in checking_receipts.into_iter() {
-            let receipt = receipt.finalize_receipt_checks(&self.checks).await;
+            let receipt = receipt.finalize_receipt_checks(&checks).await;
 
             match receipt {
                 Ok(checked) => awaiting_reserve_receipts.push(checked),
                 Err(failed) => failed_receipts.push(failed),
             }
         }
+
+        // check the thresholds before reserving escrow, as the receipts are kept for a later request.
+        // Without any valid receipt, `NoValidReceiptsForRAVRequest` is returned instead.
+        let value = awaiting_reserve_receipts
+            .iter()
+            .fold(0u128, |acc, receipt| {
+                acc.saturating_add(receipt.signed_receipt().message.value)
+            });
+        if !awaiting_reserve_receipts.is_empty()
+            && (awaiting_reserve_receipts.len() < min_receipts || value < min_value)
+        {
+            return Err(Error::NotEnoughReceiptsForRAVRequest {
+                receipts_count: awaiting_reserve_receipts.len(),
+                value,
+            });
+        }
+
         for checked in awaiting_reserve_receipts {
+            // receipts stored with `verify_and_store_receipt_with_state` may be reserved already
+            let receipt_id = checked.signed_receipt().unique_hash();
+            if self
+                .context
+                .collect_reserved_escrow(receipt_id)
+                .await
+                .map_err(|err| Error::AdapterError {
+                    source_error: anyhow::Error::new(err),
+                })?
+            {
+                reserved_receipts.push(ReceiptWithState::reserved(checked.signed_receipt));
+                continue;
+            }
             match checked
                 .check_and_reserve_escrow(&self.context, &self.domain_separator)
                 .await
@@ -155,7 +468,52 @@ where
             }
         }
 
-        Ok((reserved_receipts, failed_receipts))
+        Ok((reserved_receipts, sorted_receipts(failed_receipts)))
+    }
+
+    /// Retrieves the receipts of `allocation_id` in `min_timestamp_ns..max_timestamp_ns`, at most
+    /// `limit` of them, one page at a time with [`ReceiptRead::retrieve_receipts_paginated`], such
+    /// that the receipts of the other allocations and the ones after the range are never loaded.
+    async fn retrieve_receipts_in_range(
+        &self,
+        allocation_id: Address,
+        min_timestamp_ns: u64,
+        max_timestamp_ns: u64,
+        limit: Option<u64>,
+    ) -> Result<Vec<ReceiptWithState<Checking>>, Error> {
+        // One more receipt than `limit` tells whether truncating would split its last timestamp
+        let page_size = limit.map_or(RECEIPTS_PAGE_SIZE, |limit| {
+            usize::try_from(limit.saturating_add(1))
+                .unwrap_or(usize::MAX)
+                .min(RECEIPTS_PAGE_SIZE)
+        });
+        let mut receipts = Vec::new();
+        let mut cursor = min_timestamp_ns.checked_sub(1);
+        loop {
+            let page = self
+                .context
+                .retrieve_receipts_paginated(allocation_id, cursor, page_size)
+                .await
+                .map_err(|err| Error::AdapterError {
+                    source_error: anyhow::Error::new(err),
+                })?;
+            // pages are in timestamp order, so the page reaching the range end is the last one
+            let passed_range = page.receipts.iter().any(|receipt| {
+                receipt.signed_receipt().message.timestamp_ns.as_nanos() >= max_timestamp_ns
+            });
+            receipts.extend(page.receipts.into_iter().filter(|receipt| {
+                receipt.signed_receipt().message.timestamp_ns.as_nanos() < max_timestamp_ns
+            }));
+            let enough = limit.is_some_and(|limit| receipts.len() as u64 > limit);
+            match page.next_cursor {
+                Some(next_cursor) if !passed_range && !enough => cursor = Some(next_cursor),
+                _ => break,
+            }
+        }
+        if let Some(limit) = limit {
+            safe_truncate_receipts(&mut receipts, limit);
+        }
+        Ok(sorted_receipts(receipts))
     }
 }
 
@@ -163,9 +521,9 @@ impl<E> Manager<E>
 where
     E: ReceiptRead + RAVRead + EscrowHandler,
 {
-    /// Completes remaining checks on all receipts up to (current time - `timestamp_buffer_ns`). Returns them in
+    /// Completes remaining checks on all receipts up to (current time - `timestamp_buffer`). Returns them in
     /// two lists (valid receipts and invalid receipts) along with the expected RAV that should be received
-    /// for aggregating list of valid receipts.
+    /// for aggregating list of valid receipts. Both lists are ordered by receipt timestamp, then unique hash.
     ///
     /// Returns [`Error::AggregateOverflow`] if any receipt value causes aggregate value to overflow while generating expected RAV
     ///
@@ -173,19 +531,156 @@ where
     ///
     /// Returns [`Error::TimestampRangeError`] if the max timestamp of the previous RAV is greater than the min timestamp. Caused by timestamp buffer being too large, or requests coming too soon.
     ///
+    /// Returns [`Error::TimestampBufferTooLarge`] if `timestamp_buffer` is greater than the current time.
+    ///
+    /// Returns [`Error::NoValidReceiptsForRAVRequest`] if there are no valid receipts to aggregate, in which case there
+    /// is nothing to send to the aggregator.
+    ///
+    /// Returns [`Error::NotEnoughReceiptsForRAVRequest`] if the valid receipts are below the thresholds set with
+    /// [`Manager::with_min_rav_thresholds`]. The receipts are left in storage, and no escrow is reserved for them.
+    ///
+    /// A RAV is for a single allocation: if the pending receipts are for several allocations, the
+    /// request is for the allocation of the oldest one, and the receipts of the other allocations are
+    /// left pending. Use [`Manager::for_allocation`] to request a RAV for a given allocation.
+    ///
     pub async fn create_rav_request(
         &self,
-        timestamp_buffer_ns: u64,
+        timestamp_buffer: impl Into<TimestampNs>,
         receipts_limit: Option<u64>,
     ) -> Result<RAVRequest, Error> {
-        let previous_rav = self.get_previous_rav().await?;
+        self.rav_request(
+            None,
+            timestamp_buffer.into(),
+            None,
+            receipts_limit,
+            self.min_receipts,
+            self.min_value,
+        )
+        .await
+    }
+
+    /// Same as [`Manager::create_rav_request`], but only includes the receipts with a timestamp up to
+    /// `cutoff_ns` (inclusive), e.g. to aggregate exactly the receipts of an allocation period. Later
+    /// receipts are left pending for a later request.
+    ///
+    /// Returns [`Error::TimestampRangeError`] if `cutoff_ns` is before the end of the previous RAV.
+    pub async fn create_rav_request_until(
+        &self,
+        cutoff_ns: u64,
+        timestamp_buffer: impl Into<TimestampNs>,
+    ) -> Result<RAVRequest, Error> {
+        self.rav_request(
+            None,
+            timestamp_buffer.into(),
+            Some(cutoff_ns),
+            None,
+            self.min_receipts,
+            self.min_value,
+        )
+        .await
+    }
+
+    /// Same as [`Manager::create_rav_request`], for the receipts of `allocation_id` only, see
+    /// [`super::AllocationView::create_rav_request`].
+    pub(crate) async fn create_allocation_rav_request(
+        &self,
+        allocation_id: Address,
+        timestamp_buffer: TimestampNs,
+        receipts_limit: Option<u64>,
+    ) -> Result<RAVRequest, Error> {
+        self.rav_request(
+            Some(allocation_id),
+            timestamp_buffer,
+            None,
+            receipts_limit,
+            self.min_receipts,
+            self.min_value,
+        )
+        .await
+    }
+
+    /// Returns the escrow of `sender_id` left once its pending receipts for `allocation_id` are paid,
+    /// see [`super::AllocationView::remaining_escrow`].
+    pub(crate) async fn allocation_remaining_escrow(
+        &self,
+        allocation_id: Address,
+        sender_id: Address,
+    ) -> Result<u128, Error> {
+        let available_escrow =
+            self.context
+                .get_available_escrow(sender_id)
+                .await
+                .map_err(|err| Error::AdapterError {
+                    source_error: anyhow::Error::new(err),
+                })?;
+        // The escrow reserved for pending receipts, e.g. by an outstanding RAV request, is already
+        // deducted from the available escrow
+        let mut pending_receipts = vec![];
+        for signed_receipt in self.export_pending_receipts(allocation_id).await? {
+            let receipt_id = signed_receipt.unique_hash();
+            let reserved = self
+                .context
+                .is_escrow_reserved(receipt_id.clone())
+                .await
+                .map_err(|err| Error::AdapterError {
+                    source_error: anyhow::Error::new(err),
+                })?;
+            pending_receipts.push((receipt_id, signed_receipt, reserved));
+        }
+
+        let mut receipt_signers = self.receipt_signers.write().unwrap();
+        // the signers of the receipts aggregated meanwhile are dropped
+        let known_signers = receipt_signers.remove(&allocation_id).unwrap_or_default();
+        let mut signers = HashMap::new();
+        let mut pending_value = 0u128;
+        for (receipt_id, signed_receipt, reserved) in pending_receipts {
+            let signer = match known_signers.get(&receipt_id) {
+                Some(&signer) => signer,
+                None => match signed_receipt.recover_signer(&self.domain_separator) {
+                    Ok(signer) => signer,
+                    Err(_) => continue,
+                },
+            };
+            signers.insert(receipt_id, signer);
+            if signer == sender_id && !reserved {
+                pending_value = pending_value.saturating_add(signed_receipt.message.value);
+            }
+        }
+        receipt_signers.insert(allocation_id, signers);
+        Ok(available_escrow.saturating_sub(pending_value))
+    }
+
+    /// Builds a RAV request out of the pending receipts, or out of those of `allocation_id` only,
+    /// building upon the stored RAV of that allocation.
+    async fn rav_request(
+        &self,
+        allocation_id: Option<Address>,
+        timestamp_buffer: TimestampNs,
+        cutoff_ns: Option<u64>,
+        receipts_limit: Option<u64>,
+        min_receipts: usize,
+        min_value: u128,
+    ) -> Result<RAVRequest, Error> {
+        let allocation_id = match allocation_id {
+            Some(allocation_id) => allocation_id,
+            None => self.pending_allocation().await?,
+        };
+        let previous_rav = self.get_previous_rav(allocation_id).await?;
         let min_timestamp_ns = previous_rav
             .as_ref()
             .map(|rav| rav.message.timestampNs + 1)
             .unwrap_or(0);
 
         let (valid_receipts, invalid_receipts) = self
-            .collect_receipts(timestamp_buffer_ns, min_timestamp_ns, receipts_limit)
+            .collect_receipts(
+                allocation_id,
+                timestamp_buffer,
+                min_timestamp_ns,
+                cutoff_ns,
+                receipts_limit,
+                min_receipts,
+                min_value,
+            )
             .await?;
 
         let expected_rav = Self::generate_expected_rav(&valid_receipts, previous_rav.clone())?;
@@ -203,6 +698,16 @@ where
         })
     }
 
+    /// Returns the allocation of the oldest pending receipt.
+    ///
+    /// Returns [`Error::NoValidReceiptsForRAVRequest`] if there are no pending receipts
+    async fn pending_allocation(&self) -> Result<Address, Error> {
+        sorted_receipts(self.pending_receipts().await?)
+            .first()
+            .map(|receipt| receipt.signed_receipt().message.allocation_id)
+            .ok_or(Error::NoValidReceiptsForRAVRequest)
+    }
+
     fn generate_expected_rav(
         receipts: &[ReceiptWithState<Reserved>],
         previous_rav: Option<SignedRAV>,
@@ -225,36 +730,513 @@ where
 
 impl<E> Manager<E>
 where
-    E: ReceiptDelete + RAVRead,
+    E: ReceiptRead + ReceiptDelete + RAVRead + RAVStore + EscrowHandler,
 {
-    /// Removes obsolete receipts from storage. Obsolete receipts are receipts that are older than the last RAV, and
-    /// therefore already aggregated into the RAV.
-    /// This function should be called after a new RAV is received to limit the number of receipts stored.
-    /// No-op if there is no last RAV.
+    /// Aggregates the pending receipts of every allocation into a final RAV, regardless of the
+    /// thresholds set with [`Manager::with_min_rav_thresholds`], so that no receipt is left
+    /// unaggregated until the next start, e.g. from a server's shutdown hook. `aggregator_client` is
+    /// called with the RAV request of each allocation, sends it to the aggregator and returns the
+    /// signed RAV, which is then verified and stored as with [`Manager::verify_and_store_rav`], and
+    /// the aggregated receipts removed from storage.
+    ///
+    /// Returns the new RAVs by allocation. Allocations without valid pending receipts are left out.
     ///
     /// # Errors
     ///
-    /// Returns [`Error::AdapterError`] if there are any errors while retrieving last RAV or removing receipts
+    /// Returns [`Error::AdapterError`] if `aggregator_client` fails, or if there are any errors while
+    /// accessing the storage
     ///
-    pub async fn remove_obsolete_receipts(&self) -> Result<(), Error> {
-        match self.get_previous_rav().await? {
-            Some(last_rav) => {
-                self.context
-                    .remove_receipts_in_timestamp_range(..=last_rav.message.timestampNs)
+    /// Returns [`Error::RavAllocationIdMismatch`] if a RAV returned by `aggregator_client` is for
+    /// another allocation
+    ///
+    /// Returns the errors of [`Manager::create_rav_request`] and [`Manager::verify_and_store_rav`].
+    /// The RAVs of the allocations drained before the error are stored. If the RAV of an allocation
+    /// can't be obtained or stored, the escrow reserved for its receipts is released, as with
+    /// [`Manager::cancel_rav_request`].
+    ///
+    pub async fn drain_to_ravs<F, Fut, AggregatorError>(
+        &self,
+        aggregator_client: F,
+    ) -> Result<HashMap<Address, SignedRAV>, Error>
+    where
+        F: Fn(RAVRequest) -> Fut,
+        Fut: Future<Output = Result<SignedRAV, AggregatorError>>,
+        AggregatorError: Into<anyhow::Error>,
+    {
+        let mut signed_ravs = HashMap::new();
+        for allocation_id in self.pending_allocations().await? {
+            if let Some(signed_rav) = self.drain(allocation_id, &aggregator_client).await? {
+                signed_ravs.insert(allocation_id, signed_rav);
+            }
+        }
+        Ok(signed_ravs)
+    }
+
+    /// Same as [`Manager::drain_to_ravs`], for the receipts of `allocation_id` only. Returns the new
+    /// RAV, or `None` if there were no valid pending receipts.
+    async fn drain<F, Fut, AggregatorError>(
+        &self,
+        allocation_id: Address,
+        aggregator_client: F,
+    ) -> Result<Option<SignedRAV>, Error>
+    where
+        F: FnOnce(RAVRequest) -> Fut,
+        Fut: Future<Output = Result<SignedRAV, AggregatorError>>,
+        AggregatorError: Into<anyhow::Error>,
+    {
+        let rav_request = match self
+            .rav_request(Some(allocation_id), TimestampNs::ZERO, None, None, 0, 0)
+            .await
+        {
+            Err(Error::NoValidReceiptsForRAVRequest) => return Ok(None),
+            rav_request => rav_request?,
+        };
+        let expected_rav = rav_request.expected_rav.clone();
+        let reserved_receipts = rav_request.valid_receipts.clone();
+
+        let signed_rav = async {
+            let signed_rav =
+                aggregator_client(rav_request)
                     .await
                     .map_err(|err| Error::AdapterError {
-                        source_error: anyhow::Error::new(err),
+                        source_error: err.into(),
                     })?;
-                Ok(())
+            if signed_rav.message.allocationId != allocation_id {
+                return Err(Error::RavAllocationIdMismatch {
+                    prev_id: allocation_id.to_string(),
+                    new_id: signed_rav.message.allocationId.to_string(),
+                });
+            }
+            self.verify_and_store_rav(expected_rav, signed_rav.clone())
+                .await?;
+            Ok(signed_rav)
+        }
+        .await;
+        let signed_rav = match signed_rav {
+            Ok(signed_rav) => signed_rav,
+            Err(err) => {
+                // The receipts are not aggregated, their escrow must not stay reserved until the
+                // reservations expire
+                if let Err(release_err) = self.release_receipts(reserved_receipts).await {
+                    log::warn!(
+                        "Failed to release the escrow of the receipts of allocation {}: {}",
+                        allocation_id,
+                        release_err
+                    );
+                }
+                return Err(err);
+            }
+        };
+
+        self.remove_obsolete_receipts().await?;
+        Ok(Some(signed_rav))
+    }
+
+    /// Forces the final RAV of `allocation_id` once the allocation is closed on-chain, such that it
+    /// can be redeemed before the redemption deadline. The allocation is first marked closed, such
+    /// that any further receipt for it is rejected with [`Error::AllocationClosed`], then all its
+    /// remaining receipts are aggregated as with [`Manager::drain_to_ravs`]. The receipts of other
+    /// allocations are left pending.
+    ///
+    /// Returns the final RAV, i.e. the new RAV, or the last stored one if there were no valid
+    /// remaining receipts. Returns `None` if the allocation has neither.
+    ///
+    /// The allocation stays closed if finalizing fails, so that it can be retried without any new
+    /// receipt slipping in. Closed allocations are only kept in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::RavAllocationIdMismatch`] if the RAV returned by `aggregator_client` is for
+    /// another allocation
+    ///
+    /// Returns the errors of [`Manager::drain_to_ravs`], which releases the escrow reserved for the
+    /// receipts if the RAV can't be obtained or stored
+    ///
+    pub async fn finalize_allocation<F, Fut, AggregatorError>(
+        &self,
+        allocation_id: Address,
+        aggregator_client: F,
+    ) -> Result<Option<SignedRAV>, Error>
+    where
+        F: FnOnce(RAVRequest) -> Fut,
+        Fut: Future<Output = Result<SignedRAV, AggregatorError>>,
+        AggregatorError: Into<anyhow::Error>,
+    {
+        self.closed_allocations
+            .write()
+            .unwrap()
+            .insert(allocation_id);
+
+        if let Some(signed_rav) = self.drain(allocation_id, aggregator_client).await? {
+            return Ok(Some(signed_rav));
+        }
+        self.get_previous_rav(allocation_id).await
+    }
+}
+
+impl<E> Manager<E>
+where
+    E: EscrowHandler,
+{
+    /// Gives back the escrow reserved for receipts that were not aggregated into a RAV before their
+    /// reservation expired, see [`EscrowHandler::reclaim_expired_reservations`]. Returns the total value
+    /// given back. This function should be called periodically, so that abandoned RAV requests do not
+    /// hold escrow forever.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while reclaiming the reservations
+    ///
+    pub async fn reclaim_expired_reservations(&self) -> Result<u128, Error> {
+        self.context
+            .reclaim_expired_reservations(self.clock.now_ns()?)
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })
+    }
+
+    /// Checks whether the signer of `signed_receipt` has enough escrow left to pay for it, without
+    /// reserving anything, e.g. for admission control before accepting a connection. The escrow
+    /// already reserved for other receipts is not available, as it is deducted from the available
+    /// escrow when reserved (see [`EscrowHandler::check_and_reserve_escrow`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if the available escrow of the signer cannot be read, e.g.
+    /// for an unknown sender
+    ///
+    /// Returns the errors of [`crate::signed_message::EIP712SignedMessage::recover_signer`]
+    ///
+    pub async fn can_afford(&self, signed_receipt: &SignedReceipt) -> Result<bool, Error> {
+        let sender_id = signed_receipt.recover_signer(&self.domain_separator)?;
+        let available_escrow =
+            self.context
+                .get_available_escrow(sender_id)
+                .await
+                .map_err(|err| Error::AdapterError {
+                    source_error: anyhow::Error::new(err),
+                })?;
+        Ok(signed_receipt.message.value <= available_escrow)
+    }
+
+    /// Cancels `rav_request` before it is sent to the aggregator, e.g. on shutdown. The escrow reserved
+    /// for its valid receipts when the request was created is given back, such that the receipts, which
+    /// are still stored, are available again to the next RAV request instead of waiting for
+    /// [`Manager::reclaim_expired_reservations`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::ReceiptError`] if the escrow of a receipt can't be released, in which case the
+    /// escrow of the receipts before it was released already
+    ///
+    pub async fn cancel_rav_request(&self, rav_request: RAVRequest) -> Result<(), Error> {
+        self.release_receipts(rav_request.valid_receipts).await
+    }
+
+    /// Releases the escrow reserved for `signed_receipts`, in order.
+    async fn release_receipts(&self, signed_receipts: Vec<SignedReceipt>) -> Result<(), Error> {
+        for signed_receipt in signed_receipts {
+            self.context
+                .release_escrow(
+                    &ReceiptWithState::reserved(signed_receipt),
+                    &self.domain_separator,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl<E> Manager<E>
+where
+    E: ReceiptRead,
+{
+    /// Returns the number of receipts stored for `allocation_id`, e.g. to decide when to request a RAV.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while counting receipts
+    ///
+    pub async fn count_receipts(&self, allocation_id: Address) -> Result<u64, Error>
+    where
+        E: Sync,
+    {
+        self.context
+            .count_receipts(allocation_id)
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })
+    }
+
+    /// Compares the value of `rav` with the receipts stored for its allocation up to its timestamp, to
+    /// detect an aggregator claiming more than the receipts actually received. Meant to be called after
+    /// [`Manager::verify_and_store_rav`], before the aggregated receipts are removed with
+    /// [`Manager::remove_obsolete_receipts`]. As the receipts aggregated into earlier RAVs may have
+    /// been removed already, `rav` should only cover receipts that are still stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while retrieving receipts
+    ///
+    pub async fn reconcile_rav(&self, rav: &SignedRAV) -> Result<ReconcileReport, Error> {
+        let allocation_id = rav.message.allocationId;
+        let receipts = self
+            .context
+            .retrieve_receipts_in_timestamp_range(..=rav.message.timestampNs, None)
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })?;
+
+        let (receipts_count, receipts_value) = receipts
+            .iter()
+            .map(|receipt| &receipt.signed_receipt().message)
+            .filter(|receipt| receipt.allocation_id == allocation_id)
+            .fold((0, 0u128), |(count, value), receipt| {
+                (count + 1, value.saturating_add(receipt.value))
+            });
+
+        Ok(ReconcileReport {
+            allocation_id,
+            rav_value: rav.message.valueAggregate,
+            receipts_count,
+            receipts_value,
+        })
+    }
+}
+
+impl<E> Manager<E>
+where
+    E: ReceiptRead + RAVRead,
+{
+    /// Returns the stored receipts of `allocation_id` that are not aggregated into its stored RAV yet,
+    /// ordered by timestamp, then unique hash. Along with the stored RAV, this is the state to hand off
+    /// to another indexer instance, which can resume with [`Manager::adopt_rav`] and
+    /// [`Manager::import_receipts`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while retrieving the last RAV or the receipts
+    ///
+    pub async fn export_pending_receipts(
+        &self,
+        allocation_id: Address,
+    ) -> Result<Vec<SignedReceipt>, Error> {
+        let min_timestamp_ns = self
+            .get_previous_rav(allocation_id)
+            .await?
+            .map(|rav| rav.message.timestampNs + 1)
+            .unwrap_or(0);
+
+        let receipts = self
+            .context
+            .retrieve_receipts_in_timestamp_range(min_timestamp_ns.., None)
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })?;
+
+        Ok(sorted_receipts(receipts)
+            .into_iter()
+            .map(|receipt| receipt.signed_receipt().clone())
+            .filter(|signed_receipt| signed_receipt.message.allocation_id == allocation_id)
+            .collect())
+    }
+
+    /// Returns the senders of the pending receipts, i.e. the stored receipts not aggregated into the
+    /// stored RAV of their allocation yet, ordered by address. Meant for escrow monitoring, e.g. to
+    /// list who owes what.
+    ///
+    /// The senders are recovered from the signatures of the receipts, receipts whose signer can't be
+    /// recovered are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while retrieving the last RAV or the receipts
+    ///
+    pub async fn active_senders(&self) -> Result<Vec<Address>, Error> {
+        let senders: BTreeSet<Address> = self
+            .pending_receipts()
+            .await?
+            .iter()
+            .filter_map(|receipt| {
+                receipt
+                    .signed_receipt()
+                    .recover_signer(&self.domain_separator)
+                    .ok()
+            })
+            .collect();
+        Ok(senders.into_iter().collect())
+    }
+
+    /// Returns the stored receipts that are not aggregated into the stored RAV of their allocation
+    /// yet.
+    async fn pending_receipts(&self) -> Result<Vec<ReceiptWithState<Checking>>, Error> {
+        let receipts = self
+            .context
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })?;
+        let (pending_receipts, _) = self.partition_pending(receipts).await?;
+        Ok(pending_receipts)
+    }
+
+    /// Returns the allocations with pending receipts, ordered by address.
+    async fn pending_allocations(&self) -> Result<Vec<Address>, Error> {
+        let allocation_ids: BTreeSet<Address> = self
+            .pending_receipts()
+            .await?
+            .iter()
+            .map(|receipt| receipt.signed_receipt().message.allocation_id)
+            .collect();
+        Ok(allocation_ids.into_iter().collect())
+    }
+}
+
+impl<E> Manager<E>
+where
+    E: ReceiptRead + RAVRead + Transaction,
+{
+    /// Adds the pending receipts of another indexer instance serving the same allocations, e.g.
+    /// from [`Manager::export_pending_receipts`] when consolidating two instances, to the pending
+    /// receipts of this one. Returns the number of receipts added.
+    ///
+    /// Receipts already pending here, i.e. with the same [`EIP712SignedMessage::unique_hash`], and
+    /// receipts already aggregated into the stored RAV of their allocation are skipped, such that no
+    /// receipt is counted twice. The other receipts are verified and stored as if they were
+    /// received with [`Manager::verify_and_store_receipt_with_state`], and the ones failing a check
+    /// or belonging to a finalized allocation are skipped too.
+    ///
+    /// [`EIP712SignedMessage::unique_hash`]: crate::signed_message::EIP712SignedMessage::unique_hash
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while retrieving the last RAV or the
+    /// receipts, or storing the receipts
+    ///
+    /// Returns [`Error::ServiceUnavailable`] if receipts are not accepted, see
+    /// [`Manager::set_accepting`]
+    ///
+    pub async fn merge_pending_from(
+        &self,
+        other_receipts: Vec<SignedReceipt>,
+    ) -> Result<usize, Error> {
+        let mut unique_hashes: HashSet<_> = self
+            .pending_receipts()
+            .await?
+            .iter()
+            .map(|receipt| receipt.signed_receipt().unique_hash())
+            .collect();
+
+        let (other_receipts, _) = self
+            .partition_pending(
+                other_receipts
+                    .into_iter()
+                    .map(ReceiptWithState::new)
+                    .collect(),
+            )
+            .await?;
+        let mut merged = 0;
+        for receipt in other_receipts {
+            if !unique_hashes.insert(receipt.signed_receipt().unique_hash()) {
+                continue;
+            }
+            // a receipt received meanwhile is not stored twice either, and fails as a duplicate
+            match self
+                .verify_and_store_receipt_with_state(receipt.signed_receipt().clone())
+                .await
+            {
+                Ok(ReceiptOutcome::Reserved(_) | ReceiptOutcome::AwaitingReserve(_)) => merged += 1,
+                Ok(ReceiptOutcome::Failed(_)) | Err(Error::AllocationClosed { .. }) => {}
+                Err(err) => return Err(err),
             }
-            None => Ok(()),
         }
+        Ok(merged)
     }
 }
 
 impl<E> Manager<E>
 where
-    E: ReceiptStore,
+    E: ReceiptRead + ReceiptDelete + RAVRead,
+{
+    /// Removes obsolete receipts from storage. Obsolete receipts are receipts that are older than the last RAV of
+    /// their allocation, and therefore already aggregated into the RAV.
+    /// This function should be called after a new RAV is received to limit the number of receipts stored.
+    /// As receipts are removed by timestamp range, obsolete receipts newer than a receipt still pending for
+    /// another allocation are kept until that receipt is aggregated too.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while retrieving the last RAVs, or retrieving or
+    /// removing receipts
+    ///
+    pub async fn remove_obsolete_receipts(&self) -> Result<(), Error> {
+        let receipts = self
+            .context
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })?;
+        let (pending_receipts, obsolete_receipts) = self.partition_pending(receipts).await?;
+
+        let oldest_pending_ns = pending_receipts
+            .iter()
+            .map(|receipt| receipt.signed_receipt().message.timestamp_ns.as_nanos())
+            .min();
+        let newest_obsolete_ns = obsolete_receipts
+            .iter()
+            .map(|receipt| receipt.signed_receipt().message.timestamp_ns.as_nanos())
+            .max();
+        let range_end = match (oldest_pending_ns, newest_obsolete_ns) {
+            (_, None) => return Ok(()),
+            (Some(oldest_pending_ns), Some(_)) => Bound::Excluded(oldest_pending_ns),
+            (None, Some(newest_obsolete_ns)) => Bound::Included(newest_obsolete_ns),
+        };
+        self.context
+            .remove_receipts_in_timestamp_range((Bound::Unbounded, range_end))
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })
+    }
+}
+
+impl<E> Manager<E>
+where
+    E: FailedReceiptStore,
+{
+    /// Removes the stored failed receipts whose timestamp is older than `older_than` ago, such that
+    /// the failed receipts kept for auditing stay within a retention window. Returns the number of
+    /// receipts removed.
+    ///
+    /// The failed receipts are the ones stored by the caller, see [`FailedReceiptStore`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if there are any errors while removing the failed receipts
+    ///
+    /// Returns [`Error::InvalidSystemTime`] if the current time can't be read
+    ///
+    pub async fn prune_failed_receipts(&self, older_than: Duration) -> Result<u64, Error> {
+        let older_than_ns = u64::try_from(older_than.as_nanos()).unwrap_or(u64::MAX);
+        let cutoff_ns = self.clock.now_ns()?.saturating_sub(older_than_ns);
+        self.context
+            .remove_failed_receipts_in_timestamp_range(..cutoff_ns)
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })
+    }
+}
+
+impl<E> Manager<E>
+where
+    E: ReceiptStore + Sync,
 {
     /// Runs `initial_checks` on `signed_receipt` for initial verification, then stores received receipt.
     /// The provided `query_id` will be used as a key when chaecking query appraisal.
@@ -263,6 +1245,14 @@ where
     ///
     /// Returns [`Error::AdapterError`] if there are any errors while storing receipts
     ///
+    /// Returns [`Error::ReceiptError`] if the receipt was already stored
+    ///
+    /// Returns [`Error::AllocationClosed`] if the receipt's allocation was finalized with
+    /// [`Manager::finalize_allocation`]
+    ///
+    /// Returns [`Error::ServiceUnavailable`] if receipts are not accepted, see
+    /// [`Manager::set_accepting`]
+    ///
     /// Returns [`Error::InvalidStateForRequestedAction`] if the checks requested in `initial_checks` cannot be comleted due to: All other checks must be complete before `CheckAndReserveEscrow`
     ///
     /// Returns [`Error::InvalidCheckError`] if check in `initial_checks` is not in `required_checks` provided when manager was created
@@ -271,18 +1261,177 @@ where
         &self,
         signed_receipt: SignedReceipt,
     ) -> std::result::Result<(), Error> {
+        if !self.is_accepting() {
+            return Err(Error::ServiceUnavailable);
+        }
+        self.check_allocation_open(&signed_receipt)?;
         let mut received_receipt = ReceiptWithState::new(signed_receipt);
 
         // perform checks
-        received_receipt.perform_checks(&self.checks).await?;
+        received_receipt
+            .perform_checks(&self.enabled_checks())
+            .await?;
 
-        // store the receipt
+        // store the receipt, unless the same receipt was already stored
         self.context
-            .store_receipt(received_receipt)
+            .check_and_store_unique(received_receipt)
             .await
             .map_err(|err| Error::AdapterError {
                 source_error: anyhow::Error::new(err),
-            })?;
+            })?
+            .ok_or(ReceiptError::NonUniqueReceipt)?;
         Ok(())
     }
+
+    /// Same as [`Manager::verify_and_store_receipt`] for each of `signed_receipts`, up to
+    /// [`Manager::with_store_concurrency`] of them at once. Returns the result of each receipt, in
+    /// the order of `signed_receipts`.
+    pub async fn verify_and_store_receipts(
+        &self,
+        signed_receipts: Vec<SignedReceipt>,
+    ) -> Vec<std::result::Result<(), Error>> {
+        let mut results = Vec::new();
+        results.resize_with(signed_receipts.len(), || None);
+
+        // Receipts complete in any order, their results are put back in place
+        let mut stored = stream::iter(signed_receipts.into_iter().enumerate())
+            .map(|(index, signed_receipt)| async move {
+                (index, self.verify_and_store_receipt(signed_receipt).await)
+            })
+            .buffer_unordered(self.store_concurrency);
+        while let Some((index, result)) = stored.next().await {
+            results[index] = Some(result);
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    /// Verifies and stores receipts exported from another indexer instance with
+    /// [`Manager::export_pending_receipts`], as if they were received with
+    /// [`Manager::verify_and_store_receipts`]. Returns the result of each receipt, in order.
+    pub async fn import_receipts(
+        &self,
+        signed_receipts: Vec<SignedReceipt>,
+    ) -> Vec<std::result::Result<(), Error>> {
+        self.verify_and_store_receipts(signed_receipts).await
+    }
+}
+
+impl<E> Manager<E>
+where
+    E: Transaction,
+{
+    /// Same as [`Manager::verify_and_store_receipt`], but returns the state the receipt ended up in
+    /// instead of discarding it, e.g. to alert on a growing backlog of receipts awaiting escrow.
+    ///
+    /// A receipt failing a check, or already stored, is returned as [`ReceiptOutcome::Failed`]
+    /// rather than as an error. Otherwise it is stored, and returned as [`ReceiptOutcome::Reserved`]
+    /// if its sender's escrow covers its value, or as [`ReceiptOutcome::AwaitingReserve`] if not.
+    /// The escrow reserved for the receipt is kept until a RAV aggregating it is stored, and isn't
+    /// reserved again when the receipt is collected for a RAV request, provided the escrow adapter
+    /// tracks its reservations, see [`EscrowHandler::collect_reserved_escrow`].
+    ///
+    /// The escrow is reserved and the receipt stored in one [`Transaction`], such that a failure in
+    /// between leaves neither the reservation nor the receipt behind.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::AdapterError`] if reserving the escrow fails for another reason than the
+    /// sender's escrow not covering the receipt, if there are any errors while storing receipts, or
+    /// beginning, committing or rolling back the transaction
+    ///
+    /// Returns [`Error::AllocationClosed`] if the receipt's allocation was finalized with
+    /// [`Manager::finalize_allocation`]
+    ///
+    /// Returns [`Error::ServiceUnavailable`] if receipts are not accepted, see
+    /// [`Manager::set_accepting`]
+    pub async fn verify_and_store_receipt_with_state(
+        &self,
+        signed_receipt: SignedReceipt,
+    ) -> std::result::Result<ReceiptOutcome, Error> {
+        if !self.is_accepting() {
+            return Err(Error::ServiceUnavailable);
+        }
+        self.check_allocation_open(&signed_receipt)?;
+        let awaiting_reserve = match ReceiptWithState::new(signed_receipt.clone())
+            .finalize_receipt_checks(&self.enabled_checks())
+            .await
+        {
+            Ok(awaiting_reserve) => awaiting_reserve,
+            Err(failed) => return Ok(ReceiptOutcome::Failed(failed)),
+        };
+
+        let transaction =
+            self.context
+                .begin_transaction()
+                .await
+                .map_err(|err| Error::AdapterError {
+                    source_error: anyhow::Error::new(err),
+                })?;
+        match self
+            .reserve_and_store(&transaction, signed_receipt, awaiting_reserve)
+            .await
+        {
+            // a duplicate receipt leaves nothing to commit
+            Ok(outcome @ (ReceiptOutcome::Reserved(_) | ReceiptOutcome::AwaitingReserve(_))) => {
+                self.context
+                    .commit_transaction(transaction)
+                    .await
+                    .map_err(|err| Error::AdapterError {
+                        source_error: anyhow::Error::new(err),
+                    })?;
+                Ok(outcome)
+            }
+            result => {
+                self.context
+                    .rollback_transaction(transaction)
+                    .await
+                    .map_err(|err| Error::AdapterError {
+                        source_error: anyhow::Error::new(err),
+                    })?;
+                result
+            }
+        }
+    }
+
+    /// Reserves the escrow of `signed_receipt` if its sender's escrow covers it, then stores it,
+    /// through `transaction`, see [`Manager::verify_and_store_receipt_with_state`].
+    async fn reserve_and_store(
+        &self,
+        transaction: &E::Handle,
+        signed_receipt: SignedReceipt,
+        awaiting_reserve: ReceiptWithState<AwaitingReserve>,
+    ) -> std::result::Result<ReceiptOutcome, Error> {
+        let outcome = match awaiting_reserve
+            .clone()
+            .check_and_reserve_escrow(transaction, &self.domain_separator)
+            .await
+        {
+            Ok(reserved) => ReceiptOutcome::Reserved(reserved),
+            // the receipt is stored anyway, its escrow is reserved again when collected for a RAV
+            Err(failed) => match failed.error() {
+                ReceiptError::InsufficientEscrow { .. } | ReceiptError::SubtractEscrowFailed => {
+                    ReceiptOutcome::AwaitingReserve(awaiting_reserve.clone())
+                }
+                error => {
+                    return Err(Error::AdapterError {
+                        source_error: anyhow::Error::new(error.clone()),
+                    })
+                }
+            },
+        };
+
+        // store the receipt, unless the same receipt was already stored
+        let stored = transaction
+            .check_and_store_unique(ReceiptWithState::new(signed_receipt))
+            .await
+            .map_err(|err| Error::AdapterError {
+                source_error: anyhow::Error::new(err),
+            })?;
+        if stored.is_none() {
+            return Ok(ReceiptOutcome::Failed(
+                awaiting_reserve.perform_state_error(ReceiptError::NonUniqueReceipt),
+            ));
+        }
+        Ok(outcome)
+    }
 }
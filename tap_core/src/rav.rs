@@ -8,41 +8,153 @@
 //! The payment receiver would verify the received receipt and store it to be
 //! accumulated with other received receipts in the future.
 
+mod chain;
+mod diff;
 mod request;
 
 use std::cmp;
 
-use alloy_primitives::Address;
-use alloy_sol_types::sol;
+use alloy_primitives::{Address, B256};
+use alloy_sol_types::{sol, Eip712Domain, SolCall, SolStruct};
+#[cfg(feature = "ethers")]
+use ethers::signers::LocalWallet;
 use serde::{Deserialize, Serialize};
 
 use crate::Error;
-use crate::{receipt::Receipt, signed_message::EIP712SignedMessage};
+use crate::{receipt::Receipt, signed_message::EIP712SignedMessage, timestamp::TimestampNs};
 
 pub type SignedRAV = EIP712SignedMessage<ReceiptAggregateVoucher>;
+pub use chain::ChainError;
+pub use diff::RavDiff;
 pub use request::RAVRequest;
 
 sol! {
     /// Holds information needed for promise of payment signed with ECDSA
+    ///
+    /// The layout is the one of the escrow contract, which rebuilds the RAV to verify its
+    /// signature on redemption, so it has no signed start of the covered period. A RAV covers the
+    /// receipts after the timestamp of the previous RAV of the allocation, up to its own timestamp.
     #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
     struct ReceiptAggregateVoucher {
         /// Unique allocation id this RAV belongs to
         address allocationId;
         /// Unix Epoch timestamp in nanoseconds (Truncated to 64-bits)
-        /// corresponding to max timestamp from receipt batch aggregated
+        /// corresponding to max timestamp from receipt batch aggregated, see
+        /// [`ReceiptAggregateVoucher::timestamp`] for it as a [`TimestampNs`]
         uint64 timestampNs;
         /// Aggregated GRT value from receipt batch and any previous RAV provided (truncate to lower bits)
         uint128 valueAggregate;
     }
 }
 
+/// EIP712 type of [`ReceiptAggregateVoucher`], as rebuilt by the escrow contract to verify the
+/// signature of a redeemed RAV.
+pub const RAV_EIP712_TYPE: &str =
+    "ReceiptAggregateVoucher(address allocationId,uint64 timestampNs,uint128 valueAggregate)";
+
+/// ABI definitions of the escrow contract's `redeem` function, as found in
+/// [timeline-aggregation-protocol-contracts](https://github.com/semiotic-ai/timeline-aggregation-protocol-contracts).
+mod escrow_contract {
+    use alloy_sol_types::sol;
+
+    sol! {
+        struct ReceiptAggregateVoucher {
+            address allocationId;
+            uint64 timestampNs;
+            uint128 valueAggregate;
+        }
+
+        struct SignedRAV {
+            ReceiptAggregateVoucher rav;
+            bytes signature;
+        }
+
+        interface Escrow {
+            function redeem(SignedRAV calldata signedRAV, bytes calldata allocationIDProof) external;
+        }
+    }
+}
+
+impl SignedRAV {
+    /// Encodes the signed RAV into the calldata expected by the escrow contract's
+    /// `redeem(SignedRAV signedRAV, bytes allocationIDProof)` function, including the function selector.
+    ///
+    /// `allocation_id_proof` is the allocation ID proof signed by the receiver, it is passed through as-is.
+    pub fn to_redeem_calldata(&self, allocation_id_proof: &[u8]) -> Vec<u8> {
+        escrow_contract::Escrow::redeemCall {
+            signedRAV: escrow_contract::SignedRAV {
+                rav: escrow_contract::ReceiptAggregateVoucher {
+                    allocationId: self.message.allocationId,
+                    timestampNs: self.message.timestampNs,
+                    valueAggregate: self.message.valueAggregate,
+                },
+                signature: self.signature.to_vec(),
+            },
+            allocationIDProof: allocation_id_proof.to_vec(),
+        }
+        .abi_encode()
+    }
+
+    /// Returns the RAV and its signature as separate arguments for an on-chain redemption. The
+    /// signature is the 65 bytes `r || s || v` expected by Solidity's `ecrecover`, with `v`
+    /// normalized to 27 or 28 (from 0 or 1, or an EIP-155 value).
+    pub fn split(&self) -> (ReceiptAggregateVoucher, [u8; 65]) {
+        (self.message.clone(), self.signature.as_bytes())
+    }
+
+    /// Signs [`ReceiptAggregateVoucher::zero`], e.g. to store it as the first RAV of an allocation.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::WalletError`] if signing fails
+    #[cfg(feature = "ethers")]
+    pub fn zero(
+        domain_separator: &Eip712Domain,
+        allocation_id: Address,
+        timestamp: TimestampNs,
+        signing_wallet: &LocalWallet,
+    ) -> crate::Result<Self> {
+        Self::new(
+            domain_separator,
+            ReceiptAggregateVoucher::zero(allocation_id, timestamp),
+            signing_wallet,
+        )
+    }
+}
+
 impl ReceiptAggregateVoucher {
+    /// Returns a RAV of value zero, aggregating no receipt, to use as the previous RAV of a fresh
+    /// allocation instead of `None`. Receipts aggregated onto it must be later than `timestamp`,
+    /// e.g. the time the allocation was opened, such that aggregating them onto it gives the same
+    /// RAV as aggregating them from `None`.
+    pub fn zero(allocation_id: Address, timestamp: TimestampNs) -> Self {
+        Self {
+            allocationId: allocation_id,
+            timestampNs: timestamp.as_nanos(),
+            valueAggregate: 0,
+        }
+    }
+
+    /// Returns the EIP712 digest that the aggregator signs for this RAV under `domain_separator`.
+    ///
+    /// Lets the receiver verify that a returned signature was made over the RAV it expects.
+    pub fn eip712_signing_hash(&self, domain_separator: &Eip712Domain) -> B256 {
+        SolStruct::eip712_signing_hash(self, domain_separator)
+    }
+
+    /// Returns the RAV's timestamp, i.e. the max timestamp of the aggregated receipts.
+    pub fn timestamp(&self) -> TimestampNs {
+        TimestampNs::from_nanos(self.timestampNs)
+    }
+
     /// Aggregates a batch of validated receipts with optional validated previous RAV, returning a new RAV if all provided items are valid or an error if not.
     ///
     /// # Errors
     ///
     /// Returns [`Error::AggregateOverflow`] if any receipt value causes aggregate value to overflow
     ///
+    /// Returns [`Error::RavAllocationIdNotUniform`] if any receipt's allocation id differs from `allocation_id`
+    ///
     pub fn aggregate_receipts(
         allocation_id: Address,
         receipts: &[EIP712SignedMessage<Receipt>],
@@ -50,15 +162,19 @@ impl ReceiptAggregateVoucher {
     ) -> crate::Result<Self> {
         //TODO(#29): When receipts in flight struct in created check that the state of every receipt is OK with all checks complete (relies on #28)
         // If there is a previous RAV get initalize values from it, otherwise get default values
-        let mut timestamp_max = 0u64;
+        let mut timestamp_max = TimestampNs::ZERO;
         let mut value_aggregate = 0u128;
 
         if let Some(prev_rav) = previous_rav {
-            timestamp_max = prev_rav.message.timestampNs;
+            timestamp_max = prev_rav.message.timestamp();
             value_aggregate = prev_rav.message.valueAggregate;
         }
 
         for receipt in receipts {
+            if receipt.message.allocation_id != allocation_id {
+                return Err(Error::RavAllocationIdNotUniform);
+            }
+
             value_aggregate = value_aggregate
                 .checked_add(receipt.message.value)
                 .ok_or(Error::AggregateOverflow)?;
@@ -68,7 +184,7 @@ impl ReceiptAggregateVoucher {
 
         Ok(Self {
             allocationId: allocation_id,
-            timestampNs: timestamp_max,
+            timestampNs: timestamp_max.as_nanos(),
             valueAggregate: value_aggregate,
         })
     }
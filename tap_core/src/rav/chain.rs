@@ -0,0 +1,91 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
+use thiserror::Error;
+
+use crate::rav::SignedRAV;
+
+/// First inconsistency found by [`SignedRAV::verify_chain`], `index` being the position of the
+/// offending RAV in the chain.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    #[error("RAV {index} is not signed by the expected signer: {source_error_message}")]
+    InvalidSignature {
+        index: usize,
+        source_error_message: String,
+    },
+    #[error("RAV {index} allocation id ({allocation_id}) differs from the chain's ({expected_allocation_id})")]
+    AllocationIdMismatch {
+        index: usize,
+        allocation_id: Address,
+        expected_allocation_id: Address,
+    },
+    #[error("RAV {index} value ({value}) is lower than the previous RAV value ({previous_value})")]
+    ValueRegression {
+        index: usize,
+        previous_value: u128,
+        value: u128,
+    },
+    #[error("RAV {index} timestamp ({timestamp_ns}) is lower than the previous RAV timestamp ({previous_timestamp_ns})")]
+    TimestampRegression {
+        index: usize,
+        previous_timestamp_ns: u64,
+        timestamp_ns: u64,
+    },
+}
+
+impl SignedRAV {
+    /// Verifies that `ravs`, ordered from oldest to newest, are a consistent history of the RAVs of
+    /// a single allocation, e.g. for reconciliation against on-chain redemptions. Returns the first
+    /// inconsistency found.
+    ///
+    /// Every RAV must be signed by `expected_signer`, and share the allocation id of the first one.
+    /// As each RAV aggregates the previous one, its value and timestamp can't be lower than the
+    /// previous ones. Each RAV covers the receipts after the timestamp of the previous RAV, up to
+    /// its own timestamp, such that monotonic timestamps leave neither gap nor overlap between the
+    /// periods covered by the RAVs.
+    pub fn verify_chain(
+        ravs: &[SignedRAV],
+        domain_separator: &Eip712Domain,
+        expected_signer: Address,
+    ) -> Result<(), ChainError> {
+        for (index, rav) in ravs.iter().enumerate() {
+            rav.verify(domain_separator, expected_signer)
+                .map_err(|err| ChainError::InvalidSignature {
+                    index,
+                    source_error_message: err.to_string(),
+                })?;
+
+            let expected_allocation_id = ravs[0].message.allocationId;
+            if rav.message.allocationId != expected_allocation_id {
+                return Err(ChainError::AllocationIdMismatch {
+                    index,
+                    allocation_id: rav.message.allocationId,
+                    expected_allocation_id,
+                });
+            }
+
+            if index == 0 {
+                continue;
+            }
+            let previous = &ravs[index - 1].message;
+            if rav.message.valueAggregate < previous.valueAggregate {
+                return Err(ChainError::ValueRegression {
+                    index,
+                    previous_value: previous.valueAggregate,
+                    value: rav.message.valueAggregate,
+                });
+            }
+            if rav.message.timestampNs < previous.timestampNs {
+                return Err(ChainError::TimestampRegression {
+                    index,
+                    previous_timestamp_ns: previous.timestampNs,
+                    timestamp_ns: rav.message.timestampNs,
+                });
+            }
+        }
+        Ok(())
+    }
+}
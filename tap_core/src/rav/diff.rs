@@ -0,0 +1,73 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+
+use alloy_primitives::{Address, I256, U256};
+
+use crate::rav::ReceiptAggregateVoucher;
+
+/// Differences between two RAVs, as returned by [`ReceiptAggregateVoucher::diff`]. Each delta is
+/// the value of the other RAV minus the value of this one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RavDiff {
+    /// Allocation ids of this and the other RAV, if they differ
+    pub allocation_ids: Option<(Address, Address)>,
+    pub timestamp_ns_delta: i128,
+    pub value_aggregate_delta: I256,
+}
+
+impl RavDiff {
+    /// Returns whether both RAVs are identical.
+    pub fn is_empty(&self) -> bool {
+        self.allocation_ids.is_none()
+            && self.timestamp_ns_delta == 0
+            && self.value_aggregate_delta.is_zero()
+    }
+}
+
+impl fmt::Display for RavDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "identical RAVs");
+        }
+
+        let mut differences = vec![];
+        if let Some((allocation_id, other_allocation_id)) = self.allocation_ids {
+            differences.push(format!(
+                "allocationId: {allocation_id} -> {other_allocation_id}"
+            ));
+        }
+        if self.timestamp_ns_delta != 0 {
+            differences.push(format!("timestampNs: {:+}", self.timestamp_ns_delta));
+        }
+        if !self.value_aggregate_delta.is_zero() {
+            let sign = if self.value_aggregate_delta.is_positive() {
+                "+"
+            } else {
+                ""
+            };
+            differences.push(format!(
+                "valueAggregate: {sign}{}",
+                self.value_aggregate_delta
+            ));
+        }
+        write!(f, "{}", differences.join(", "))
+    }
+}
+
+impl ReceiptAggregateVoucher {
+    /// Returns how `other` differs from this RAV, e.g. to reconcile the RAV expected by the indexer
+    /// with the one returned by the aggregator when [`crate::manager::Manager::verify_and_store_rav`]
+    /// fails.
+    pub fn diff(&self, other: &ReceiptAggregateVoucher) -> RavDiff {
+        RavDiff {
+            allocation_ids: (self.allocationId != other.allocationId)
+                .then_some((self.allocationId, other.allocationId)),
+            timestamp_ns_delta: i128::from(other.timestampNs) - i128::from(self.timestampNs),
+            // Both values fit in 128 bits, so their difference can't overflow
+            value_aggregate_delta: I256::from_raw(U256::from(other.valueAggregate))
+                - I256::from_raw(U256::from(self.valueAggregate)),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ops::RangeInclusive;
+
+use alloy_primitives::Address;
+use thiserror::Error;
+
+use crate::receipt::Receipt;
+
+/// Misconfiguration detected by [`ReceiptBuilder::build`], before the receipt is signed.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ReceiptBuilderError {
+    #[error("Receipt allocation id is the zero address")]
+    ZeroAllocationId,
+    #[error("Receipt value {value} is out of the accepted bounds [{min_value}, {max_value}]")]
+    ValueOutOfBounds {
+        value: u128,
+        min_value: u128,
+        max_value: u128,
+    },
+    #[error("Failed to get current system time: {source_error_message}")]
+    InvalidSystemTime { source_error_message: String },
+}
+
+/// Builds a [`Receipt`] like [`Receipt::new`] does, but validates its inputs first, so that a
+/// misconfigured sender fails at build time rather than having its receipts rejected by the
+/// receiver's checks.
+///
+/// By default, only zero-valued receipts are rejected. Tighter bounds can be set with
+/// [`ReceiptBuilder::with_value_bounds`].
+#[derive(Debug, Clone)]
+pub struct ReceiptBuilder {
+    allocation_id: Address,
+    value: u128,
+    metadata: Option<[u8; 32]>,
+    value_bounds: RangeInclusive<u128>,
+}
+
+impl ReceiptBuilder {
+    pub fn new(allocation_id: Address, value: u128) -> Self {
+        Self {
+            allocation_id,
+            value,
+            metadata: None,
+            value_bounds: 1..=u128::MAX,
+        }
+    }
+
+    /// Sets the range the receipt value must be in.
+    pub fn with_value_bounds(mut self, value_bounds: RangeInclusive<u128>) -> Self {
+        self.value_bounds = value_bounds;
+        self
+    }
+
+    /// Sets the receipt's opaque `metadata` tag. See [`Receipt::with_metadata`].
+    pub fn with_metadata(mut self, metadata: [u8; 32]) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Validates the inputs and returns the receipt, timestamped with the current time.
+    pub fn build(self) -> Result<Receipt, ReceiptBuilderError> {
+        if self.allocation_id == Address::ZERO {
+            return Err(ReceiptBuilderError::ZeroAllocationId);
+        }
+        if !self.value_bounds.contains(&self.value) {
+            return Err(ReceiptBuilderError::ValueOutOfBounds {
+                value: self.value,
+                min_value: *self.value_bounds.start(),
+                max_value: *self.value_bounds.end(),
+            });
+        }
+
+        let receipt = Receipt::new(self.allocation_id, self.value).map_err(|err| {
+            ReceiptBuilderError::InvalidSystemTime {
+                source_error_message: err.to_string(),
+            }
+        })?;
+        Ok(match self.metadata {
+            Some(metadata) => receipt.with_metadata(metadata),
+            None => receipt,
+        })
+    }
+}
+
+#[cfg(test)]
+mod receipt_builder_unit_test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn build_validates_inputs() {
+        let allocation_id =
+            Address::from_str("0xabababababababababababababababababababab").unwrap();
+
+        let receipt = ReceiptBuilder::new(allocation_id, 42)
+            .with_value_bounds(10..=100)
+            .with_metadata([1u8; 32])
+            .build()
+            .unwrap();
+        assert_eq!(receipt.allocation_id, allocation_id);
+        assert_eq!(receipt.value, 42);
+        assert_eq!(receipt.metadata, Some([1u8; 32].into()));
+
+        assert_eq!(
+            ReceiptBuilder::new(Address::ZERO, 42).build(),
+            Err(ReceiptBuilderError::ZeroAllocationId)
+        );
+        assert_eq!(
+            ReceiptBuilder::new(allocation_id, 0).build(),
+            Err(ReceiptBuilderError::ValueOutOfBounds {
+                value: 0,
+                min_value: 1,
+                max_value: u128::MAX,
+            })
+        );
+        assert_eq!(
+            ReceiptBuilder::new(allocation_id, 101)
+                .with_value_bounds(10..=100)
+                .build(),
+            Err(ReceiptBuilderError::ValueOutOfBounds {
+                value: 101,
+                min_value: 10,
+                max_value: 100,
+            })
+        );
+    }
+}
@@ -1,12 +1,16 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::receipt::{Checking, ReceiptError, ReceiptWithState};
+use crate::receipt::{Checking, Receipt, ReceiptError, ReceiptWithState};
+use alloy_primitives::{Address, U256};
+use serde::Deserialize;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
     ops::Deref,
     sync::{Arc, RwLock},
 };
+use thiserror::Error;
 
 use super::Failed;
 
@@ -14,6 +18,7 @@ pub type ReceiptCheck = Arc<dyn Check + Sync + Send>;
 
 pub type CheckResult = anyhow::Result<()>;
 
+#[derive(Clone)]
 pub struct Checks(Arc<[ReceiptCheck]>);
 
 impl Checks {
@@ -37,6 +42,28 @@ impl Deref for Checks {
 #[async_trait::async_trait]
 pub trait Check {
     async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult;
+
+    /// Name of the check, used to disable it with [`crate::manager::Manager::with_disabled_checks`].
+    /// Defaults to the type name of the check, as returned by [`std::any::type_name`].
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Checks are identified by their [`Check::name`], such that a set of [`ReceiptCheck`] can be
+/// looked up, e.g. by [`crate::manager::Manager::with_disabled_checks`].
+impl PartialEq for dyn Check + Sync + Send {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl Eq for dyn Check + Sync + Send {}
+
+impl Hash for dyn Check + Sync + Send {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name().hash(state);
+    }
 }
 
 pub trait CheckBatch {
@@ -52,28 +79,54 @@ pub trait CheckBatch {
 #[derive(Debug)]
 pub struct TimestampCheck {
     min_timestamp_ns: RwLock<u64>,
+    /// Minimum timestamps of the allocations with a RAV, on top of `min_timestamp_ns`
+    allocation_min_timestamps_ns: RwLock<HashMap<Address, u64>>,
 }
 
 impl TimestampCheck {
     pub fn new(min_timestamp_ns: u64) -> Self {
         Self {
             min_timestamp_ns: RwLock::new(min_timestamp_ns),
+            allocation_min_timestamps_ns: RwLock::new(HashMap::new()),
         }
     }
     /// Updates the minimum timestamp that will be accepted for a receipt (exclusive).
     pub fn update_min_timestamp_ns(&self, min_timestamp_ns: u64) {
         *self.min_timestamp_ns.write().unwrap() = min_timestamp_ns;
     }
+
+    /// Updates the minimum timestamp that will be accepted for a receipt of `allocation_id`
+    /// (exclusive), e.g. the timestamp of the allocation's last RAV. The receipts of other
+    /// allocations are not affected.
+    pub fn update_allocation_min_timestamp_ns(
+        &self,
+        allocation_id: Address,
+        min_timestamp_ns: u64,
+    ) {
+        self.allocation_min_timestamps_ns
+            .write()
+            .unwrap()
+            .insert(allocation_id, min_timestamp_ns);
+    }
 }
 
 #[async_trait::async_trait]
 impl Check for TimestampCheck {
     async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
-        let min_timestamp_ns = *self.min_timestamp_ns.read().unwrap();
         let signed_receipt = receipt.signed_receipt();
-        if signed_receipt.message.timestamp_ns <= min_timestamp_ns {
+        let allocation_min_timestamp_ns = self
+            .allocation_min_timestamps_ns
+            .read()
+            .unwrap()
+            .get(&signed_receipt.message.allocation_id)
+            .copied()
+            .unwrap_or(0);
+        let min_timestamp_ns =
+            (*self.min_timestamp_ns.read().unwrap()).max(allocation_min_timestamp_ns);
+        let timestamp_ns = signed_receipt.message.timestamp_ns.as_nanos();
+        if timestamp_ns <= min_timestamp_ns {
             return Err(ReceiptError::InvalidTimestamp {
-                received_timestamp: signed_receipt.message.timestamp_ns,
+                received_timestamp: timestamp_ns,
                 timestamp_min: min_timestamp_ns,
             }
             .into());
@@ -82,6 +135,120 @@ impl Check for TimestampCheck {
     }
 }
 
+/// Rejects receipts with a value of zero, before they use up storage and further checks.
+///
+/// This check is opt-in: zero-value receipts are accepted by default, since some protocols
+/// legitimately use them as probes. Add it to the list of checks to reject them.
+#[derive(Debug, Default)]
+pub struct NonZeroValueCheck;
+
+#[async_trait::async_trait]
+impl Check for NonZeroValueCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let value = receipt.signed_receipt().message.value;
+        if value == 0 {
+            return Err(ReceiptError::InvalidValue {
+                received_value: value,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Source of the expected value of receipts, e.g. a live on-chain price feed, for [`PriceFeedCheck`].
+#[async_trait::async_trait]
+pub trait PriceSource: Send + Sync {
+    /// Returns the value `receipt` is expected to have.
+    async fn price(&self, receipt: &Receipt) -> anyhow::Result<u128>;
+}
+
+/// Rejects receipts whose value deviates from the price given by a [`PriceSource`] by more than
+/// `tolerance_percent` percent of that price, in either direction.
+pub struct PriceFeedCheck {
+    price_source: Arc<dyn PriceSource>,
+    tolerance_percent: u32,
+}
+
+impl PriceFeedCheck {
+    pub fn new(price_source: Arc<dyn PriceSource>, tolerance_percent: u32) -> Self {
+        Self {
+            price_source,
+            tolerance_percent,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for PriceFeedCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let value = receipt.signed_receipt().message.value;
+        let price = self
+            .price_source
+            .price(&receipt.signed_receipt().message)
+            .await
+            .map_err(|e| ReceiptError::CheckFailedToComplete(e.to_string()))?;
+
+        // deviation / price > tolerance_percent / 100, without rounding nor overflow
+        let deviation = U256::from(value.abs_diff(price));
+        if deviation * U256::from(100) > U256::from(price) * U256::from(self.tolerance_percent) {
+            return Err(ReceiptError::InvalidValue {
+                received_value: value,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Source of the on-chain lifetime of allocations, for [`AllocationLifetimeCheck`].
+#[async_trait::async_trait]
+pub trait AllocationLifetimeSource: Send + Sync {
+    /// Returns the timestamps (in ns) of the blocks in which `allocation_id` was opened and closed,
+    /// or `None` if the allocation is unknown. Allocations still open are closed at `u64::MAX`.
+    async fn lifetime(&self, allocation_id: Address) -> anyhow::Result<Option<(u64, u64)>>;
+}
+
+/// Rejects receipts whose timestamp is not strictly between the open and close block timestamps
+/// of their allocation, as given by an [`AllocationLifetimeSource`].
+///
+/// Unlike [`TimestampCheck`], the bounds are specific to each allocation and follow the chain.
+pub struct AllocationLifetimeCheck {
+    lifetime_source: Arc<dyn AllocationLifetimeSource>,
+}
+
+impl AllocationLifetimeCheck {
+    pub fn new(lifetime_source: Arc<dyn AllocationLifetimeSource>) -> Self {
+        Self { lifetime_source }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for AllocationLifetimeCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let receipt = &receipt.signed_receipt().message;
+        let (open_timestamp, close_timestamp) = self
+            .lifetime_source
+            .lifetime(receipt.allocation_id)
+            .await
+            .map_err(|e| ReceiptError::CheckFailedToComplete(e.to_string()))?
+            .ok_or(ReceiptError::InvalidAllocationID {
+                received_allocation_id: receipt.allocation_id,
+            })?;
+
+        let timestamp_ns = receipt.timestamp_ns.as_nanos();
+        if timestamp_ns <= open_timestamp || timestamp_ns >= close_timestamp {
+            return Err(ReceiptError::OutsideAllocationLifetime {
+                received_timestamp: timestamp_ns,
+                open_timestamp,
+                close_timestamp,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
 /// Timestamp Check verifies if the receipt is **greater or equal** than the minimum timestamp provided.
 pub struct BatchTimestampCheck(pub u64);
 
@@ -95,7 +262,7 @@ impl CheckBatch for BatchTimestampCheck {
     ) {
         let (mut checking, mut failed) = (vec![], vec![]);
         for receipt in receipts.into_iter() {
-            let receipt_timestamp_ns = receipt.signed_receipt().message.timestamp_ns;
+            let receipt_timestamp_ns = receipt.signed_receipt().message.timestamp_ns.as_nanos();
             let min_timestamp_ns = self.0;
             if receipt_timestamp_ns >= min_timestamp_ns {
                 checking.push(receipt);
@@ -120,7 +287,7 @@ impl CheckBatch for UniqueCheck {
         Vec<ReceiptWithState<Checking>>,
         Vec<ReceiptWithState<Failed>>,
     ) {
-        let mut signatures: HashSet<ethers::types::Signature> = HashSet::new();
+        let mut signatures: HashSet<crate::signed_message::Signature> = HashSet::new();
         let (mut checking, mut failed) = (vec![], vec![]);
 
         for received_receipt in receipts.into_iter() {
@@ -134,3 +301,75 @@ impl CheckBatch for UniqueCheck {
         (checking, failed)
     }
 }
+
+/// Checks required on received receipts, as configured by the operator, such that they can be
+/// changed without a rebuild. Checks are referred to by their [`Check::name`], e.g. in TOML, as read
+/// by [`checks_from_config`] with the `toml` feature:
+///
+/// ```toml
+/// required_checks = [
+///     "tap_core::receipt::checks::TimestampCheck",
+///     "tap_core::receipt::checks::NonZeroValueCheck",
+/// ]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChecksConfig {
+    pub required_checks: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum ChecksConfigError {
+    #[cfg(feature = "toml")]
+    #[error("Failed to read the checks config: {0}")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "toml")]
+    #[error("Failed to parse the checks config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Unknown receipt check `{name}`, expected one of: {}", .available.join(", "))]
+    UnknownCheck {
+        name: String,
+        available: Vec<String>,
+    },
+}
+
+impl ChecksConfig {
+    /// Picks the configured checks out of `available_checks`, in the configured order.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChecksConfigError::UnknownCheck`] if a configured name matches none of the
+    /// `available_checks`
+    pub fn select(
+        &self,
+        available_checks: &[ReceiptCheck],
+    ) -> Result<Vec<ReceiptCheck>, ChecksConfigError> {
+        self.required_checks
+            .iter()
+            .map(|name| {
+                available_checks
+                    .iter()
+                    .find(|check| check.name() == name)
+                    .cloned()
+                    .ok_or_else(|| ChecksConfigError::UnknownCheck {
+                        name: name.clone(),
+                        available: available_checks
+                            .iter()
+                            .map(|check| check.name().to_string())
+                            .collect(),
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Reads the [`ChecksConfig`] TOML file at `path`, and picks the configured checks out of
+/// `available_checks` with [`ChecksConfig::select`].
+#[cfg(feature = "toml")]
+pub fn checks_from_config(
+    path: impl AsRef<std::path::Path>,
+    available_checks: &[ReceiptCheck],
+) -> Result<Vec<ReceiptCheck>, ChecksConfigError> {
+    let config: ChecksConfig = toml::from_str(&std::fs::read_to_string(path)?)?;
+    config.select(available_checks)
+}
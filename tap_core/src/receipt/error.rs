@@ -8,6 +8,10 @@ use serde::{Deserialize, Serialize};
 pub enum ReceiptError {
     #[error("invalid allocation ID: {received_allocation_id}")]
     InvalidAllocationID { received_allocation_id: Address },
+    #[error("zero allocation ID, the receipt was likely built without setting it")]
+    ZeroAllocationID,
+    #[error("closed allocation ID: {received_allocation_id}")]
+    ClosedAllocationID { received_allocation_id: Address },
     #[error("Signature check failed:\n{source_error_message}")]
     InvalidSignature { source_error_message: String },
     #[error("invalid timestamp: {received_timestamp} (expected min {timestamp_min})")]
@@ -15,12 +19,30 @@ pub enum ReceiptError {
         received_timestamp: u64,
         timestamp_min: u64,
     },
+    #[error(
+        "invalid timestamp: {received_timestamp} (expected between allocation open {open_timestamp} and close {close_timestamp})"
+    )]
+    OutsideAllocationLifetime {
+        received_timestamp: u64,
+        open_timestamp: u64,
+        close_timestamp: u64,
+    },
     #[error("Invalid Value: {received_value} ")]
     InvalidValue { received_value: u128 },
     #[error("Receipt is not unique")]
     NonUniqueReceipt,
     #[error("Attempt to collect escrow failed")]
     SubtractEscrowFailed,
+    #[error(
+        "Insufficient escrow: {required} required, {available} available, {shortfall} missing"
+    )]
+    InsufficientEscrow {
+        required: u128,
+        available: u128,
+        shortfall: u128,
+    },
+    #[error("Attempt to release escrow failed")]
+    ReleaseEscrowFailed,
     #[error("Issue encountered while performing check: {0}")]
     CheckFailedToComplete(String),
 }
@@ -1,18 +1,57 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+mod builder;
 pub mod checks;
 mod error;
 mod receipt_sol;
 mod received_receipt;
 
+pub use builder::{ReceiptBuilder, ReceiptBuilderError};
 pub use error::ReceiptError;
 pub use receipt_sol::Receipt;
 pub use received_receipt::{
-    AwaitingReserve, Checking, Failed, ReceiptState, ReceiptWithState, Reserved, ResultReceipt,
+    AwaitingReserve, Checking, Failed, ReceiptOutcome, ReceiptState, ReceiptWithState, Reserved,
+    ResultReceipt, StateSummary,
 };
 
-use crate::signed_message::EIP712SignedMessage;
+use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
+
+use crate::{signed_message::EIP712SignedMessage, Error};
 
 pub type SignedReceipt = EIP712SignedMessage<Receipt>;
 pub type ReceiptResult<T> = Result<T, ReceiptError>;
+
+/// Recovers the signers of `receipts` and returns their common sender, such that a batch mixing
+/// senders is rejected before being aggregated into a single RAV.
+///
+/// # Errors
+///
+/// Returns [`Error::NoValidReceiptsForRAVRequest`] if `receipts` is empty
+///
+/// Returns [`Error::MixedSenders`] at the first receipt signed by another sender than the first
+/// receipt
+///
+/// Returns the errors of [`EIP712SignedMessage::recover_signer`]
+///
+pub fn validate_single_sender(
+    receipts: &[SignedReceipt],
+    domain_separator: &Eip712Domain,
+) -> Result<Address, Error> {
+    let (first, others) = receipts
+        .split_first()
+        .ok_or(Error::NoValidReceiptsForRAVRequest)?;
+    let expected = first.recover_signer(domain_separator)?;
+    for (index, receipt) in others.iter().enumerate() {
+        let received = receipt.recover_signer(domain_separator)?;
+        if received != expected {
+            return Err(Error::MixedSenders {
+                index: index + 1,
+                expected,
+                received,
+            });
+        }
+    }
+    Ok(expected)
+}
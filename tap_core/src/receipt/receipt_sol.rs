@@ -8,38 +8,199 @@
 //! The payment receiver would verify the received receipt and store it to be
 //! accumulated with other received receipts in the future.
 
-use alloy_primitives::Address;
-use alloy_sol_types::sol;
+use std::borrow::Cow;
+
+use alloy_primitives::{hex, Address, B256, U256};
+use alloy_sol_types::Eip712Domain;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    signed_message::{Eip712Message, MessageId},
+    timestamp::TimestampNs,
+};
 
-sol! {
-    /// Holds information needed for promise of payment signed with ECDSA
-    #[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
-    struct Receipt {
-        /// Unique allocation id this receipt belongs to
-        address allocation_id;
-        /// Unix Epoch timestamp in nanoseconds (Truncated to 64-bits)
-        uint64 timestamp_ns;
-        /// Random value used to avoid collisions from multiple receipts with one timestamp
-        uint64 nonce;
-        /// GRT value for transaction (truncate to lower bits)
-        uint128 value;
+/// Holds information needed for promise of payment signed with ECDSA
+///
+/// The EIP712 type of a receipt only lists its optional fields that are set, such that a receipt
+/// without them has the same type, and thus the same signature, as the receipts of the escrow
+/// contract: `Receipt(address allocation_id,uint64 timestamp_ns,uint64 nonce,uint128 value)`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Receipt {
+    /// Unique allocation id this receipt belongs to
+    pub allocation_id: Address,
+    /// Unix Epoch timestamp in nanoseconds (Truncated to 64-bits), serialized as a bare integer
+    pub timestamp_ns: TimestampNs,
+    /// Random value used to avoid collisions from multiple receipts with one timestamp
+    pub nonce: u64,
+    /// GRT value for transaction (truncate to lower bits)
+    pub value: u128,
+    /// Opaque application tag (e.g. subgraph deployment id). When set, it is signed along with the
+    /// receipt as a trailing `bytes32 metadata` field, but ignored when aggregating.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<B256>,
+    /// Unique hash of the previous receipt of the same session, if chained. When set, it is signed
+    /// along with the receipt as a trailing `bytes32 parent` field, but ignored when aggregating.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<B256>,
+}
+
+impl Eip712Message for Receipt {
+    fn encode_type(&self) -> Cow<'static, str> {
+        let mut encode_type =
+            "Receipt(address allocation_id,uint64 timestamp_ns,uint64 nonce,uint128 value"
+                .to_string();
+        if self.metadata.is_some() {
+            encode_type.push_str(",bytes32 metadata");
+        }
+        if self.parent.is_some() {
+            encode_type.push_str(",bytes32 parent");
+        }
+        encode_type.push(')');
+        Cow::Owned(encode_type)
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(32 * 6);
+        data.extend_from_slice(self.allocation_id.into_word().as_slice());
+        data.extend_from_slice(&U256::from(self.timestamp_ns.as_nanos()).to_be_bytes::<32>());
+        data.extend_from_slice(&U256::from(self.nonce).to_be_bytes::<32>());
+        data.extend_from_slice(&U256::from(self.value).to_be_bytes::<32>());
+        if let Some(metadata) = &self.metadata {
+            data.extend_from_slice(metadata.as_slice());
+        }
+        if let Some(parent) = &self.parent {
+            data.extend_from_slice(parent.as_slice());
+        }
+        data
     }
 }
 
 impl Receipt {
     /// Returns a receipt with provided values
     pub fn new(allocation_id: Address, value: u128) -> crate::Result<Self> {
-        let timestamp_ns = crate::get_current_timestamp_u64_ns()?;
+        let timestamp_ns = TimestampNs::from_nanos(crate::get_current_timestamp_u64_ns()?);
         let nonce = thread_rng().gen::<u64>();
         Ok(Self {
             allocation_id,
             timestamp_ns,
             nonce,
             value,
+            metadata: None,
+            parent: None,
+        })
+    }
+
+    /// Returns the receipt's timestamp.
+    pub fn timestamp(&self) -> TimestampNs {
+        self.timestamp_ns
+    }
+
+    /// Sets the receipt's opaque `metadata` tag, which changes its EIP712 type, see [`Receipt`].
+    pub fn with_metadata(mut self, metadata: [u8; 32]) -> Self {
+        self.metadata = Some(metadata.into());
+        self
+    }
+
+    /// Returns the EIP-712 typed data of the receipt for `domain`, in the JSON structure expected by
+    /// wallets' `eth_signTypedData_v4`, such that browser-based senders can sign receipts.
+    ///
+    /// Integers that may not fit in a JavaScript number are encoded as decimal strings.
+    pub fn eip712_typed_data_json(&self, domain: &Eip712Domain) -> serde_json::Value {
+        let mut domain_types = vec![];
+        let mut domain_values = serde_json::Map::new();
+        if let Some(name) = &domain.name {
+            domain_types.push(json!({ "name": "name", "type": "string" }));
+            domain_values.insert("name".into(), json!(name));
+        }
+        if let Some(version) = &domain.version {
+            domain_types.push(json!({ "name": "version", "type": "string" }));
+            domain_values.insert("version".into(), json!(version));
+        }
+        if let Some(chain_id) = &domain.chain_id {
+            domain_types.push(json!({ "name": "chainId", "type": "uint256" }));
+            let chain_id = match u64::try_from(*chain_id) {
+                Ok(chain_id) => json!(chain_id),
+                Err(_) => json!(chain_id.to_string()),
+            };
+            domain_values.insert("chainId".into(), chain_id);
+        }
+        if let Some(verifying_contract) = &domain.verifying_contract {
+            domain_types.push(json!({ "name": "verifyingContract", "type": "address" }));
+            domain_values.insert(
+                "verifyingContract".into(),
+                json!(verifying_contract.to_checksum(None)),
+            );
+        }
+        if let Some(salt) = &domain.salt {
+            domain_types.push(json!({ "name": "salt", "type": "bytes32" }));
+            domain_values.insert("salt".into(), json!(hex::encode_prefixed(salt)));
+        }
+
+        let mut receipt_types = vec![
+            json!({ "name": "allocation_id", "type": "address" }),
+            json!({ "name": "timestamp_ns", "type": "uint64" }),
+            json!({ "name": "nonce", "type": "uint64" }),
+            json!({ "name": "value", "type": "uint128" }),
+        ];
+        let mut receipt_values = serde_json::Map::new();
+        receipt_values.insert(
+            "allocation_id".into(),
+            json!(self.allocation_id.to_checksum(None)),
+        );
+        receipt_values.insert(
+            "timestamp_ns".into(),
+            json!(self.timestamp_ns.as_nanos().to_string()),
+        );
+        receipt_values.insert("nonce".into(), json!(self.nonce.to_string()));
+        receipt_values.insert("value".into(), json!(self.value.to_string()));
+        if let Some(metadata) = &self.metadata {
+            receipt_types.push(json!({ "name": "metadata", "type": "bytes32" }));
+            receipt_values.insert("metadata".into(), json!(hex::encode_prefixed(metadata)));
+        }
+        if let Some(parent) = &self.parent {
+            receipt_types.push(json!({ "name": "parent", "type": "bytes32" }));
+            receipt_values.insert("parent".into(), json!(hex::encode_prefixed(parent)));
+        }
+
+        json!({
+            "types": {
+                "EIP712Domain": domain_types,
+                "Receipt": receipt_types,
+            },
+            "primaryType": "Receipt",
+            "domain": domain_values,
+            "message": receipt_values,
         })
     }
+
+    /// Chains the receipt to `parent`, the [`crate::signed_message::EIP712SignedMessage::unique_hash`]
+    /// of the previous receipt of the same session, such that the receiver can detect gaps and forks
+    /// in the sender's receipt chain. This changes the receipt's EIP712 type, see [`Receipt`].
+    pub fn with_parent(mut self, parent: MessageId) -> Self {
+        self.parent = Some(parent.0.into());
+        self
+    }
+
+    /// Returns the unique hash of the previous receipt of the session, `None` if unchained.
+    pub fn parent(&self) -> Option<MessageId> {
+        self.parent.map(|parent| MessageId(parent.0))
+    }
+
+    /// Returns a receipt built from raw parts, e.g. for fuzzing or property tests with edge-case
+    /// timestamps. Use [`Receipt::new`] otherwise.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn from_parts(allocation_id: Address, timestamp_ns: u64, nonce: u64, value: u128) -> Self {
+        Self {
+            allocation_id,
+            timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+            nonce,
+            value,
+            metadata: None,
+            parent: None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -49,6 +210,8 @@ mod receipt_unit_test {
     use std::str::FromStr;
     use std::time::{SystemTime, UNIX_EPOCH};
 
+    use alloy_sol_types::SolStruct;
+
     #[fixture]
     fn allocation_ids() -> Vec<Address> {
         vec![
@@ -73,8 +236,9 @@ mod receipt_unit_test {
             .duration_since(UNIX_EPOCH)
             .expect("Current system time should be greater than `UNIX_EPOCH`")
             .as_nanos() as u64;
-        assert!(receipt.timestamp_ns <= now);
-        assert!(receipt.timestamp_ns >= now - 5000000); // 5 second tolerance
+        assert!(receipt.timestamp_ns <= TimestampNs::from_nanos(now));
+        assert!(receipt.timestamp_ns >= TimestampNs::from_nanos(now - 5000000));
+        // 5 second tolerance
     }
 
     #[rstest]
@@ -95,10 +259,124 @@ mod receipt_unit_test {
         //       of the nonce generation.
         assert_ne!(receipt1.nonce, receipt2.nonce);
 
-        assert!(receipt1.timestamp_ns <= now);
-        assert!(receipt1.timestamp_ns >= now - 5000000); // 5 second tolerance
+        assert!(receipt1.timestamp_ns <= TimestampNs::from_nanos(now));
+        assert!(receipt1.timestamp_ns >= TimestampNs::from_nanos(now - 5000000)); // 5 second tolerance
+
+        assert!(receipt2.timestamp_ns <= TimestampNs::from_nanos(now));
+        assert!(receipt2.timestamp_ns >= TimestampNs::from_nanos(now - 5000000));
+        // 5 second tolerance
+    }
+
+    mod escrow_contract {
+        alloy_sol_types::sol! {
+            struct Receipt {
+                address allocation_id;
+                uint64 timestamp_ns;
+                uint64 nonce;
+                uint128 value;
+            }
+        }
+    }
+
+    #[test]
+    fn optional_fields_are_typed_only_when_set() {
+        let receipt = Receipt::from_parts(Address::from([0xab; 20]), 1_000, 42, 1234);
+        let contract_receipt = escrow_contract::Receipt {
+            allocation_id: receipt.allocation_id,
+            timestamp_ns: receipt.timestamp_ns.as_nanos(),
+            nonce: receipt.nonce,
+            value: receipt.value,
+        };
+        assert_eq!(
+            receipt.encode_type(),
+            escrow_contract::Receipt::eip712_encode_type()
+        );
+        assert_eq!(receipt.hash_struct(), contract_receipt.eip712_hash_struct());
+
+        let tagged_receipt = receipt.clone().with_metadata([0x00; 32]);
+        assert_eq!(
+            tagged_receipt.encode_type(),
+            "Receipt(address allocation_id,uint64 timestamp_ns,uint64 nonce,uint128 value,bytes32 metadata)"
+        );
+        // Even a zero tag is signed, such that it can't be stripped from the receipt
+        assert_ne!(tagged_receipt.hash_struct(), receipt.hash_struct());
+
+        let chained_receipt = tagged_receipt.with_parent(MessageId([0x00; 32]));
+        assert_eq!(
+            chained_receipt.encode_type(),
+            "Receipt(address allocation_id,uint64 timestamp_ns,uint64 nonce,uint128 value,bytes32 metadata,bytes32 parent)"
+        );
+        assert_eq!(chained_receipt.parent(), Some(MessageId([0x00; 32])));
+    }
+
+    #[test]
+    fn test_eip712_typed_data_json() {
+        let domain = crate::tap_eip712_domain(
+            1,
+            Address::from_str("0x1111111111111111111111111111111111111111").unwrap(),
+        );
+        let receipt = Receipt::from_parts(
+            Address::from_str("0x1234567890123456789012345678901234567890").unwrap(),
+            1_700_000_000_000_000_000,
+            42,
+            1234,
+        )
+        .with_metadata([0x01; 32]);
+
+        let typed_data = receipt.eip712_typed_data_json(&domain);
+        assert_eq!(typed_data["primaryType"], "Receipt");
+        assert_eq!(
+            typed_data["types"],
+            serde_json::json!({
+                "EIP712Domain": [
+                    { "name": "name", "type": "string" },
+                    { "name": "version", "type": "string" },
+                    { "name": "chainId", "type": "uint256" },
+                    { "name": "verifyingContract", "type": "address" },
+                ],
+                "Receipt": [
+                    { "name": "allocation_id", "type": "address" },
+                    { "name": "timestamp_ns", "type": "uint64" },
+                    { "name": "nonce", "type": "uint64" },
+                    { "name": "value", "type": "uint128" },
+                    { "name": "metadata", "type": "bytes32" },
+                ],
+            })
+        );
+        assert_eq!(
+            typed_data["domain"],
+            serde_json::json!({
+                "name": "TAP",
+                "version": "1",
+                "chainId": 1,
+                "verifyingContract": "0x1111111111111111111111111111111111111111",
+            })
+        );
+        assert_eq!(
+            typed_data["message"],
+            serde_json::json!({
+                "allocation_id": "0x1234567890123456789012345678901234567890",
+                "timestamp_ns": "1700000000000000000",
+                "nonce": "42",
+                "value": "1234",
+                "metadata": "0x0101010101010101010101010101010101010101010101010101010101010101",
+            })
+        );
 
-        assert!(receipt2.timestamp_ns <= now);
-        assert!(receipt2.timestamp_ns >= now - 5000000); // 5 second tolerance
+        // The receipt type must match the one hashed when signing
+        let receipt_type = typed_data["types"]["Receipt"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| {
+                format!(
+                    "{} {}",
+                    field["type"].as_str().unwrap(),
+                    field["name"].as_str().unwrap()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(format!("Receipt({receipt_type})"), receipt.encode_type());
     }
 }
@@ -13,12 +13,22 @@
 //! This module is useful for managing and tracking the state of received receipts, as well as
 //! their progress through various checks and stages of inclusion in RAV requests and received RAVs.
 
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use alloy_primitives::{Address, B256};
 use alloy_sol_types::Eip712Domain;
+use serde::Serialize;
 
 use super::{Receipt, ReceiptError, ReceiptResult, SignedReceipt};
 use crate::{
-    manager::adapters::EscrowHandler, receipt::checks::ReceiptCheck,
-    signed_message::EIP712SignedMessage,
+    manager::adapters::EscrowHandler,
+    receipt::checks::ReceiptCheck,
+    signed_message::{cached_eip712_signing_hash, EIP712SignedMessage},
+    timestamp::TimestampNs,
 };
 
 #[derive(Debug, Clone)]
@@ -36,14 +46,70 @@ pub struct AwaitingReserve;
 #[derive(Debug, Clone)]
 pub struct Reserved;
 
-pub trait ReceiptState {}
-impl ReceiptState for Checking {}
-impl ReceiptState for AwaitingReserve {}
-impl ReceiptState for Reserved {}
-impl ReceiptState for Failed {}
+pub trait ReceiptState {
+    /// Name of the state, as reported by [`ReceiptWithState::state_summary`]
+    fn name(&self) -> &'static str;
+
+    /// Reason the receipt ended up in this state, if it failed
+    fn failure(&self) -> Option<&ReceiptError> {
+        None
+    }
+}
+
+impl ReceiptState for Checking {
+    fn name(&self) -> &'static str {
+        "Checking"
+    }
+}
+
+impl ReceiptState for AwaitingReserve {
+    fn name(&self) -> &'static str {
+        "AwaitingReserve"
+    }
+}
+
+impl ReceiptState for Reserved {
+    fn name(&self) -> &'static str {
+        "Reserved"
+    }
+}
+
+impl ReceiptState for Failed {
+    fn name(&self) -> &'static str {
+        "Failed"
+    }
+
+    fn failure(&self) -> Option<&ReceiptError> {
+        Some(&self.error)
+    }
+}
+
+/// Snapshot of a receipt and its lifecycle state, e.g. for dashboards, see
+/// [`ReceiptWithState::state_summary`].
+#[derive(Debug, Clone, Serialize)]
+pub struct StateSummary {
+    pub state: &'static str,
+    pub allocation_id: Address,
+    pub value: u128,
+    pub timestamp_ns: TimestampNs,
+    /// Why the receipt failed, for receipts in the `Failed` state
+    pub failure: Option<ReceiptError>,
+}
 
 pub type ResultReceipt<S> = std::result::Result<ReceiptWithState<S>, ReceiptWithState<Failed>>;
 
+/// State a receipt ended up in when received, as returned by
+/// [`crate::manager::Manager::verify_and_store_receipt_with_state`].
+#[derive(Debug, Clone)]
+pub enum ReceiptOutcome {
+    /// The receipt passed its checks and was stored, and its sender's escrow is reserved for it
+    Reserved(ReceiptWithState<Reserved>),
+    /// The receipt passed its checks and was stored, but its sender's escrow doesn't cover its value
+    AwaitingReserve(ReceiptWithState<AwaitingReserve>),
+    /// The receipt failed a check and was not stored
+    Failed(ReceiptWithState<Failed>),
+}
+
 #[derive(Debug, Clone)]
 /// Wrapper class for metadata and state of a received receipt
 pub struct ReceiptWithState<S>
@@ -54,6 +120,9 @@ where
     pub(crate) signed_receipt: EIP712SignedMessage<Receipt>,
     /// The current state of the receipt (e.g., received, checking, failed, accepted, etc.)
     pub(crate) _state: S,
+    /// EIP712 signing hash computed on first use, along with the domain separator and message it was
+    /// computed for, so that checks recovering the signer don't recompute it
+    signing_hash: OnceLock<(Eip712Domain, Receipt, B256)>,
 }
 
 impl ReceiptWithState<AwaitingReserve> {
@@ -75,11 +144,40 @@ impl ReceiptWithState<AwaitingReserve> {
     }
 }
 
+impl ReceiptWithState<Reserved> {
+    /// Wraps a receipt whose escrow is known to be reserved, e.g. a valid receipt of a
+    /// [`crate::rav::RAVRequest`].
+    pub(crate) fn reserved(signed_receipt: SignedReceipt) -> Self {
+        ReceiptWithState {
+            signed_receipt,
+            _state: Reserved,
+            signing_hash: OnceLock::new(),
+        }
+    }
+
+    /// Releases the escrow reserved for the receipt and moves it back to `Checking`, such that it can
+    /// be checked and reserved again, e.g. after the sender's escrow was reduced on-chain.
+    pub async fn into_checking<E>(
+        self,
+        auditor: &E,
+        domain_separator: &Eip712Domain,
+    ) -> ResultReceipt<Checking>
+    where
+        E: EscrowHandler,
+    {
+        match auditor.release_escrow(&self, domain_separator).await {
+            Ok(_) => Ok(self.perform_state_changes(Checking)),
+            Err(e) => Err(self.perform_state_error(e)),
+        }
+    }
+}
+
 impl ReceiptWithState<Checking> {
     pub fn new(signed_receipt: SignedReceipt) -> ReceiptWithState<Checking> {
         ReceiptWithState {
             signed_receipt,
             _state: Checking,
+            signing_hash: OnceLock::new(),
         }
     }
 
@@ -104,6 +202,48 @@ impl ReceiptWithState<Checking> {
         Ok(())
     }
 
+    /// Same as [`ReceiptWithState::perform_checks`], but runs every check instead of stopping at
+    /// the first failure, to find out all the reasons a receipt is rejected.
+    ///
+    /// Returns each failed check with its error, in the order of `checks`. Errors that are not a
+    /// [`ReceiptError`] are reported as [`ReceiptError::CheckFailedToComplete`].
+    pub async fn perform_checks_collect(
+        &self,
+        checks: &[ReceiptCheck],
+    ) -> Vec<(ReceiptCheck, ReceiptError)> {
+        let mut failures = Vec::new();
+        for check in checks {
+            if let Err(e) = check.check(self).await {
+                let error = e
+                    .downcast::<ReceiptError>()
+                    .unwrap_or_else(|e| ReceiptError::CheckFailedToComplete(e.to_string()));
+                failures.push((check.clone(), error));
+            }
+        }
+        failures
+    }
+
+    /// Same as [`ReceiptWithState::perform_checks`], but also measures how long each check took, to
+    /// find the slow ones. Returns the durations keyed by [`crate::receipt::checks::Check::name`],
+    /// summed for checks sharing a name. [`ReceiptWithState::perform_checks`] itself is not timed.
+    pub async fn perform_checks_timed(
+        &mut self,
+        checks: &[ReceiptCheck],
+    ) -> ReceiptResult<HashMap<&'static str, Duration>> {
+        let mut timings = HashMap::new();
+        for check in checks {
+            let start = Instant::now();
+            let result = check.check(self).await;
+            let elapsed = start.elapsed();
+            log::trace!("Receipt check {} took {:?}", check.name(), elapsed);
+            *timings.entry(check.name()).or_insert(Duration::ZERO) += elapsed;
+
+            // return early on an error
+            result.map_err(|e| ReceiptError::CheckFailedToComplete(e.to_string()))?;
+        }
+        Ok(timings)
+    }
+
     /// Completes all remaining checks and stores the results
     ///
     /// Returns `Err` only if unable to complete a check, returns `Ok` if no check failed to complete (*Important:* this is not the result of the check, just the result of _completing_ the check)
@@ -123,14 +263,22 @@ impl ReceiptWithState<Checking> {
     }
 }
 
+impl ReceiptWithState<Failed> {
+    /// Returns the reason the receipt failed
+    pub fn error(&self) -> &ReceiptError {
+        &self._state.error
+    }
+}
+
 impl<S> ReceiptWithState<S>
 where
     S: ReceiptState,
 {
-    pub(super) fn perform_state_error(self, error: ReceiptError) -> ReceiptWithState<Failed> {
+    pub(crate) fn perform_state_error(self, error: ReceiptError) -> ReceiptWithState<Failed> {
         ReceiptWithState {
             signed_receipt: self.signed_receipt,
             _state: Failed { error },
+            signing_hash: self.signing_hash,
         }
     }
 
@@ -141,10 +289,145 @@ where
         ReceiptWithState {
             signed_receipt: self.signed_receipt,
             _state: new_state,
+            signing_hash: self.signing_hash,
         }
     }
 
     pub fn signed_receipt(&self) -> &EIP712SignedMessage<Receipt> {
         &self.signed_receipt
     }
+
+    /// Returns the current state of the receipt along with its main fields, ready to be serialized.
+    pub fn state_summary(&self) -> StateSummary {
+        let receipt = &self.signed_receipt.message;
+        StateSummary {
+            state: self._state.name(),
+            allocation_id: receipt.allocation_id,
+            value: receipt.value,
+            timestamp_ns: receipt.timestamp_ns,
+            failure: self._state.failure().cloned(),
+        }
+    }
+
+    /// Returns the EIP712 signing hash of the receipt, computed once and then reused.
+    ///
+    /// The cached hash is only reused for the same domain separator and an unchanged message.
+    pub fn signing_hash(&self, domain_separator: &Eip712Domain) -> B256 {
+        if let Some((domain, message, hash)) = self.signing_hash.get() {
+            if domain == domain_separator && *message == self.signed_receipt.message {
+                return *hash;
+            }
+        }
+
+        let hash = cached_eip712_signing_hash(&self.signed_receipt.message, domain_separator);
+        // Only the first computed hash is cached
+        let _ = self.signing_hash.set((
+            domain_separator.clone(),
+            self.signed_receipt.message.clone(),
+            hash,
+        ));
+        hash
+    }
+
+    /// Recovers and returns the signer of the receipt, using the cached signing hash.
+    pub fn recover_signer(&self, domain_separator: &Eip712Domain) -> crate::Result<Address> {
+        self.signed_receipt
+            .recover_signer_from_hash(self.signing_hash(domain_separator))
+    }
+
+    /// Verifies the receipt value against `appraisal`, without going through a [`ReceiptCheck`].
+    ///
+    /// Useful for one-off verification, e.g. when the appraisal comes from a pricing oracle
+    /// rather than from a stored map of query appraisals.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ReceiptError::InvalidValue`] if the receipt value does not match the appraisal.
+    pub fn check_value_against(&self, appraisal: u128) -> ReceiptResult<()> {
+        let value = self.signed_receipt.message.value;
+        if value != appraisal {
+            return Err(ReceiptError::InvalidValue {
+                received_value: value,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    test,
+    feature = "in_memory",
+    feature = "ethers",
+    feature = "ethers-compat"
+))]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, RwLock},
+    };
+
+    use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
+
+    use super::*;
+    use crate::{
+        ethers_compat::convert_address, manager::context::memory::checks::get_full_list_of_checks,
+        signed_message::Eip712Message, tap_eip712_domain,
+    };
+
+    #[tokio::test]
+    async fn signing_hash_computed_once_across_checks() {
+        let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()
+            .unwrap();
+        let address = convert_address(wallet.address());
+        let allocation_id = Address::from([0xabu8; 20]);
+        let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+
+        let checks = get_full_list_of_checks(
+            domain_separator.clone(),
+            HashSet::from([address]),
+            Arc::new(RwLock::new(HashSet::from([allocation_id]))),
+            Arc::new(RwLock::new(Default::default())),
+        );
+        let mut received_receipt = ReceiptWithState::new(
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_id, 42).unwrap(),
+                &wallet,
+            )
+            .unwrap(),
+        );
+
+        assert!(received_receipt.signing_hash.get().is_none());
+        received_receipt.perform_checks(&checks).await.unwrap();
+        assert_eq!(
+            received_receipt.recover_signer(&domain_separator).unwrap(),
+            address
+        );
+
+        // The hash computed by the checks is the one cached
+        let expected_hash = received_receipt
+            .signed_receipt
+            .message
+            .eip712_signing_hash(&domain_separator);
+        assert_eq!(
+            received_receipt.signing_hash.get(),
+            Some(&(
+                domain_separator.clone(),
+                received_receipt.signed_receipt.message.clone(),
+                expected_hash
+            ))
+        );
+
+        // The cached hash isn't reused once the message changed
+        received_receipt.signed_receipt.message.value += 1;
+        assert_eq!(
+            received_receipt.signing_hash(&domain_separator),
+            received_receipt
+                .signed_receipt
+                .message
+                .eip712_signing_hash(&domain_separator)
+        );
+    }
 }
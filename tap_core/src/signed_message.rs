@@ -4,64 +4,736 @@
 //! Module containing EIP712 message and signature
 //!
 
-use alloy_primitives::Address;
+use std::{borrow::Cow, cell::RefCell, collections::VecDeque};
+
+use alloy_primitives::{hex, keccak256, Address, B256, U256};
 use alloy_sol_types::{Eip712Domain, SolStruct};
-use ethers::{signers::LocalWallet, types::Signature};
+#[cfg(feature = "ethers")]
+use ethers::{
+    signers::{LocalWallet, Signer},
+    types::{
+        transaction::eip712::{EIP712Domain, Eip712, Eip712Error},
+        H160,
+    },
+};
+use k256::ecdsa::{RecoveryId, Signature as EcdsaSignature, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
-use crate::Result;
+/// Secp256k1 private key, to sign messages with [`EIP712SignedMessage::new_with_key`] without an
+/// ethers wallet.
+#[cfg(feature = "alloy-signer")]
+pub use k256::ecdsa::SigningKey;
+
+use crate::{Error, Result};
+
+/// Version of the binary encoding of [`EIP712SignedMessage`], see [`EIP712SignedMessage::to_bytes`].
+///
+/// This is the version of the wire format only, unrelated to the version of the EIP712 domain.
+pub const SIGNED_MESSAGE_VERSION: u8 = 1;
+
+/// Message that can be signed into an [`EIP712SignedMessage`].
+///
+/// Implemented for every [`SolStruct`], and by hand for messages whose EIP712 type depends on which
+/// of their optional fields are set, such as [`crate::receipt::Receipt`].
+pub trait Eip712Message {
+    /// Returns the EIP712 `encodeType` of the message.
+    fn encode_type(&self) -> Cow<'static, str>;
+
+    /// Returns the EIP712 `encodeData` of the message.
+    fn encode_data(&self) -> Vec<u8>;
+
+    /// Returns the EIP712 `hashStruct` of the message.
+    fn hash_struct(&self) -> B256 {
+        let mut data = keccak256(self.encode_type().as_bytes()).to_vec();
+        data.extend(self.encode_data());
+        keccak256(data)
+    }
+
+    /// Returns the EIP712 signing hash of the message under `domain_separator`.
+    fn eip712_signing_hash(&self, domain_separator: &Eip712Domain) -> B256 {
+        signing_hash_with_domain_hash(domain_separator.hash_struct(), self)
+    }
+}
+
+impl<M: SolStruct> Eip712Message for M {
+    fn encode_type(&self) -> Cow<'static, str> {
+        M::eip712_encode_type()
+    }
+
+    fn encode_data(&self) -> Vec<u8> {
+        self.eip712_encode_data()
+    }
+
+    fn hash_struct(&self) -> B256 {
+        self.eip712_hash_struct()
+    }
+
+    fn eip712_signing_hash(&self, domain_separator: &Eip712Domain) -> B256 {
+        SolStruct::eip712_signing_hash(self, domain_separator)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct EIP712SignedMessage<M: SolStruct> {
+pub struct EIP712SignedMessage<M: Eip712Message> {
     /// Message to be signed
     pub message: M,
     /// ECDSA Signature of eip712 hash of message
     pub signature: Signature,
+    /// Curve of the signature, omitted from the serialized message when secp256k1
+    #[serde(default, skip_serializing_if = "SignatureScheme::is_secp256k1")]
+    pub scheme: SignatureScheme,
+}
+
+/// Signature scheme of an [`EIP712SignedMessage`].
+///
+/// Senders sign with secp256k1 (Ethereum) keys. Other schemes, such as the P-256 (secp256r1) keys
+/// of account abstraction wallets, are enabled by their feature flag. Messages tagged with an
+/// unknown scheme fail to deserialize, rather than being verified as secp256k1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureScheme {
+    #[default]
+    Secp256k1,
+    /// Placeholder for P-256 signatures, whose verification is not implemented yet.
+    #[cfg(feature = "p256")]
+    Secp256r1,
+}
+
+impl SignatureScheme {
+    fn is_secp256k1(&self) -> bool {
+        *self == SignatureScheme::Secp256k1
+    }
+
+    /// Returns the hash signed under this scheme, given the EIP712 hash of the message.
+    ///
+    /// Messages are signed, recovered and verified over this hash only. Secp256k1 signs the EIP712
+    /// hash as is, as expected by the escrow contract and by EIP712 wallets. The other schemes fold
+    /// their tag into it, so that a signature can't be verified under another scheme than the one
+    /// it was made with.
+    pub fn signing_hash(&self, eip712_hash: B256) -> B256 {
+        match self {
+            SignatureScheme::Secp256k1 => eip712_hash,
+            #[cfg(feature = "p256")]
+            SignatureScheme::Secp256r1 => {
+                keccak256([eip712_hash.as_slice(), b"secp256r1"].concat())
+            }
+        }
+    }
+}
+
+/// Secp256k1 ECDSA signature of a message, serialized the same way as an ethers signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Signature {
+    pub r: U256,
+    pub s: U256,
+    /// Recovery id, either `0`/`1`, `27`/`28` or EIP155 encoded
+    pub v: u64,
+}
+
+/// Error recovering or verifying the signer of a [`Signature`].
+#[derive(thiserror::Error, Debug)]
+pub enum SignatureError {
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(#[from] k256::ecdsa::Error),
+    #[error("Signature verification failed. Expected {expected}, got {recovered}")]
+    VerificationError {
+        expected: Address,
+        recovered: Address,
+    },
+}
+
+impl Signature {
+    /// Recovers the address of the key that signed `message_hash`.
+    ///
+    /// High-s signatures, which k256 rejects, are normalized to their low-s counterpart first, the
+    /// same way as ethers does, such that both recover the same signer.
+    pub fn recover(
+        &self,
+        message_hash: impl Into<B256>,
+    ) -> std::result::Result<Address, SignatureError> {
+        let mut recovery_id = self
+            .recovery_id()
+            .and_then(RecoveryId::from_byte)
+            .ok_or_else(k256::ecdsa::Error::new)?;
+        let mut signature = EcdsaSignature::from_slice(&self.as_bytes()[..64])?;
+        if let Some(normalized) = signature.normalize_s() {
+            signature = normalized;
+            recovery_id = RecoveryId::from_byte(recovery_id.to_byte() ^ 1)
+                .ok_or_else(k256::ecdsa::Error::new)?;
+        }
+        let verifying_key = VerifyingKey::recover_from_prehash(
+            message_hash.into().as_slice(),
+            &signature,
+            recovery_id,
+        )?;
+        Ok(verifying_key_address(&verifying_key))
+    }
+
+    /// Checks that `message_hash` was signed by `expected`.
+    pub fn verify(
+        &self,
+        message_hash: impl Into<B256>,
+        expected: Address,
+    ) -> std::result::Result<(), SignatureError> {
+        let recovered = self.recover(message_hash)?;
+        if recovered != expected {
+            return Err(SignatureError::VerificationError {
+                expected,
+                recovered,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the 65 bytes `r || s || v` of the signature, as expected by Solidity's `ecrecover`,
+    /// with `v` normalized to 27 or 28 (from 0 or 1, or an EIP-155 value).
+    pub fn as_bytes(&self) -> [u8; 65] {
+        let mut bytes = [0u8; 65];
+        bytes[..32].copy_from_slice(&self.r.to_be_bytes::<32>());
+        bytes[32..64].copy_from_slice(&self.s.to_be_bytes::<32>());
+        bytes[64] = match self.recovery_id() {
+            Some(recovery_id) => recovery_id + 27,
+            // Invalid recovery ids are below 35, and kept as is
+            None => self.v as u8,
+        };
+        bytes
+    }
+
+    /// Returns the recovery id, 0 or 1, encoded in `v`, or `None` if `v` is not a valid encoding.
+    fn recovery_id(&self) -> Option<u8> {
+        match self.v {
+            0 | 27 => Some(0),
+            1 | 28 => Some(1),
+            v if v >= 35 => Some(((v - 1) % 2) as u8),
+            _ => None,
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(self.as_bytes()))
+    }
+}
+
+#[cfg(feature = "ethers")]
+impl From<ethers::types::Signature> for Signature {
+    fn from(signature: ethers::types::Signature) -> Self {
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        signature.r.to_big_endian(&mut r);
+        signature.s.to_big_endian(&mut s);
+        Self {
+            r: U256::from_be_bytes(r),
+            s: U256::from_be_bytes(s),
+            v: signature.v,
+        }
+    }
+}
+
+#[cfg(feature = "ethers")]
+impl From<Signature> for ethers::types::Signature {
+    fn from(signature: Signature) -> Self {
+        Self {
+            r: ethers::types::U256::from_big_endian(&signature.r.to_be_bytes::<32>()),
+            s: ethers::types::U256::from_big_endian(&signature.s.to_be_bytes::<32>()),
+            v: signature.v,
+        }
+    }
+}
+
+/// Number of domain separators whose hash is cached on each thread, such that a receiver verifying
+/// receipts of a few chains alternately still hits the cache.
+const DOMAIN_HASH_CACHE_CAPACITY: usize = 4;
+
+thread_local! {
+    /// Domain separators last hashed on this thread along with their hash, most recent first.
+    static DOMAIN_HASHES: RefCell<VecDeque<(Eip712Domain, B256)>> =
+        const { RefCell::new(VecDeque::new()) };
 }
 
-#[derive(Debug, Eq, PartialEq, Hash)]
+/// Returns the EIP712 signing hash of `message` under `domain_separator`, same as
+/// [`Eip712Message::eip712_signing_hash`].
+///
+/// The hashes of the last domain separators used are cached on each thread, such that signing or
+/// verifying many messages under a few domains hashes each domain only once.
+pub fn cached_eip712_signing_hash<M: Eip712Message + ?Sized>(
+    message: &M,
+    domain_separator: &Eip712Domain,
+) -> B256 {
+    let domain_hash = DOMAIN_HASHES.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let entry = match cache
+            .iter()
+            .position(|(domain, _)| domain == domain_separator)
+        {
+            Some(index) => cache.remove(index).unwrap(),
+            None => (domain_separator.clone(), domain_separator.hash_struct()),
+        };
+        let hash = entry.1;
+        cache.push_front(entry);
+        cache.truncate(DOMAIN_HASH_CACHE_CAPACITY);
+        hash
+    });
+    signing_hash_with_domain_hash(domain_hash, message)
+}
+
+/// EIP712 signing hash of `message`, given the hash of the domain separator.
+fn signing_hash_with_domain_hash<M: Eip712Message + ?Sized>(
+    domain_hash: B256,
+    message: &M,
+) -> B256 {
+    let mut digest_input = [0u8; 66];
+    digest_input[0..2].copy_from_slice(&[0x19, 0x01]);
+    digest_input[2..34].copy_from_slice(domain_hash.as_slice());
+    digest_input[34..66].copy_from_slice(message.hash_struct().as_slice());
+    keccak256(digest_input)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub struct MessageId(pub [u8; 32]);
 
-impl<M: SolStruct> EIP712SignedMessage<M> {
+/// Hash function used to compute a [`MessageId`] with [`EIP712SignedMessage::unique_hash_with`].
+///
+/// Allows downstream storage to key messages with its own content-addressing scheme (e.g. sha256).
+pub trait Hasher {
+    fn hash(&self, data: &[u8]) -> [u8; 32];
+}
+
+/// Default [`Hasher`], producing the EIP712 struct hash of the message.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Keccak256Hasher;
+
+impl Hasher for Keccak256Hasher {
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        keccak256(data).into()
+    }
+}
+
+/// Returns the address of `signing_key`, i.e. the signer recovered from the messages it signs.
+#[cfg(feature = "alloy-signer")]
+pub fn signing_key_address(signing_key: &SigningKey) -> Address {
+    verifying_key_address(signing_key.verifying_key())
+}
+
+fn verifying_key_address(verifying_key: &VerifyingKey) -> Address {
+    let public_key = verifying_key.to_encoded_point(false);
+    Address::from_slice(&keccak256(&public_key.as_bytes()[1..])[12..])
+}
+
+impl<M: Eip712Message> EIP712SignedMessage<M> {
     /// creates signed message with signed EIP712 hash of `message` using `signing_wallet`
+    #[cfg(feature = "ethers")]
     pub fn new(
         domain_separator: &Eip712Domain,
         message: M,
         signing_wallet: &LocalWallet,
     ) -> Result<Self> {
-        let recovery_message_hash: [u8; 32] = message.eip712_signing_hash(domain_separator).into();
+        let recovery_message_hash: [u8; 32] =
+            cached_eip712_signing_hash(&message, domain_separator).into();
+
+        let signature = signing_wallet
+            .sign_hash(recovery_message_hash.into())?
+            .into();
+
+        Ok(Self {
+            message,
+            signature,
+            scheme: SignatureScheme::Secp256k1,
+        })
+    }
+
+    /// Same as [`EIP712SignedMessage::new`] for each of `messages`, e.g. for a sender issuing many
+    /// receipts at once. The domain separator is hashed once for the whole batch, and the messages
+    /// are signed on as many threads as available. The signatures are the same as with
+    /// [`EIP712SignedMessage::new`], and are returned in the order of `messages`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::WalletError`] if signing any of the messages fails
+    ///
+    #[cfg(feature = "ethers")]
+    pub fn sign_batch(
+        domain_separator: &Eip712Domain,
+        messages: &[M],
+        signing_wallet: &LocalWallet,
+    ) -> Result<Vec<Self>>
+    where
+        M: Clone + Send + Sync,
+    {
+        let domain_hash = domain_separator.hash_struct();
+        let sign = |message: &M| -> Result<Self> {
+            let recovery_message_hash: [u8; 32] =
+                signing_hash_with_domain_hash(domain_hash, message).into();
+
+            Ok(Self {
+                message: message.clone(),
+                signature: signing_wallet
+                    .sign_hash(recovery_message_hash.into())?
+                    .into(),
+                scheme: SignatureScheme::Secp256k1,
+            })
+        };
+
+        let threads = std::thread::available_parallelism().map_or(1, usize::from);
+        let chunk_size = messages.len().div_ceil(threads).max(1);
+        std::thread::scope(|scope| {
+            let handles = messages
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || chunk.iter().map(sign).collect::<Result<Vec<_>>>())
+                })
+                .collect::<Vec<_>>();
+            let mut signed_messages = Vec::with_capacity(messages.len());
+            for handle in handles {
+                let signed_chunk = handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+                signed_messages.extend(signed_chunk);
+            }
+            Ok(signed_messages)
+        })
+    }
 
-        let signature = signing_wallet.sign_hash(recovery_message_hash.into())?;
+    /// Same as [`EIP712SignedMessage::new`], using any ethers [`Signer`], such as a Ledger hardware wallet.
+    ///
+    /// The signer is asked to sign the EIP712 typed data of `message`, which yields the same signature
+    /// as [`EIP712SignedMessage::new`] for the same key.
+    ///
+    /// Every message needs its own signature, the protocol does not allow signing several receipts at
+    /// once. With a hardware wallet, this means a device roundtrip (and possibly a user confirmation)
+    /// per receipt, which can take from tens of milliseconds to seconds. Such signers are better suited
+    /// to signing few receipts of higher value, or RAVs, than to signing a receipt per query.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::SignerError`] if the signer fails to sign the message
+    ///
+    #[cfg(feature = "ethers")]
+    pub async fn new_with_signer<S: Signer>(
+        domain_separator: &Eip712Domain,
+        message: M,
+        signer: &S,
+    ) -> Result<Self>
+    where
+        M: Send + Sync,
+    {
+        let signature = signer
+            .sign_typed_data(&TypedMessage {
+                domain_separator,
+                message: &message,
+            })
+            .await
+            .map_err(|e| Error::SignerError {
+                source_error_message: e.to_string(),
+            })?;
 
-        Ok(Self { message, signature })
+        Ok(Self {
+            message,
+            signature: signature.into(),
+            scheme: SignatureScheme::Secp256k1,
+        })
+    }
+
+    /// Same as [`EIP712SignedMessage::new`], signing with `signing_key` directly rather than with an
+    /// ethers wallet, such that tap_core doesn't need ethers to sign messages. The signatures are the
+    /// same as with [`EIP712SignedMessage::new`] for the same key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::SignerError`] if signing the message fails
+    ///
+    #[cfg(feature = "alloy-signer")]
+    pub fn new_with_key(
+        domain_separator: &Eip712Domain,
+        message: M,
+        signing_key: &SigningKey,
+    ) -> Result<Self> {
+        let recovery_message_hash = cached_eip712_signing_hash(&message, domain_separator);
+        let (signature, recovery_id) = signing_key
+            .sign_prehash_recoverable(recovery_message_hash.as_slice())
+            .map_err(|e| Error::SignerError {
+                source_error_message: e.to_string(),
+            })?;
+        let signature_bytes = signature.to_bytes();
+
+        Ok(Self {
+            message,
+            signature: Signature {
+                r: U256::from_be_slice(&signature_bytes[..32]),
+                s: U256::from_be_slice(&signature_bytes[32..]),
+                v: u64::from(recovery_id.to_byte()) + 27,
+            },
+            scheme: SignatureScheme::Secp256k1,
+        })
     }
 
     /// Recovers and returns the signer of the message from the signature.
+    ///
+    /// Only the ECDSA recovery over the EIP712 digest is done, none of the receipt checks (allocation
+    /// id, value, escrow...) are run, e.g. to route a receipt by sender before checking it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::SignatureError`] if the signer can't be recovered from the signature
+    ///
+    /// Returns [`Error::UnsupportedSignatureScheme`] if the signer can't be recovered with the
+    /// message's signature scheme
+    ///
     pub fn recover_signer(&self, domain_separator: &Eip712Domain) -> Result<Address> {
-        let recovery_message_hash: [u8; 32] =
-            self.message.eip712_signing_hash(domain_separator).into();
-        let recovered_address: [u8; 20] = self.signature.recover(recovery_message_hash)?.into();
-        Ok(recovered_address.into())
+        self.recover_signer_from_hash(cached_eip712_signing_hash(&self.message, domain_separator))
+    }
+
+    /// Same as [`EIP712SignedMessage::recover_signer`], with the EIP712 hash of the message already
+    /// computed.
+    pub(crate) fn recover_signer_from_hash(&self, eip712_hash: B256) -> Result<Address> {
+        let signing_hash = self.scheme.signing_hash(eip712_hash);
+        match self.scheme {
+            SignatureScheme::Secp256k1 => Ok(self.signature.recover(signing_hash)?),
+            // P-256 public keys can't be recovered from a signature
+            #[cfg(feature = "p256")]
+            SignatureScheme::Secp256r1 => Err(Error::UnsupportedSignatureScheme {
+                scheme: self.scheme,
+            }),
+        }
     }
 
     /// Checks that receipts signature is valid for given verifying key, returns `Ok` if it is valid.
+    /// The signature is verified according to the message's signature scheme.
     ///
     /// # Errors
     ///
     /// Returns [`Error::InvalidSignature`] if the signature is not valid with provided `verifying_key`
     ///
+    /// Returns [`Error::UnsupportedSignatureScheme`] if the message's signature scheme can't be
+    /// verified yet
+    ///
     pub fn verify(&self, domain_separator: &Eip712Domain, expected_address: Address) -> Result<()> {
-        let recovery_message_hash: [u8; 32] =
-            self.message.eip712_signing_hash(domain_separator).into();
-        let expected_address: [u8; 20] = expected_address.into();
+        let signing_hash = self
+            .scheme
+            .signing_hash(cached_eip712_signing_hash(&self.message, domain_separator));
 
-        self.signature
-            .verify(recovery_message_hash, expected_address)?;
-        Ok(())
+        match self.scheme {
+            SignatureScheme::Secp256k1 => {
+                self.signature.verify(signing_hash, expected_address)?;
+                Ok(())
+            }
+            #[cfg(feature = "p256")]
+            SignatureScheme::Secp256r1 => {
+                // TODO: verify against the P-256 key of the `expected_address` account
+                let _ = (signing_hash, expected_address);
+                Err(Error::UnsupportedSignatureScheme {
+                    scheme: self.scheme,
+                })
+            }
+        }
+    }
+
+    /// Encodes the signed message to bytes: the [`SIGNED_MESSAGE_VERSION`] byte, followed by the
+    /// JSON serialization of the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::EIP712EncodeError`] if the message can't be serialized
+    ///
+    pub fn to_bytes(&self) -> Result<Vec<u8>>
+    where
+        M: Serialize,
+    {
+        let mut bytes = vec![SIGNED_MESSAGE_VERSION];
+        serde_json::to_writer(&mut bytes, self).map_err(|e| Error::EIP712EncodeError {
+            source_error_message: e.to_string(),
+        })?;
+        Ok(bytes)
+    }
+
+    /// Decodes a signed message encoded with [`EIP712SignedMessage::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::UnsupportedVersion`] if the bytes were encoded with another version than
+    /// [`SIGNED_MESSAGE_VERSION`], e.g. by a newer release, rather than attempting to parse them
+    ///
+    /// Returns [`Error::SignedMessageDecodeError`] if the bytes are not a valid signed message
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self>
+    where
+        M: for<'de> Deserialize<'de>,
+    {
+        let (&version, payload) =
+            bytes
+                .split_first()
+                .ok_or_else(|| Error::SignedMessageDecodeError {
+                    source_error_message: "Empty input".to_string(),
+                })?;
+        if version != SIGNED_MESSAGE_VERSION {
+            return Err(Error::UnsupportedVersion {
+                version,
+                supported: SIGNED_MESSAGE_VERSION,
+            });
+        }
+        serde_json::from_slice(payload).map_err(|e| Error::SignedMessageDecodeError {
+            source_error_message: e.to_string(),
+        })
     }
 
     /// Use this a simple key for testing
     pub fn unique_hash(&self) -> MessageId {
-        MessageId(self.message.eip712_hash_struct().into())
+        self.unique_hash_with(&Keccak256Hasher)
+    }
+
+    /// Same as [`EIP712SignedMessage::unique_hash`], using `hasher` over the EIP712 type hash and
+    /// encoded data of the message.
+    pub fn unique_hash_with<H: Hasher>(&self, hasher: &H) -> MessageId {
+        let mut data = keccak256(self.message.encode_type().as_bytes()).to_vec();
+        data.extend(self.message.encode_data());
+        MessageId(hasher.hash(&data))
+    }
+}
+
+/// Ethers [`Eip712`] view of a message, such that it can be signed by any ethers [`Signer`].
+#[cfg(feature = "ethers")]
+struct TypedMessage<'a, M> {
+    domain_separator: &'a Eip712Domain,
+    message: &'a M,
+}
+
+#[cfg(feature = "ethers")]
+impl<M: Eip712Message> Eip712 for TypedMessage<'_, M> {
+    type Error = Eip712Error;
+
+    fn domain_separator(&self) -> std::result::Result<[u8; 32], Self::Error> {
+        Ok(self.domain_separator.separator().into())
+    }
+
+    fn domain(&self) -> std::result::Result<EIP712Domain, Self::Error> {
+        Ok(EIP712Domain {
+            name: self
+                .domain_separator
+                .name
+                .as_ref()
+                .map(|name| name.to_string()),
+            version: self
+                .domain_separator
+                .version
+                .as_ref()
+                .map(|version| version.to_string()),
+            chain_id: self.domain_separator.chain_id.map(|chain_id| {
+                ethers::types::U256::from_big_endian(&chain_id.to_be_bytes::<32>())
+            }),
+            verifying_contract: self
+                .domain_separator
+                .verifying_contract
+                .map(|address| H160::from(<[u8; 20]>::from(address))),
+            salt: self.domain_separator.salt.map(|salt| salt.into()),
+        })
+    }
+
+    fn type_hash() -> std::result::Result<[u8; 32], Self::Error> {
+        // The type of a message may depend on its fields, signers only need its struct hash
+        Err(Eip712Error::Message(
+            "The EIP712 type hash depends on the message".to_string(),
+        ))
+    }
+
+    fn struct_hash(&self) -> std::result::Result<[u8; 32], Self::Error> {
+        Ok(self.message.hash_struct().into())
+    }
+
+    fn encode_eip712(&self) -> std::result::Result<[u8; 32], Self::Error> {
+        Ok(self
+            .message
+            .eip712_signing_hash(self.domain_separator)
+            .into())
+    }
+}
+
+#[cfg(all(test, feature = "ethers"))]
+mod tests {
+    use alloy_primitives::{hex, Address, U256};
+    use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
+
+    use super::{
+        cached_eip712_signing_hash, EIP712SignedMessage, Eip712Message, Signature,
+        DOMAIN_HASH_CACHE_CAPACITY,
+    };
+    use crate::{receipt::Receipt, tap_eip712_domain};
+
+    /// Order of the secp256k1 curve.
+    const SECP256K1_N: U256 = U256::from_be_bytes(hex!(
+        "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141"
+    ));
+
+    #[test]
+    fn high_s_signature_recovers_signer() {
+        let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()
+            .unwrap();
+        let address = Address::from(<[u8; 20]>::from(wallet.address()));
+        let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+        let receipt = Receipt::new(Address::from([0xabu8; 20]), 42).unwrap();
+        let signed_receipt = EIP712SignedMessage::new(&domain_separator, receipt, &wallet).unwrap();
+        let hash = signed_receipt
+            .message
+            .eip712_signing_hash(&domain_separator);
+
+        // The malleable counterpart of the signature, with `s` flipped to the upper half of the
+        // curve order and the recovery id flipped along
+        let signature = signed_receipt.signature;
+        let high_s = Signature {
+            r: signature.r,
+            s: SECP256K1_N - signature.s,
+            v: if signature.v == 27 { 28 } else { 27 },
+        };
+        assert!(high_s.s > SECP256K1_N >> 1);
+        assert_eq!(high_s.recover(hash).unwrap(), address);
+        assert_eq!(signature.recover(hash).unwrap(), address);
+    }
+
+    #[test]
+    fn signature_bytes_normalize_v() {
+        let signature = |v| Signature {
+            r: U256::from(1),
+            s: U256::from(2),
+            v,
+        };
+        for (v, expected) in [(0, 27), (1, 28), (27, 27), (28, 28), (37, 27), (38, 28)] {
+            assert_eq!(signature(v).as_bytes()[64], expected);
+        }
+        // EIP-155 values don't fit in a byte, they are not truncated
+        assert_eq!(signature(2 * 1_000_000 + 35).as_bytes()[64], 27);
+        assert_eq!(signature(2 * 1_000_000 + 36).as_bytes()[64], 28);
+    }
+
+    #[test]
+    fn cached_signing_hash_matches_uncached() {
+        let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()
+            .unwrap();
+        let address = Address::from(<[u8; 20]>::from(wallet.address()));
+        let receipt = Receipt::new(Address::from([0xabu8; 20]), 42).unwrap();
+
+        // More domains than the cache holds, used alternately, such that they evict each other
+        let domain_separators = (1..=DOMAIN_HASH_CACHE_CAPACITY as u64 + 1)
+            .map(|chain_id| tap_eip712_domain(chain_id, Address::from([0x11u8; 20])))
+            .collect::<Vec<_>>();
+        for _ in 0..3 {
+            for domain_separator in &domain_separators {
+                assert_eq!(
+                    cached_eip712_signing_hash(&receipt, domain_separator),
+                    receipt.eip712_signing_hash(domain_separator)
+                );
+
+                let signed_receipt =
+                    EIP712SignedMessage::new(domain_separator, receipt.clone(), &wallet).unwrap();
+                signed_receipt.verify(domain_separator, address).unwrap();
+            }
+        }
     }
 }
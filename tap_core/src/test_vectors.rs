@@ -0,0 +1,161 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Module containing fixed RAV signing test vectors
+//!
+//! Other implementations of TAP can check their EIP712 encoding and signing against these vectors:
+//! the receipts of [`receipts`] are aggregated into [`INITIAL_RAV`], without a previous RAV, then
+//! the receipts of [`continuation_receipts`] are aggregated on top of it into
+//! [`CONTINUATION_RAV`]. The RAVs are signed under [`domain_separator`] by the first account of
+//! [`MNEMONIC`] (derivation path `m/44'/60'/0'/0/0`).
+//!
+//! Any change to these values is a change of the wire format.
+
+use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
+
+use crate::{
+    rav::ReceiptAggregateVoucher, receipt::Receipt, tap_eip712_domain, timestamp::TimestampNs,
+};
+
+/// Mnemonic of the signer.
+pub const MNEMONIC: &str =
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+/// Address of the first account of [`MNEMONIC`].
+pub const SIGNER_ADDRESS: Address = Address::new([
+    0x98, 0x58, 0xef, 0xfd, 0x23, 0x2b, 0x40, 0x33, 0xe4, 0x7d, 0x90, 0x00, 0x3d, 0x41, 0xec, 0x34,
+    0xec, 0xae, 0xda, 0x94,
+]);
+
+pub const CHAIN_ID: u64 = 1;
+
+pub const VERIFYING_CONTRACT: Address = Address::new([0x11; 20]);
+
+pub const ALLOCATION_ID: Address = Address::new([0xab; 20]);
+
+/// Timestamp of the first receipt, the others following 100ns apart.
+const FIRST_TIMESTAMP_NS: u64 = 1_700_000_000_000_000_000;
+
+/// Expected RAV along with its EIP712 digest and signature, both hex encoded without `0x` prefix.
+/// The signature is encoded as `r || s || v`, with `v` either 27 or 28.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RavVector {
+    pub timestamp_ns: u64,
+    pub value_aggregate: u128,
+    pub digest: &'static str,
+    pub signature: &'static str,
+}
+
+impl RavVector {
+    pub fn rav(&self) -> ReceiptAggregateVoucher {
+        ReceiptAggregateVoucher {
+            allocationId: ALLOCATION_ID,
+            timestampNs: self.timestamp_ns,
+            valueAggregate: self.value_aggregate,
+        }
+    }
+}
+
+/// RAV of [`receipts`], without previous RAV.
+pub const INITIAL_RAV: RavVector = RavVector {
+    timestamp_ns: FIRST_TIMESTAMP_NS + 100,
+    value_aggregate: 30,
+    digest: "9cd2d5dde78c5478de649f90085b999e594ac2e68282663ac8081d1a5af2c33e",
+    signature: "0c6c36a1511417e57cbb7c5e39363cfd32cfe9a8d6e9a19e1b2634c9401c11dd\
+                4d01ec72973c5fbf8cc17b789631ccf14b39c992717d8914fd48773ba288d8051c",
+};
+
+/// RAV of [`continuation_receipts`], with [`INITIAL_RAV`] as previous RAV.
+pub const CONTINUATION_RAV: RavVector = RavVector {
+    timestamp_ns: FIRST_TIMESTAMP_NS + 300,
+    value_aggregate: 100,
+    digest: "3d5fff8e4244ce0f17c12d67418680a2ac3c187e4ace45596d968e2def5a5463",
+    signature: "831fd78112ce24fdcc2f3416167c3de0d9e6b824f9f1d2de1e85ea01271cad30\
+                4671fa7e4e5c9481e547cd4ba41c9e6d39498410ccfcb01139f34f6b4d9117a81b",
+};
+
+pub fn domain_separator() -> Eip712Domain {
+    tap_eip712_domain(CHAIN_ID, VERIFYING_CONTRACT)
+}
+
+fn receipt(index: u64) -> Receipt {
+    Receipt {
+        allocation_id: ALLOCATION_ID,
+        timestamp_ns: TimestampNs::from_nanos(FIRST_TIMESTAMP_NS + 100 * index),
+        nonce: index + 1,
+        value: 10 * (index as u128 + 1),
+        metadata: None,
+        parent: None,
+    }
+}
+
+/// Receipts aggregated into [`INITIAL_RAV`].
+pub fn receipts() -> Vec<Receipt> {
+    (0..2).map(receipt).collect()
+}
+
+/// Receipts aggregated into [`CONTINUATION_RAV`].
+pub fn continuation_receipts() -> Vec<Receipt> {
+    (2..4).map(receipt).collect()
+}
+
+#[cfg(all(test, feature = "ethers", feature = "ethers-compat"))]
+mod tests {
+    use alloy_primitives::hex;
+    use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
+
+    use super::*;
+    use crate::{
+        ethers_compat::convert_address, rav::SignedRAV, signed_message::EIP712SignedMessage,
+    };
+
+    fn signed_rav(
+        wallet: &LocalWallet,
+        receipts: Vec<Receipt>,
+        previous_rav: Option<SignedRAV>,
+        expected: &RavVector,
+    ) -> SignedRAV {
+        let domain_separator = domain_separator();
+        let receipts = receipts
+            .into_iter()
+            .map(|receipt| EIP712SignedMessage::new(&domain_separator, receipt, wallet).unwrap())
+            .collect::<Vec<_>>();
+        let rav =
+            ReceiptAggregateVoucher::aggregate_receipts(ALLOCATION_ID, &receipts, previous_rav)
+                .unwrap();
+        assert_eq!(rav, expected.rav());
+        assert_eq!(
+            hex::encode(rav.eip712_signing_hash(&domain_separator)),
+            expected.digest
+        );
+
+        let signed_rav = EIP712SignedMessage::new(&domain_separator, rav, wallet).unwrap();
+        assert_eq!(
+            hex::encode(signed_rav.signature.to_vec()),
+            expected.signature
+        );
+        assert_eq!(
+            signed_rav.recover_signer(&domain_separator).unwrap(),
+            SIGNER_ADDRESS
+        );
+        signed_rav
+    }
+
+    #[test]
+    fn rav_test_vectors() {
+        let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+            .phrase(MNEMONIC)
+            .build()
+            .unwrap();
+        assert_eq!(convert_address::<Address>(wallet.address()), SIGNER_ADDRESS);
+
+        let initial_rav = signed_rav(&wallet, receipts(), None, &INITIAL_RAV);
+        signed_rav(
+            &wallet,
+            continuation_receipts(),
+            Some(initial_rav),
+            &CONTINUATION_RAV,
+        );
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Module containing the `TimestampNs` type
+//!
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Unix Epoch timestamp, or duration, in nanoseconds.
+///
+/// It can be built from a [`Duration`] but not from a bare integer, so that units can't be mixed
+/// up at call sites:
+///
+/// ```compile_fail
+/// # use tap_core::timestamp::TimestampNs;
+/// fn timestamp_buffer(buffer: impl Into<TimestampNs>) -> TimestampNs {
+///     buffer.into()
+/// }
+/// // Milliseconds or nanoseconds?
+/// timestamp_buffer(1000u64);
+/// ```
+///
+/// ```
+/// # use std::time::Duration;
+/// # use tap_core::timestamp::TimestampNs;
+/// fn timestamp_buffer(buffer: impl Into<TimestampNs>) -> TimestampNs {
+///     buffer.into()
+/// }
+/// assert_eq!(timestamp_buffer(Duration::from_millis(1)).as_nanos(), 1_000_000);
+/// ```
+///
+/// It is serialized as a bare integer.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct TimestampNs(u64);
+
+impl TimestampNs {
+    pub const ZERO: Self = Self(0);
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self(nanos)
+    }
+
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<Duration> for TimestampNs {
+    /// Truncates the duration to 64 bits, as receipt and RAV timestamps are.
+    fn from(duration: Duration) -> Self {
+        Self(duration.as_nanos() as u64)
+    }
+}
+
+impl From<TimestampNs> for Duration {
+    fn from(timestamp: TimestampNs) -> Self {
+        Duration::from_nanos(timestamp.0)
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
+use rstest::*;
+
+use tap_core::{
+    rav::ReceiptAggregateVoucher,
+    receipt::Receipt,
+    signed_message::{signing_key_address, EIP712SignedMessage, SigningKey},
+    tap_eip712_domain, Error,
+};
+
+#[fixture]
+fn domain_separator() -> Eip712Domain {
+    tap_eip712_domain(1, Address::from([0x11u8; 20]))
+}
+
+#[fixture]
+fn signing_key() -> SigningKey {
+    SigningKey::from_slice(&[0x42u8; 32]).unwrap()
+}
+
+#[rstest]
+fn signed_with_key_are_verified(domain_separator: Eip712Domain, signing_key: SigningKey) {
+    let address = signing_key_address(&signing_key);
+    let allocation_id = Address::from([0xabu8; 20]);
+
+    let signed_receipt = EIP712SignedMessage::new_with_key(
+        &domain_separator,
+        Receipt::new(allocation_id, 42).unwrap(),
+        &signing_key,
+    )
+    .unwrap();
+    assert_eq!(
+        signed_receipt.recover_signer(&domain_separator).unwrap(),
+        address
+    );
+    signed_receipt.verify(&domain_separator, address).unwrap();
+
+    let signed_rav = EIP712SignedMessage::new_with_key(
+        &domain_separator,
+        ReceiptAggregateVoucher::aggregate_receipts(allocation_id, &[signed_receipt], None)
+            .unwrap(),
+        &signing_key,
+    )
+    .unwrap();
+    signed_rav.verify(&domain_separator, address).unwrap();
+
+    // Another key's address is rejected
+    let other_address = signing_key_address(&SigningKey::from_slice(&[0x43u8; 32]).unwrap());
+    assert!(matches!(
+        signed_rav.verify(&domain_separator, other_address),
+        Err(Error::SignatureError(_))
+    ));
+}
+
+#[cfg(all(feature = "ethers", feature = "ethers-compat"))]
+#[rstest]
+fn signed_with_key_match_ethers_wallet(domain_separator: Eip712Domain, signing_key: SigningKey) {
+    use ethers::signers::{LocalWallet, Signer};
+    use tap_core::ethers_compat::convert_address;
+
+    let wallet = LocalWallet::from(signing_key.clone());
+    assert_eq!(
+        signing_key_address(&signing_key),
+        convert_address::<Address>(wallet.address())
+    );
+
+    let receipt = Receipt::new(Address::from([0xabu8; 20]), 42).unwrap();
+    assert_eq!(
+        EIP712SignedMessage::new_with_key(&domain_separator, receipt.clone(), &signing_key)
+            .unwrap(),
+        EIP712SignedMessage::new(&domain_separator, receipt, &wallet).unwrap()
+    );
+}
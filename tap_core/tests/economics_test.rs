@@ -0,0 +1,139 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::str::FromStr;
+
+use alloy_primitives::{Address, U256};
+use alloy_sol_types::Eip712Domain;
+use ethers::signers::coins_bip39::English;
+use ethers::signers::{LocalWallet, MnemonicBuilder};
+use rstest::*;
+
+use tap_core::{
+    economics::{rav_is_economical, unredeemed_value, GRT_BASE_UNITS},
+    rav::{ReceiptAggregateVoucher, SignedRAV},
+    signed_message::EIP712SignedMessage,
+    tap_eip712_domain,
+};
+
+#[fixture]
+fn domain_separator() -> Eip712Domain {
+    tap_eip712_domain(1, Address::from([0x11u8; 20]))
+}
+
+#[fixture]
+fn wallet() -> LocalWallet {
+    MnemonicBuilder::<English>::default()
+        .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+        .build()
+        .unwrap()
+}
+
+fn signed_rav(
+    domain_separator: &Eip712Domain,
+    wallet: &LocalWallet,
+    value_aggregate: u128,
+) -> SignedRAV {
+    EIP712SignedMessage::new(
+        domain_separator,
+        ReceiptAggregateVoucher {
+            allocationId: Address::from_str("0xabababababababababababababababababababab").unwrap(),
+            timestampNs: 1,
+            valueAggregate: value_aggregate,
+        },
+        wallet,
+    )
+    .unwrap()
+}
+
+#[rstest]
+fn rav_is_economical_break_even(domain_separator: Eip712Domain, wallet: LocalWallet) {
+    // 1 GRT is worth 0.0001 ETH, redeeming costs 100_000 gas at 10 gwei, i.e. 0.001 ETH or 10 GRT
+    let token_price = U256::from(100_000_000_000_000u128);
+    let gas_price = U256::from(10_000_000_000u128);
+    let gas_estimate = 100_000;
+    let break_even = 10 * GRT_BASE_UNITS;
+
+    let rav = signed_rav(&domain_separator, &wallet, break_even);
+    assert!(rav_is_economical(
+        &rav,
+        gas_price,
+        gas_estimate,
+        token_price
+    ));
+
+    let rav = signed_rav(&domain_separator, &wallet, break_even - 1);
+    assert!(!rav_is_economical(
+        &rav,
+        gas_price,
+        gas_estimate,
+        token_price
+    ));
+
+    let rav = signed_rav(&domain_separator, &wallet, break_even + 1);
+    assert!(rav_is_economical(
+        &rav,
+        gas_price,
+        gas_estimate,
+        token_price
+    ));
+
+    // Same RAV, but gas is one wei more expensive
+    let rav = signed_rav(&domain_separator, &wallet, break_even);
+    assert!(!rav_is_economical(
+        &rav,
+        gas_price + U256::from(1),
+        gas_estimate,
+        token_price
+    ));
+}
+
+#[rstest]
+fn rav_is_economical_free_redemption(domain_separator: Eip712Domain, wallet: LocalWallet) {
+    let rav = signed_rav(&domain_separator, &wallet, 0);
+    assert!(rav_is_economical(&rav, U256::ZERO, 100_000, U256::from(1)));
+    assert!(!rav_is_economical(
+        &rav,
+        U256::from(1),
+        100_000,
+        U256::from(1)
+    ));
+}
+
+#[rstest]
+fn rav_is_economical_overflowing_cost(domain_separator: Eip712Domain, wallet: LocalWallet) {
+    let rav = signed_rav(&domain_separator, &wallet, u128::MAX);
+    assert!(!rav_is_economical(&rav, U256::MAX, 2, U256::MAX));
+    assert!(!rav_is_economical(
+        &rav,
+        U256::MAX / U256::from(GRT_BASE_UNITS),
+        2,
+        U256::MAX
+    ));
+}
+
+#[rstest]
+fn rav_is_economical_overflowing_value(domain_separator: Eip712Domain, wallet: LocalWallet) {
+    let rav = signed_rav(&domain_separator, &wallet, u128::MAX);
+    assert!(rav_is_economical(&rav, U256::from(1), 1, U256::MAX));
+}
+
+#[rstest]
+fn unredeemed_value_fully_redeemed(domain_separator: Eip712Domain, wallet: LocalWallet) {
+    let rav = signed_rav(&domain_separator, &wallet, 1000);
+    assert_eq!(unredeemed_value(&rav, U256::from(1000)), U256::ZERO);
+}
+
+#[rstest]
+fn unredeemed_value_partially_redeemed(domain_separator: Eip712Domain, wallet: LocalWallet) {
+    let rav = signed_rav(&domain_separator, &wallet, 1000);
+    assert_eq!(unredeemed_value(&rav, U256::from(400)), U256::from(600));
+    assert_eq!(unredeemed_value(&rav, U256::ZERO), U256::from(1000));
+}
+
+#[rstest]
+fn unredeemed_value_over_redeemed(domain_separator: Eip712Domain, wallet: LocalWallet) {
+    let rav = signed_rav(&domain_separator, &wallet, 1000);
+    assert_eq!(unredeemed_value(&rav, U256::from(1001)), U256::ZERO);
+    assert_eq!(unredeemed_value(&rav, U256::MAX), U256::ZERO);
+}
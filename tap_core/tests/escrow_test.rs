@@ -6,18 +6,25 @@ use std::{
     sync::{Arc, RwLock},
 };
 
+use alloy_primitives::Address;
 use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
 use rstest::*;
 
 use tap_core::{
-    manager::{adapters::EscrowHandler, context::memory::InMemoryContext},
-    receipt::checks::TimestampCheck,
+    ethers_compat::convert_address,
+    manager::{
+        adapters::EscrowHandler,
+        context::memory::{InMemoryContext, RevertedDeposits},
+    },
+    receipt::{checks::TimestampCheck, Receipt, ReceiptError, ReceiptWithState},
+    signed_message::EIP712SignedMessage,
+    tap_eip712_domain,
 };
 
 #[fixture]
 fn context() -> InMemoryContext {
     let escrow_storage = Arc::new(RwLock::new(HashMap::new()));
-    let rav_storage = Arc::new(RwLock::new(None));
+    let rav_storage = Arc::new(RwLock::new(HashMap::new()));
     let receipt_storage = Arc::new(RwLock::new(HashMap::new()));
 
     let timestamp_check = Arc::new(TimestampCheck::new(0));
@@ -36,15 +43,13 @@ async fn escrow_handler_test(mut context: InMemoryContext) {
          .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
          .build()
          .unwrap();
-    let sender_id: [u8; 20] = wallet.address().into();
-    let sender_id = sender_id.into();
+    let sender_id = convert_address(wallet.address());
 
     let invalid_wallet: LocalWallet = MnemonicBuilder::<English>::default()
         .phrase("wrong century settle satisfy market forest title connect ten push alley depend")
         .build()
         .unwrap();
-    let invalid_sender_id: [u8; 20] = invalid_wallet.address().into();
-    let invalid_sender_id = invalid_sender_id.into();
+    let invalid_sender_id = convert_address(invalid_wallet.address());
 
     let initial_value = 500u128;
 
@@ -77,3 +82,255 @@ async fn escrow_handler_test(mut context: InMemoryContext) {
         .await
         .is_err());
 }
+
+#[rstest]
+#[tokio::test]
+async fn escrow_handler_deposit_withdraw_test(context: InMemoryContext) {
+    let sender_id = Address::from([0xfbu8; 20]);
+    let other_sender_id = Address::from([0xfau8; 20]);
+
+    // Deposits create and then credit the sender's escrow
+    context.deposit(sender_id, 300).await.unwrap();
+    context.deposit(sender_id, 200).await.unwrap();
+    context.deposit(other_sender_id, 100).await.unwrap();
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 500);
+    assert_eq!(
+        context.get_available_escrow(other_sender_id).await.unwrap(),
+        100
+    );
+
+    // Reserving escrow for receipts reduces the available escrow
+    context.subtract_escrow(sender_id, 150).await.unwrap();
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 350);
+
+    // Withdrawing within the balance succeeds
+    context.withdraw(sender_id, 300).await.unwrap();
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 50);
+
+    // Withdrawing beyond the balance fails and leaves it untouched
+    assert!(context.withdraw(sender_id, 51).await.is_err());
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 50);
+
+    // Overflowing deposits fail and leave the balance untouched
+    assert!(context.deposit(sender_id, u128::MAX).await.is_err());
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 50);
+
+    // Other senders are unaffected
+    assert_eq!(
+        context.get_available_escrow(other_sender_id).await.unwrap(),
+        100
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn escrow_revert_to_block_test(context: InMemoryContext) {
+    let sender_id = Address::from([0xfbu8; 20]);
+    let other_sender_id = Address::from([0xfau8; 20]);
+
+    context.deposit_at_block(sender_id, 300, 10).unwrap();
+    context.deposit_at_block(sender_id, 200, 11).unwrap();
+    context.deposit_at_block(other_sender_id, 100, 11).unwrap();
+    context.deposit_at_block(sender_id, 100, 12).unwrap();
+
+    // Reserving escrow for receipts reduces the available escrow
+    context.subtract_escrow(sender_id, 50).await.unwrap();
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 550);
+
+    // Block 12 is reorged
+    assert_eq!(
+        context.revert_to_block(11),
+        RevertedDeposits {
+            value: 100,
+            overdrawn: HashMap::new(),
+        }
+    );
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 450);
+
+    // Blocks 11 and 12 are reorged, the reservation is kept
+    assert_eq!(context.revert_to_block(10).value, 300);
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 250);
+    assert_eq!(
+        context.get_available_escrow(other_sender_id).await.unwrap(),
+        0
+    );
+
+    // Deposits of the new canonical block 11 are counted
+    context.deposit_at_block(sender_id, 40, 11).unwrap();
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 290);
+    assert_eq!(context.revert_to_block(11), RevertedDeposits::default());
+}
+
+#[rstest]
+#[tokio::test]
+async fn escrow_revert_to_block_overdrawn_test(context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let sender_id = convert_address(wallet.address());
+    let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+    let allocation_id = Address::from([0xabu8; 20]);
+
+    let context = context.with_escrow_grace(50);
+    context.deposit_at_block(sender_id, 20, 9).unwrap();
+
+    let reserve = |value: u128| {
+        let context = &context;
+        let domain_separator = &domain_separator;
+        let signed_receipt = EIP712SignedMessage::new(
+            domain_separator,
+            Receipt::new(allocation_id, value).unwrap(),
+            &wallet,
+        )
+        .unwrap();
+        async move {
+            ReceiptWithState::new(signed_receipt)
+                .finalize_receipt_checks(&[])
+                .await
+                .unwrap()
+                .check_and_reserve_escrow(context, domain_separator)
+                .await
+                .is_ok()
+        }
+    };
+
+    // The deposit of block 10 pays back the overdrawn escrow, then credits the rest
+    assert!(reserve(50).await);
+    assert_eq!(context.escrow_grace_used(sender_id), 30);
+    context.deposit_at_block(sender_id, 100, 10).unwrap();
+    assert_eq!(context.escrow_grace_used(sender_id), 0);
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 70);
+
+    // Most of the credited escrow is reserved before block 10 is reorged
+    assert!(reserve(60).await);
+    assert_eq!(
+        context.revert_to_block(9),
+        RevertedDeposits {
+            value: 100,
+            overdrawn: HashMap::from([(sender_id, 60)]),
+        }
+    );
+
+    // Both the paid back escrow and the reserved escrow are overdrawn, no escrow is left
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 0);
+    assert_eq!(context.escrow_grace_used(sender_id), 90);
+    assert!(!reserve(1).await);
+
+    // The sender's next deposit pays it back
+    context.deposit_at_block(sender_id, 100, 10).unwrap();
+    assert_eq!(context.escrow_grace_used(sender_id), 0);
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 10);
+}
+
+#[rstest]
+#[tokio::test]
+async fn escrow_grace_test(context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let sender_id = convert_address(wallet.address());
+    let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+    let allocation_id = Address::from([0xabu8; 20]);
+
+    let mut context = context.with_escrow_grace(50);
+    context.deposit(sender_id, 100).await.unwrap();
+
+    let reserve = |value: u128| {
+        let context = &context;
+        let domain_separator = &domain_separator;
+        let signed_receipt = EIP712SignedMessage::new(
+            domain_separator,
+            Receipt::new(allocation_id, value).unwrap(),
+            &wallet,
+        )
+        .unwrap();
+        async move {
+            ReceiptWithState::new(signed_receipt)
+                .finalize_receipt_checks(&[])
+                .await
+                .unwrap()
+                .check_and_reserve_escrow(context, domain_separator)
+                .await
+                .is_ok()
+        }
+    };
+
+    // Within the balance
+    assert!(reserve(80).await);
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 20);
+
+    // Into the grace zone
+    assert!(reserve(60).await);
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 0);
+    assert_eq!(context.escrow_grace_used(sender_id), 40);
+
+    // Beyond the grace, the receipt is rejected and nothing is reserved
+    assert!(!reserve(20).await);
+    assert_eq!(context.escrow_grace_used(sender_id), 40);
+
+    // Up to the grace
+    assert!(reserve(10).await);
+    assert_eq!(context.escrow_grace_used(sender_id), 50);
+    assert!(!reserve(1).await);
+
+    // Topping up pays back the overdrawn escrow first
+    context.deposit(sender_id, 100).await.unwrap();
+    assert_eq!(context.escrow_grace_used(sender_id), 0);
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 50);
+
+    // Increasing the escrow directly pays it back too
+    assert!(reserve(60).await);
+    assert_eq!(context.escrow_grace_used(sender_id), 10);
+    context.increase_escrow(sender_id, 30);
+    assert_eq!(context.escrow_grace_used(sender_id), 0);
+    assert_eq!(context.get_available_escrow(sender_id).await.unwrap(), 20);
+}
+
+#[rstest]
+#[tokio::test]
+async fn escrow_insufficient_shortfall_test(context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let sender_id = convert_address(wallet.address());
+    let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+
+    let available = 100u128;
+    let value = 150u128;
+    context.deposit(sender_id, available).await.unwrap();
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(Address::from([0xabu8; 20]), value).unwrap(),
+        &wallet,
+    )
+    .unwrap();
+    let failed = ReceiptWithState::new(signed_receipt)
+        .finalize_receipt_checks(&[])
+        .await
+        .unwrap()
+        .check_and_reserve_escrow(&context, &domain_separator)
+        .await
+        .unwrap_err();
+
+    // The sender knows exactly how much to deposit, and nothing was reserved
+    match failed.error() {
+        &ReceiptError::InsufficientEscrow {
+            required,
+            available: reported_available,
+            shortfall,
+        } => {
+            assert_eq!(required, value);
+            assert_eq!(reported_available, available);
+            assert_eq!(shortfall, value - available);
+        }
+        error => panic!("Unexpected error: {error}"),
+    }
+    assert_eq!(
+        context.get_available_escrow(sender_id).await.unwrap(),
+        available
+    );
+}
@@ -1,13 +1,16 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     str::FromStr,
-    sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use alloy_primitives::Address;
+use alloy_primitives::{Address, U256};
 use alloy_sol_types::Eip712Domain;
 use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
 use rstest::*;
@@ -17,19 +20,28 @@ fn get_current_timestamp_u64_ns() -> anyhow::Result<u64> {
 }
 
 use tap_core::{
+    clock::ManualClock,
+    ethers_compat::convert_address,
     manager::{
-        adapters::ReceiptRead,
+        adapters::{
+            EscrowHandler, FailedReceiptStore, RAVRead, ReceiptRead, ReceiptStore, Transaction,
+        },
         context::memory::{
-            checks::get_full_list_of_checks, EscrowStorage, InMemoryContext, QueryAppraisals,
+            checks::get_full_list_of_checks, EscrowStorage, InMemoryContext, InMemoryError,
+            InMemoryTransaction, QueryAppraisals,
         },
-        Manager,
+        Manager, ReconcileReport,
     },
+    rav::{RAVRequest, ReceiptAggregateVoucher, SignedRAV},
     receipt::{
-        checks::{Checks, TimestampCheck},
-        Receipt,
+        checks::{Check, CheckResult, Checks, ReceiptCheck, TimestampCheck},
+        AwaitingReserve, Checking, Receipt, ReceiptError, ReceiptOutcome, ReceiptResult,
+        ReceiptWithState,
     },
     signed_message::EIP712SignedMessage,
     tap_eip712_domain,
+    timestamp::TimestampNs,
+    Error,
 };
 
 #[fixture]
@@ -38,11 +50,9 @@ fn keys() -> (LocalWallet, Address) {
         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
         .build()
         .unwrap();
-    // Alloy library does not have feature parity with ethers library (yet) This workaround is needed to get the address
-    // to convert to an alloy Address. This will not be needed when the alloy library has wallet support.
-    let address: [u8; 20] = wallet.address().into();
+    let address = convert_address(wallet.address());
 
-    (wallet, address.into())
+    (wallet, address)
 }
 
 #[fixture]
@@ -85,7 +95,7 @@ fn context(
     keys: (LocalWallet, Address),
 ) -> ContextFixture {
     let escrow_storage = Arc::new(RwLock::new(HashMap::new()));
-    let rav_storage = Arc::new(RwLock::new(None));
+    let rav_storage = Arc::new(RwLock::new(HashMap::new()));
     let query_appraisals = Arc::new(RwLock::new(HashMap::new()));
     let receipt_storage = Arc::new(RwLock::new(HashMap::new()));
     let timestamp_check = Arc::new(TimestampCheck::new(0));
@@ -148,6 +158,149 @@ async fn manager_verify_and_store_varying_initial_checks(
         .is_ok());
 }
 
+#[rstest]
+#[tokio::test]
+async fn manager_pause_and_resume_receipt_acceptance(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+    let signed_receipt = || {
+        EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 20).unwrap(),
+            &keys.0,
+        )
+        .unwrap()
+    };
+
+    manager.set_accepting(false);
+    assert!(!manager.is_accepting());
+    assert!(matches!(
+        manager.verify_and_store_receipt(signed_receipt()).await,
+        Err(Error::ServiceUnavailable)
+    ));
+    assert_eq!(manager.count_receipts(allocation_ids[0]).await.unwrap(), 0);
+
+    manager.set_accepting(true);
+    manager
+        .verify_and_store_receipt(signed_receipt())
+        .await
+        .unwrap();
+    assert_eq!(manager.count_receipts(allocation_ids[0]).await.unwrap(), 1);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_can_afford_at_escrow_boundary(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+    let signed_receipt = |value: u128| {
+        EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], value).unwrap(),
+            &keys.0,
+        )
+        .unwrap()
+    };
+
+    // Unknown senders have no escrow to read
+    assert!(manager.can_afford(&signed_receipt(1)).await.is_err());
+
+    escrow_storage.write().unwrap().insert(keys.1, 100);
+    assert!(manager.can_afford(&signed_receipt(100)).await.unwrap());
+    assert!(!manager.can_afford(&signed_receipt(101)).await.unwrap());
+
+    // The escrow reserved for other receipts can't pay for it, and nothing was reserved
+    context.subtract_escrow(keys.1, 30).await.unwrap();
+    assert!(manager.can_afford(&signed_receipt(70)).await.unwrap());
+    assert!(!manager.can_afford(&signed_receipt(71)).await.unwrap());
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 70);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_verify_and_store_receipt_with_state(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, checks);
+    escrow_storage.write().unwrap().insert(keys.1, 30);
+
+    let mut signed_receipts = vec![];
+    for value in [20u128, 40u128] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        let query_id = signed_receipt.unique_hash();
+        query_appraisals.write().unwrap().insert(query_id, value);
+        signed_receipts.push(signed_receipt);
+    }
+
+    // the escrow covers the first receipt, and is reserved for it
+    let outcome = manager
+        .verify_and_store_receipt_with_state(signed_receipts[0].clone())
+        .await
+        .unwrap();
+    assert!(matches!(outcome, ReceiptOutcome::Reserved(_)));
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 10);
+
+    // but not the second one, which is stored anyway
+    let outcome = manager
+        .verify_and_store_receipt_with_state(signed_receipts[1].clone())
+        .await
+        .unwrap();
+    assert!(matches!(outcome, ReceiptOutcome::AwaitingReserve(_)));
+
+    // the first receipt was already stored, and is not reserved twice
+    let outcome = manager
+        .verify_and_store_receipt_with_state(signed_receipts[0].clone())
+        .await
+        .unwrap();
+    assert!(matches!(outcome, ReceiptOutcome::Failed(_)));
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 10);
+
+    // nor when it is collected for a RAV request, while the second one is still not covered
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts, vec![signed_receipts[0].clone()]);
+    assert_eq!(rav_request.invalid_receipts.len(), 1);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 10);
+}
+
 #[rstest]
 #[tokio::test]
 async fn manager_create_rav_request_all_valid_receipts(
@@ -183,7 +336,7 @@ async fn manager_create_rav_request_all_valid_receipts(
             .await
             .is_ok());
     }
-    let rav_request_result = manager.create_rav_request(0, None).await;
+    let rav_request_result = manager.create_rav_request(Duration::ZERO, None).await;
     assert!(rav_request_result.is_ok());
 
     let rav_request = rav_request_result.unwrap();
@@ -243,7 +396,7 @@ async fn manager_create_multiple_rav_requests_all_valid_receipts(
             .is_ok());
         expected_accumulated_value += value;
     }
-    let rav_request_result = manager.create_rav_request(0, None).await;
+    let rav_request_result = manager.create_rav_request(Duration::ZERO, None).await;
     assert!(rav_request_result.is_ok());
 
     let rav_request = rav_request_result.unwrap();
@@ -289,7 +442,7 @@ async fn manager_create_multiple_rav_requests_all_valid_receipts(
             .is_ok());
         expected_accumulated_value += value;
     }
-    let rav_request_result = manager.create_rav_request(0, None).await;
+    let rav_request_result = manager.create_rav_request(Duration::ZERO, None).await;
     assert!(rav_request_result.is_ok());
 
     let rav_request = rav_request_result.unwrap();
@@ -344,7 +497,7 @@ async fn manager_create_multiple_rav_requests_all_valid_receipts_consecutive_tim
     for query_id in 0..10 {
         let value = 20u128;
         let mut receipt = Receipt::new(allocation_ids[0], value).unwrap();
-        receipt.timestamp_ns = starting_min_timestamp + query_id + 1;
+        receipt.timestamp_ns = TimestampNs::from_nanos(starting_min_timestamp + query_id + 1);
         let signed_receipt = EIP712SignedMessage::new(&domain_separator, receipt, &keys.0).unwrap();
 
         let query_id = signed_receipt.unique_hash();
@@ -363,7 +516,7 @@ async fn manager_create_multiple_rav_requests_all_valid_receipts_consecutive_tim
         manager.remove_obsolete_receipts().await.unwrap();
     }
 
-    let rav_request_1_result = manager.create_rav_request(0, None).await;
+    let rav_request_1_result = manager.create_rav_request(Duration::ZERO, None).await;
     assert!(rav_request_1_result.is_ok());
 
     let rav_request_1 = rav_request_1_result.unwrap();
@@ -397,7 +550,7 @@ async fn manager_create_multiple_rav_requests_all_valid_receipts_consecutive_tim
     for query_id in 10..20 {
         let value = 20u128;
         let mut receipt = Receipt::new(allocation_ids[0], value).unwrap();
-        receipt.timestamp_ns = starting_min_timestamp + query_id + 1;
+        receipt.timestamp_ns = TimestampNs::from_nanos(starting_min_timestamp + query_id + 1);
         let signed_receipt = EIP712SignedMessage::new(&domain_separator, receipt, &keys.0).unwrap();
         let query_id = signed_receipt.unique_hash();
         stored_signed_receipts.push(signed_receipt.clone());
@@ -423,7 +576,7 @@ async fn manager_create_multiple_rav_requests_all_valid_receipts_consecutive_tim
         );
     }
 
-    let rav_request_2_result = manager.create_rav_request(0, None).await;
+    let rav_request_2_result = manager.create_rav_request(Duration::ZERO, None).await;
     assert!(rav_request_2_result.is_ok());
 
     let rav_request_2 = rav_request_2_result.unwrap();
@@ -453,3 +606,2421 @@ async fn manager_create_multiple_rav_requests_all_valid_receipts_consecutive_tim
         .await
         .is_ok());
 }
+
+#[rstest]
+#[tokio::test]
+async fn manager_create_rav_request_until_cutoff(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let starting_min_timestamp = get_current_timestamp_u64_ns().unwrap() - 500000000;
+
+    let manager = Manager::new(domain_separator.clone(), context, checks);
+
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    let mut stored_signed_receipts = Vec::new();
+    for query_id in 0..10 {
+        let value = 20u128;
+        let mut receipt = Receipt::new(allocation_ids[0], value).unwrap();
+        receipt.timestamp_ns = TimestampNs::from_nanos(starting_min_timestamp + query_id + 1);
+        let signed_receipt = EIP712SignedMessage::new(&domain_separator, receipt, &keys.0).unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), value);
+        stored_signed_receipts.push(signed_receipt.clone());
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    // Only the receipts up to the cutoff (inclusive) are in the request
+    let cutoff_ns = starting_min_timestamp + 5;
+    let rav_request = manager
+        .create_rav_request_until(cutoff_ns, Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts, stored_signed_receipts[..5]);
+    assert_eq!(rav_request.invalid_receipts.len(), 0);
+    assert_eq!(rav_request.expected_rav.timestampNs, cutoff_ns);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 100);
+
+    let signed_rav =
+        EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav.clone(), &keys.0)
+            .unwrap();
+    manager
+        .verify_and_store_rav(rav_request.expected_rav, signed_rav)
+        .await
+        .unwrap();
+
+    // The later receipts were left pending
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts, stored_signed_receipts[5..]);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 200);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_uses_stored_ravs_after_restart(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        checks,
+        escrow_storage,
+        ..
+    } = context;
+
+    // The RAV stored before restarting
+    let baseline_timestamp_ns = get_current_timestamp_u64_ns().unwrap() - 1_000_000_000;
+    let baseline_rav = EIP712SignedMessage::new(
+        &domain_separator,
+        ReceiptAggregateVoucher {
+            allocationId: allocation_ids[0],
+            timestampNs: baseline_timestamp_ns,
+            valueAggregate: 100,
+        },
+        &keys.0,
+    )
+    .unwrap();
+    let rav_storage = Arc::new(RwLock::new(HashMap::from([(
+        allocation_ids[0],
+        baseline_rav.clone(),
+    )])));
+    let context = InMemoryContext::new(
+        rav_storage,
+        Arc::new(RwLock::new(HashMap::new())),
+        escrow_storage.clone(),
+        Arc::new(TimestampCheck::new(0)),
+    )
+    .with_sender_address(keys.1);
+    assert_eq!(
+        context.load_latest_ravs().await.unwrap(),
+        HashMap::from([(allocation_ids[0], baseline_rav.clone())])
+    );
+
+    let manager = Manager::new(domain_separator.clone(), context, checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+    for value in [20, 30] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    // The RAV stored before restarting is the previous RAV
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.previous_rav, Some(baseline_rav.clone()));
+    assert_eq!(rav_request.valid_receipts.len(), 2);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 150);
+    manager.cancel_rav_request(rav_request).await.unwrap();
+
+    // With receipts of another allocation pending, the RAV is for the allocation of the oldest one
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[1], 40).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    manager
+        .verify_and_store_receipt(signed_receipt)
+        .await
+        .unwrap();
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.previous_rav, Some(baseline_rav.clone()));
+    assert_eq!(rav_request.valid_receipts.len(), 2);
+    assert_eq!(rav_request.expected_rav.allocationId, allocation_ids[0]);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 150);
+    manager.cancel_rav_request(rav_request).await.unwrap();
+
+    // Scoped to an allocation, its own stored RAV is used
+    let rav_request = manager
+        .for_allocation(allocation_ids[0])
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.previous_rav, Some(baseline_rav));
+    assert_eq!(rav_request.expected_rav.valueAggregate, 150);
+    let rav_request = manager
+        .for_allocation(allocation_ids[1])
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.previous_rav, None);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 40);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_reconcile_rav_flags_surplus(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    let mut signed_receipts = vec![];
+    for value in [20, 30] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        signed_receipts.push(signed_receipt.clone());
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+    let timestamp_ns = signed_receipts
+        .iter()
+        .map(|receipt| receipt.message.timestamp_ns)
+        .max()
+        .unwrap();
+    let rav = |value_aggregate| {
+        EIP712SignedMessage::new(
+            &domain_separator,
+            ReceiptAggregateVoucher {
+                allocationId: allocation_ids[0],
+                timestampNs: timestamp_ns.as_nanos(),
+                valueAggregate: value_aggregate,
+            },
+            &keys.0,
+        )
+        .unwrap()
+    };
+
+    // The RAV claims more than the receipts held
+    let report = manager.reconcile_rav(&rav(80)).await.unwrap();
+    assert_eq!(
+        report,
+        ReconcileReport {
+            allocation_id: allocation_ids[0],
+            rav_value: 80,
+            receipts_count: 2,
+            receipts_value: 50,
+        }
+    );
+    assert_eq!(report.surplus(), 30);
+    assert_eq!(report.shortfall(), 0);
+    assert!(!report.is_balanced());
+
+    let report = manager.reconcile_rav(&rav(50)).await.unwrap();
+    assert!(report.is_balanced());
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_adopt_rav(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context, checks, ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+
+    let sign_rav = |value_aggregate: u128| {
+        EIP712SignedMessage::new(
+            &domain_separator,
+            ReceiptAggregateVoucher {
+                allocationId: allocation_ids[0],
+                timestampNs: 1000,
+                valueAggregate: value_aggregate,
+            },
+            &keys.0,
+        )
+        .unwrap()
+    };
+
+    // Adopting a first RAV, then a higher-value one, succeeds
+    manager.adopt_rav(sign_rav(100)).await.unwrap();
+    manager.adopt_rav(sign_rav(200)).await.unwrap();
+    assert_eq!(
+        context
+            .last_rav(allocation_ids[0])
+            .await
+            .unwrap()
+            .unwrap()
+            .message
+            .valueAggregate,
+        200
+    );
+
+    // Adopting a lower-value RAV is rejected, and the stored RAV is kept
+    assert!(matches!(
+        manager.adopt_rav(sign_rav(150)).await,
+        Err(Error::RavRegression {
+            stored_rav,
+            received_rav,
+        }) if stored_rav.valueAggregate == 200 && received_rav.valueAggregate == 150
+    ));
+    assert_eq!(
+        context
+            .last_rav(allocation_ids[0])
+            .await
+            .unwrap()
+            .unwrap()
+            .message
+            .valueAggregate,
+        200
+    );
+}
+
+#[rstest]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn manager_concurrent_store_same_receipt(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Arc::new(Manager::new(
+        domain_separator.clone(),
+        context.clone(),
+        checks,
+    ));
+
+    let value = 20u128;
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], value).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    let query_id = signed_receipt.unique_hash();
+    query_appraisals.write().unwrap().insert(query_id, value);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    let tasks = (0..2)
+        .map(|_| {
+            let manager = manager.clone();
+            let signed_receipt = signed_receipt.clone();
+            tokio::spawn(async move { manager.verify_and_store_receipt(signed_receipt).await })
+        })
+        .collect::<Vec<_>>();
+    let mut stored_count = 0;
+    for task in tasks {
+        if task.await.unwrap().is_ok() {
+            stored_count += 1;
+        }
+    }
+
+    // Exactly one of the concurrent calls stores the receipt
+    assert_eq!(stored_count, 1);
+    assert_eq!(
+        context
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_create_rav_request_below_min_thresholds(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks)
+        .with_min_rav_thresholds(3, 50);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    let store_receipt = |value: u128| {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), value);
+        manager.verify_and_store_receipt(signed_receipt)
+    };
+
+    // Below the minimum number of receipts
+    store_receipt(20).await.unwrap();
+    store_receipt(20).await.unwrap();
+    assert!(matches!(
+        manager.create_rav_request(Duration::ZERO, None).await,
+        Err(Error::NotEnoughReceiptsForRAVRequest {
+            receipts_count: 2,
+            value: 40
+        })
+    ));
+
+    // Enough receipts, but below the minimum value
+    store_receipt(5).await.unwrap();
+    assert!(matches!(
+        manager.create_rav_request(Duration::ZERO, None).await,
+        Err(Error::NotEnoughReceiptsForRAVRequest {
+            receipts_count: 3,
+            value: 45
+        })
+    ));
+
+    // The receipts are kept and no escrow was reserved for them
+    assert_eq!(
+        context
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(
+        *escrow_storage.read().unwrap().get(&keys.1).unwrap(),
+        999999
+    );
+
+    // Both thresholds reached
+    store_receipt(10).await.unwrap();
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts.len(), 4);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 55);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_drain_to_ravs_below_min_thresholds(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks)
+        .with_min_rav_thresholds(3, 50);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    // Signs the expected RAV, as the aggregator would
+    let aggregator_client = |rav_request: RAVRequest| {
+        let signed_rav =
+            EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav, &keys.0);
+        async move { signed_rav }
+    };
+
+    // Nothing to drain yet
+    assert!(manager
+        .drain_to_ravs(aggregator_client)
+        .await
+        .unwrap()
+        .is_empty());
+
+    for (allocation_id, value) in [
+        (allocation_ids[0], 20),
+        (allocation_ids[1], 10),
+        (allocation_ids[0], 20),
+    ] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_id, value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), value);
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+    for allocation_id in &allocation_ids[..2] {
+        assert!(matches!(
+            manager
+                .for_allocation(*allocation_id)
+                .create_rav_request(Duration::ZERO, None)
+                .await,
+            Err(Error::NotEnoughReceiptsForRAVRequest { .. })
+        ));
+    }
+
+    // The receipts of both allocations are aggregated despite the thresholds, and removed from
+    // storage
+    let signed_ravs = manager.drain_to_ravs(aggregator_client).await.unwrap();
+    assert_eq!(signed_ravs.len(), 2);
+    for (allocation_id, value_aggregate) in [(allocation_ids[0], 40), (allocation_ids[1], 10)] {
+        let signed_rav = &signed_ravs[&allocation_id];
+        assert_eq!(signed_rav.message.allocationId, allocation_id);
+        assert_eq!(signed_rav.message.valueAggregate, value_aggregate);
+        assert_eq!(
+            context.last_rav(allocation_id).await.unwrap().as_ref(),
+            Some(signed_rav)
+        );
+        assert_eq!(manager.count_receipts(allocation_id).await.unwrap(), 0);
+    }
+
+    // Nothing is left to drain
+    assert!(manager
+        .drain_to_ravs(aggregator_client)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_drain_to_ravs_releases_escrow_on_failure(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, Checks::new(vec![]));
+    escrow_storage.write().unwrap().insert(keys.1, 1000);
+    for value in [20, 30] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    // The aggregator can't be reached
+    let failing_aggregator_client =
+        |_: RAVRequest| async { Err::<SignedRAV, _>(anyhow::anyhow!("connection refused")) };
+    assert!(matches!(
+        manager.drain_to_ravs(failing_aggregator_client).await,
+        Err(Error::AdapterError { .. })
+    ));
+    assert_eq!(manager.count_receipts(allocation_ids[0]).await.unwrap(), 2);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 1000);
+
+    // The aggregator returns a RAV that doesn't match the request
+    let overcharging_aggregator_client = |rav_request: RAVRequest| {
+        let signed_rav = EIP712SignedMessage::new(
+            &domain_separator,
+            ReceiptAggregateVoucher {
+                valueAggregate: rav_request.expected_rav.valueAggregate + 1,
+                ..rav_request.expected_rav
+            },
+            &keys.0,
+        );
+        async move { signed_rav }
+    };
+    assert!(matches!(
+        manager.drain_to_ravs(overcharging_aggregator_client).await,
+        Err(Error::InvalidReceivedRAV { .. })
+    ));
+    assert_eq!(manager.count_receipts(allocation_ids[0]).await.unwrap(), 2);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 1000);
+
+    // The receipts are available again to the next drain
+    let aggregator_client = |rav_request: RAVRequest| {
+        let signed_rav =
+            EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav, &keys.0);
+        async move { signed_rav }
+    };
+    let signed_ravs = manager.drain_to_ravs(aggregator_client).await.unwrap();
+    assert_eq!(signed_ravs[&allocation_ids[0]].message.valueAggregate, 50);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 950);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_finalize_allocation_rejects_late_receipts(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    // Signs the expected RAV, as the aggregator would
+    let aggregator_client = |rav_request: RAVRequest| {
+        let signed_rav =
+            EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav, &keys.0);
+        async move { signed_rav }
+    };
+    let signed_receipt = |value: u128| {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), value);
+        signed_receipt
+    };
+
+    for value in [20, 30] {
+        manager
+            .verify_and_store_receipt(signed_receipt(value))
+            .await
+            .unwrap();
+    }
+
+    let final_rav = manager
+        .finalize_allocation(allocation_ids[0], aggregator_client)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(final_rav.message.allocationId, allocation_ids[0]);
+    assert_eq!(final_rav.message.valueAggregate, 50);
+    assert_eq!(manager.count_receipts(allocation_ids[0]).await.unwrap(), 0);
+
+    // A late receipt is rejected, and finalizing again returns the same final RAV
+    assert!(matches!(
+        manager.verify_and_store_receipt(signed_receipt(40)).await,
+        Err(Error::AllocationClosed { allocation_id }) if allocation_id == allocation_ids[0]
+    ));
+    assert_eq!(manager.count_receipts(allocation_ids[0]).await.unwrap(), 0);
+    assert_eq!(
+        manager
+            .finalize_allocation(allocation_ids[0], aggregator_client)
+            .await
+            .unwrap(),
+        Some(final_rav)
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_finalize_allocation_keeps_other_allocations(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    // Signs the expected RAV, as the aggregator would
+    let aggregator_client = |rav_request: RAVRequest| {
+        let signed_rav =
+            EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav, &keys.0);
+        async move { signed_rav }
+    };
+    let store_receipt = |allocation_id: Address, value: u128| {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_id, value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), value);
+        manager.verify_and_store_receipt(signed_receipt)
+    };
+
+    // The receipt of the other allocation is older than the final RAV of the first one
+    store_receipt(allocation_ids[1], 10).await.unwrap();
+    for value in [20, 30] {
+        store_receipt(allocation_ids[0], value).await.unwrap();
+    }
+    let final_rav = manager
+        .finalize_allocation(allocation_ids[0], aggregator_client)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(final_rav.message.valueAggregate, 50);
+
+    // The other allocation is still aggregatable, from its own receipts only
+    assert_eq!(manager.count_receipts(allocation_ids[1]).await.unwrap(), 1);
+    store_receipt(allocation_ids[1], 15).await.unwrap();
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.previous_rav, None);
+    assert_eq!(rav_request.valid_receipts.len(), 2);
+    assert_eq!(rav_request.invalid_receipts.len(), 0);
+    assert_eq!(rav_request.expected_rav.allocationId, allocation_ids[1]);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 25);
+
+    let signed_rav =
+        EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav.clone(), &keys.0)
+            .unwrap();
+    manager
+        .verify_and_store_rav(rav_request.expected_rav, signed_rav.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        context.last_rav(allocation_ids[0]).await.unwrap(),
+        Some(final_rav)
+    );
+    assert_eq!(
+        context.last_rav(allocation_ids[1]).await.unwrap(),
+        Some(signed_rav)
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_timestamp_boundary_with_manual_clock(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let clock = Arc::new(ManualClock::new(250));
+    let manager = Manager::new(domain_separator.clone(), context, checks).with_clock(clock.clone());
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    let signed_receipt = |timestamp_ns: u64| {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt {
+                allocation_id: allocation_ids[0],
+                timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                nonce: timestamp_ns,
+                value: 20,
+                metadata: None,
+                parent: None,
+            },
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        signed_receipt
+    };
+
+    for timestamp_ns in [100, 200, 300] {
+        manager
+            .verify_and_store_receipt(signed_receipt(timestamp_ns))
+            .await
+            .unwrap();
+    }
+
+    // Only the receipts before the clock's current time are aggregated
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts.len(), 2);
+    assert_eq!(rav_request.expected_rav.timestampNs, 200);
+    let signed_rav =
+        EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav.clone(), &keys.0)
+            .unwrap();
+    manager
+        .verify_and_store_rav(rav_request.expected_rav, signed_rav)
+        .await
+        .unwrap();
+
+    // The timestamp check now rejects receipts up to the RAV timestamp, inclusive
+    assert!(manager
+        .verify_and_store_receipt(signed_receipt(200))
+        .await
+        .is_err());
+    manager
+        .verify_and_store_receipt(signed_receipt(201))
+        .await
+        .unwrap();
+
+    // Once the clock moves past the remaining receipts, they are aggregated
+    clock.advance(150);
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts.len(), 2);
+    assert_eq!(rav_request.expected_rav.timestampNs, 300);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 80);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_create_rav_request_deterministic_order(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, checks)
+        .with_clock(Arc::new(ManualClock::new(1000)));
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    // Shuffled timestamps, with some receipts sharing a timestamp
+    let mut stored_signed_receipts = Vec::new();
+    for (nonce, timestamp_ns) in [300u64, 100, 200, 100, 300, 200, 100]
+        .into_iter()
+        .enumerate()
+    {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt {
+                allocation_id: allocation_ids[0],
+                timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                nonce: nonce as u64,
+                value: 20,
+                metadata: None,
+                parent: None,
+            },
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        stored_signed_receipts.push(signed_receipt.clone());
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    stored_signed_receipts
+        .sort_by_key(|receipt| (receipt.message.timestamp_ns, receipt.unique_hash()));
+
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts, stored_signed_receipts);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_create_rav_request_with_receipts_limit(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, checks)
+        .with_clock(Arc::new(ManualClock::new(1000)));
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    // Receipts of another allocation are interleaved, and the limit falls within a timestamp
+    for (nonce, (allocation_id, timestamp_ns)) in [
+        (allocation_ids[0], 100u64),
+        (allocation_ids[1], 150),
+        (allocation_ids[0], 100),
+        (allocation_ids[0], 200),
+        (allocation_ids[1], 150),
+        (allocation_ids[0], 100),
+        (allocation_ids[0], 200),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt {
+                allocation_id,
+                timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                nonce: nonce as u64,
+                value: 20,
+                metadata: None,
+                parent: None,
+            },
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, Some(4))
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts.len(), 3);
+    assert_eq!(rav_request.expected_rav.allocationId, allocation_ids[0]);
+    assert_eq!(rav_request.expected_rav.timestampNs, 100);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 60);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_create_rav_request_no_receipts(
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context, checks, ..
+    } = context;
+    let manager = Manager::new(domain_separator, context, checks).with_min_rav_thresholds(2, 100);
+
+    assert!(matches!(
+        manager.create_rav_request(Duration::ZERO, None).await,
+        Err(Error::NoValidReceiptsForRAVRequest)
+    ));
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_create_rav_request_timestamp_buffer_too_large(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, checks)
+        .with_clock(Arc::new(ManualClock::new(1000)));
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt {
+            allocation_id: allocation_ids[0],
+            timestamp_ns: TimestampNs::from_nanos(500),
+            nonce: 0,
+            value: 20,
+            metadata: None,
+            parent: None,
+        },
+        &keys.0,
+    )
+    .unwrap();
+    query_appraisals
+        .write()
+        .unwrap()
+        .insert(signed_receipt.unique_hash(), 20);
+    manager
+        .verify_and_store_receipt(signed_receipt)
+        .await
+        .unwrap();
+
+    // The buffer reaches before the epoch
+    assert!(matches!(
+        manager
+            .create_rav_request(Duration::from_nanos(1001), None)
+            .await,
+        Err(Error::TimestampBufferTooLarge {
+            timestamp_buffer_ns: 1001,
+            now_ns: 1000,
+        })
+    ));
+    let rav_request = manager
+        .create_rav_request(Duration::from_nanos(400), None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts.len(), 1);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_rejects_aggregated_receipt_after_restart(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    sender_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+) {
+    // Storages persisted across restarts
+    let escrow_storage = Arc::new(RwLock::new(HashMap::from([(keys.1, 999999)])));
+    let rav_storage = Arc::new(RwLock::new(HashMap::new()));
+    let receipt_storage = Arc::new(RwLock::new(HashMap::new()));
+    let replay_watermark_storage = Arc::new(RwLock::new(HashMap::new()));
+
+    // Everything else is recreated on (re)start. Without a timestamp check, replays are only
+    // rejected by the storage
+    let start_manager = || {
+        let context = InMemoryContext::new(
+            rav_storage.clone(),
+            receipt_storage.clone(),
+            escrow_storage.clone(),
+            Arc::new(TimestampCheck::new(0)),
+        )
+        .with_sender_address(keys.1)
+        .with_replay_watermark_storage(replay_watermark_storage.clone())
+        .with_receipt_capacity(2);
+        let checks = get_full_list_of_checks(
+            domain_separator.clone(),
+            sender_ids.iter().cloned().collect(),
+            Arc::new(RwLock::new(allocation_ids.iter().cloned().collect())),
+            Arc::new(RwLock::new(HashMap::new())),
+        );
+        Manager::new(domain_separator.clone(), context, Checks::new(checks))
+    };
+    let new_receipt = || {
+        EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 20).unwrap(),
+            &keys.0,
+        )
+        .unwrap()
+    };
+
+    // Aggregate the receipt, then drop the aggregated receipts from storage
+    let signed_receipt = new_receipt();
+    let manager = start_manager();
+    manager
+        .verify_and_store_receipt(signed_receipt.clone())
+        .await
+        .unwrap();
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    let signed_rav =
+        EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav.clone(), &keys.0)
+            .unwrap();
+    manager
+        .verify_and_store_rav(rav_request.expected_rav, signed_rav)
+        .await
+        .unwrap();
+    manager.remove_obsolete_receipts().await.unwrap();
+
+    // After a restart, the aggregated receipt is still rejected, the RAV being its lower bound
+    let manager = start_manager();
+    assert!(matches!(
+        manager.verify_and_store_receipt(signed_receipt).await,
+        Err(Error::ReceiptError(ReceiptError::NonUniqueReceipt))
+    ));
+
+    // New receipts are accepted, and do not overwrite the stored ones across restarts
+    let mut signed_receipts = Vec::new();
+    for _ in 0..3 {
+        let signed_receipt = new_receipt();
+        start_manager()
+            .verify_and_store_receipt(signed_receipt.clone())
+            .await
+            .unwrap();
+        signed_receipts.push(signed_receipt);
+    }
+    assert_eq!(receipt_storage.read().unwrap().len(), 2);
+
+    // The receipt evicted from the storage, but never aggregated, is still rejected after a restart
+    assert!(matches!(
+        start_manager()
+            .verify_and_store_receipt(signed_receipts[0].clone())
+            .await,
+        Err(Error::ReceiptError(ReceiptError::NonUniqueReceipt))
+    ));
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_verify_and_store_rav_inflated_value(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    for _ in 0..3 {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 20).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.expected_rav.valueAggregate, 60);
+
+    // The aggregator over-credits the receipts, with a valid signature
+    let inflated_rav = ReceiptAggregateVoucher {
+        valueAggregate: rav_request.expected_rav.valueAggregate + 1,
+        ..rav_request.expected_rav.clone()
+    };
+    let signed_rav = EIP712SignedMessage::new(&domain_separator, inflated_rav, &keys.0).unwrap();
+
+    assert!(matches!(
+        manager
+            .verify_and_store_rav(rav_request.expected_rav, signed_rav)
+            .await,
+        Err(Error::InvalidReceivedRAV { .. })
+    ));
+    assert!(context.last_rav(allocation_ids[0]).await.unwrap().is_none());
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_verify_and_store_rav_rejects_regression(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    for _ in 0..3 {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 20).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    let stored_rav = rav_request.expected_rav.clone();
+    let signed_rav =
+        EIP712SignedMessage::new(&domain_separator, stored_rav.clone(), &keys.0).unwrap();
+    manager
+        .verify_and_store_rav(rav_request.expected_rav, signed_rav.clone())
+        .await
+        .unwrap();
+
+    // An older RAV of the same allocation, e.g. replayed by the aggregator
+    let older_rav = ReceiptAggregateVoucher {
+        valueAggregate: stored_rav.valueAggregate - 20,
+        ..stored_rav.clone()
+    };
+    let signed_older_rav =
+        EIP712SignedMessage::new(&domain_separator, older_rav.clone(), &keys.0).unwrap();
+    assert!(matches!(
+        manager
+            .verify_and_store_rav(older_rav.clone(), signed_older_rav.clone())
+            .await,
+        Err(Error::RavRegression { .. })
+    ));
+    assert_eq!(
+        context.last_rav(allocation_ids[0]).await.unwrap(),
+        Some(signed_rav.clone())
+    );
+
+    // A RAV of another allocation stored in between doesn't hide the regression
+    let other_rav = ReceiptAggregateVoucher {
+        allocationId: allocation_ids[1],
+        timestampNs: 1,
+        valueAggregate: 5,
+    };
+    let signed_other_rav =
+        EIP712SignedMessage::new(&domain_separator, other_rav.clone(), &keys.0).unwrap();
+    manager
+        .verify_and_store_rav(other_rav, signed_other_rav.clone())
+        .await
+        .unwrap();
+    assert!(matches!(
+        manager
+            .verify_and_store_rav(older_rav, signed_older_rav)
+            .await,
+        Err(Error::RavRegression { .. })
+    ));
+    assert_eq!(
+        context.last_rav(allocation_ids[0]).await.unwrap(),
+        Some(signed_rav.clone())
+    );
+    assert_eq!(
+        context.last_rav(allocation_ids[1]).await.unwrap(),
+        Some(signed_other_rav)
+    );
+
+    // Storing the same RAV again is not a regression
+    manager
+        .verify_and_store_rav(stored_rav, signed_rav)
+        .await
+        .unwrap();
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_reclaim_expired_reservations(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let clock = Arc::new(ManualClock::new(1000));
+    let context = context
+        .with_clock(clock.clone())
+        .with_reservation_ttl(Duration::from_nanos(100));
+    let manager =
+        Manager::new(domain_separator.clone(), context.clone(), checks).with_clock(clock.clone());
+    escrow_storage.write().unwrap().insert(keys.1, 100);
+
+    for timestamp_ns in [100, 200] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt {
+                allocation_id: allocation_ids[0],
+                timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                nonce: timestamp_ns,
+                value: 20,
+                metadata: None,
+                parent: None,
+            },
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    // The RAV request reserves the escrow, but the RAV never comes back
+    manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(context.escrow(keys.1).unwrap(), 60);
+
+    // The reservation is kept until it expires
+    clock.advance(99);
+    assert_eq!(manager.reclaim_expired_reservations().await.unwrap(), 0);
+    assert_eq!(context.escrow(keys.1).unwrap(), 60);
+
+    clock.advance(1);
+    assert_eq!(manager.reclaim_expired_reservations().await.unwrap(), 40);
+    assert_eq!(context.escrow(keys.1).unwrap(), 100);
+
+    // Once the RAV is stored, the reservation is committed and never reclaimed
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    let signed_rav =
+        EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav.clone(), &keys.0)
+            .unwrap();
+    manager
+        .verify_and_store_rav(rav_request.expected_rav, signed_rav)
+        .await
+        .unwrap();
+    clock.advance(100);
+    assert_eq!(manager.reclaim_expired_reservations().await.unwrap(), 0);
+    assert_eq!(context.escrow(keys.1).unwrap(), 60);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_export_import_pending_receipts(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+    #[from(context)] new_context: ContextFixture,
+) {
+    let signed_receipt = |allocation_id: Address, timestamp_ns: u64| {
+        EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt {
+                allocation_id,
+                timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                nonce: timestamp_ns,
+                value: 20,
+                metadata: None,
+                parent: None,
+            },
+            &keys.0,
+        )
+        .unwrap()
+    };
+
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    // The first receipts are aggregated into a RAV
+    let receipts = [
+        signed_receipt(allocation_ids[0], 100),
+        signed_receipt(allocation_ids[0], 200),
+    ];
+    for signed_receipt in receipts {
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    let signed_rav =
+        EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav.clone(), &keys.0)
+            .unwrap();
+    manager
+        .verify_and_store_rav(rav_request.expected_rav, signed_rav.clone())
+        .await
+        .unwrap();
+
+    let receipts = [
+        signed_receipt(allocation_ids[0], 300),
+        signed_receipt(allocation_ids[0], 400),
+        signed_receipt(allocation_ids[1], 500),
+    ];
+    for signed_receipt in receipts {
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    // Only the receipts of the allocation that are not covered by the RAV are exported
+    let exported_receipts = manager
+        .export_pending_receipts(allocation_ids[0])
+        .await
+        .unwrap();
+    assert_eq!(
+        exported_receipts
+            .iter()
+            .map(|signed_receipt| signed_receipt.message.timestamp_ns.as_nanos())
+            .collect::<Vec<_>>(),
+        vec![300, 400]
+    );
+
+    // The new instance resumes from the RAV and the exported receipts
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+    } = new_context;
+    let imported_manager = Manager::new(domain_separator.clone(), context, checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+    for signed_receipt in &exported_receipts {
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+    }
+    imported_manager
+        .adopt_rav(signed_rav.clone())
+        .await
+        .unwrap();
+    assert!(imported_manager
+        .import_receipts(exported_receipts.clone())
+        .await
+        .iter()
+        .all(Result::is_ok));
+
+    // Importing the same receipts again fails, as they are already stored
+    assert!(imported_manager
+        .import_receipts(exported_receipts.clone())
+        .await
+        .iter()
+        .all(Result::is_err));
+
+    // The next RAV is the one the original instance would have produced for that allocation
+    let rav_request = imported_manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        rav_request.expected_rav,
+        ReceiptAggregateVoucher::aggregate_receipts(
+            allocation_ids[0],
+            &exported_receipts,
+            Some(signed_rav)
+        )
+        .unwrap()
+    );
+    assert_eq!(rav_request.expected_rav.valueAggregate, 80);
+}
+
+/// Rejects every receipt, counting how many times it was executed.
+struct RejectAllCheck(Arc<AtomicUsize>);
+
+#[async_trait::async_trait]
+impl Check for RejectAllCheck {
+    async fn check(&self, _receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Err(anyhow::anyhow!("Rejected"))
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_disabled_checks_are_never_executed(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        escrow_storage,
+        ..
+    } = context;
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    let executions = Arc::new(AtomicUsize::new(0));
+    let mut checks = checks.to_vec();
+    checks.push(Arc::new(RejectAllCheck(executions.clone())) as ReceiptCheck);
+    let checks = Checks::new(checks);
+
+    let signed_receipt = |timestamp_ns: u64| {
+        EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt {
+                allocation_id: allocation_ids[0],
+                timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                nonce: timestamp_ns,
+                value: 20,
+                metadata: None,
+                parent: None,
+            },
+            &keys.0,
+        )
+        .unwrap()
+    };
+
+    // Without disabling it, the check rejects the receipt
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks.clone());
+    assert!(manager
+        .verify_and_store_receipt(signed_receipt(100))
+        .await
+        .is_err());
+    assert_eq!(executions.load(Ordering::SeqCst), 1);
+
+    // Once disabled, the check is skipped, although it is part of the provided checks
+    executions.store(0, Ordering::SeqCst);
+    let reject_all_check: ReceiptCheck = Arc::new(RejectAllCheck(executions.clone()));
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks.clone())
+        .with_disabled_checks(HashSet::from([reject_all_check]))
+        .unwrap();
+    manager
+        .verify_and_store_receipt(signed_receipt(200))
+        .await
+        .unwrap();
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts.len(), 1);
+    assert_eq!(executions.load(Ordering::SeqCst), 0);
+
+    // A check that the manager doesn't have cannot be disabled
+    let unknown_check: ReceiptCheck = Arc::new(TimestampCheck::new(0));
+    let manager = Manager::new(
+        domain_separator,
+        context,
+        Checks::new(vec![Arc::new(RejectAllCheck(executions)) as ReceiptCheck]),
+    );
+    assert!(matches!(
+        manager.with_disabled_checks(HashSet::from([unknown_check])),
+        Err(Error::InvalidCheckError { .. })
+    ));
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_prune_failed_receipts(
+    keys: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context, checks, ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks.clone())
+        .with_clock(Arc::new(ManualClock::new(10_000)));
+
+    // Receipts for an unknown allocation fail their checks
+    for timestamp_ns in [1_000, 5_000, 8_000, 9_500] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt {
+                allocation_id: Address::from([0x99u8; 20]),
+                timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                nonce: timestamp_ns,
+                value: 20,
+                metadata: None,
+                parent: None,
+            },
+            &keys.0,
+        )
+        .unwrap();
+        let failed = ReceiptWithState::new(signed_receipt)
+            .finalize_receipt_checks(&checks)
+            .await
+            .unwrap_err();
+        context.store_failed_receipt(failed).await.unwrap();
+    }
+
+    // Only the receipts older than the retention window are removed
+    assert_eq!(
+        manager
+            .prune_failed_receipts(Duration::from_nanos(2_000))
+            .await
+            .unwrap(),
+        2
+    );
+    let remaining_timestamps = context
+        .retrieve_failed_receipts_in_timestamp_range(..)
+        .await
+        .unwrap()
+        .iter()
+        .map(|failed| failed.signed_receipt().message.timestamp_ns.as_nanos())
+        .collect::<Vec<_>>();
+    assert_eq!(remaining_timestamps, vec![8_000, 9_500]);
+
+    // Pruning again with the same window is a no-op
+    assert_eq!(
+        manager
+            .prune_failed_receipts(Duration::from_nanos(2_000))
+            .await
+            .unwrap(),
+        0
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_active_senders(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context, checks, ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+    assert!(manager.active_senders().await.unwrap().is_empty());
+
+    let other_wallet: LocalWallet = MnemonicBuilder::<English>::default()
+        .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+        .index(1u32)
+        .unwrap()
+        .build()
+        .unwrap();
+    let other_address = Address::from(<[u8; 20]>::from(other_wallet.address()));
+    assert_ne!(other_address, keys.1);
+
+    // Two receipts from each sender
+    for wallet in [&keys.0, &other_wallet, &keys.0, &other_wallet] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 20).unwrap(),
+            wallet,
+        )
+        .unwrap();
+        context
+            .store_receipt(ReceiptWithState::new(signed_receipt))
+            .await
+            .unwrap();
+    }
+
+    let mut expected_senders = vec![keys.1, other_address];
+    expected_senders.sort();
+    assert_eq!(manager.active_senders().await.unwrap(), expected_senders);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_cancel_rav_request(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+    // Just enough escrow for the receipts to be reserved once
+    escrow_storage.write().unwrap().insert(keys.1, 40);
+
+    for _ in 0..2 {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 20).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    let selected_receipts = rav_request.valid_receipts.clone();
+    assert_eq!(selected_receipts.len(), 2);
+    assert_eq!(context.escrow(keys.1).unwrap(), 0);
+
+    // While the request is pending, its receipts can't be reserved again
+    assert!(matches!(
+        manager.create_rav_request(Duration::ZERO, None).await,
+        Err(Error::NoValidReceiptsForRAVRequest)
+    ));
+
+    manager.cancel_rav_request(rav_request).await.unwrap();
+    assert_eq!(context.escrow(keys.1).unwrap(), 40);
+
+    // The same receipts are selected by the next request
+    let rav_request = manager
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.valid_receipts, selected_receipts);
+}
+
+/// [`InMemoryContext`] whose transactions fail to store receipts, to interrupt the manager
+/// mid-transaction.
+struct FailingStoreContext(InMemoryContext);
+
+/// Transaction of a [`FailingStoreContext`]. Before failing to store a receipt, it deposits escrow
+/// outside of the transaction, as a concurrent writer would.
+struct FailingStoreTransaction {
+    transaction: InMemoryTransaction,
+    context: InMemoryContext,
+}
+
+#[async_trait::async_trait]
+impl ReceiptStore for FailingStoreTransaction {
+    type AdapterError = InMemoryError;
+
+    async fn store_receipt(
+        &self,
+        _receipt: ReceiptWithState<Checking>,
+    ) -> Result<u64, Self::AdapterError> {
+        unimplemented!()
+    }
+
+    async fn check_and_store_unique(
+        &self,
+        receipt: ReceiptWithState<Checking>,
+    ) -> Result<Option<u64>, Self::AdapterError> {
+        let sender_id = receipt
+            .signed_receipt()
+            .recover_signer(&domain_separator())
+            .unwrap();
+        self.context.deposit(sender_id, 5).await.unwrap();
+        Err(InMemoryError::AdapterError {
+            error: "storage unavailable".to_owned(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EscrowHandler for FailingStoreTransaction {
+    type AdapterError = InMemoryError;
+
+    async fn get_available_escrow(&self, sender_id: Address) -> Result<u128, Self::AdapterError> {
+        self.transaction.get_available_escrow(sender_id).await
+    }
+
+    async fn subtract_escrow(
+        &self,
+        sender_id: Address,
+        value: u128,
+    ) -> Result<(), Self::AdapterError> {
+        self.transaction.subtract_escrow(sender_id, value).await
+    }
+
+    async fn deposit(&self, sender_id: Address, value: u128) -> Result<(), Self::AdapterError> {
+        self.transaction.deposit(sender_id, value).await
+    }
+
+    async fn withdraw(&self, sender_id: Address, value: u128) -> Result<(), Self::AdapterError> {
+        self.transaction.withdraw(sender_id, value).await
+    }
+
+    async fn verify_signer(&self, signer_address: Address) -> Result<bool, Self::AdapterError> {
+        self.transaction.verify_signer(signer_address).await
+    }
+
+    async fn check_and_reserve_escrow(
+        &self,
+        received_receipt: &ReceiptWithState<AwaitingReserve>,
+        domain_separator: &Eip712Domain,
+    ) -> ReceiptResult<()> {
+        self.transaction
+            .check_and_reserve_escrow(received_receipt, domain_separator)
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl Transaction for FailingStoreContext {
+    type AdapterError = InMemoryError;
+    type Handle = FailingStoreTransaction;
+
+    async fn begin_transaction(&self) -> Result<Self::Handle, Self::AdapterError> {
+        Ok(FailingStoreTransaction {
+            transaction: self.0.begin_transaction().await?,
+            context: self.0.clone(),
+        })
+    }
+
+    async fn commit_transaction(
+        &self,
+        transaction: Self::Handle,
+    ) -> Result<(), Self::AdapterError> {
+        self.0.commit_transaction(transaction.transaction).await
+    }
+
+    async fn rollback_transaction(
+        &self,
+        transaction: Self::Handle,
+    ) -> Result<(), Self::AdapterError> {
+        self.0.rollback_transaction(transaction.transaction).await
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_verify_and_store_receipt_with_state_rolls_back(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(
+        domain_separator.clone(),
+        FailingStoreContext(context.clone()),
+        checks,
+    );
+    escrow_storage.write().unwrap().insert(keys.1, 30);
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], 20).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    query_appraisals
+        .write()
+        .unwrap()
+        .insert(signed_receipt.unique_hash(), 20);
+
+    // The receipt's escrow is reserved, then storing the receipt fails
+    let res = manager
+        .verify_and_store_receipt_with_state(signed_receipt.clone())
+        .await;
+    assert!(matches!(res, Err(Error::AdapterError { .. })));
+
+    // Neither the receipt nor the reservation remain, but the deposit made meanwhile does
+    assert!(context
+        .retrieve_receipts_in_timestamp_range(.., None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 35);
+
+    // The transaction ended, the context is usable again
+    let manager = Manager::new(
+        domain_separator.clone(),
+        context.clone(),
+        Checks::new(vec![]),
+    );
+    let outcome = manager
+        .verify_and_store_receipt_with_state(signed_receipt)
+        .await
+        .unwrap();
+    assert!(matches!(outcome, ReceiptOutcome::Reserved(_)));
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 15);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_verify_and_store_receipt_with_state_propagates_reserve_errors(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        escrow_storage,
+        ..
+    } = context;
+    // Without checks, the signer is first recovered when reserving the escrow
+    let manager = Manager::new(
+        domain_separator.clone(),
+        context.clone(),
+        Checks::new(vec![]),
+    );
+    escrow_storage.write().unwrap().insert(keys.1, 30);
+
+    let mut signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], 20).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    signed_receipt.signature.r = U256::ZERO;
+
+    // The receipt is not left awaiting escrow, as its escrow can never be reserved
+    let res = manager
+        .verify_and_store_receipt_with_state(signed_receipt)
+        .await;
+    assert!(matches!(res, Err(Error::AdapterError { .. })));
+    assert!(context
+        .retrieve_receipts_in_timestamp_range(.., None)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 30);
+}
+
+#[rstest]
+#[case::commit(true)]
+#[case::rollback(false)]
+#[tokio::test]
+async fn in_memory_transaction_is_isolated(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+    #[case] commit: bool,
+) {
+    let ContextFixture {
+        context,
+        escrow_storage,
+        ..
+    } = context;
+    escrow_storage.write().unwrap().insert(keys.1, 30);
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], 20).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+
+    let transaction = context.begin_transaction().await.unwrap();
+    assert!(transaction
+        .check_and_store_unique(ReceiptWithState::new(signed_receipt.clone()))
+        .await
+        .unwrap()
+        .is_some());
+    transaction.subtract_escrow(keys.1, 20).await.unwrap();
+
+    // The transaction sees its own operations, the context doesn't until they are committed
+    assert_eq!(transaction.get_available_escrow(keys.1).await.unwrap(), 10);
+    assert!(transaction
+        .check_and_store_unique(ReceiptWithState::new(signed_receipt.clone()))
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(context.escrow(keys.1).unwrap(), 30);
+    assert!(context
+        .retrieve_receipts_in_timestamp_range(.., None)
+        .await
+        .unwrap()
+        .is_empty());
+
+    if commit {
+        context.commit_transaction(transaction).await.unwrap();
+        assert_eq!(context.escrow(keys.1).unwrap(), 10);
+        assert_eq!(
+            context
+                .retrieve_receipts_in_timestamp_range(.., None)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    } else {
+        context.rollback_transaction(transaction).await.unwrap();
+        assert_eq!(context.escrow(keys.1).unwrap(), 30);
+        assert!(context
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn in_memory_transaction_commit_applies_nothing_on_conflict(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        escrow_storage,
+        ..
+    } = context;
+    escrow_storage.write().unwrap().insert(keys.1, 30);
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], 20).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+
+    let transaction = context.begin_transaction().await.unwrap();
+    transaction.subtract_escrow(keys.1, 20).await.unwrap();
+    transaction
+        .check_and_store_unique(ReceiptWithState::new(signed_receipt.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The same receipt is stored outside of the transaction meanwhile
+    context
+        .check_and_store_unique(ReceiptWithState::new(signed_receipt))
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(context.commit_transaction(transaction).await.is_err());
+    assert_eq!(context.escrow(keys.1).unwrap(), 30);
+    assert_eq!(
+        context
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+/// Context taking `latency` to store each receipt, as a remote database would.
+struct SlowStoreContext {
+    context: InMemoryContext,
+    latency: Duration,
+}
+
+#[async_trait::async_trait]
+impl ReceiptStore for SlowStoreContext {
+    type AdapterError = InMemoryError;
+
+    async fn store_receipt(
+        &self,
+        receipt: ReceiptWithState<Checking>,
+    ) -> Result<u64, Self::AdapterError> {
+        tokio::time::sleep(self.latency).await;
+        self.context.store_receipt(receipt).await
+    }
+
+    async fn check_and_store_unique(
+        &self,
+        receipt: ReceiptWithState<Checking>,
+    ) -> Result<Option<u64>, Self::AdapterError> {
+        tokio::time::sleep(self.latency).await;
+        self.context.check_and_store_unique(receipt).await
+    }
+}
+
+#[rstest]
+#[case::sequential(1)]
+#[case::concurrent(8)]
+#[tokio::test]
+async fn manager_verify_and_store_receipts_concurrently(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+    #[case] store_concurrency: usize,
+) {
+    let latency = Duration::from_millis(50);
+    let manager = Manager::new(
+        domain_separator.clone(),
+        SlowStoreContext {
+            context: context.context.clone(),
+            latency,
+        },
+        Checks::new(vec![]),
+    )
+    .with_store_concurrency(store_concurrency);
+
+    let signed_receipts = (0..8)
+        .map(|_| {
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_ids[0], 20).unwrap(),
+                &keys.0,
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    // One of the receipts is already stored, so that its result stands out
+    manager
+        .verify_and_store_receipt(signed_receipts[3].clone())
+        .await
+        .unwrap();
+
+    let start = std::time::Instant::now();
+    let results = manager.verify_and_store_receipts(signed_receipts).await;
+    let elapsed = start.elapsed();
+
+    // Results are in the order of the receipts
+    assert_eq!(results.len(), 8);
+    for (index, result) in results.iter().enumerate() {
+        assert_eq!(result.is_ok(), index != 3);
+    }
+    assert_eq!(
+        context
+            .context
+            .retrieve_receipts_in_timestamp_range(.., None)
+            .await
+            .unwrap()
+            .len(),
+        8
+    );
+
+    // The storage latency is paid once per batch of `store_concurrency` receipts
+    if store_concurrency == 1 {
+        assert!(elapsed >= latency * 8);
+    } else {
+        assert!(elapsed < latency * 4);
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_merge_pending_from(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture { context, .. } = context;
+    let manager = Manager::new(
+        domain_separator.clone(),
+        context.clone(),
+        Checks::new(vec![]),
+    );
+    let signed_receipts = (0..8)
+        .map(|_| {
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_ids[0], 20).unwrap(),
+                &keys.0,
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    // Both instances received receipts 3 and 4
+    for signed_receipt in &signed_receipts[..5] {
+        manager
+            .verify_and_store_receipt(signed_receipt.clone())
+            .await
+            .unwrap();
+    }
+    let other_receipts = signed_receipts[3..].to_vec();
+
+    assert_eq!(
+        manager
+            .merge_pending_from(other_receipts.clone())
+            .await
+            .unwrap(),
+        3
+    );
+    let stored = context
+        .retrieve_receipts_in_timestamp_range(.., None)
+        .await
+        .unwrap();
+    assert_eq!(stored.len(), 8);
+    let stored_hashes: HashSet<_> = stored
+        .iter()
+        .map(|receipt| receipt.signed_receipt().unique_hash())
+        .collect();
+    assert_eq!(
+        stored_hashes,
+        signed_receipts.iter().map(|r| r.unique_hash()).collect()
+    );
+
+    // Merging again adds nothing
+    assert_eq!(manager.merge_pending_from(other_receipts).await.unwrap(), 0);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_merge_pending_from_verifies_receipts(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        checks,
+        query_appraisals,
+        escrow_storage,
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context.clone(), checks);
+    escrow_storage.write().unwrap().insert(keys.1, 999999);
+
+    let unknown_wallet: LocalWallet = MnemonicBuilder::<English>::default()
+        .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+        .index(1u32)
+        .unwrap()
+        .build()
+        .unwrap();
+    let mut other_receipts = vec![];
+    for wallet in [&keys.0, &unknown_wallet, &keys.0] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_ids[0], 20).unwrap(),
+            wallet,
+        )
+        .unwrap();
+        query_appraisals
+            .write()
+            .unwrap()
+            .insert(signed_receipt.unique_hash(), 20);
+        other_receipts.push(signed_receipt);
+    }
+    // The value of the last receipt is altered after signing, its signature no longer matches
+    other_receipts[2].message.value = 2000;
+    query_appraisals
+        .write()
+        .unwrap()
+        .insert(other_receipts[2].unique_hash(), 2000);
+
+    // Only the receipt of the known sender with a valid signature is merged, and its escrow is
+    // reserved
+    assert_eq!(
+        manager
+            .merge_pending_from(other_receipts.clone())
+            .await
+            .unwrap(),
+        1
+    );
+    let stored = context
+        .retrieve_receipts_in_timestamp_range(.., None)
+        .await
+        .unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].signed_receipt(), &other_receipts[0]);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 999999 - 20);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_allocation_view(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, Checks::new(vec![]));
+    escrow_storage.write().unwrap().insert(keys.1, 1000);
+    for (allocation_id, value) in [
+        (allocation_ids[0], 20),
+        (allocation_ids[1], 40),
+        (allocation_ids[0], 30),
+    ] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_id, value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        manager
+            .for_allocation(allocation_id)
+            .store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    let allocation = manager.for_allocation(allocation_ids[0]);
+    assert_eq!(allocation.count_receipts().await.unwrap(), 2);
+    assert_eq!(allocation.remaining_escrow(keys.1).await.unwrap(), 950);
+    assert_eq!(
+        manager
+            .for_allocation(allocation_ids[1])
+            .remaining_escrow(keys.1)
+            .await
+            .unwrap(),
+        960
+    );
+
+    // Only the receipts of the allocation are aggregated, and only their escrow is reserved
+    let rav_request = allocation
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.expected_rav.allocationId, allocation_ids[0]);
+    assert_eq!(rav_request.valid_receipts.len(), 2);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 50);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 950);
+
+    // The reserved receipts are not deducted again while the RAV request is outstanding
+    assert_eq!(allocation.remaining_escrow(keys.1).await.unwrap(), 950);
+    assert_eq!(
+        manager
+            .for_allocation(allocation_ids[1])
+            .remaining_escrow(keys.1)
+            .await
+            .unwrap(),
+        910
+    );
+
+    let rav_request = manager
+        .for_allocation(allocation_ids[1])
+        .create_rav_request(Duration::ZERO, None)
+        .await
+        .unwrap();
+    assert_eq!(rav_request.expected_rav.allocationId, allocation_ids[1]);
+    assert_eq!(rav_request.expected_rav.valueAggregate, 40);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 910);
+}
+
+#[rstest]
+#[tokio::test]
+async fn manager_finalize_allocation_leaves_other_allocations(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        context,
+        escrow_storage,
+        ..
+    } = context;
+    let manager = Manager::new(domain_separator.clone(), context, Checks::new(vec![]));
+    escrow_storage.write().unwrap().insert(keys.1, 1000);
+    for (allocation_id, value) in [
+        (allocation_ids[0], 20),
+        (allocation_ids[1], 40),
+        (allocation_ids[0], 30),
+    ] {
+        let signed_receipt = EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_id, value).unwrap(),
+            &keys.0,
+        )
+        .unwrap();
+        manager
+            .verify_and_store_receipt(signed_receipt)
+            .await
+            .unwrap();
+    }
+
+    // Signs the expected RAV, as the aggregator would
+    let aggregator_client = |rav_request: RAVRequest| {
+        let signed_rav =
+            EIP712SignedMessage::new(&domain_separator, rav_request.expected_rav, &keys.0);
+        async move { signed_rav }
+    };
+    let final_rav = manager
+        .finalize_allocation(allocation_ids[0], aggregator_client)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(final_rav.message.allocationId, allocation_ids[0]);
+    assert_eq!(final_rav.message.valueAggregate, 50);
+
+    // The receipts of the other allocation are still pending, and their escrow isn't reserved
+    assert_eq!(manager.count_receipts(allocation_ids[1]).await.unwrap(), 1);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 950);
+
+    // A RAV for another allocation than the finalized one is rejected
+    let misdirected_aggregator_client = |rav_request: RAVRequest| {
+        let signed_rav = EIP712SignedMessage::new(
+            &domain_separator,
+            ReceiptAggregateVoucher {
+                allocationId: allocation_ids[2],
+                ..rav_request.expected_rav
+            },
+            &keys.0,
+        );
+        async move { signed_rav }
+    };
+    assert!(matches!(
+        manager
+            .finalize_allocation(allocation_ids[1], misdirected_aggregator_client)
+            .await,
+        Err(Error::RavAllocationIdMismatch { .. })
+    ));
+    assert_eq!(manager.count_receipts(allocation_ids[1]).await.unwrap(), 1);
+    assert_eq!(escrow_storage.read().unwrap()[&keys.1], 950);
+}
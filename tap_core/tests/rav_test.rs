@@ -5,7 +5,7 @@ use std::collections::HashMap;
 use std::sync::RwLock;
 use std::{str::FromStr, sync::Arc};
 
-use alloy_primitives::Address;
+use alloy_primitives::{Address, I256};
 use alloy_sol_types::Eip712Domain;
 use ethers::signers::coins_bip39::English;
 use ethers::signers::{LocalWallet, MnemonicBuilder};
@@ -13,11 +13,13 @@ use rstest::*;
 
 use tap_core::manager::context::memory::InMemoryContext;
 use tap_core::{
+    ethers_compat::convert_address,
     manager::adapters::{RAVRead, RAVStore},
-    rav::ReceiptAggregateVoucher,
+    rav::{ChainError, RavDiff, ReceiptAggregateVoucher, SignedRAV},
     receipt::{checks::TimestampCheck, Receipt},
     signed_message::EIP712SignedMessage,
     tap_eip712_domain,
+    timestamp::TimestampNs,
 };
 
 #[fixture]
@@ -28,7 +30,7 @@ fn domain_separator() -> Eip712Domain {
 #[fixture]
 fn context() -> InMemoryContext {
     let escrow_storage = Arc::new(RwLock::new(HashMap::new()));
-    let rav_storage = Arc::new(RwLock::new(None));
+    let rav_storage = Arc::new(RwLock::new(HashMap::new()));
     let receipt_storage = Arc::new(RwLock::new(HashMap::new()));
 
     let timestamp_check = Arc::new(TimestampCheck::new(0));
@@ -48,10 +50,7 @@ async fn rav_storage_adapter_test(domain_separator: Eip712Domain, context: InMem
          .build()
          .unwrap();
 
-    let allocation_id: [u8; 20] = Address::from_str("0xabababababababababababababababababababab")
-        .unwrap()
-        .into();
-    let allocation_id = allocation_id.into();
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
 
     // Create receipts
     let mut receipts = Vec::new();
@@ -76,7 +75,7 @@ async fn rav_storage_adapter_test(domain_separator: Eip712Domain, context: InMem
     context.update_last_rav(signed_rav.clone()).await.unwrap();
 
     // Retreive rav
-    let retrieved_rav = context.last_rav().await;
+    let retrieved_rav = context.last_rav(allocation_id).await;
     assert!(retrieved_rav.unwrap().unwrap() == signed_rav);
 
     // Testing the last rav update...
@@ -105,6 +104,336 @@ async fn rav_storage_adapter_test(domain_separator: Eip712Domain, context: InMem
     context.update_last_rav(signed_rav.clone()).await.unwrap();
 
     // Retreive rav
-    let retrieved_rav = context.last_rav().await;
+    let retrieved_rav = context.last_rav(allocation_id).await;
     assert!(retrieved_rav.unwrap().unwrap() == signed_rav);
+
+    // The RAV of another allocation is stored alongside
+    let other_allocation_id =
+        Address::from_str("0xdeaddeaddeaddeaddeaddeaddeaddeaddeaddead").unwrap();
+    let other_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(other_allocation_id, 70).unwrap(),
+        &wallet,
+    )
+    .unwrap();
+    let other_signed_rav = EIP712SignedMessage::new(
+        &domain_separator,
+        ReceiptAggregateVoucher::aggregate_receipts(other_allocation_id, &[other_receipt], None)
+            .unwrap(),
+        &wallet,
+    )
+    .unwrap();
+    context
+        .update_last_rav(other_signed_rav.clone())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        context.last_rav(allocation_id).await.unwrap(),
+        Some(signed_rav.clone())
+    );
+    assert_eq!(
+        context.last_rav(other_allocation_id).await.unwrap(),
+        Some(other_signed_rav.clone())
+    );
+    assert_eq!(
+        context.load_latest_ravs().await.unwrap(),
+        HashMap::from([
+            (allocation_id, signed_rav),
+            (other_allocation_id, other_signed_rav)
+        ])
+    );
+}
+
+#[rstest]
+#[test]
+fn signed_rav_to_redeem_calldata(domain_separator: Eip712Domain) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+
+    let signed_rav = EIP712SignedMessage::new(
+        &domain_separator,
+        ReceiptAggregateVoucher {
+            allocationId: allocation_id,
+            timestampNs: 1234,
+            valueAggregate: 5678,
+        },
+        &wallet,
+    )
+    .unwrap();
+    let allocation_id_proof = [0x42u8; 65];
+
+    let calldata = signed_rav.to_redeem_calldata(&allocation_id_proof);
+
+    // keccak256("redeem(((address,uint64,uint128),bytes),bytes)")[..4]
+    assert_eq!(calldata[..4], [0x45, 0x6b, 0x43, 0x16]);
+
+    let words = calldata[4..].chunks(32).collect::<Vec<_>>();
+    let word = |value: u64| {
+        let mut word = [0u8; 32];
+        word[24..].copy_from_slice(&value.to_be_bytes());
+        word
+    };
+    // Head: offsets of the signed RAV tuple and of the proof
+    assert_eq!(words[0], word(0x40));
+    assert_eq!(words[1], word(0x140));
+    // Signed RAV tuple: RAV fields, then offset, length and padded data of the signature
+    assert_eq!(words[2][..12], [0u8; 12]);
+    assert_eq!(words[2][12..], allocation_id.0[..]);
+    assert_eq!(words[3], word(1234));
+    assert_eq!(words[4], word(5678));
+    assert_eq!(words[5], word(0x80));
+    assert_eq!(words[6], word(65));
+    assert_eq!(
+        words[7..10].concat()[..65],
+        signed_rav.signature.to_vec()[..]
+    );
+    // Proof: length and padded data
+    assert_eq!(words[10], word(65));
+    assert_eq!(words[11..14].concat()[..65], allocation_id_proof[..]);
+    assert_eq!(calldata.len(), 4 + 14 * 32);
+}
+
+#[rstest]
+#[test]
+fn signed_rav_split(domain_separator: Eip712Domain) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let signed_rav = EIP712SignedMessage::new(
+        &domain_separator,
+        ReceiptAggregateVoucher {
+            allocationId: Address::from_str("0xabababababababababababababababababababab").unwrap(),
+            timestampNs: 1234,
+            valueAggregate: 5678,
+        },
+        &wallet,
+    )
+    .unwrap();
+
+    let (rav, signature) = signed_rav.split();
+    assert_eq!(rav, signed_rav.message);
+    assert_eq!(signature[..], signed_rav.signature.to_vec()[..]);
+    assert!(signature[64] == 27 || signature[64] == 28);
+    let parity = signature[64] - 27;
+
+    // `ecrecover` only accepts a `v` of 27 or 28
+    for v in [
+        u64::from(parity),
+        u64::from(parity) + 27,
+        u64::from(parity) + 35 + 2 * 1337,
+    ] {
+        let mut signed_rav = signed_rav.clone();
+        signed_rav.signature.v = v;
+        let (_, split_signature) = signed_rav.split();
+        assert_eq!(split_signature, signature);
+    }
+}
+
+#[rstest]
+#[test]
+fn aggregate_onto_zero_rav(domain_separator: Eip712Domain) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+    let opened_at = TimestampNs::from_nanos(1000);
+
+    let zero_rav = SignedRAV::zero(&domain_separator, allocation_id, opened_at, &wallet).unwrap();
+    assert_eq!(
+        zero_rav.message,
+        ReceiptAggregateVoucher::zero(allocation_id, opened_at)
+    );
+    assert_eq!(zero_rav.message.valueAggregate, 0);
+    assert_eq!(zero_rav.message.timestamp(), opened_at);
+
+    let receipts = [1300, 1100, 1200]
+        .map(|timestamp_ns| {
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt {
+                    timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                    ..Receipt::new(allocation_id, 42).unwrap()
+                },
+                &wallet,
+            )
+            .unwrap()
+        })
+        .to_vec();
+    assert_eq!(
+        ReceiptAggregateVoucher::aggregate_receipts(allocation_id, &receipts, Some(zero_rav))
+            .unwrap(),
+        ReceiptAggregateVoucher::aggregate_receipts(allocation_id, &receipts, None).unwrap()
+    );
+}
+
+#[rstest]
+#[test]
+fn verify_rav_chain_from_zero(domain_separator: Eip712Domain) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let signer: Address = convert_address(ethers::signers::Signer::address(&wallet));
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+    let opened_at = TimestampNs::from_nanos(1000);
+
+    // Each RAV aggregates new receipts into the previous one, starting from the zero RAV
+    let mut ravs =
+        vec![SignedRAV::zero(&domain_separator, allocation_id, opened_at, &wallet).unwrap()];
+    for timestamps in [vec![1200, 1100], vec![1300], vec![1500, 1400]] {
+        let receipts = timestamps
+            .into_iter()
+            .map(|timestamp_ns| {
+                EIP712SignedMessage::new(
+                    &domain_separator,
+                    Receipt {
+                        timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                        ..Receipt::new(allocation_id, 42).unwrap()
+                    },
+                    &wallet,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let rav = ReceiptAggregateVoucher::aggregate_receipts(
+            allocation_id,
+            &receipts,
+            ravs.last().cloned(),
+        )
+        .unwrap();
+        ravs.push(EIP712SignedMessage::new(&domain_separator, rav, &wallet).unwrap());
+    }
+    assert_eq!(ravs[1].message.timestampNs, 1200);
+    assert_eq!(
+        SignedRAV::verify_chain(&ravs, &domain_separator, signer),
+        Ok(())
+    );
+
+    // The first RAV can't end before the zero RAV
+    let early_rav = ReceiptAggregateVoucher {
+        timestampNs: 900,
+        ..ravs[1].message.clone()
+    };
+    ravs[1] = EIP712SignedMessage::new(&domain_separator, early_rav, &wallet).unwrap();
+    assert_eq!(
+        SignedRAV::verify_chain(&ravs[..2], &domain_separator, signer),
+        Err(ChainError::TimestampRegression {
+            index: 1,
+            previous_timestamp_ns: 1000,
+            timestamp_ns: 900,
+        })
+    );
+}
+
+#[rstest]
+#[test]
+fn verify_rav_chain(domain_separator: Eip712Domain) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let signer: Address = convert_address(ethers::signers::Signer::address(&wallet));
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+
+    // Each RAV aggregates new receipts into the previous one
+    let mut ravs: Vec<SignedRAV> = Vec::new();
+    for values in [vec![10, 20], vec![30], vec![40, 50]] {
+        let receipts = values
+            .into_iter()
+            .map(|value| {
+                EIP712SignedMessage::new(
+                    &domain_separator,
+                    Receipt::new(allocation_id, value).unwrap(),
+                    &wallet,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let rav = ReceiptAggregateVoucher::aggregate_receipts(
+            allocation_id,
+            &receipts,
+            ravs.last().cloned(),
+        )
+        .unwrap();
+        ravs.push(EIP712SignedMessage::new(&domain_separator, rav, &wallet).unwrap());
+    }
+    assert_eq!(
+        SignedRAV::verify_chain(&ravs, &domain_separator, signer),
+        Ok(())
+    );
+
+    // A RAV signed by someone else breaks the chain
+    assert!(matches!(
+        SignedRAV::verify_chain(&ravs, &domain_separator, Address::ZERO),
+        Err(ChainError::InvalidSignature { index: 0, .. })
+    ));
+
+    // A RAV with a lower value than its predecessor breaks the chain
+    let regressed_rav = ReceiptAggregateVoucher {
+        valueAggregate: ravs[0].message.valueAggregate - 1,
+        ..ravs[1].message.clone()
+    };
+    ravs[1] = EIP712SignedMessage::new(&domain_separator, regressed_rav, &wallet).unwrap();
+    assert_eq!(
+        SignedRAV::verify_chain(&ravs, &domain_separator, signer),
+        Err(ChainError::ValueRegression {
+            index: 1,
+            previous_value: 30,
+            value: 29,
+        })
+    );
+}
+
+#[test]
+fn diff_ravs() {
+    let allocation_id = Address::from([0xabu8; 20]);
+    let other_allocation_id = Address::from([0xcdu8; 20]);
+    let expected_rav = ReceiptAggregateVoucher {
+        allocationId: allocation_id,
+        timestampNs: 20,
+        valueAggregate: 100,
+    };
+    let returned_rav = ReceiptAggregateVoucher {
+        allocationId: allocation_id,
+        timestampNs: 25,
+        valueAggregate: 90,
+    };
+
+    let diff = expected_rav.diff(&returned_rav);
+    assert_eq!(
+        diff,
+        RavDiff {
+            allocation_ids: None,
+            timestamp_ns_delta: 5,
+            value_aggregate_delta: I256::try_from(-10i128).unwrap(),
+        }
+    );
+    assert_eq!(diff.to_string(), "timestampNs: +5, valueAggregate: -10");
+
+    let other_rav = ReceiptAggregateVoucher {
+        allocationId: other_allocation_id,
+        valueAggregate: u128::MAX,
+        ..expected_rav.clone()
+    };
+    let diff = expected_rav.diff(&other_rav);
+    assert_eq!(
+        diff.allocation_ids,
+        Some((allocation_id, other_allocation_id))
+    );
+    assert_eq!(
+        diff.value_aggregate_delta,
+        I256::try_from(u128::MAX - 100).unwrap()
+    );
+
+    assert!(expected_rav.diff(&expected_rav).is_empty());
+    assert_eq!(
+        expected_rav.diff(&expected_rav).to_string(),
+        "identical RAVs"
+    );
 }
@@ -2,20 +2,25 @@
 // SPDX-License-Identifier: Apache-2.0
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use tap_core::manager::context::memory::InMemoryContext;
-use tap_core::receipt::{Checking, ReceiptWithState};
+use tap_core::receipt::{validate_single_sender, Checking, ReceiptWithState};
 
-use alloy_primitives::Address;
+use alloy_primitives::{Address, FixedBytes};
 use alloy_sol_types::Eip712Domain;
-use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder};
+use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
 use rstest::*;
 use tap_core::receipt::checks::TimestampCheck;
 use tap_core::{
-    manager::adapters::ReceiptStore, receipt::Receipt, signed_message::EIP712SignedMessage,
+    ethers_compat::convert_address,
+    manager::adapters::{RAVRead, RAVStore, ReceiptDelete, ReceiptRead, ReceiptStore},
+    rav::ReceiptAggregateVoucher,
+    receipt::Receipt,
+    signed_message::EIP712SignedMessage,
     tap_eip712_domain,
+    timestamp::TimestampNs,
 };
 
 #[fixture]
@@ -26,7 +31,7 @@ fn domain_separator() -> Eip712Domain {
 #[fixture]
 fn context() -> InMemoryContext {
     let escrow_storage = Arc::new(RwLock::new(HashMap::new()));
-    let rav_storage = Arc::new(RwLock::new(None));
+    let rav_storage = Arc::new(RwLock::new(HashMap::new()));
     let receipt_storage = Arc::new(RwLock::new(HashMap::new()));
 
     let timestamp_check = Arc::new(TimestampCheck::new(0));
@@ -80,6 +85,69 @@ async fn receipt_adapter_test(domain_separator: Eip712Domain, mut context: InMem
     assert!(context.remove_receipt_by_id(receipt_id).await.is_err());
 }
 
+#[rstest]
+#[tokio::test]
+async fn unique_receipt_adapter_test(domain_separator: Eip712Domain, mut context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+    let received_receipt = ReceiptWithState::new(
+        EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_id, 100).unwrap(),
+            &wallet,
+        )
+        .unwrap(),
+    );
+
+    // A receipt is stored once
+    let receipt_id = context
+        .check_and_store_unique(received_receipt.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(context
+        .check_and_store_unique(received_receipt.clone())
+        .await
+        .unwrap()
+        .is_none());
+
+    // Receipts stored without the check are seen by it too
+    let other_receipt = ReceiptWithState::new(
+        EIP712SignedMessage::new(
+            &domain_separator,
+            Receipt::new(allocation_id, 200).unwrap(),
+            &wallet,
+        )
+        .unwrap(),
+    );
+    context.store_receipt(other_receipt.clone()).await.unwrap();
+    assert!(context
+        .check_and_store_unique(other_receipt)
+        .await
+        .unwrap()
+        .is_none());
+
+    // Once removed, the receipt is still seen, through the replay watermark of its allocation
+    context.remove_receipt_by_id(receipt_id).await.unwrap();
+    assert!(context
+        .check_and_store_unique(received_receipt.clone())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        context.replay_watermark(allocation_id).await.unwrap(),
+        received_receipt
+            .signed_receipt()
+            .message
+            .timestamp_ns
+            .as_nanos()
+    );
+}
+
 #[rstest]
 #[tokio::test]
 async fn multi_receipt_adapter_test(domain_separator: Eip712Domain, mut context: InMemoryContext) {
@@ -111,23 +179,23 @@ async fn multi_receipt_adapter_test(domain_separator: Eip712Domain, mut context:
 
     // Retreive receipts with timestamp
     assert!(context
-        .retrieve_receipts_by_timestamp(receipt_timestamps[0])
+        .retrieve_receipts_by_timestamp(receipt_timestamps[0].as_nanos())
         .await
         .is_ok());
     assert!(!context
-        .retrieve_receipts_by_timestamp(receipt_timestamps[0])
+        .retrieve_receipts_by_timestamp(receipt_timestamps[0].as_nanos())
         .await
         .unwrap()
         .is_empty());
 
     // Retreive receipts before timestamp
     assert!(context
-        .retrieve_receipts_upto_timestamp(receipt_timestamps[3])
+        .retrieve_receipts_upto_timestamp(receipt_timestamps[3].as_nanos())
         .await
         .is_ok());
     assert!(
         context
-            .retrieve_receipts_upto_timestamp(receipt_timestamps[3])
+            .retrieve_receipts_upto_timestamp(receipt_timestamps[3].as_nanos())
             .await
             .unwrap()
             .len()
@@ -150,6 +218,255 @@ async fn multi_receipt_adapter_test(domain_separator: Eip712Domain, mut context:
     }
 }
 
+#[rstest]
+#[tokio::test]
+async fn bounded_receipt_adapter_test(domain_separator: Eip712Domain, context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let context = context.with_receipt_capacity(3);
+
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+
+    // Store receipts with increasing timestamps, in a shuffled order
+    let mut timestamps = (1..=5u64).collect::<Vec<_>>();
+    timestamps.shuffle(&mut thread_rng());
+    for timestamp_ns in timestamps {
+        let received_receipt = ReceiptWithState::new(
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt {
+                    allocation_id,
+                    timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                    nonce: 0,
+                    value: 100,
+                    metadata: None,
+                    parent: None,
+                },
+                &wallet,
+            )
+            .unwrap(),
+        );
+        context.store_receipt(received_receipt).await.unwrap();
+    }
+
+    // The oldest receipts are gone, while the newest remain
+    for timestamp_ns in 1..=2 {
+        assert!(context
+            .retrieve_receipts_by_timestamp(timestamp_ns)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+    for timestamp_ns in 3..=5 {
+        assert_eq!(
+            context
+                .retrieve_receipts_by_timestamp(timestamp_ns)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    // A RAV can't be created over a range that contained evicted receipts
+    assert!(context
+        .retrieve_receipts_in_timestamp_range(1..=5, None)
+        .await
+        .is_err());
+    assert_eq!(
+        context
+            .retrieve_receipts_in_timestamp_range(3..=5, None)
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[rstest]
+#[tokio::test]
+async fn count_receipts_adapter_test(domain_separator: Eip712Domain, mut context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+
+    let allocation_id_1 = Address::from_str("0xabababababababababababababababababababab").unwrap();
+    let allocation_id_2 = Address::from_str("0xdeaddeaddeaddeaddeaddeaddeaddeaddeaddead").unwrap();
+
+    let mut receipt_ids = Vec::new();
+    for (allocation_id, timestamp_ns) in (1..=5u64)
+        .map(|timestamp_ns| (allocation_id_1, timestamp_ns))
+        .chain((10..=11u64).map(|timestamp_ns| (allocation_id_2, timestamp_ns)))
+    {
+        let received_receipt = ReceiptWithState::new(
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt {
+                    allocation_id,
+                    timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+                    nonce: 0,
+                    value: 100,
+                    metadata: None,
+                    parent: None,
+                },
+                &wallet,
+            )
+            .unwrap(),
+        );
+        receipt_ids.push(context.store_receipt(received_receipt).await.unwrap());
+    }
+
+    assert_eq!(context.count_receipts(allocation_id_1).await.unwrap(), 5);
+    assert_eq!(context.count_receipts(allocation_id_2).await.unwrap(), 2);
+    assert_eq!(context.count_receipts(Address::ZERO).await.unwrap(), 0);
+
+    // Only the receipts of the first allocation are in that range
+    context
+        .remove_receipts_in_timestamp_range(..=3)
+        .await
+        .unwrap();
+    assert_eq!(context.count_receipts(allocation_id_1).await.unwrap(), 2);
+    assert_eq!(context.count_receipts(allocation_id_2).await.unwrap(), 2);
+
+    context
+        .remove_receipt_by_id(*receipt_ids.last().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(context.count_receipts(allocation_id_1).await.unwrap(), 2);
+    assert_eq!(context.count_receipts(allocation_id_2).await.unwrap(), 1);
+}
+
+#[rstest]
+#[tokio::test]
+async fn paginated_receipts_adapter_test(domain_separator: Eip712Domain, context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+
+    let allocation_id_1 = Address::from_str("0xabababababababababababababababababababab").unwrap();
+    let allocation_id_2 = Address::from_str("0xdeaddeaddeaddeaddeaddeaddeaddeaddeaddead").unwrap();
+
+    // Receipts of the first allocation come by 3 per timestamp, interleaved with the second one's
+    let num_receipts = 600u64;
+    for nonce in 0..num_receipts {
+        let allocation_id = if nonce % 4 == 0 {
+            allocation_id_2
+        } else {
+            allocation_id_1
+        };
+        let received_receipt = ReceiptWithState::new(
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt {
+                    allocation_id,
+                    timestamp_ns: TimestampNs::from_nanos(nonce / 3),
+                    nonce,
+                    value: 100,
+                    metadata: None,
+                    parent: None,
+                },
+                &wallet,
+            )
+            .unwrap(),
+        );
+        context.store_receipt(received_receipt).await.unwrap();
+    }
+
+    let limit = 10;
+    let mut visited = HashSet::new();
+    let mut visited_count = 0;
+    let mut cursor = None;
+    loop {
+        let page = context
+            .retrieve_receipts_paginated(allocation_id_1, cursor, limit)
+            .await
+            .unwrap();
+        assert!(page.receipts.len() <= limit);
+        for rx_receipt in &page.receipts {
+            let receipt = &rx_receipt.signed_receipt().message;
+            assert_eq!(receipt.allocation_id, allocation_id_1);
+            assert!(cursor.is_none_or(|cursor| receipt.timestamp_ns.as_nanos() > cursor));
+            visited.insert(rx_receipt.signed_receipt().unique_hash());
+            visited_count += 1;
+        }
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => break,
+        }
+    }
+
+    // Every receipt of the allocation is visited exactly once
+    let expected_count = context.count_receipts(allocation_id_1).await.unwrap();
+    assert_eq!(visited_count, expected_count);
+    assert_eq!(visited.len() as u64, expected_count);
+}
+
+#[cfg(feature = "zstd")]
+#[rstest]
+#[tokio::test]
+async fn compressed_receipt_adapter_test(domain_separator: Eip712Domain) {
+    use tap_core::manager::adapters::{JsonCodec, ReceiptCodec, ZstdCodec};
+
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+
+    let receipt_storage = Arc::new(RwLock::new(HashMap::new()));
+    let context = InMemoryContext::new(
+        Arc::new(RwLock::new(HashMap::new())),
+        receipt_storage.clone(),
+        Arc::new(RwLock::new(HashMap::new())),
+        Arc::new(TimestampCheck::new(0)),
+    )
+    .with_codec(ZstdCodec::default());
+
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_id, 100u128).unwrap(),
+        &wallet,
+    )
+    .unwrap();
+    let raw_encoding = JsonCodec.encode(&signed_receipt).unwrap();
+
+    let receipt_id = context
+        .store_receipt(ReceiptWithState::new(signed_receipt.clone()))
+        .await
+        .unwrap();
+
+    // The stored blob is compressed
+    let stored_blob = receipt_storage.read().unwrap()[&receipt_id].clone();
+    assert!(stored_blob.len() < raw_encoding.len());
+    assert_eq!(
+        ZstdCodec::default().decode(&stored_blob).unwrap(),
+        signed_receipt
+    );
+
+    // The stored receipt is decoded for the uniqueness check
+    assert_eq!(
+        context
+            .check_and_store_unique(ReceiptWithState::new(signed_receipt.clone()))
+            .await
+            .unwrap(),
+        None
+    );
+
+    // Decompression is transparent on retrieval
+    let retrieved_receipt = context.retrieve_receipt_by_id(receipt_id).await.unwrap();
+    assert_eq!(*retrieved_receipt.signed_receipt(), signed_receipt);
+    assert_eq!(
+        JsonCodec
+            .encode(retrieved_receipt.signed_receipt())
+            .unwrap(),
+        raw_encoding
+    );
+}
+
 /// The test code will shuffle the input timestamps prior to calling safe_truncate_receipts.
 #[rstest]
 #[case(vec![1, 2, 3, 4, 5], 3, vec![1, 2, 3])]
@@ -178,9 +495,11 @@ fn safe_truncate_receipts_test(
                 &domain_separator,
                 Receipt {
                     allocation_id: Address::ZERO,
-                    timestamp_ns: *timestamp,
+                    timestamp_ns: TimestampNs::from_nanos(*timestamp),
                     nonce: 0,
                     value: 0,
+                    metadata: None,
+                    parent: None,
                 },
                 &wallet,
             )
@@ -201,7 +520,274 @@ fn safe_truncate_receipts_test(
         // Check timestamps
         assert_eq!(
             elem_trun.signed_receipt().message.timestamp_ns,
-            *expected_timestamp
+            TimestampNs::from_nanos(*expected_timestamp)
         );
     }
 }
+
+#[rstest]
+#[tokio::test]
+async fn context_snapshot_restore_test(
+    domain_separator: Eip712Domain,
+    mut context: InMemoryContext,
+) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let sender_id = Address::from_str("0xfbfbfbfbfbfbfbfbfbfbfbfbfbfbfbfbfbfbfbfb").unwrap();
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+    let new_receipt = || {
+        ReceiptWithState::new(
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_id, 100).unwrap(),
+                &wallet,
+            )
+            .unwrap(),
+        )
+    };
+
+    context.increase_escrow(sender_id, 500);
+    let first_receipt_id = context.store_receipt(new_receipt()).await.unwrap();
+    let snapshot = context.snapshot();
+
+    // Mutate every storage
+    let second_receipt_id = context.store_receipt(new_receipt()).await.unwrap();
+    context.reduce_escrow(sender_id, 200).unwrap();
+    let rav = ReceiptAggregateVoucher {
+        allocationId: allocation_id,
+        timestampNs: 1,
+        valueAggregate: 100,
+    };
+    context
+        .update_last_rav(EIP712SignedMessage::new(&domain_separator, rav, &wallet).unwrap())
+        .await
+        .unwrap();
+
+    context.restore(&snapshot);
+
+    assert!(context
+        .retrieve_receipt_by_id(first_receipt_id)
+        .await
+        .is_ok());
+    assert!(context
+        .retrieve_receipt_by_id(second_receipt_id)
+        .await
+        .is_err());
+    assert_eq!(context.escrow(sender_id).unwrap(), 500);
+    assert!(context.last_rav(allocation_id).await.unwrap().is_none());
+    // Receipt ids are replayed from the snapshot as well
+    assert_eq!(
+        context.store_receipt(new_receipt()).await.unwrap(),
+        second_receipt_id
+    );
+}
+
+#[rstest]
+fn receipt_recover_signer_without_checks(domain_separator: Eip712Domain) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+
+    // A zero value receipt fails the checks, but its sender can still be recovered
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(Address::from([0xabu8; 20]), 0).unwrap(),
+        &wallet,
+    )
+    .unwrap();
+    assert_eq!(
+        signed_receipt.recover_signer(&domain_separator).unwrap(),
+        convert_address::<Address>(wallet.address())
+    );
+}
+
+#[rstest]
+fn validate_single_sender_test(domain_separator: Eip712Domain) {
+    let wallets = (0..2u32)
+        .map(|index| {
+            MnemonicBuilder::<English>::default()
+                .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+                .index(index)
+                .unwrap()
+                .build()
+                .unwrap()
+        })
+        .collect::<Vec<LocalWallet>>();
+    let allocation_id = Address::from([0xabu8; 20]);
+    let receipts = [&wallets[0], &wallets[0], &wallets[1]]
+        .into_iter()
+        .map(|wallet| {
+            EIP712SignedMessage::new(
+                &domain_separator,
+                Receipt::new(allocation_id, 42).unwrap(),
+                wallet,
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let sender: Address = convert_address(wallets[0].address());
+
+    // A uniform batch returns its sender
+    assert_eq!(
+        validate_single_sender(&receipts[..2], &domain_separator).unwrap(),
+        sender
+    );
+
+    // A mixed batch is rejected at the first receipt of another sender
+    assert!(matches!(
+        validate_single_sender(&receipts, &domain_separator),
+        Err(tap_core::Error::MixedSenders { index: 2, expected, received })
+            if expected == sender && received == convert_address::<Address>(wallets[1].address())
+    ));
+
+    assert!(matches!(
+        validate_single_sender(&[], &domain_separator),
+        Err(tap_core::Error::NoValidReceiptsForRAVRequest)
+    ));
+}
+
+#[rstest]
+#[tokio::test]
+async fn receipt_metadata_adapter_test(domain_separator: Eip712Domain, context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+    let metadata = [0x42u8; 32];
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_id, 100)
+            .unwrap()
+            .with_metadata(metadata),
+        &wallet,
+    )
+    .unwrap();
+    let signer = signed_receipt.recover_signer(&domain_separator).unwrap();
+
+    let receipt_id = context
+        .store_receipt(ReceiptWithState::new(signed_receipt.clone()))
+        .await
+        .unwrap();
+    let retrieved_receipt = context.retrieve_receipt_by_id(receipt_id).await.unwrap();
+    assert_eq!(*retrieved_receipt.signed_receipt(), signed_receipt);
+    assert_eq!(
+        retrieved_receipt.signed_receipt().message.metadata,
+        Some(metadata.into())
+    );
+
+    // The metadata is signed, tampering with it or stripping it changes the recovered signer
+    for tampered_metadata in [Some(FixedBytes::ZERO), None] {
+        let mut tampered_receipt = signed_receipt.clone();
+        tampered_receipt.message.metadata = tampered_metadata;
+        assert_ne!(
+            tampered_receipt.recover_signer(&domain_separator).unwrap(),
+            signer
+        );
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn receipt_chain_adapter_test(domain_separator: Eip712Domain, context: InMemoryContext) {
+    let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+         .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+         .build()
+         .unwrap();
+    let allocation_id = Address::from_str("0xabababababababababababababababababababab").unwrap();
+
+    let first_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_id, 100).unwrap(),
+        &wallet,
+    )
+    .unwrap();
+    let second_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_id, 100)
+            .unwrap()
+            .with_parent(first_receipt.unique_hash()),
+        &wallet,
+    )
+    .unwrap();
+    assert_eq!(first_receipt.message.parent(), None);
+    let signer = second_receipt.recover_signer(&domain_separator).unwrap();
+
+    let receipt_id = context
+        .store_receipt(ReceiptWithState::new(second_receipt.clone()))
+        .await
+        .unwrap();
+    let retrieved_receipt = context.retrieve_receipt_by_id(receipt_id).await.unwrap();
+    assert_eq!(
+        retrieved_receipt.signed_receipt().message.parent(),
+        Some(first_receipt.unique_hash())
+    );
+
+    // The parent is ignored when aggregating
+    let rav = ReceiptAggregateVoucher::aggregate_receipts(
+        allocation_id,
+        &[first_receipt.clone(), second_receipt.clone()],
+        None,
+    )
+    .unwrap();
+    assert_eq!(rav.valueAggregate, 200);
+
+    // The parent is signed, re-linking the receipt to another parent changes the recovered signer
+    let mut tampered_receipt = second_receipt;
+    let tampered_parent = tampered_receipt.unique_hash();
+    tampered_receipt.message = tampered_receipt.message.with_parent(tampered_parent);
+    assert_ne!(
+        tampered_receipt.recover_signer(&domain_separator).unwrap(),
+        signer
+    );
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn checks_from_config_test() {
+    use tap_core::receipt::checks::{
+        checks_from_config, ChecksConfigError, NonZeroValueCheck, ReceiptCheck,
+    };
+
+    let available_checks: Vec<ReceiptCheck> = vec![
+        Arc::new(TimestampCheck::new(0)),
+        Arc::new(NonZeroValueCheck),
+    ];
+    let config_path = std::env::temp_dir().join(format!("tap_checks_{}.toml", std::process::id()));
+
+    std::fs::write(
+        &config_path,
+        r#"required_checks = ["tap_core::receipt::checks::NonZeroValueCheck"]"#,
+    )
+    .unwrap();
+    let checks = checks_from_config(&config_path, &available_checks).unwrap();
+    assert_eq!(checks.len(), 1);
+    assert_eq!(
+        checks[0].name(),
+        "tap_core::receipt::checks::NonZeroValueCheck"
+    );
+
+    // A typo'd check name is rejected, listing the available checks
+    std::fs::write(
+        &config_path,
+        r#"required_checks = ["tap_core::receipt::checks::TimestamCheck"]"#,
+    )
+    .unwrap();
+    let error = checks_from_config(&config_path, &available_checks)
+        .err()
+        .unwrap();
+    std::fs::remove_file(&config_path).unwrap();
+    assert!(matches!(
+        &error,
+        ChecksConfigError::UnknownCheck { name, .. } if name == "tap_core::receipt::checks::TimestamCheck"
+    ));
+    assert_eq!(
+        error.to_string(),
+        "Unknown receipt check `tap_core::receipt::checks::TimestamCheck`, expected one of: \
+         tap_core::receipt::checks::TimestampCheck, tap_core::receipt::checks::NonZeroValueCheck"
+    );
+}
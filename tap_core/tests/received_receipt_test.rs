@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     str::FromStr,
     sync::{Arc, RwLock},
 };
@@ -12,15 +12,21 @@ use alloy_sol_types::Eip712Domain;
 use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
 use rstest::*;
 use tap_core::{
+    ethers_compat::convert_address,
     manager::context::memory::{
-        checks::get_full_list_of_checks, EscrowStorage, InMemoryContext, QueryAppraisals,
+        checks::{get_full_list_of_checks, AllocationIdCheck},
+        EscrowStorage, InMemoryContext, QueryAppraisals,
     },
     receipt::{
-        checks::{ReceiptCheck, TimestampCheck},
-        Receipt, ReceiptWithState,
+        checks::{
+            AllocationLifetimeCheck, AllocationLifetimeSource, Check, CheckResult,
+            NonZeroValueCheck, PriceFeedCheck, PriceSource, ReceiptCheck, TimestampCheck,
+        },
+        Checking, Receipt, ReceiptError, ReceiptWithState,
     },
     signed_message::EIP712SignedMessage,
     tap_eip712_domain,
+    timestamp::TimestampNs,
 };
 
 #[fixture]
@@ -29,11 +35,9 @@ fn keys() -> (LocalWallet, Address) {
          .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
          .build()
          .unwrap();
-    // Alloy library does not have feature parity with ethers library (yet) This workaround is needed to get the address
-    // to convert to an alloy Address. This will not be needed when the alloy library has wallet support.
-    let address: [u8; 20] = wallet.address().into();
+    let address = convert_address(wallet.address());
 
-    (wallet, address.into())
+    (wallet, address)
 }
 
 #[fixture]
@@ -76,7 +80,7 @@ fn context(
     keys: (LocalWallet, Address),
 ) -> ContextFixture {
     let escrow_storage = Arc::new(RwLock::new(HashMap::new()));
-    let rav_storage = Arc::new(RwLock::new(None));
+    let rav_storage = Arc::new(RwLock::new(HashMap::new()));
     let receipt_storage = Arc::new(RwLock::new(HashMap::new()));
     let query_appraisals = Arc::new(RwLock::new(HashMap::new()));
 
@@ -146,6 +150,34 @@ async fn partial_then_full_check_valid_receipt(
     assert!(result.is_ok());
 }
 
+#[rstest]
+#[tokio::test]
+async fn perform_checks_timed_records_each_check(
+    keys: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    allocation_ids: Vec<Address>,
+    context: ContextFixture,
+) {
+    let ContextFixture { checks, .. } = context;
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], 20).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    let mut received_receipt = ReceiptWithState::new(signed_receipt);
+
+    let timings = received_receipt
+        .perform_checks_timed(&checks)
+        .await
+        .unwrap();
+    assert_eq!(timings.len(), checks.len());
+    for check in &checks {
+        assert!(timings.contains_key(check.name()));
+    }
+}
+
 #[rstest]
 #[tokio::test]
 async fn partial_then_finalize_valid_receipt(
@@ -242,3 +274,421 @@ async fn standard_lifetime_valid_receipt(
         .await;
     assert!(receipt.is_ok());
 }
+
+#[rstest]
+#[tokio::test]
+async fn reserved_receipt_into_checking(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+) {
+    let ContextFixture {
+        checks,
+        context,
+        escrow_storage,
+        ..
+    } = context;
+
+    let query_value = 20u128;
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], query_value).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    escrow_storage
+        .write()
+        .unwrap()
+        .insert(keys.1, query_value + 500);
+
+    let reserved_receipt = ReceiptWithState::new(signed_receipt)
+        .finalize_receipt_checks(&checks)
+        .await
+        .unwrap()
+        .check_and_reserve_escrow(&context, &domain_separator)
+        .await
+        .unwrap();
+    assert_eq!(*escrow_storage.read().unwrap().get(&keys.1).unwrap(), 500);
+
+    // Downgrading the receipt releases its escrow
+    let checking_receipt = reserved_receipt
+        .into_checking(&context, &domain_separator)
+        .await
+        .unwrap();
+    assert_eq!(
+        *escrow_storage.read().unwrap().get(&keys.1).unwrap(),
+        query_value + 500
+    );
+
+    // After the sender's escrow was reduced, re-checking the receipt fails to reserve escrow
+    escrow_storage
+        .write()
+        .unwrap()
+        .insert(keys.1, query_value - 1);
+    let receipt = checking_receipt
+        .finalize_receipt_checks(&checks)
+        .await
+        .unwrap()
+        .check_and_reserve_escrow(&context, &domain_separator)
+        .await;
+    assert!(receipt.is_err());
+}
+
+#[rstest]
+fn check_value_against_appraisal(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+) {
+    let query_value = 20u128;
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], query_value).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+
+    let received_receipt = ReceiptWithState::new(signed_receipt);
+
+    assert!(received_receipt.check_value_against(query_value).is_ok());
+    assert!(matches!(
+        received_receipt.check_value_against(query_value + 1),
+        Err(ReceiptError::InvalidValue { received_value }) if received_value == query_value
+    ));
+}
+
+#[rstest]
+#[case::check_disabled(false)]
+#[case::check_enabled(true)]
+#[tokio::test]
+async fn zero_value_receipt(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    context: ContextFixture,
+    #[case] non_zero_value_check: bool,
+) {
+    let ContextFixture { mut checks, .. } = context;
+    if non_zero_value_check {
+        checks.push(Arc::new(NonZeroValueCheck));
+    }
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], 0).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+
+    let received_receipt = ReceiptWithState::new(signed_receipt);
+
+    let result = received_receipt.finalize_receipt_checks(&checks).await;
+    assert_eq!(result.is_ok(), !non_zero_value_check);
+}
+
+/// Price feed quoting a fixed price for every receipt.
+struct MockPriceFeed(u128);
+
+#[async_trait::async_trait]
+impl PriceSource for MockPriceFeed {
+    async fn price(&self, _receipt: &Receipt) -> anyhow::Result<u128> {
+        Ok(self.0)
+    }
+}
+
+#[rstest]
+#[case::exact_price(100, true)]
+#[case::lower_bound(95, true)]
+#[case::upper_bound(105, true)]
+#[case::too_low(94, false)]
+#[case::too_high(106, false)]
+#[tokio::test]
+async fn price_feed_check(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    #[case] value: u128,
+    #[case] accepted: bool,
+) {
+    let check = PriceFeedCheck::new(Arc::new(MockPriceFeed(100)), 5);
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], value).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    let received_receipt = ReceiptWithState::new(signed_receipt);
+
+    let result = check.check(&received_receipt).await;
+    assert_eq!(result.is_ok(), accepted);
+    if !accepted {
+        assert_eq!(
+            result
+                .unwrap_err()
+                .downcast_ref::<ReceiptError>()
+                .unwrap()
+                .to_string(),
+            ReceiptError::InvalidValue {
+                received_value: value
+            }
+            .to_string()
+        );
+    }
+}
+
+/// Allocation lifetimes, as read from the chain.
+struct MockAllocationLifetimes(HashMap<Address, (u64, u64)>);
+
+#[async_trait::async_trait]
+impl AllocationLifetimeSource for MockAllocationLifetimes {
+    async fn lifetime(&self, allocation_id: Address) -> anyhow::Result<Option<(u64, u64)>> {
+        Ok(self.0.get(&allocation_id).copied())
+    }
+}
+
+#[rstest]
+#[case::before_open(999, false)]
+#[case::at_open(1_000, false)]
+#[case::within(1_500, true)]
+#[case::at_close(2_000, false)]
+#[case::after_close(2_001, false)]
+#[tokio::test]
+async fn allocation_lifetime_check(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    #[case] timestamp_ns: u64,
+    #[case] accepted: bool,
+) {
+    let check = AllocationLifetimeCheck::new(Arc::new(MockAllocationLifetimes(HashMap::from([(
+        allocation_ids[0],
+        (1_000, 2_000),
+    )]))));
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt {
+            timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+            ..Receipt::new(allocation_ids[0], 42).unwrap()
+        },
+        &keys.0,
+    )
+    .unwrap();
+    let result = check.check(&ReceiptWithState::new(signed_receipt)).await;
+    assert_eq!(result.is_ok(), accepted);
+    if !accepted {
+        assert_eq!(
+            result
+                .unwrap_err()
+                .downcast_ref::<ReceiptError>()
+                .unwrap()
+                .to_string(),
+            ReceiptError::OutsideAllocationLifetime {
+                received_timestamp: timestamp_ns,
+                open_timestamp: 1_000,
+                close_timestamp: 2_000,
+            }
+            .to_string()
+        );
+    }
+
+    // Receipts of allocations never opened on-chain are rejected as well
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt {
+            timestamp_ns: TimestampNs::from_nanos(timestamp_ns),
+            ..Receipt::new(allocation_ids[1], 42).unwrap()
+        },
+        &keys.0,
+    )
+    .unwrap();
+    assert!(check
+        .check(&ReceiptWithState::new(signed_receipt))
+        .await
+        .is_err());
+}
+
+fn closed_allocation_error(received_allocation_id: Address) -> ReceiptError {
+    ReceiptError::ClosedAllocationID {
+        received_allocation_id,
+    }
+}
+
+fn invalid_allocation_error(received_allocation_id: Address) -> ReceiptError {
+    ReceiptError::InvalidAllocationID {
+        received_allocation_id,
+    }
+}
+
+#[rstest]
+#[case::active(0, None)]
+#[case::known_but_closed(1, Some(closed_allocation_error as fn(Address) -> ReceiptError))]
+#[case::unknown(2, Some(invalid_allocation_error as fn(Address) -> ReceiptError))]
+#[tokio::test]
+async fn allocation_id_check(
+    keys: (LocalWallet, Address),
+    allocation_ids: Vec<Address>,
+    domain_separator: Eip712Domain,
+    #[case] allocation_index: usize,
+    #[case] expected_error: Option<fn(Address) -> ReceiptError>,
+) {
+    let active_allocation_ids = Arc::new(RwLock::new(HashSet::from([allocation_ids[0]])));
+    let known_allocation_ids = Arc::new(RwLock::new(HashSet::from([
+        allocation_ids[0],
+        allocation_ids[1],
+    ])));
+    let check = AllocationIdCheck::new(active_allocation_ids)
+        .with_known_allocation_ids(known_allocation_ids);
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[allocation_index], 20).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    let received_receipt = ReceiptWithState::new(signed_receipt);
+
+    let result = check.check(&received_receipt).await;
+    match expected_error {
+        None => assert!(result.is_ok()),
+        Some(expected_error) => assert_eq!(
+            result
+                .unwrap_err()
+                .downcast_ref::<ReceiptError>()
+                .unwrap()
+                .to_string(),
+            expected_error(allocation_ids[allocation_index]).to_string()
+        ),
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn allocation_id_check_rejects_zero(
+    keys: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+) {
+    // Even if the zero address made it into the active allocations
+    let check = AllocationIdCheck::new(Arc::new(RwLock::new(HashSet::from([Address::ZERO]))));
+
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(Address::ZERO, 20).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    let result = check.check(&ReceiptWithState::new(signed_receipt)).await;
+    assert!(matches!(
+        result.unwrap_err().downcast_ref::<ReceiptError>(),
+        Some(ReceiptError::ZeroAllocationID)
+    ));
+}
+
+struct EscrowCheck {
+    escrow_storage: EscrowStorage,
+    sender_id: Address,
+}
+
+#[async_trait::async_trait]
+impl Check for EscrowCheck {
+    async fn check(&self, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let escrow = self
+            .escrow_storage
+            .read()
+            .unwrap()
+            .get(&self.sender_id)
+            .copied()
+            .unwrap_or(0);
+        if escrow < receipt.signed_receipt().message.value {
+            return Err(ReceiptError::SubtractEscrowFailed.into());
+        }
+        Ok(())
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn perform_checks_collect_reports_every_failure(
+    keys: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    allocation_ids: Vec<Address>,
+) {
+    let escrow_storage: EscrowStorage = Arc::new(RwLock::new(HashMap::from([(keys.1, 50)])));
+    let checks: Vec<ReceiptCheck> = vec![
+        Arc::new(AllocationIdCheck::new(Arc::new(RwLock::new(
+            allocation_ids.iter().cloned().collect(),
+        )))),
+        Arc::new(PriceFeedCheck::new(Arc::new(MockPriceFeed(100)), 5)),
+        Arc::new(EscrowCheck {
+            escrow_storage,
+            sender_id: keys.1,
+        }),
+    ];
+
+    // Valid allocation, but both overpriced and not covered by the escrow
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], 200).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    let received_receipt = ReceiptWithState::new(signed_receipt);
+
+    let failures = received_receipt.perform_checks_collect(&checks).await;
+    assert_eq!(failures.len(), 2);
+    assert!(Arc::ptr_eq(&failures[0].0, &checks[1]));
+    assert!(matches!(
+        failures[0].1,
+        ReceiptError::InvalidValue {
+            received_value: 200
+        }
+    ));
+    assert!(Arc::ptr_eq(&failures[1].0, &checks[2]));
+    assert!(matches!(failures[1].1, ReceiptError::SubtractEscrowFailed));
+
+    // perform_checks stops at the first of them
+    let mut received_receipt = received_receipt;
+    assert!(received_receipt.perform_checks(&checks).await.is_err());
+}
+
+#[rstest]
+#[tokio::test]
+async fn state_summary_of_failed_receipt(
+    keys: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    allocation_ids: Vec<Address>,
+) {
+    let signed_receipt = EIP712SignedMessage::new(
+        &domain_separator,
+        Receipt::new(allocation_ids[0], 0).unwrap(),
+        &keys.0,
+    )
+    .unwrap();
+    let timestamp_ns = signed_receipt.message.timestamp_ns;
+    let received_receipt = ReceiptWithState::new(signed_receipt);
+
+    let summary = received_receipt.state_summary();
+    assert_eq!(summary.state, "Checking");
+    assert!(summary.failure.is_none());
+
+    let checks: Vec<ReceiptCheck> = vec![Arc::new(NonZeroValueCheck)];
+    let failed_receipt = received_receipt
+        .finalize_receipt_checks(&checks)
+        .await
+        .unwrap_err();
+
+    let summary = serde_json::to_value(failed_receipt.state_summary()).unwrap();
+    assert_eq!(summary["state"], "Failed");
+    assert_eq!(
+        summary["allocation_id"],
+        serde_json::to_value(allocation_ids[0]).unwrap()
+    );
+    assert_eq!(summary["value"], 0);
+    assert_eq!(summary["timestamp_ns"], timestamp_ns.as_nanos());
+    assert_eq!(
+        summary["failure"],
+        serde_json::json!({ "CheckFailedToComplete": "Invalid Value: 0 " })
+    );
+}
@@ -0,0 +1,190 @@
+// Copyright 2023-, Semiotic AI, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+// Test harness starting a sender aggregator and a mock Indexer in one call, such that new tests don't have to wire up
+// the adapters and servers by hand.
+use std::{
+    collections::{HashMap, HashSet},
+    net::TcpListener,
+    sync::{Arc, RwLock},
+};
+
+use alloy_primitives::Address;
+use alloy_sol_types::Eip712Domain;
+use anyhow::Result;
+use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
+use jsonrpsee::{
+    core::client::ClientT,
+    http_client::{HttpClient, HttpClientBuilder},
+    server::ServerHandle,
+};
+
+use tap_aggregator::server as agg_server;
+use tap_core::{
+    ethers_compat::convert_address,
+    manager::context::memory::{checks::get_full_list_of_checks, InMemoryContext},
+    receipt::{
+        checks::{Checks, TimestampCheck},
+        Receipt, SignedReceipt,
+    },
+    signed_message::EIP712SignedMessage,
+    tap_eip712_domain,
+};
+
+use crate::indexer_mock;
+
+/// Settings of a [`TestHarness`].
+pub struct HarnessConfig {
+    /// Number of receipts the Indexer collects before requesting a RAV
+    pub receipt_threshold: u64,
+    /// Escrow of the sender, as known by the Indexer
+    pub escrow: u128,
+}
+
+impl Default for HarnessConfig {
+    fn default() -> Self {
+        Self {
+            receipt_threshold: 1,
+            escrow: 1_000_000,
+        }
+    }
+}
+
+/// A sender aggregator and a mock Indexer, running on local ports, along with clients to reach them and the sender's
+/// signing wallet. The Indexer accepts the receipts of the sender for `allocation_id`, and requests a RAV from the
+/// aggregator every `receipt_threshold` receipts.
+pub struct TestHarness {
+    pub wallet: LocalWallet,
+    pub sender_id: Address,
+    pub allocation_id: Address,
+    pub domain_separator: Eip712Domain,
+    /// Client of the mock Indexer, whose `request` method takes a signed receipt
+    pub indexer_client: HttpClient,
+    /// Client of the sender aggregator
+    pub aggregator_client: HttpClient,
+    /// Storage of the Indexer, e.g. to read the last RAV
+    pub indexer_context: InMemoryContext,
+    indexer_handle: ServerHandle,
+    aggregator_handle: ServerHandle,
+}
+
+impl TestHarness {
+    pub async fn start() -> Result<Self> {
+        Self::start_with_config(HarnessConfig::default()).await
+    }
+
+    pub async fn start_with_config(config: HarnessConfig) -> Result<Self> {
+        let wallet: LocalWallet = MnemonicBuilder::<English>::default()
+            .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
+            .build()?;
+        let sender_id: Address = convert_address(wallet.address());
+        let allocation_id = Address::from([0xabu8; 20]);
+        let domain_separator = tap_eip712_domain(1, Address::from([0x11u8; 20]));
+
+        let (aggregator_handle, aggregator_addr) = agg_server::run_server(
+            free_port()?,
+            wallet.clone(),
+            HashSet::from([sender_id]),
+            domain_separator.clone(),
+            100 * 1024,
+            100 * 1024,
+            2,
+            4,
+            false,
+        )
+        .await?;
+
+        let mut indexer_context = InMemoryContext::new(
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(TimestampCheck::new(0)),
+        )
+        .with_sender_address(sender_id);
+        indexer_context.increase_escrow(sender_id, config.escrow);
+        let checks = Checks::new(get_full_list_of_checks(
+            domain_separator.clone(),
+            HashSet::from([sender_id]),
+            Arc::new(RwLock::new(HashSet::from([allocation_id]))),
+            Arc::new(RwLock::new(HashMap::new())),
+        ));
+        let (indexer_handle, indexer_addr) = indexer_mock::run_server(
+            free_port()?,
+            domain_separator.clone(),
+            indexer_context.clone(),
+            checks,
+            config.receipt_threshold,
+            format!("http://{}", aggregator_addr),
+            "0.0".to_string(),
+        )
+        .await?;
+
+        Ok(Self {
+            wallet,
+            sender_id,
+            allocation_id,
+            domain_separator,
+            indexer_client: HttpClientBuilder::default()
+                .build(format!("http://{}", indexer_addr))?,
+            aggregator_client: HttpClientBuilder::default()
+                .build(format!("http://{}", aggregator_addr))?,
+            indexer_context,
+            indexer_handle,
+            aggregator_handle,
+        })
+    }
+
+    /// Returns a receipt of `value` for the harness' allocation, signed by the sender.
+    pub fn signed_receipt(&self, value: u128) -> Result<SignedReceipt> {
+        Ok(EIP712SignedMessage::new(
+            &self.domain_separator,
+            Receipt::new(self.allocation_id, value)?,
+            &self.wallet,
+        )?)
+    }
+
+    /// Sends `receipt` to the Indexer, as a sender would along with a query.
+    pub async fn send_receipt(&self, receipt: SignedReceipt) -> Result<()> {
+        self.indexer_client.request("request", (receipt,)).await?;
+        Ok(())
+    }
+
+    /// Stops both servers and waits for them to shut down.
+    pub async fn stop(self) -> Result<()> {
+        self.indexer_handle.stop()?;
+        self.aggregator_handle.stop()?;
+        self.indexer_handle.stopped().await;
+        self.aggregator_handle.stopped().await;
+        Ok(())
+    }
+}
+
+fn free_port() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+#[tokio::test]
+async fn test_harness_receipt_to_rav() -> Result<()> {
+    use tap_core::manager::adapters::RAVRead;
+
+    let harness = TestHarness::start().await?;
+
+    // With the default threshold of one receipt, the receipt is aggregated right away
+    let receipt = harness.signed_receipt(42)?;
+    harness.send_receipt(receipt).await?;
+
+    let rav = harness
+        .indexer_context
+        .last_rav(harness.allocation_id)
+        .await?
+        .expect("The Indexer should have stored a RAV");
+    assert_eq!(rav.message.allocationId, harness.allocation_id);
+    assert_eq!(rav.message.valueAggregate, 42);
+    assert_eq!(
+        rav.recover_signer(&harness.domain_separator)?,
+        harness.sender_id
+    );
+
+    harness.stop().await
+}
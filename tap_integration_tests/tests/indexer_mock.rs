@@ -1,29 +1,44 @@
 // Copyright 2023-, Semiotic AI, Inc.
 // SPDX-License-Identifier: Apache-2.0
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
+    time::Duration,
 };
 
+use alloy_primitives::Address;
 use alloy_sol_types::Eip712Domain;
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 use jsonrpsee::{
-    core::{async_trait, client::ClientT},
+    core::{async_trait, client::ClientT, params::ArrayParams},
     http_client::{HttpClient, HttpClientBuilder},
     proc_macros::rpc,
     rpc_params,
     server::{ServerBuilder, ServerHandle},
 };
+use serde::de::DeserializeOwned;
 
 use tap_aggregator::jsonrpsee_helpers;
 use tap_core::{
+    clock::{Clock, SystemClock},
     manager::{
-        adapters::{EscrowHandler, RAVRead, RAVStore, ReceiptRead, ReceiptStore},
+        adapters::{EscrowHandler, RAVRead, RAVStore, ReceiptDelete, ReceiptRead, ReceiptStore},
         Manager,
     },
-    rav::SignedRAV,
-    receipt::{checks::Checks, SignedReceipt},
+    rav::{RAVRequest, SignedRAV},
+    receipt::{checks::Checks, Failed, ReceiptWithState, SignedReceipt},
+    timestamp::TimestampNs,
 };
+/// Once the receipts pending aggregation reach `MAX_PENDING_RECEIPTS_FACTOR * threshold` (e.g. because the
+/// aggregator is unreachable), new receipts are rejected with a retriable error until a RAV request succeeds.
+pub const MAX_PENDING_RECEIPTS_FACTOR: u64 = 2;
+
+/// JSON-RPC error code for receipts rejected due to back-pressure or while paused, the request can be retried later.
+const RETRIABLE_ERROR_CODE: i32 = -32001;
+
 /// Rpc trait represents a JSON-RPC server that has a single async method `request`.
 /// This method is designed to handle incoming JSON-RPC requests.
 #[rpc(server)]
@@ -34,88 +49,352 @@ pub trait Rpc {
         &self,
         receipt: SignedReceipt, // Signed receipt associated with the request
     ) -> Result<(), jsonrpsee::types::ErrorObjectOwned>; // The result of the request, a JSON-RPC error if it fails
+
+    // Pauses (`false`) or resumes (`true`) the acceptance of new receipts, e.g. for maintenance.
+    #[method(name = "set_accepting")]
+    async fn set_accepting(
+        &self,
+        accepting: bool,
+    ) -> Result<(), jsonrpsee::types::ErrorObjectOwned>;
 }
 
-/// RpcManager is a struct that implements the `Rpc` trait and it represents a JSON-RPC server manager.
-/// It includes a manager, initial_checks, receipt_count, threshold and aggregator_client.
+/// Number of receipts collected for an allocation before a RAV request is triggered for it.
+/// Allocations without a specific threshold use the default one.
+pub struct RavThresholds {
+    default: u64,
+    per_allocation: HashMap<Address, u64>,
+}
+
+impl RavThresholds {
+    pub fn new(default: u64) -> Self {
+        Self {
+            default,
+            per_allocation: HashMap::new(),
+        }
+    }
+
+    /// Sets the threshold of `allocation_id`, e.g. a lower one for a high-volume allocation to RAV more often.
+    pub fn with_allocation_threshold(mut self, allocation_id: Address, threshold: u64) -> Self {
+        self.per_allocation.insert(allocation_id, threshold);
+        self
+    }
+
+    fn threshold(&self, allocation_id: &Address) -> u64 {
+        self.per_allocation
+            .get(allocation_id)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl From<u64> for RavThresholds {
+    fn from(default: u64) -> Self {
+        Self::new(default)
+    }
+}
+
+/// RAV state of a single allocation: its manager and threshold.
 /// Manager holds an Arc to an instance of a generic `Manager` object which is shared and can be accessed by multiple threads.
-/// initial_checks is a list of checks that needs to be performed for every incoming request.
-/// receipt_count is a thread-safe counter that increments with each receipt verified and stored.
-/// threshold is a limit to which receipt_count can increment, after reaching which RAV request is triggered.
-/// aggregator_client is an HTTP client used for making JSON-RPC requests to another server.
-pub struct RpcManager<E> {
+/// threshold is the number of stored receipts, after reaching which RAV request is triggered. The receipts are
+/// counted by the storage, and removed from it once aggregated into a RAV.
+struct AllocationManager<E> {
     manager: Arc<Manager<E>>, // Manager object reference counted with an Arc
-    receipt_count: Arc<AtomicU64>, // Thread-safe atomic counter for receipts
     threshold: u64,           // The count at which a RAV request will be triggered
-    aggregator_client: (HttpClient, String), // HTTP client for sending requests to the aggregator server
+}
+
+/// Settings of the HTTP client used to reach the aggregator. The pool of connections of the client is managed by
+/// jsonrpsee, which doesn't expose its size nor keep-alive settings. Instead, a request failing on a stale pooled
+/// connection (e.g. after the aggregator restarted) is retried on a new client, with a fresh pool.
+#[derive(Debug, Clone)]
+pub struct AggregatorClientConfig {
+    pub request_timeout: Duration, // Timeout of each request to the aggregator
+    pub max_reconnects: u32, // Number of times a request failing with a connection error is retried on a new client
+}
+
+impl Default for AggregatorClientConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout: Duration::from_secs(60),
+            max_reconnects: 1,
+        }
+    }
+}
+
+/// HTTP client to the aggregator, rebuilt on connection errors, see `AggregatorClientConfig`.
+struct AggregatorConnection {
+    address: String,
+    api_version: String,
+    config: AggregatorClientConfig,
+    client: RwLock<Arc<HttpClient>>,
+}
+
+impl AggregatorConnection {
+    fn new(address: String, api_version: String, config: AggregatorClientConfig) -> Result<Self> {
+        let client = Self::build_client(&address, &config)?;
+        Ok(Self {
+            address,
+            api_version,
+            config,
+            client: RwLock::new(Arc::new(client)),
+        })
+    }
+
+    fn build_client(address: &str, config: &AggregatorClientConfig) -> Result<HttpClient> {
+        Ok(HttpClientBuilder::default()
+            .request_timeout(config.request_timeout)
+            .build(address)?)
+    }
+
+    fn client(&self) -> Arc<HttpClient> {
+        self.client
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Replaces the client, dropping its pooled connections
+    fn reconnect(&self) -> Result<()> {
+        let client = Self::build_client(&self.address, &self.config)?;
+        *self.client.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(client);
+        Ok(())
+    }
+
+    // Calls `method` with the parameters built by `params`, reconnecting on connection errors
+    async fn request<R: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: impl Fn() -> ArrayParams,
+    ) -> Result<R> {
+        let mut reconnects = 0;
+        loop {
+            match self.client().request(method, params()).await {
+                Err(jsonrpsee::core::Error::Transport(e))
+                    if reconnects < self.config.max_reconnects =>
+                {
+                    log::warn!("Connection to the aggregator failed ({}), reconnecting", e);
+                    self.reconnect()?;
+                    reconnects += 1;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+}
+
+/// RpcManager is a struct that implements the `Rpc` trait and it represents a JSON-RPC server manager.
+/// It keeps one `AllocationManager` per allocation, created from `new_context` when the first receipt for that allocation
+/// is received, such that each allocation is aggregated into its own RAVs, at its own threshold.
+/// aggregator_client is an HTTP client used for making JSON-RPC requests to the aggregator server.
+pub struct RpcManager<E> {
+    domain_separator: Eip712Domain,
+    new_context: Box<dyn Fn(Address) -> E + Send + Sync>, // Creates the context of a newly seen allocation
+    required_checks: Checks,
+    thresholds: RavThresholds,
+    allocation_managers: Mutex<HashMap<Address, Arc<AllocationManager<E>>>>,
+    aggregator_client: AggregatorConnection, // HTTP client for sending requests to the aggregator server
+    accepting: AtomicBool, // Whether new receipts are accepted, also applied to newly created managers
+    failed_receipts: Mutex<Vec<ReceiptWithState<Failed>>>, // Receipts excluded from RAV requests
+    max_clock_drift: Option<Duration>, // Maximum drift from the aggregator's clock, checked before each RAV request
 }
 
 /// Implementation for `RpcManager`, includes the constructor and the `request` method.
 /// Constructor initializes a new instance of `RpcManager`.
 /// `request` method handles incoming JSON-RPC requests and it verifies and stores the receipt from the request.
-impl<E> RpcManager<E>
-where
-    E: Clone,
-{
+impl<E> RpcManager<E> {
     pub fn new(
         domain_separator: Eip712Domain,
-        context: E,
+        new_context: impl Fn(Address) -> E + Send + Sync + 'static,
         required_checks: Checks,
-        threshold: u64,
+        thresholds: impl Into<RavThresholds>,
         aggregate_server_address: String,
         aggregate_server_api_version: String,
     ) -> Result<Self> {
         Ok(Self {
-            manager: Arc::new(Manager::<E>::new(
-                domain_separator,
-                context,
-                required_checks,
-            )),
-            receipt_count: Arc::new(AtomicU64::new(0)),
-            threshold,
-            aggregator_client: (
-                HttpClientBuilder::default().build(aggregate_server_address)?,
+            domain_separator,
+            new_context: Box::new(new_context),
+            required_checks,
+            thresholds: thresholds.into(),
+            allocation_managers: Mutex::new(HashMap::new()),
+            aggregator_client: AggregatorConnection::new(
+                aggregate_server_address,
                 aggregate_server_api_version,
-            ),
+                AggregatorClientConfig::default(),
+            )?,
+            accepting: AtomicBool::new(true),
+            failed_receipts: Mutex::new(Vec::new()),
+            max_clock_drift: None,
         })
     }
+
+    /// Replaces the default settings of the HTTP client used to reach the aggregator.
+    pub fn with_aggregator_client_config(mut self, config: AggregatorClientConfig) -> Result<Self> {
+        self.aggregator_client = AggregatorConnection::new(
+            self.aggregator_client.address,
+            self.aggregator_client.api_version,
+            config,
+        )?;
+        Ok(self)
+    }
+
+    /// Reads the aggregator's time before each RAV request, and refuses to request a RAV if the local clock drifts
+    /// from it by more than `max_clock_drift`. Otherwise, the drift is compensated for when selecting the receipts.
+    pub fn with_max_clock_drift(mut self, max_clock_drift: Duration) -> Self {
+        self.max_clock_drift = Some(max_clock_drift);
+        self
+    }
+
+    /// Number of receipts stored for `allocation_id` since its last RAV.
+    pub async fn receipt_count(&self, allocation_id: Address) -> Result<u64>
+    where
+        E: ReceiptRead,
+    {
+        let allocation = self.lock_allocation_managers().get(&allocation_id).cloned();
+        match allocation {
+            Some(allocation) => Ok(allocation.manager.count_receipts(allocation_id).await?),
+            None => Ok(0),
+        }
+    }
+
+    /// Receipts found invalid while creating RAV requests, hence excluded from the RAVs, along with the reason.
+    pub fn failed_receipts(&self) -> Vec<ReceiptWithState<Failed>> {
+        self.failed_receipts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Aggregates the pending receipts of every allocation into a final RAV, whatever their threshold, such that
+    /// none are stranded until the next start. Meant to be called when shutting down. Returns the new RAVs.
+    pub async fn drain_to_ravs(&self) -> Result<Vec<SignedRAV>>
+    where
+        E: ReceiptRead + ReceiptDelete + RAVRead + RAVStore + EscrowHandler,
+    {
+        let allocations = self
+            .lock_allocation_managers()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut ravs = Vec::new();
+        for allocation in allocations {
+            let allocation_ravs = allocation
+                .manager
+                .drain_to_ravs(|rav_request| async move {
+                    aggregate_receipts(&self.aggregator_client, &rav_request).await
+                })
+                .await?;
+            ravs.extend(allocation_ravs.into_values());
+        }
+        Ok(ravs)
+    }
+
+    /// Locks the allocation managers, even if a panic happened while they were locked. The panic can only
+    /// have happened while creating a manager, before it was inserted, so the map itself is never left
+    /// half-updated. Otherwise, a single panicking request would make every later request panic too.
+    fn lock_allocation_managers(
+        &self,
+    ) -> MutexGuard<'_, HashMap<Address, Arc<AllocationManager<E>>>> {
+        self.allocation_managers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn allocation_manager(&self, allocation_id: Address) -> Arc<AllocationManager<E>> {
+        self.lock_allocation_managers()
+            .entry(allocation_id)
+            .or_insert_with(|| {
+                let manager = Manager::new(
+                    self.domain_separator.clone(),
+                    (self.new_context)(allocation_id),
+                    self.required_checks.clone(),
+                );
+                manager.set_accepting(self.accepting.load(Ordering::SeqCst));
+                Arc::new(AllocationManager {
+                    manager: Arc::new(manager),
+                    threshold: self.thresholds.threshold(&allocation_id),
+                })
+            })
+            .clone()
+    }
 }
 
 #[async_trait]
 impl<E> RpcServer for RpcManager<E>
 where
-    E: ReceiptStore + ReceiptRead + RAVStore + RAVRead + EscrowHandler + Send + Sync + 'static,
+    E: ReceiptStore
+        + ReceiptRead
+        + ReceiptDelete
+        + RAVStore
+        + RAVRead
+        + EscrowHandler
+        + Send
+        + Sync
+        + 'static,
 {
     async fn request(
         &self,
         receipt: SignedReceipt,
     ) -> Result<(), jsonrpsee::types::ErrorObjectOwned> {
-        let verify_result = match self.manager.verify_and_store_receipt(receipt).await {
+        let time_stamp_buffer = TimestampNs::ZERO;
+        let allocation_id = receipt.message.allocation_id;
+        let allocation = self.allocation_manager(allocation_id);
+
+        // Apply back-pressure if too many receipts are pending aggregation, unless a RAV request now succeeds
+        let pending_receipts = allocation
+            .manager
+            .count_receipts(allocation_id)
+            .await
+            .map_err(|e| to_rpc_error(e.into(), "Failed to count receipts"))?;
+        if pending_receipts >= MAX_PENDING_RECEIPTS_FACTOR * allocation.threshold
+            && request_rav(
+                &allocation.manager,
+                time_stamp_buffer,
+                &self.aggregator_client,
+                &self.failed_receipts,
+                self.max_clock_drift,
+                pending_receipts,
+            )
+            .await
+            .is_err()
+        {
+            return Err(jsonrpsee::types::ErrorObject::owned(
+                RETRIABLE_ERROR_CODE,
+                "Too many receipts pending aggregation, retry later",
+                None::<()>,
+            ));
+        }
+
+        let verify_result = match allocation.manager.verify_and_store_receipt(receipt).await {
             Ok(_) => Ok(()),
-            Err(e) => Err(to_rpc_error(
-                Box::new(e),
-                "Failed to verify and store receipt",
-            )),
+            Err(tap_core::Error::ServiceUnavailable) => {
+                return Err(jsonrpsee::types::ErrorObject::owned(
+                    RETRIABLE_ERROR_CODE,
+                    "Receipts are not accepted at the moment, retry later",
+                    None::<()>,
+                ))
+            }
+            Err(e) => Err(to_rpc_error(e.into(), "Failed to verify and store receipt")),
         };
 
-        // Increment the receipt count
-        self.receipt_count.fetch_add(1, Ordering::Relaxed);
-        let rav_request_valid = if self.receipt_count.load(Ordering::SeqCst) >= self.threshold {
-            // Reset the counter after reaching the threshold
-            self.receipt_count.store(0, Ordering::SeqCst);
-
-            // Create the aggregate_receipts request params
-            let time_stamp_buffer = 0;
+        let pending_receipts = allocation
+            .manager
+            .count_receipts(allocation_id)
+            .await
+            .map_err(|e| to_rpc_error(e.into(), "Failed to count receipts"))?;
+        let rav_request_valid = if pending_receipts >= allocation.threshold {
+            // The receipts are only removed once a RAV is received, such that a failed request is retried
             match request_rav(
-                &self.manager,
+                &allocation.manager,
                 time_stamp_buffer,
                 &self.aggregator_client,
-                self.threshold as usize,
+                &self.failed_receipts,
+                self.max_clock_drift,
+                pending_receipts,
             )
             .await
             {
                 Ok(_) => Ok(()),
-                Err(e) => Err(to_rpc_error(e.into(), "Failed to request rav")),
+                Err(e) => Err(to_rpc_error(e, "Failed to request rav")),
             }
         } else {
             Ok(())
@@ -127,6 +406,19 @@ where
             (Err(e), _) | (_, Err(e)) => Err(e),
         }
     }
+
+    async fn set_accepting(
+        &self,
+        accepting: bool,
+    ) -> Result<(), jsonrpsee::types::ErrorObjectOwned> {
+        // Hold the lock while updating, such that a manager created concurrently can't miss the change
+        let allocation_managers = self.lock_allocation_managers();
+        self.accepting.store(accepting, Ordering::SeqCst);
+        for allocation in allocation_managers.values() {
+            allocation.manager.set_accepting(accepting);
+        }
+        Ok(())
+    }
 }
 
 /// run_server function initializes and starts a JSON-RPC server that handles incoming requests.
@@ -142,6 +434,7 @@ pub async fn run_server<E>(
 where
     E: ReceiptStore
         + ReceiptRead
+        + ReceiptDelete
         + RAVStore
         + RAVRead
         + EscrowHandler
@@ -158,9 +451,10 @@ where
         .await?;
     let addr = server.local_addr()?;
     println!("Listening on: {}", addr);
+    // All the allocations share the given context
     let rpc_manager = RpcManager::new(
         domain_separator,
-        context,
+        move |_| context.clone(),
         required_checks,
         threshold,
         aggregate_server_address,
@@ -174,42 +468,108 @@ where
 // request_rav function creates a request for aggregate receipts (RAV), sends it to another server and verifies the result.
 async fn request_rav<E>(
     manager: &Arc<Manager<E>>,
-    time_stamp_buffer: u64, // Buffer for timestamping, see tap_core for details
-    aggregator_client: &(HttpClient, String), // HttpClient for making requests to the tap_aggregator server
-    threshold: usize,
+    time_stamp_buffer: TimestampNs, // Buffer for timestamping, see tap_core for details
+    aggregator_client: &AggregatorConnection, // HttpClient for making requests to the tap_aggregator server
+    failed_receipts: &Mutex<Vec<ReceiptWithState<Failed>>>, // Collects the receipts excluded from the RAV request
+    max_clock_drift: Option<Duration>, // Maximum drift from the aggregator's clock, unchecked if `None`
+    expected_receipt_count: u64, // Receipts stored since the last RAV, all expected in the RAV request
 ) -> Result<()>
 where
-    E: ReceiptRead + RAVRead + RAVStore + EscrowHandler,
+    E: ReceiptRead + ReceiptDelete + RAVRead + RAVStore + EscrowHandler,
 {
-    // Create the aggregate_receipts request params
+    let time_stamp_buffer = match max_clock_drift {
+        Some(max_clock_drift) => {
+            let drift_ns = clock_drift_ns(aggregator_client).await?;
+            if drift_ns.unsigned_abs() > max_clock_drift.as_nanos() {
+                bail!(
+                    "Clock drift with the aggregator ({} ns) exceeds the maximum ({:?})",
+                    drift_ns,
+                    max_clock_drift
+                );
+            }
+            // When ahead of the aggregator, only select the receipts that are old enough from its point of view
+            TimestampNs::from_nanos(time_stamp_buffer.as_nanos() + drift_ns.max(0) as u64)
+        }
+        None => time_stamp_buffer,
+    };
     let rav_request = manager.create_rav_request(time_stamp_buffer, None).await?;
 
-    // To-do: Need to add previous RAV, when tap_manager supports replacing receipts
-    let params = rpc_params!(
-        &aggregator_client.1,
-        &rav_request.valid_receipts,
-        rav_request.previous_rav
-    );
+    // Invalid receipts are not sent to the aggregator, keep track of them instead of silently dropping them
+    for invalid_receipt in &rav_request.invalid_receipts {
+        let receipt = &invalid_receipt.signed_receipt().message;
+        log::warn!(
+            "Receipt excluded from RAV request, allocation: {}, value: {}, reason: {}",
+            receipt.allocation_id,
+            receipt.value,
+            invalid_receipt.error()
+        );
+    }
+    failed_receipts
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .extend(rav_request.invalid_receipts.iter().cloned());
 
-    // Call the aggregate_receipts method on the other server
-    let remote_rav_result: jsonrpsee_helpers::JsonRpcResponse<SignedRAV> = aggregator_client
-        .0
-        .request("aggregate_receipts", params)
-        .await?;
+    let remote_rav = aggregate_receipts(aggregator_client, &rav_request).await?;
     manager
-        .verify_and_store_rav(rav_request.expected_rav, remote_rav_result.data)
+        .verify_and_store_rav(rav_request.expected_rav, remote_rav)
         .await?;
+    // The aggregated receipts are not needed anymore, which also resets the receipt count
+    manager.remove_obsolete_receipts().await?;
 
-    // For these tests, we expect every receipt to be valid, i.e. there should be no invalid receipts, nor any missing receipts (less than the expected threshold).
-    // If there is throw an error.
-    match rav_request.invalid_receipts.is_empty() && (rav_request.valid_receipts.len() == threshold)
-    {
+    // For these tests, we expect every receipt to be valid, i.e. there should be no invalid receipts, nor any missing receipts (less than the expected count).
+    // If there is throw an error, carrying the reason of the first invalid receipt for the sender.
+    if let Some(invalid_receipt) = rav_request.invalid_receipts.first() {
+        return Err(Error::new(tap_core::Error::ReceiptError(
+            invalid_receipt.error().clone(),
+        ))
+        .context("Invalid receipts found"));
+    }
+    match rav_request.valid_receipts.len() as u64 == expected_receipt_count {
         true => Ok(()),
         false => Err(Error::msg("Invalid receipts found")),
     }?;
     Ok(())
 }
 
-fn to_rpc_error(e: Box<dyn std::error::Error>, msg: &str) -> jsonrpsee::types::ErrorObjectOwned {
-    jsonrpsee::types::ErrorObject::owned(-32000, format!("{} - {}", e, msg), None::<()>)
+// clock_drift_ns function returns how far ahead of the aggregator's clock the local clock is, in nanoseconds.
+async fn clock_drift_ns(
+    aggregator_client: &AggregatorConnection, // HttpClient for making requests to the tap_aggregator server
+) -> Result<i128> {
+    let server_time: jsonrpsee_helpers::JsonRpcResponse<u64> = aggregator_client
+        .request("server_time", || rpc_params!())
+        .await?;
+    Ok(SystemClock.now_ns()? as i128 - server_time.data as i128)
+}
+
+// aggregate_receipts function sends a RAV request to the tap_aggregator server, and returns the signed RAV.
+async fn aggregate_receipts(
+    aggregator_client: &AggregatorConnection, // HttpClient for making requests to the tap_aggregator server
+    rav_request: &RAVRequest,
+) -> Result<SignedRAV> {
+    // Create the aggregate_receipts request params
+    let params = || {
+        rpc_params!(
+            &aggregator_client.api_version,
+            &rav_request.valid_receipts,
+            &rav_request.previous_rav
+        )
+    };
+
+    // Call the aggregate_receipts method on the other server
+    let remote_rav_result: jsonrpsee_helpers::RawJsonRpcResponse = aggregator_client
+        .request("aggregate_receipts", params)
+        .await?;
+    // Catch a damaged response here, before its signature is checked by `verify_and_store_rav`
+    let remote_rav_result = remote_rav_result.verify_checksum::<SignedRAV>()?;
+    Ok(remote_rav_result.data)
+}
+
+// to_rpc_error function returns a JSON-RPC error, with the receipt error (if any) in its `data` field, such that the
+// sender can act on it, e.g. deposit the shortfall of `ReceiptError::InsufficientEscrow`.
+fn to_rpc_error(e: Error, msg: &str) -> jsonrpsee::types::ErrorObjectOwned {
+    let receipt_error = match e.downcast_ref::<tap_core::Error>() {
+        Some(tap_core::Error::ReceiptError(receipt_error)) => Some(receipt_error.clone()),
+        _ => None,
+    };
+    jsonrpsee::types::ErrorObject::owned(-32000, format!("{} - {}", e, msg), receipt_error)
 }
@@ -8,33 +8,43 @@ use std::{
     collections::{HashMap, HashSet},
     convert::TryInto,
     net::{SocketAddr, TcpListener},
+    panic::AssertUnwindSafe,
     str::FromStr,
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use alloy_primitives::Address;
 use alloy_sol_types::Eip712Domain;
 use anyhow::{Error, Result};
 use ethers::signers::{coins_bip39::English, LocalWallet, MnemonicBuilder, Signer};
+use futures::FutureExt;
 use jsonrpsee::{
-    core::client::ClientT, http_client::HttpClientBuilder, rpc_params, server::ServerHandle,
+    core::client::ClientT,
+    http_client::HttpClientBuilder,
+    proc_macros::rpc,
+    rpc_params,
+    server::{ServerBuilder, ServerHandle},
 };
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use rstest::*;
 
 use tap_aggregator::{jsonrpsee_helpers, server as agg_server};
 use tap_core::{
+    clock::{Clock, SystemClock},
+    ethers_compat::convert_address,
     manager::context::memory::{checks::get_full_list_of_checks, *},
     rav::SignedRAV,
     receipt::{
         checks::{Checks, TimestampCheck},
-        Receipt,
+        Receipt, ReceiptError,
     },
     signed_message::{EIP712SignedMessage, MessageId},
     tap_eip712_domain,
+    timestamp::TimestampNs,
 };
 
-use crate::indexer_mock;
+use crate::indexer_mock::{self, RpcServer};
 
 // Fixtures for sender aggregator server
 #[fixture]
@@ -52,6 +62,11 @@ fn http_max_concurrent_connections() -> u32 {
     2
 }
 
+#[fixture]
+fn max_concurrent_aggregations() -> u32 {
+    4
+}
+
 #[fixture]
 fn aggregate_server_api_version() -> String {
     "0.0".to_string()
@@ -89,11 +104,9 @@ fn keys_sender() -> (LocalWallet, Address) {
     .phrase("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about")
     .build()
     .unwrap();
-    // Alloy library does not have feature parity with ethers library (yet) This workaround is needed to get the address
-    // to convert to an alloy Address. This will not be needed when the alloy library has wallet support.
-    let address: [u8; 20] = wallet.address().into();
+    let address = convert_address(wallet.address());
 
-    (wallet, address.into())
+    (wallet, address)
 }
 
 // The private key (LocalWallet) and public key (Address) of a Sender. This key is used to test when the Sender's key differs from the Indexer's expectation.
@@ -103,11 +116,9 @@ fn wrong_keys_sender() -> (LocalWallet, Address) {
         .phrase("devote force reopen galaxy humor virtual hobby chief grit nothing bag pulse")
         .build()
         .unwrap();
-    // Alloy library does not have feature parity with ethers library (yet) This workaround is needed to get the address
-    // to convert to an alloy Address. This will not be needed when the alloy library has wallet support.
-    let address: [u8; 20] = wallet.address().into();
+    let address = convert_address(wallet.address());
 
-    (wallet, address.into())
+    (wallet, address)
 }
 
 // Allocation IDs are used to ensure receipts cannot be double-counted
@@ -181,7 +192,7 @@ fn context(
 ) -> ContextFixture {
     let receipt_storage = Arc::new(RwLock::new(HashMap::new()));
     let escrow_storage = Arc::new(RwLock::new(HashMap::new()));
-    let rav_storage = Arc::new(RwLock::new(None));
+    let rav_storage = Arc::new(RwLock::new(HashMap::new()));
     let timestamp_check = Arc::new(TimestampCheck::new(0));
     let context = InMemoryContext::new(
         rav_storage,
@@ -281,6 +292,8 @@ fn repeated_timestamp_request(
         timestamp_ns: repeat_timestamp,
         nonce: target_receipt.nonce,
         value: target_receipt.value,
+        metadata: target_receipt.metadata,
+        parent: target_receipt.parent,
     };
 
     // Sign the new receipt and insert it in the second batch
@@ -309,16 +322,21 @@ fn repeated_timestamp_incremented_by_one_request(
     );
 
     // Create a new receipt with the timestamp equal to the latest receipt timestamp+1 in the first RAV request batch
-    let repeat_timestamp = requests[receipt_threshold_1 as usize - 1]
-        .message
-        .timestamp_ns
-        + 1;
+    let repeat_timestamp = TimestampNs::from_nanos(
+        requests[receipt_threshold_1 as usize - 1]
+            .message
+            .timestamp_ns
+            .as_nanos()
+            + 1,
+    );
     let target_receipt = &requests[receipt_threshold_1 as usize].message;
     let repeat_receipt = Receipt {
         allocation_id: target_receipt.allocation_id,
         timestamp_ns: repeat_timestamp,
         nonce: target_receipt.nonce,
         value: target_receipt.value,
+        metadata: target_receipt.metadata,
+        parent: target_receipt.parent,
     };
 
     // Sign the new receipt and insert it in the second batch
@@ -591,9 +609,10 @@ async fn test_manager_wrong_aggregator_keys(
         // The rav request is being made with messages that have been signed with a key that differs from the sender aggregator's.
         // So the Sender Aggregator should send an error to the requesting Indexer.
         // And so the Indexer should then return an error to the clinet when a rav request is made.
-        // A rav request is made when the number of receipts sent = receipt_threshold_1.
-        // result should be an error when counter = multiple of receipt_threshold_1 and Ok otherwise.
-        if (counter % receipt_threshold_1) == 0 {
+        // A rav request is made when the number of receipts sent = receipt_threshold_1, and retried on every
+        // following receipt since the receipt count is only reset once a RAV is received.
+        // result should be an error when counter >= receipt_threshold_1 and Ok otherwise.
+        if counter >= receipt_threshold_1 {
             assert!(
                 result.is_err(),
                 "Sender Aggregator should have sent an error to the Indexer."
@@ -636,6 +655,477 @@ async fn test_manager_wrong_requestor_keys(
     Ok(())
 }
 
+#[rstest]
+#[tokio::test]
+async fn test_manager_aggregator_outage(
+    keys_sender: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    indexer_1_context: ContextFixture,
+    available_escrow: u128,
+    receipt_threshold_1: u64,
+    requests_1: Vec<EIP712SignedMessage<Receipt>>,
+    allocation_ids: Vec<Address>,
+) -> Result<()> {
+    // Nothing listens on that port, the aggregator is unreachable
+    let unreachable_port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+    let ContextFixture {
+        mut context,
+        checks,
+    } = indexer_1_context;
+    context.increase_escrow(keys_sender.1, available_escrow);
+    let context = context.with_sender_address(keys_sender.1);
+    let rpc_manager = indexer_mock::RpcManager::new(
+        domain_separator,
+        move |_| context.clone(),
+        checks,
+        receipt_threshold_1,
+        format!("http://127.0.0.1:{}", unreachable_port),
+        aggregate_server_api_version(),
+    )?;
+
+    let max_pending_receipts = indexer_mock::MAX_PENDING_RECEIPTS_FACTOR * receipt_threshold_1;
+    assert!(requests_1.len() as u64 > max_pending_receipts);
+
+    let mut counter = 1;
+    for receipt_1 in requests_1 {
+        let result = rpc_manager.request(receipt_1).await;
+        // Every RAV request fails from the threshold on, and receipts are rejected once too many are pending.
+        if counter >= receipt_threshold_1 {
+            assert!(result.is_err(), "RAV request should have failed");
+        } else {
+            assert!(result.is_ok(), "Error making receipt request: {:?}", result);
+        }
+        // The receipt count is not reset on failure, and stops growing once back-pressure kicks in.
+        assert_eq!(
+            rpc_manager.receipt_count(allocation_ids[0]).await?,
+            counter.min(max_pending_receipts)
+        );
+        counter += 1;
+    }
+
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_manager_aggregator_restart(
+    keys_sender: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    http_request_size_limit: u32,
+    http_response_size_limit: u32,
+    http_max_concurrent_connections: u32,
+    indexer_1_context: ContextFixture,
+    available_escrow: u128,
+    receipt_threshold_1: u64,
+    requests_1: Vec<EIP712SignedMessage<Receipt>>,
+    allocation_ids: Vec<Address>,
+) -> Result<()> {
+    let aggregator_port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let start_aggregator = || {
+        agg_server::run_server(
+            aggregator_port,
+            keys_sender.0.clone(),
+            HashSet::from([keys_sender.1]),
+            domain_separator.clone(),
+            http_request_size_limit,
+            http_response_size_limit,
+            http_max_concurrent_connections,
+            max_concurrent_aggregations(),
+            false,
+        )
+    };
+    let (aggregator_handle, _) = start_aggregator().await?;
+
+    let ContextFixture {
+        mut context,
+        checks,
+    } = indexer_1_context;
+    context.increase_escrow(keys_sender.1, available_escrow);
+    let context = context.with_sender_address(keys_sender.1);
+    let rpc_manager = indexer_mock::RpcManager::new(
+        domain_separator.clone(),
+        move |_| context.clone(),
+        checks,
+        receipt_threshold_1,
+        format!("http://127.0.0.1:{}", aggregator_port),
+        aggregate_server_api_version(),
+    )?
+    .with_aggregator_client_config(indexer_mock::AggregatorClientConfig {
+        request_timeout: Duration::from_secs(10),
+        max_reconnects: 2,
+    })?;
+
+    let mut requests = requests_1.into_iter();
+    for receipt_1 in requests.by_ref().take(receipt_threshold_1 as usize) {
+        let result = rpc_manager.request(receipt_1).await;
+        assert!(result.is_ok(), "Error making receipt request: {:?}", result);
+    }
+    assert_eq!(rpc_manager.receipt_count(allocation_ids[0]).await?, 0);
+
+    // Restart the aggregator, the connections pooled by the indexer are now stale
+    aggregator_handle.stop()?;
+    aggregator_handle.stopped().await;
+    let (_aggregator_handle, _) = start_aggregator().await?;
+
+    // The next RAV request succeeds after reconnecting
+    for receipt_1 in requests.take(receipt_threshold_1 as usize) {
+        let result = rpc_manager.request(receipt_1).await;
+        assert!(result.is_ok(), "Error making receipt request: {:?}", result);
+    }
+    assert_eq!(rpc_manager.receipt_count(allocation_ids[0]).await?, 0);
+
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_manager_poisoned_lock(
+    keys_sender: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    indexer_1_context: ContextFixture,
+    available_escrow: u128,
+    receipt_threshold_1: u64,
+    requests_1: Vec<EIP712SignedMessage<Receipt>>,
+    requests_2: Vec<EIP712SignedMessage<Receipt>>,
+    allocation_ids: Vec<Address>,
+) -> Result<()> {
+    let ContextFixture {
+        mut context,
+        checks,
+    } = indexer_1_context;
+    context.increase_escrow(keys_sender.1, available_escrow);
+    let context = context.with_sender_address(keys_sender.1);
+    // Creating the context of the second allocation panics, while the allocations are locked
+    let faulty_allocation_id = allocation_ids[1];
+    let rpc_manager = indexer_mock::RpcManager::new(
+        domain_separator,
+        move |allocation_id| {
+            assert_ne!(allocation_id, faulty_allocation_id, "faulty context");
+            context.clone()
+        },
+        checks,
+        receipt_threshold_1,
+        "http://127.0.0.1:0".to_string(),
+        aggregate_server_api_version(),
+    )?;
+
+    let panicked = AssertUnwindSafe(rpc_manager.request(requests_2[0].clone()))
+        .catch_unwind()
+        .await;
+    assert!(panicked.is_err());
+
+    // Later requests are still served
+    let result = rpc_manager.request(requests_1[0].clone()).await;
+    assert!(result.is_ok(), "Error making receipt request: {:?}", result);
+    assert_eq!(rpc_manager.receipt_count(allocation_ids[0]).await?, 1);
+    assert_eq!(rpc_manager.receipt_count(faulty_allocation_id).await?, 0);
+
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_manager_per_allocation_thresholds(
+    keys_sender: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    http_request_size_limit: u32,
+    http_response_size_limit: u32,
+    http_max_concurrent_connections: u32,
+    indexer_1_context: ContextFixture,
+    available_escrow: u128,
+    receipt_threshold_1: u64,
+    receipt_threshold_2: u64,
+    requests_1: Vec<EIP712SignedMessage<Receipt>>,
+    requests_2: Vec<EIP712SignedMessage<Receipt>>,
+    allocation_ids: Vec<Address>,
+) -> Result<()> {
+    let sender_id = keys_sender.1;
+    let (sender_handle, sender_addr) = start_sender_aggregator(
+        keys_sender,
+        domain_separator.clone(),
+        http_request_size_limit,
+        http_response_size_limit,
+        http_max_concurrent_connections,
+    )
+    .await?;
+
+    // Each allocation gets its own storage, hence its own RAVs
+    let new_context = move |_: Address| {
+        let mut context = InMemoryContext::new(
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(TimestampCheck::new(0)),
+        );
+        context.increase_escrow(sender_id, available_escrow);
+        context.with_sender_address(sender_id)
+    };
+    // The first allocation RAVs more often than the others
+    let thresholds = indexer_mock::RavThresholds::new(receipt_threshold_2)
+        .with_allocation_threshold(allocation_ids[0], receipt_threshold_1);
+    let rpc_manager = indexer_mock::RpcManager::new(
+        domain_separator,
+        new_context,
+        indexer_1_context.checks,
+        thresholds,
+        format!("http://{}", sender_addr),
+        aggregate_server_api_version(),
+    )?;
+
+    let mut counter = 1;
+    for (receipt_1, receipt_2) in requests_1.into_iter().zip(requests_2) {
+        for receipt in [receipt_1, receipt_2] {
+            let result = rpc_manager.request(receipt).await;
+            assert!(result.is_ok(), "Error making receipt request: {:?}", result);
+        }
+        // Each allocation's receipt count is reset by its own RAVs, at its own threshold
+        assert_eq!(
+            rpc_manager.receipt_count(allocation_ids[0]).await?,
+            counter % receipt_threshold_1
+        );
+        assert_eq!(
+            rpc_manager.receipt_count(allocation_ids[1]).await?,
+            counter % receipt_threshold_2
+        );
+        counter += 1;
+    }
+
+    sender_handle.stop()?;
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_manager_drain_to_ravs(
+    keys_sender: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    http_request_size_limit: u32,
+    http_response_size_limit: u32,
+    http_max_concurrent_connections: u32,
+    indexer_1_context: ContextFixture,
+    available_escrow: u128,
+    requests_1: Vec<EIP712SignedMessage<Receipt>>,
+    requests_2: Vec<EIP712SignedMessage<Receipt>>,
+    allocation_ids: Vec<Address>,
+) -> Result<()> {
+    let sender_id = keys_sender.1;
+    let (sender_handle, sender_addr) = start_sender_aggregator(
+        keys_sender,
+        domain_separator.clone(),
+        http_request_size_limit,
+        http_response_size_limit,
+        http_max_concurrent_connections,
+    )
+    .await?;
+
+    // Each allocation gets its own storage, hence its own RAVs
+    let new_context = move |_: Address| {
+        let mut context = InMemoryContext::new(
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(TimestampCheck::new(0)),
+        );
+        context.increase_escrow(sender_id, available_escrow);
+        context.with_sender_address(sender_id)
+    };
+    // The threshold is never reached
+    let num_receipts = 5;
+    let rpc_manager = indexer_mock::RpcManager::new(
+        domain_separator,
+        new_context,
+        indexer_1_context.checks,
+        num_receipts + 1,
+        format!("http://{}", sender_addr),
+        aggregate_server_api_version(),
+    )?;
+
+    let receipts_1 = requests_1.into_iter().take(num_receipts as usize);
+    let receipts_2 = requests_2.into_iter().take(num_receipts as usize);
+    for receipt in receipts_1.chain(receipts_2) {
+        let result = rpc_manager.request(receipt).await;
+        assert!(result.is_ok(), "Error making receipt request: {:?}", result);
+    }
+    for allocation_id in &allocation_ids {
+        assert_eq!(
+            rpc_manager.receipt_count(*allocation_id).await?,
+            num_receipts
+        );
+    }
+
+    // On shutdown, the pending receipts of both allocations are aggregated
+    let ravs = rpc_manager.drain_to_ravs().await?;
+    let rav_allocation_ids = ravs
+        .iter()
+        .map(|rav| rav.message.allocationId)
+        .collect::<HashSet<_>>();
+    assert_eq!(rav_allocation_ids, allocation_ids.iter().cloned().collect());
+    for allocation_id in &allocation_ids {
+        assert_eq!(rpc_manager.receipt_count(*allocation_id).await?, 0);
+    }
+
+    // Nothing is left to drain
+    assert!(rpc_manager.drain_to_ravs().await?.is_empty());
+
+    sender_handle.stop()?;
+    Ok(())
+}
+
+// Aggregator whose clock lags by `skew_ns`, only serving its time
+#[rpc(server)]
+trait SkewedAggregator {
+    #[method(name = "server_time")]
+    fn server_time(&self) -> jsonrpsee_helpers::JsonRpcResult<u64>;
+}
+
+struct SkewedAggregatorImpl {
+    skew_ns: u64,
+}
+
+impl SkewedAggregatorServer for SkewedAggregatorImpl {
+    fn server_time(&self) -> jsonrpsee_helpers::JsonRpcResult<u64> {
+        let now_ns = SystemClock.now_ns().unwrap();
+        Ok(jsonrpsee_helpers::JsonRpcResponse::ok(
+            now_ns - self.skew_ns,
+        ))
+    }
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_manager_max_clock_drift(
+    keys_sender: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    indexer_1_context: ContextFixture,
+    available_escrow: u128,
+    receipt_threshold_1: u64,
+    requests_1: Vec<EIP712SignedMessage<Receipt>>,
+    allocation_ids: Vec<Address>,
+) -> Result<()> {
+    let server = ServerBuilder::new().build("127.0.0.1:0").await?;
+    let aggregator_addr = server.local_addr()?;
+    let aggregator_handle = server.start(
+        SkewedAggregatorImpl {
+            skew_ns: Duration::from_secs(60).as_nanos() as u64,
+        }
+        .into_rpc(),
+    )?;
+
+    let ContextFixture {
+        mut context,
+        checks,
+    } = indexer_1_context;
+    context.increase_escrow(keys_sender.1, available_escrow);
+    let context = context.with_sender_address(keys_sender.1);
+    let rpc_manager = indexer_mock::RpcManager::new(
+        domain_separator,
+        move |_| context.clone(),
+        checks,
+        receipt_threshold_1,
+        format!("http://{}", aggregator_addr),
+        aggregate_server_api_version(),
+    )?
+    .with_max_clock_drift(Duration::from_secs(1));
+
+    let mut counter = 1;
+    for receipt_1 in requests_1.into_iter().take(receipt_threshold_1 as usize) {
+        let result = rpc_manager.request(receipt_1).await;
+        if counter < receipt_threshold_1 {
+            assert!(result.is_ok(), "Error making receipt request: {:?}", result);
+        } else {
+            // The RAV request is refused before selecting any receipt, which are kept for later
+            let error = result.unwrap_err();
+            assert!(error.message().contains("Clock drift"), "{:?}", error);
+        }
+        counter += 1;
+    }
+    assert_eq!(
+        rpc_manager.receipt_count(allocation_ids[0]).await?,
+        receipt_threshold_1
+    );
+
+    aggregator_handle.stop()?;
+    Ok(())
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_manager_keeps_invalid_receipts(
+    keys_sender: (LocalWallet, Address),
+    domain_separator: Eip712Domain,
+    http_request_size_limit: u32,
+    http_response_size_limit: u32,
+    http_max_concurrent_connections: u32,
+    indexer_1_context: ContextFixture,
+    receipt_threshold_1: u64,
+    requests_1: Vec<EIP712SignedMessage<Receipt>>,
+    allocation_ids: Vec<Address>,
+) -> Result<()> {
+    let sender_id = keys_sender.1;
+    let (sender_handle, sender_addr) = start_sender_aggregator(
+        keys_sender,
+        domain_separator.clone(),
+        http_request_size_limit,
+        http_response_size_limit,
+        http_max_concurrent_connections,
+    )
+    .await?;
+
+    let receipts = requests_1
+        .into_iter()
+        .take(receipt_threshold_1 as usize)
+        .collect::<Vec<_>>();
+    // The escrow covers every receipt but the last one, which fails when reserving escrow for the RAV request
+    let escrow: u128 = receipts[..receipts.len() - 1]
+        .iter()
+        .map(|receipt| receipt.message.value)
+        .sum();
+    let ContextFixture {
+        mut context,
+        checks,
+    } = indexer_1_context;
+    context.increase_escrow(sender_id, escrow);
+    let context = context.with_sender_address(sender_id);
+    let rpc_manager = indexer_mock::RpcManager::new(
+        domain_separator,
+        move |_| context.clone(),
+        checks,
+        receipt_threshold_1,
+        format!("http://{}", sender_addr),
+        aggregate_server_api_version(),
+    )?;
+
+    let (last_receipt, receipts) = receipts.split_last().unwrap();
+    for receipt in receipts {
+        let result = rpc_manager.request(receipt.clone()).await;
+        assert!(result.is_ok(), "Error making receipt request: {:?}", result);
+    }
+    assert!(rpc_manager.failed_receipts().is_empty());
+
+    // The other receipts are aggregated, while the last one is reported rather than lost
+    let result = rpc_manager.request(last_receipt.clone()).await;
+    let error = result.expect_err("Should have reported the invalid receipt");
+    let failed_receipts = rpc_manager.failed_receipts();
+    assert_eq!(failed_receipts.len(), 1);
+    assert_eq!(failed_receipts[0].signed_receipt(), last_receipt);
+    // The escrow covers all the receipts but the last one, which is missing in full
+    let last_value = last_receipt.message.value;
+    assert!(matches!(
+        failed_receipts[0].error(),
+        &ReceiptError::InsufficientEscrow { required, available: 0, shortfall }
+            if required == last_value && shortfall == last_value
+    ));
+    // The sender is told the shortfall through the JSON-RPC error
+    assert!(error
+        .data()
+        .is_some_and(|data| data.get().contains("InsufficientEscrow")));
+    assert_eq!(rpc_manager.receipt_count(allocation_ids[0]).await?, 1);
+
+    sender_handle.stop()?;
+    Ok(())
+}
+
 #[rstest]
 #[tokio::test]
 async fn test_tap_manager_rav_timestamp_cuttoff(
@@ -863,6 +1353,8 @@ async fn start_sender_aggregator(
         http_request_size_limit,
         http_response_size_limit,
         http_max_concurrent_connections,
+        max_concurrent_aggregations(),
+        false,
     )
     .await?;
 